@@ -0,0 +1,31 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "grpc")]
+    grpc();
+}
+
+/// Generate the service of proto/fdsum.proto for the messages defined
+/// in src/grpc.rs, without needing protoc
+#[cfg(feature = "grpc")]
+fn grpc() {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    let method = |name: &str, route: &str, input: &str| {
+        Method::builder()
+            .name(name)
+            .route_name(route)
+            .input_type(format!("crate::grpc::{}", input))
+            .output_type("crate::grpc::Update")
+            .codec_path("tonic::codec::ProstCodec")
+            .server_streaming()
+            .build()
+    };
+    let service = Service::builder()
+        .name("Fdsum")
+        .package("fdsum.v1")
+        .method(method("hash", "Hash", "HashRequest"))
+        .method(method("verify", "Verify", "VerifyRequest"))
+        .method(method("diff", "Diff", "DiffRequest"))
+        .build();
+    Builder::new().compile(&[service]);
+}
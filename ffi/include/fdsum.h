@@ -0,0 +1,47 @@
+/* C interface to fdsum, see ffi/src/lib.rs for details */
+#ifndef FDSUM_H
+#define FDSUM_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct FdsumJob fdsum_job;
+
+typedef struct {
+    uint64_t entries_total;
+    uint64_t entries_done;
+    uint64_t bytes_total;
+    uint64_t bytes_done;
+    double elapsed_seconds;
+} fdsum_progress;
+
+/* Start hashing path on a background thread. flags may be NULL for the
+ * defaults, threads 0 for the default. Returns NULL on invalid input. */
+fdsum_job *fdsum_job_start(const char *path, const char *flags, unsigned int threads);
+
+/* Fill out (may be NULL) with the progress; returns 1 once finished. */
+int fdsum_job_progress(const fdsum_job *job, fdsum_progress *out);
+
+/* Wait for the job; result JSON or NULL on failure. */
+char *fdsum_job_result_json(const fdsum_job *job);
+
+/* Wait for the job; error message or NULL on success. */
+char *fdsum_job_error(const fdsum_job *job);
+
+/* Ask the job to stop. */
+void fdsum_job_cancel(const fdsum_job *job);
+
+/* Cancel if running, wait and release the job. */
+void fdsum_job_free(fdsum_job *job);
+
+/* Release a string returned by fdsum_job_result_json or fdsum_job_error. */
+void fdsum_string_free(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,209 @@
+//! C bindings for fdsum. A job runs on a background thread; its
+//! progress can be polled and it can be cancelled. See
+//! `include/fdsum.h` for the C declarations.
+
+use fdsum::{CancelToken, HashJob, HashResultJson, StatsSnapshot};
+use std::ffi::{CStr, CString, c_char, c_int, c_uint};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Progress of a job as reported by `fdsum_job_progress`
+#[repr(C)]
+pub struct FdsumProgress {
+    pub entries_total: u64,
+    pub entries_done: u64,
+    pub bytes_total: u64,
+    pub bytes_done: u64,
+    pub elapsed_seconds: f64,
+}
+
+impl From<&StatsSnapshot> for FdsumProgress {
+    fn from(stats: &StatsSnapshot) -> Self {
+        Self {
+            entries_total: stats.entries_total,
+            entries_done: stats.entries_done,
+            bytes_total: stats.bytes_total,
+            bytes_done: stats.bytes_done,
+            elapsed_seconds: stats.elapsed.as_secs_f64(),
+        }
+    }
+}
+
+type Outcome = Result<HashResultJson, String>;
+
+pub struct FdsumJob {
+    cancel: CancelToken,
+    progress: Arc<Mutex<Option<StatsSnapshot>>>,
+    handle: Mutex<Option<JoinHandle<Outcome>>>,
+    outcome: Mutex<Option<Outcome>>,
+}
+
+impl FdsumJob {
+    /// Wait for the job thread and keep its outcome
+    fn join(&self) {
+        let handle = self.handle.lock().unwrap().take();
+        if let Some(handle) = handle {
+            let outcome = handle
+                .join()
+                .unwrap_or_else(|_| Err("job panicked".to_string()));
+            *self.outcome.lock().unwrap() = Some(outcome);
+        }
+    }
+}
+
+fn to_c_string(s: String) -> *mut c_char {
+    CString::new(s.replace('\0', "")).unwrap().into_raw()
+}
+
+/// Start hashing `path` on a background thread. `flags` is a flags
+/// string such as "v1:sha256:cspugm" or NULL for the defaults,
+/// `threads` the number of worker threads or 0 for the default.
+/// Returns NULL if the arguments are invalid.
+///
+/// # Safety
+///
+/// `path` must be a valid NUL-terminated string, `flags` NULL or a
+/// valid NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fdsum_job_start(
+    path: *const c_char,
+    flags: *const c_char,
+    threads: c_uint,
+) -> *mut FdsumJob {
+    if path.is_null() {
+        return std::ptr::null_mut();
+    }
+    let Ok(path) = unsafe { CStr::from_ptr(path) }.to_str().map(str::to_string) else {
+        return std::ptr::null_mut();
+    };
+
+    let progress = Arc::new(Mutex::new(None));
+    let sink = progress.clone();
+    let mut builder = HashJob::builder()
+        .progress_interval(Duration::from_millis(100))
+        .progress(move |stats| *sink.lock().unwrap() = Some(*stats));
+    if !flags.is_null() {
+        let Ok(flags) = unsafe { CStr::from_ptr(flags) }.to_str() else {
+            return std::ptr::null_mut();
+        };
+        builder = builder.flags(flags);
+    }
+    if threads > 0 {
+        builder = builder.threads(threads as usize);
+    }
+    let Ok(job) = builder.build() else {
+        return std::ptr::null_mut();
+    };
+
+    let cancel = job.cancel_token();
+    let handle = std::thread::spawn(move || job.run(path).map_err(|e| format!("{:#}", e)));
+    Box::into_raw(Box::new(FdsumJob {
+        cancel,
+        progress,
+        handle: Mutex::new(Some(handle)),
+        outcome: Mutex::new(None),
+    }))
+}
+
+/// Fill `out` with the current progress of the job. Returns 1 if the
+/// job has finished, 0 if it is still running.
+///
+/// # Safety
+///
+/// `job` must be a pointer returned by `fdsum_job_start`, `out` NULL or
+/// a valid pointer.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fdsum_job_progress(
+    job: *const FdsumJob,
+    out: *mut FdsumProgress,
+) -> c_int {
+    let job = unsafe { &*job };
+    if let (Some(stats), Some(out)) = (job.progress.lock().unwrap().as_ref(), unsafe {
+        out.as_mut()
+    }) {
+        *out = FdsumProgress::from(stats);
+    }
+    let running = job
+        .handle
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|h| !h.is_finished());
+    c_int::from(!running)
+}
+
+/// Wait for the job to finish and return its result as JSON, or NULL
+/// if it failed. The string must be released with `fdsum_string_free`.
+///
+/// # Safety
+///
+/// `job` must be a pointer returned by `fdsum_job_start`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fdsum_job_result_json(job: *const FdsumJob) -> *mut c_char {
+    let job = unsafe { &*job };
+    job.join();
+    match job.outcome.lock().unwrap().as_ref() {
+        Some(Ok(result)) => match serde_json::to_string(result) {
+            Ok(json) => to_c_string(json),
+            Err(_) => std::ptr::null_mut(),
+        },
+        _ => std::ptr::null_mut(),
+    }
+}
+
+/// Wait for the job to finish and return its error message, or NULL
+/// if it succeeded. The string must be released with
+/// `fdsum_string_free`.
+///
+/// # Safety
+///
+/// `job` must be a pointer returned by `fdsum_job_start`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fdsum_job_error(job: *const FdsumJob) -> *mut c_char {
+    let job = unsafe { &*job };
+    job.join();
+    match job.outcome.lock().unwrap().as_ref() {
+        Some(Err(err)) => to_c_string(err.clone()),
+        _ => std::ptr::null_mut(),
+    }
+}
+
+/// Ask the job to stop. It finishes with an error shortly after.
+///
+/// # Safety
+///
+/// `job` must be a pointer returned by `fdsum_job_start`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fdsum_job_cancel(job: *const FdsumJob) {
+    unsafe { &*job }.cancel.cancel();
+}
+
+/// Cancel the job if still running, wait for it and release it
+///
+/// # Safety
+///
+/// `job` must be NULL or a pointer returned by `fdsum_job_start` that
+/// has not been freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fdsum_job_free(job: *mut FdsumJob) {
+    if job.is_null() {
+        return;
+    }
+    let job = unsafe { Box::from_raw(job) };
+    job.cancel.cancel();
+    job.join();
+}
+
+/// Release a string returned by this library
+///
+/// # Safety
+///
+/// `s` must be NULL or a string returned by this library that has not
+/// been freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fdsum_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(unsafe { CString::from_raw(s) });
+    }
+}
@@ -0,0 +1,154 @@
+//! Python bindings for fdsum. Results are returned as dicts with the
+//! same keys as the JSON output of the command line tool.
+
+use fdsum::tree::DiffKind;
+use fdsum::{HashJob, HashResultJson, StatsSnapshot};
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList, PyString};
+use std::path::PathBuf;
+
+fn error(err: anyhow::Error) -> PyErr {
+    PyRuntimeError::new_err(format!("{:#}", err))
+}
+
+fn stats_dict<'py>(py: Python<'py>, stats: &StatsSnapshot) -> PyResult<Bound<'py, PyDict>> {
+    let dict = PyDict::new(py);
+    dict.set_item("entries_total", stats.entries_total)?;
+    dict.set_item("entries_done", stats.entries_done)?;
+    dict.set_item("bytes_total", stats.bytes_total)?;
+    dict.set_item("bytes_done", stats.bytes_done)?;
+    dict.set_item("elapsed_seconds", stats.elapsed.as_secs_f64())?;
+    Ok(dict)
+}
+
+/// `result` as a dict, by way of its JSON so that the keys cannot
+/// drift from the JSON output
+fn result_dict<'py>(py: Python<'py>, result: &HashResultJson) -> PyResult<Bound<'py, PyDict>> {
+    let json = serde_json::to_string(result).map_err(|err| error(err.into()))?;
+    Ok(py
+        .import("json")?
+        .call_method1("loads", (json,))?
+        .downcast_into::<PyDict>()?)
+}
+
+/// Build a job, passing progress to a Python callable. Exceptions
+/// raised by the callback are reported as unraisable.
+fn build_job(
+    flags: Option<&str>,
+    threads: Option<usize>,
+    archive: bool,
+    progress: Option<PyObject>,
+) -> PyResult<HashJob> {
+    let mut builder = HashJob::builder().archive(archive);
+    if let Some(flags) = flags {
+        builder = builder.flags(flags);
+    }
+    if let Some(threads) = threads {
+        builder = builder.threads(threads);
+    }
+    if let Some(callback) = progress {
+        builder = builder.progress(move |stats| {
+            Python::with_gil(|py| {
+                let outcome = stats_dict(py, stats).and_then(|s| callback.call1(py, (s,)));
+                if let Err(err) = outcome {
+                    err.write_unraisable(py, Some(callback.bind(py)));
+                }
+            })
+        });
+    }
+    builder.build().map_err(error)
+}
+
+/// Hash the tree at `path` and return the result as a dict
+#[pyfunction]
+#[pyo3(signature = (path, flags=None, threads=None, archive=false, progress=None))]
+fn hash<'py>(
+    py: Python<'py>,
+    path: PathBuf,
+    flags: Option<&str>,
+    threads: Option<usize>,
+    archive: bool,
+    progress: Option<PyObject>,
+) -> PyResult<Bound<'py, PyDict>> {
+    let job = build_job(flags, threads, archive, progress)?;
+    let result = py.allow_threads(|| job.run(&path)).map_err(error)?;
+    result_dict(py, &result)
+}
+
+/// Verify a tree against a manifest, given as a dict as returned by
+/// `hash` or as the path of a JSON file. Unless `path` is given, the
+/// tree named in the manifest is hashed.
+#[pyfunction]
+#[pyo3(signature = (manifest, path=None, threads=None, archive=false, progress=None))]
+fn verify<'py>(
+    py: Python<'py>,
+    manifest: &Bound<'py, PyAny>,
+    path: Option<PathBuf>,
+    threads: Option<usize>,
+    archive: bool,
+    progress: Option<PyObject>,
+) -> PyResult<Bound<'py, PyDict>> {
+    let reference: HashResultJson = if let Ok(file) = manifest.downcast::<PyString>() {
+        fdsum::job::read_manifest(file.to_str()?).map_err(error)?
+    } else {
+        let json = py
+            .import("json")?
+            .call_method1("dumps", (manifest,))?
+            .extract::<String>()?;
+        HashResultJson::parse(&json).map_err(error)?
+    };
+    let job = build_job(None, threads, archive, progress)?;
+    let verification = py
+        .allow_threads(|| job.verify(reference, path.as_deref()))
+        .map_err(error)?;
+
+    let dict = PyDict::new(py);
+    dict.set_item("match", verification.is_match())?;
+    dict.set_item("reference", result_dict(py, &verification.reference)?)?;
+    dict.set_item("result", result_dict(py, &verification.result)?)?;
+    Ok(dict)
+}
+
+/// Compare two trees and return the differing entries as a list of
+/// dicts with `path` and `kind`
+#[pyfunction]
+#[pyo3(signature = (left, right, flags=None, threads=None, progress=None))]
+fn diff<'py>(
+    py: Python<'py>,
+    left: PathBuf,
+    right: PathBuf,
+    flags: Option<&str>,
+    threads: Option<usize>,
+    progress: Option<PyObject>,
+) -> PyResult<Bound<'py, PyList>> {
+    let job = build_job(flags, threads, false, progress)?;
+    let differences = py
+        .allow_threads(|| job.diff(&left, &right))
+        .map_err(error)?;
+
+    let list = PyList::empty(py);
+    for difference in differences {
+        let kind = match difference.kind {
+            DiffKind::OnlyLeft => "only_left",
+            DiffKind::OnlyRight => "only_right",
+            DiffKind::Type => "type",
+            DiffKind::Metadata => "metadata",
+            DiffKind::Content => "content",
+        };
+        let dict = PyDict::new(py);
+        dict.set_item("path", difference.path.to_string_lossy())?;
+        dict.set_item("kind", kind)?;
+        list.append(dict)?;
+    }
+    Ok(list)
+}
+
+#[pymodule]
+#[pyo3(name = "fdsum")]
+fn fdsum_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(hash, m)?)?;
+    m.add_function(wrap_pyfunction!(verify, m)?)?;
+    m.add_function(wrap_pyfunction!(diff, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,127 @@
+//! Report which accelerated implementations the hash algorithms use on
+//! this CPU. The sha2, blake3 and crc crates choose at runtime; the
+//! detection here mirrors theirs.
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Backends {
+    pub sha256: &'static str,
+    pub blake3: &'static str,
+    pub crc32: &'static str,
+    pub crc32c: &'static str,
+}
+
+impl Backends {
+    pub fn detect() -> Self {
+        Self {
+            sha256: sha256_backend(),
+            blake3: blake3_backend(),
+            crc32: crc32_backend(),
+            crc32c: crc32c_backend(),
+        }
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn sha256_backend() -> &'static str {
+    if is_x86_feature_detected!("sha")
+        && is_x86_feature_detected!("sse2")
+        && is_x86_feature_detected!("ssse3")
+        && is_x86_feature_detected!("sse4.1")
+    {
+        "sha-ni"
+    } else {
+        "portable"
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn sha256_backend() -> &'static str {
+    if std::arch::is_aarch64_feature_detected!("sha2") {
+        "armv8-sha2"
+    } else {
+        "portable"
+    }
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+fn sha256_backend() -> &'static str {
+    "portable"
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn blake3_backend() -> &'static str {
+    if is_x86_feature_detected!("avx512f") && is_x86_feature_detected!("avx512vl") {
+        "avx512"
+    } else if is_x86_feature_detected!("avx2") {
+        "avx2"
+    } else if is_x86_feature_detected!("sse4.1") {
+        "sse4.1"
+    } else if is_x86_feature_detected!("sse2") {
+        "sse2"
+    } else {
+        "portable"
+    }
+}
+
+/// blake3 uses NEON unconditionally when built with its `neon` feature
+#[cfg(target_arch = "aarch64")]
+fn blake3_backend() -> &'static str {
+    "neon"
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+fn blake3_backend() -> &'static str {
+    "portable"
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn crc32_backend() -> &'static str {
+    if is_x86_feature_detected!("pclmulqdq")
+        && is_x86_feature_detected!("sse4.1")
+        && is_x86_feature_detected!("ssse3")
+    {
+        "pclmulqdq"
+    } else {
+        "portable"
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn crc32_backend() -> &'static str {
+    if std::arch::is_aarch64_feature_detected!("crc") {
+        "armv8-crc"
+    } else {
+        "portable"
+    }
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+fn crc32_backend() -> &'static str {
+    "portable"
+}
+
+/// crc32c uses SSE 4.2 on x86_64 only
+#[cfg(target_arch = "x86_64")]
+fn crc32c_backend() -> &'static str {
+    if is_x86_feature_detected!("sse4.2") {
+        "sse4.2"
+    } else {
+        "portable"
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn crc32c_backend() -> &'static str {
+    if std::arch::is_aarch64_feature_detected!("crc") {
+        "armv8-crc"
+    } else {
+        "portable"
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn crc32c_backend() -> &'static str {
+    "portable"
+}
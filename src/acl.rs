@@ -0,0 +1,69 @@
+//! NFSv4 ACLs as exposed by the `system.nfs4_acl` extended attribute,
+//! the XDR encoding of RFC 7530's `nfsace4` list. The order of the
+//! entries is kept, since it decides which entry applies.
+
+use anyhow::{Result, anyhow};
+
+/// The name of the extended attribute
+pub const NFS4_ACL_XATTR: &str = "system.nfs4_acl";
+
+/// Re-encode the ACL `raw` so that equal ACLs are equal byte for byte:
+/// without the padding of XDR, and with the domains of principals in
+/// lowercase, as they compare. Each entry is written as its type,
+/// flags, access mask and the length and bytes of its principal.
+pub fn canonical_nfs4(raw: &[u8]) -> Result<Vec<u8>> {
+    let mut reader = Xdr { data: raw };
+    let count = reader.u32()?;
+    let mut out = Vec::with_capacity(raw.len());
+    out.extend(count.to_le_bytes());
+    for _ in 0..count {
+        // type, flags and access mask
+        for _ in 0..3 {
+            out.extend(reader.u32()?.to_le_bytes());
+        }
+        let who = reader.opaque()?;
+        let who = match who.iter().position(|&b| b == b'@') {
+            // OWNER@, GROUP@ and EVERYONE@ have no domain
+            Some(at) if at + 1 < who.len() => {
+                let mut who = who.to_vec();
+                who[at + 1..].make_ascii_lowercase();
+                who
+            }
+            _ => who.to_vec(),
+        };
+        out.extend((who.len() as u32).to_le_bytes());
+        out.extend(who);
+    }
+    if !reader.data.is_empty() {
+        return Err(anyhow!("Invalid NFSv4 ACL: trailing data"));
+    }
+    Ok(out)
+}
+
+struct Xdr<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Xdr<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(anyhow!("Invalid NFSv4 ACL: truncated"));
+        }
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes(bytes.try_into().expect("4 bytes")))
+    }
+
+    /// Bytes preceded by their length and padded to a multiple of four
+    fn opaque(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?;
+        self.take(len.next_multiple_of(4) - len)?;
+        Ok(bytes)
+    }
+}
@@ -0,0 +1,131 @@
+//! Pinning the threads of a run to a set of CPUs, or to the NUMA node
+//! of the storage read. Read buffers are allocated by the threads
+//! filling them, and so on the node they run on.
+
+use anyhow::{Result, anyhow};
+use std::fmt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// CPUs by number, as listed by `taskset -c`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuSet(pub Vec<usize>);
+
+impl CpuSet {
+    /// Parse a list of CPU numbers and ranges, e.g. `0-3,8,10-11`
+    pub fn parse(value: &str) -> Result<Self> {
+        let mut cpus = Vec::new();
+        for part in value.split(',').map(str::trim) {
+            let number = |s: &str| {
+                s.trim()
+                    .parse::<usize>()
+                    .map_err(|_| anyhow!("Not a CPU number: {}", s))
+            };
+            let (first, last) = match part.split_once('-') {
+                Some((first, last)) => (number(first)?, number(last)?),
+                None => (number(part)?, number(part)?),
+            };
+            if first > last {
+                return Err(anyhow!("Empty range of CPUs: {}", part));
+            }
+            cpus.extend(first..=last);
+        }
+        cpus.sort_unstable();
+        cpus.dedup();
+        Ok(Self(cpus))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Display for CpuSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cpus: Vec<String> = self.0.iter().map(usize::to_string).collect();
+        write!(f, "{}", cpus.join(","))
+    }
+}
+
+/// Restrict the calling thread, and the threads it starts from then
+/// on, to `cpus`
+#[cfg(target_os = "linux")]
+pub fn pin(cpus: &CpuSet) -> Result<()> {
+    // SAFETY: cpu_set_t is a plain bit set, valid when zeroed
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    let max = 8 * std::mem::size_of::<libc::cpu_set_t>();
+    for &cpu in &cpus.0 {
+        if cpu >= max {
+            return Err(anyhow!("No CPU {}", cpu));
+        }
+        // SAFETY: cpu is within the set
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+    // SAFETY: set is initialized and its size given
+    if unsafe { libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set) } != 0 {
+        let err = std::io::Error::last_os_error();
+        return Err(anyhow!("Failed to pin to CPUs {}: {}", cpus, err));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin(_cpus: &CpuSet) -> Result<()> {
+    Err(anyhow!("Pinning to CPUs is only supported on Linux"))
+}
+
+/// The NUMA node to run on: the one the storage holding the tree is
+/// attached to, or the one given
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NumaNode {
+    Auto,
+    Node(usize),
+}
+
+impl NumaNode {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "auto" => Ok(Self::Auto),
+            _ => value
+                .parse()
+                .map(Self::Node)
+                .map_err(|_| anyhow!("Expected auto or a node number: {}", value)),
+        }
+    }
+
+    /// The CPUs of the node, for the tree at `path`. `None` if the node
+    /// of the storage is not known, e.g. on hosts with a single node.
+    pub fn cpus(&self, path: &Path) -> Result<Option<CpuSet>> {
+        let node = match self {
+            Self::Node(node) => *node,
+            Self::Auto => match storage_node(path) {
+                Some(node) => node,
+                None => return Ok(None),
+            },
+        };
+        let file = format!("/sys/devices/system/node/node{}/cpulist", node);
+        let list = std::fs::read_to_string(&file).map_err(|_| anyhow!("No NUMA node {}", node))?;
+        CpuSet::parse(list.trim()).map(Some)
+    }
+}
+
+/// The NUMA node of the device holding `path`, as reported by the
+/// closest of the devices it is attached through
+fn storage_node(path: &Path) -> Option<usize> {
+    let dev = std::fs::metadata(path).ok()?.dev();
+    let device = std::fs::canonicalize(format!(
+        "/sys/dev/block/{}:{}",
+        libc::major(dev),
+        libc::minor(dev)
+    ))
+    .ok()?;
+    device.ancestors().find_map(|dir| {
+        let node = std::fs::read_to_string(dir.join("numa_node")).ok()?;
+        // -1 for devices not attached to a node
+        node.trim().parse::<usize>().ok()
+    })
+}
@@ -1,3 +1,5 @@
+use md5::Md5;
+use sha1::Sha1;
 use sha2::{Digest, Sha256};
 
 pub trait Hasher: Send {
@@ -5,15 +7,50 @@ pub trait Hasher: Send {
     fn finalize(self: Box<Self>) -> [u8; 32];
 }
 
-impl Hasher for Sha256 {
+/// Digests shorter than 32 bytes are written to the start of the output
+macro_rules! digest_hasher {
+    ($($digest:ty),*) => {$(
+        impl Hasher for $digest {
+            fn update(&mut self, data: &[u8]) {
+                Digest::update(self, data);
+            }
+
+            fn finalize(self: Box<Self>) -> [u8; 32] {
+                let result = Digest::finalize(*self);
+                let mut out = [0u8; 32];
+                out[..result.len()].copy_from_slice(&result);
+                out
+            }
+        }
+    )*};
+}
+
+digest_hasher!(Sha256, Sha1, Md5);
+
+/// CRC digests are written big-endian to the start of the output
+impl Hasher for crc32fast::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        crc32fast::Hasher::update(self, data);
+    }
+
+    fn finalize(self: Box<Self>) -> [u8; 32] {
+        let mut out = [0u8; 32];
+        out[..4].copy_from_slice(&crc32fast::Hasher::finalize(*self).to_be_bytes());
+        out
+    }
+}
+
+#[derive(Default)]
+pub struct Crc32c(u32);
+
+impl Hasher for Crc32c {
     fn update(&mut self, data: &[u8]) {
-        Digest::update(self, data);
+        self.0 = crc32c::crc32c_append(self.0, data);
     }
 
     fn finalize(self: Box<Self>) -> [u8; 32] {
-        let result = Digest::finalize(*self);
         let mut out = [0u8; 32];
-        out.copy_from_slice(&result);
+        out[..4].copy_from_slice(&self.0.to_be_bytes());
         out
     }
 }
@@ -26,6 +63,12 @@ impl Blake3Wrapper {
     }
 }
 
+impl Default for Blake3Wrapper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Hasher for Blake3Wrapper {
     fn update(&mut self, data: &[u8]) {
         self.0.update(data);
@@ -35,3 +78,18 @@ impl Hasher for Blake3Wrapper {
         *self.0.finalize().as_bytes()
     }
 }
+
+#[derive(Default)]
+pub struct K12Wrapper(k12::KangarooTwelve<'static>);
+
+impl Hasher for K12Wrapper {
+    fn update(&mut self, data: &[u8]) {
+        k12::digest::Update::update(&mut self.0, data);
+    }
+
+    fn finalize(self: Box<Self>) -> [u8; 32] {
+        let mut out = [0u8; 32];
+        k12::digest::ExtendableOutput::finalize_xof_into(self.0, &mut out);
+        out
+    }
+}
@@ -0,0 +1,273 @@
+use anyhow::{Context, Result, anyhow};
+use flate2::read::GzDecoder;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Component, Path};
+
+use crate::config::Config;
+use crate::hash::{EntryMeta, hash_stream};
+use crate::tree::{Node, NodeKind, VirtualTree};
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFREG: u32 = 0o100000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFIFO: u32 = 0o010000;
+
+/// Archive formats that can be hashed as virtual trees
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ArchiveFormat {
+    /// Detect the archive format from the leading bytes of the file
+    pub fn detect(file: &mut File) -> Result<Self> {
+        let mut magic = [0u8; 512];
+        let mut n = 0;
+        while n < magic.len() {
+            let read = file.read(&mut magic[n..])?;
+            if read == 0 {
+                break;
+            }
+            n += read;
+        }
+        file.seek(SeekFrom::Start(0))?;
+        let magic = &magic[..n];
+
+        if magic.starts_with(b"PK\x03\x04") || magic.starts_with(b"PK\x05\x06") {
+            Ok(ArchiveFormat::Zip)
+        } else if magic.starts_with(b"\x1f\x8b") {
+            Ok(ArchiveFormat::TarGz)
+        } else if magic.get(257..262) == Some(b"ustar") {
+            Ok(ArchiveFormat::Tar)
+        } else {
+            Err(anyhow!("unsupported archive format"))
+        }
+    }
+}
+
+/// Read the archive at `path` into a virtual tree
+pub fn read_archive(config: &Config, path: &Path) -> Result<VirtualTree> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open archive: {}", path.display()))?;
+    let format = ArchiveFormat::detect(&mut file).with_context(|| path.display().to_string())?;
+    let mut tree = match format {
+        ArchiveFormat::Zip => read_zip(config, file),
+        ArchiveFormat::Tar => read_tar(config, BufReader::new(file)),
+        ArchiveFormat::TarGz => read_tar(config, GzDecoder::new(BufReader::new(file))),
+    }
+    .with_context(|| path.display().to_string())?;
+    tree.fill_dir_meta();
+    // all at once rather than one at a time while hashing
+    let missing = tree.missing_meta(config);
+    if !missing.is_empty() {
+        let (what, hints): (Vec<_>, Vec<_>) = missing.into_iter().unzip();
+        return Err(anyhow!(
+            "{}: {} not available for all members ({})",
+            path.display(),
+            what.join(", "),
+            hints.join(", ")
+        ));
+    }
+    config.stats.done_entries(1);
+    Ok(tree)
+}
+
+/// Hash the members of the archive at `path` as if it were extracted
+pub fn hash_archive(config: &Config, path: &Path) -> Result<[u8; 32]> {
+    read_archive(config, path)?.digest(config, path)
+}
+
+fn file_node(config: &Config, meta: EntryMeta, reader: &mut dyn Read, name: &Path) -> Result<Node> {
+    let content = if config.include_file_content {
+        config.stats.add_bytes(meta.size.unwrap_or(0));
+        let digest = hash_stream(config, reader)
+            .with_context(|| format!("Failed to read archive member: {}", name.display()))?;
+        Some(digest)
+    } else {
+        None
+    };
+    Ok(Node {
+        meta,
+        kind: NodeKind::File(content),
+    })
+}
+
+/// Parse the Info-ZIP "ux" extra field (0x7875) for the owner UID/GID
+fn zip_owner(extra: &[u8]) -> Option<(u32, u32)> {
+    let mut rest = extra;
+    while rest.len() >= 4 {
+        let id = u16::from_le_bytes([rest[0], rest[1]]);
+        let len = u16::from_le_bytes([rest[2], rest[3]]) as usize;
+        let data = rest.get(4..4 + len)?;
+        if id == 0x7875 && data.first() == Some(&1) {
+            let uid_len = *data.get(1)? as usize;
+            let uid = data.get(2..2 + uid_len)?;
+            let gid_len = *data.get(2 + uid_len)? as usize;
+            let gid = data.get(3 + uid_len..3 + uid_len + gid_len)?;
+            let to_u32 = |b: &[u8]| {
+                (b.len() <= 4).then(|| b.iter().rev().fold(0u32, |acc, &x| acc << 8 | x as u32))
+            };
+            return Some((to_u32(uid)?, to_u32(gid)?));
+        }
+        rest = &rest[4 + len..];
+    }
+    None
+}
+
+fn read_zip(config: &Config, file: File) -> Result<VirtualTree> {
+    let mut archive = zip::ZipArchive::new(BufReader::new(file))?;
+    let mut tree = VirtualTree::new();
+
+    for index in 0..archive.len() {
+        let mut member = archive.by_index(index)?;
+        let Some(name) = member.enclosed_name() else {
+            return Err(anyhow!("unsafe path in archive: {}", member.name()));
+        };
+
+        let mtime = member.extra_data_fields().find_map(|field| match field {
+            zip::ExtraField::ExtendedTimestamp(ts) => ts.mod_time().map(i64::from),
+            _ => None,
+        });
+        let owner = member.extra_data().and_then(zip_owner);
+        let mut meta = EntryMeta {
+            mode: member.unix_mode(),
+            uid: owner.map(|(uid, _)| uid),
+            gid: owner.map(|(_, gid)| gid),
+            mtime,
+            ..Default::default()
+        };
+
+        let node = if member.is_dir() || meta.mode.is_some_and(|m| m & S_IFMT == S_IFDIR) {
+            Node {
+                meta,
+                kind: NodeKind::Dir(BTreeMap::new()),
+            }
+        } else if meta.mode.is_some_and(|m| m & S_IFMT == S_IFLNK) {
+            let mut target = Vec::new();
+            member.read_to_end(&mut target)?;
+            Node {
+                meta,
+                kind: NodeKind::Symlink(target),
+            }
+        } else {
+            meta.is_file = true;
+            meta.size = Some(member.size());
+            file_node(config, meta, &mut member, &name)?
+        };
+        tree.insert(config, &name, node)?;
+    }
+    Ok(tree)
+}
+
+fn check_tar_path(path: &Path) -> Result<()> {
+    if path
+        .components()
+        .any(|c| matches!(c, Component::ParentDir | Component::RootDir))
+    {
+        return Err(anyhow!("unsafe path in archive: {}", path.display()));
+    }
+    Ok(())
+}
+
+/// Combine major and minor device numbers as glibc's `makedev` does
+fn makedev(major: u32, minor: u32) -> u64 {
+    let (major, minor) = (major as u64, minor as u64);
+    ((major & 0xfffff000) << 32)
+        | ((major & 0x00000fff) << 8)
+        | ((minor & 0xffffff00) << 12)
+        | (minor & 0x000000ff)
+}
+
+/// Read a tar stream which may be gzip compressed
+pub fn read_tar_stream(config: &Config, mut reader: impl BufRead) -> Result<VirtualTree> {
+    let magic = reader.fill_buf()?;
+    if magic.starts_with(b"\x1f\x8b") {
+        read_tar(config, GzDecoder::new(reader))
+    } else if magic.starts_with(b"\x28\xb5\x2f\xfd") {
+        Err(anyhow!("zstd compressed tar streams are not supported"))
+    } else {
+        read_tar(config, reader)
+    }
+}
+
+pub fn read_tar(config: &Config, reader: impl Read) -> Result<VirtualTree> {
+    let mut archive = tar::Archive::new(reader);
+    let mut tree = VirtualTree::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.into_owned();
+        check_tar_path(&name)?;
+
+        let header = entry.header();
+        let entry_type = header.entry_type();
+        let type_bits = match entry_type {
+            tar::EntryType::Directory => S_IFDIR,
+            tar::EntryType::Symlink => S_IFLNK,
+            tar::EntryType::Char => S_IFCHR,
+            tar::EntryType::Block => S_IFBLK,
+            tar::EntryType::Fifo => S_IFIFO,
+            _ => S_IFREG,
+        };
+        let mut meta = EntryMeta {
+            mode: Some(header.mode()? & 0o7777 | type_bits),
+            uid: Some(u32::try_from(header.uid()?)?),
+            gid: Some(u32::try_from(header.gid()?)?),
+            mtime: Some(header.mtime()? as i64),
+            ..Default::default()
+        };
+
+        let node = match entry_type {
+            tar::EntryType::Directory => Node {
+                meta,
+                kind: NodeKind::Dir(BTreeMap::new()),
+            },
+            tar::EntryType::Symlink => Node {
+                meta,
+                kind: NodeKind::Symlink(entry.link_name_bytes().unwrap_or_default().into_owned()),
+            },
+            tar::EntryType::Link => {
+                let target = entry
+                    .link_name()?
+                    .ok_or_else(|| anyhow!("hard link without target: {}", name.display()))?
+                    .into_owned();
+                check_tar_path(&target)?;
+                tree.insert_hard_link(config, &name, &target)?;
+                continue;
+            }
+            tar::EntryType::Char | tar::EntryType::Block => {
+                let major = header.device_major()?.unwrap_or(0);
+                let minor = header.device_minor()?.unwrap_or(0);
+                Node {
+                    meta,
+                    kind: NodeKind::Device(makedev(major, minor)),
+                }
+            }
+            tar::EntryType::Fifo => Node {
+                meta,
+                kind: NodeKind::Special,
+            },
+            tar::EntryType::Regular | tar::EntryType::Continuous | tar::EntryType::GNUSparse => {
+                meta.is_file = true;
+                meta.size = Some(entry.size());
+                file_node(config, meta, &mut entry, &name)?
+            }
+            other => {
+                return Err(anyhow!(
+                    "unsupported tar entry type {:?}: {}",
+                    other,
+                    name.display()
+                ));
+            }
+        };
+        tree.insert(config, &name, node)?;
+    }
+    Ok(tree)
+}
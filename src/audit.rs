@@ -0,0 +1,70 @@
+use anyhow::{Context, Result, anyhow};
+use std::fs::File;
+use std::io::BufReader;
+
+use fdsum::config::Config;
+use fdsum::formats::{self, FileEntry};
+use fdsum::hash::hash_file;
+use fdsum::job;
+use fdsum::vfs::FileKind;
+
+use crate::color;
+
+/// Audit files against the hashdeep file set `manifest`. With a PATH,
+/// files below it that are not in the set are reported as well.
+pub fn run(config: &mut Config, manifest: &str) -> Result<()> {
+    let file = File::open(manifest).with_context(|| manifest.to_string())?;
+    let (algorithm, known) = formats::read_hashdeep(BufReader::new(file), &config.algorithm)
+        .with_context(|| manifest.to_string())?;
+    config.algorithm = algorithm;
+    config.check_algorithm()?;
+
+    let current = match &config.path {
+        Some(path) => formats::list_files(config, path)?,
+        None => known_files(config, &known)?,
+    };
+    let differences = formats::audit(&known, &current);
+    if config.lists_changed() {
+        crate::write_changed_files(config, &differences)?;
+    }
+
+    let name = config
+        .path
+        .as_ref()
+        .map_or(manifest.to_string(), |p| p.display().to_string());
+    if differences.is_empty() {
+        println!("{}: {}", name, color::ok("Ok"));
+        return Ok(());
+    }
+    println!("{}: {}", name, color::mismatch("Mismatch"));
+    for difference in &differences {
+        println!(
+            "  {}: {}",
+            difference.path.display(),
+            color::change(difference.kind, job::change_name(difference.kind))
+        );
+    }
+    Err(anyhow!("{} files differ", differences.len()))
+}
+
+/// Hash those of the known files that exist
+fn known_files(config: &Config, known: &[FileEntry]) -> Result<Vec<FileEntry>> {
+    let mut out = Vec::new();
+    for entry in known {
+        let Ok(stat) = config.vfs.symlink_metadata(&entry.path) else {
+            continue;
+        };
+        if stat.kind != FileKind::File {
+            continue;
+        }
+        let size = stat.meta.size.unwrap_or(0);
+        out.push(FileEntry {
+            path: entry.path.clone(),
+            size,
+            hash: config
+                .algorithm
+                .encode(&hash_file(config, &entry.path, size)?),
+        });
+    }
+    Ok(out)
+}
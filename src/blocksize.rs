@@ -0,0 +1,103 @@
+//! Choose the size of the reads of each file from the device it is on.
+//! Rotating disks and network filesystems read sequentially best in
+//! large blocks; solid state disks and the page cache need no more
+//! than the default. Files smaller than a block are read in one.
+
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// The block size for files on solid state disks and the like
+pub const DEFAULT_BLOCK_SIZE: usize = 128 * 1024;
+
+/// The block size for rotating disks and network filesystems
+pub const SEQUENTIAL_BLOCK_SIZE: usize = 1024 * 1024;
+
+const MIN_BLOCK_SIZE: usize = 4096;
+const MAX_BLOCK_SIZE: usize = 8 * 1024 * 1024;
+
+/// The block sizes of the devices seen, by device number
+#[derive(Debug, Default)]
+pub struct BlockSizes {
+    devices: Mutex<HashMap<u64, usize>>,
+}
+
+impl BlockSizes {
+    /// The size of the reads of the local file at `path`, or `None` if
+    /// it cannot be found
+    pub fn for_file(&self, path: &Path) -> Option<usize> {
+        let meta = fs::metadata(path).ok()?;
+        let device = *self
+            .devices
+            .lock()
+            .unwrap()
+            .entry(meta.dev())
+            .or_insert_with(|| probe(path, &meta));
+        let size = usize::try_from(meta.len()).unwrap_or(usize::MAX);
+        Some(
+            size.checked_next_power_of_two()
+                .unwrap_or(usize::MAX)
+                .clamp(MIN_BLOCK_SIZE, device.max(MIN_BLOCK_SIZE)),
+        )
+    }
+}
+
+/// The block size for the device holding `path`, at least the one the
+/// filesystem prefers
+fn probe(path: &Path, meta: &Metadata) -> usize {
+    let size = if is_sequential(path, meta) {
+        SEQUENTIAL_BLOCK_SIZE
+    } else {
+        DEFAULT_BLOCK_SIZE
+    };
+    size.max(meta.blksize() as usize).min(MAX_BLOCK_SIZE)
+}
+
+/// Whether `path` is on a network filesystem or a rotating disk
+#[cfg(target_os = "linux")]
+fn is_sequential(path: &Path, meta: &Metadata) -> bool {
+    is_network(path) || is_rotational(meta.dev())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_sequential(_path: &Path, _meta: &Metadata) -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+fn is_network(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    // magic numbers of statfs(2)
+    const NETWORK: [i64; 7] = [
+        0x6969,             // NFS
+        0x517b,             // SMB
+        0xff534d42u32 as _, // CIFS
+        0xfe534d42u32 as _, // SMB2
+        0x00c36400,         // Ceph
+        0x013111a8,         // IBRIX
+        0x5346414f,         // AFS
+    ];
+    let Ok(path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: path is NUL-terminated and stat is a valid statfs buffer
+    if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+        return false;
+    }
+    NETWORK.contains(&(stat.f_type as i64))
+}
+
+/// Whether the block device `dev`, or the disk holding the partition,
+/// reports itself as rotating
+#[cfg(target_os = "linux")]
+fn is_rotational(dev: u64) -> bool {
+    let dir = format!("/sys/dev/block/{}:{}", libc::major(dev), libc::minor(dev));
+    ["queue/rotational", "../queue/rotational"]
+        .iter()
+        .find_map(|file| fs::read_to_string(Path::new(&dir).join(file)).ok())
+        .is_some_and(|value| value.trim() == "1")
+}
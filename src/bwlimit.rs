@@ -0,0 +1,51 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Limits the combined rate at which all threads read file content
+#[derive(Debug)]
+pub struct BandwidthLimit {
+    /// Bytes per second, unlimited if 0
+    rate: AtomicU64,
+    /// The start of the current rate and the bytes read since
+    window: Mutex<(Instant, u64)>,
+}
+
+impl BandwidthLimit {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: AtomicU64::new(rate),
+            window: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    /// Change the rate, 0 lifting the limit, from now on
+    pub fn set_rate(&self, rate: u64) {
+        let mut window = self.window.lock().unwrap();
+        self.rate.store(rate, Ordering::Relaxed);
+        *window = (Instant::now(), 0);
+    }
+
+    /// Account for `n` bytes read, sleeping until the total read so
+    /// far is within the limit
+    pub fn consume(&self, n: u64) {
+        let (start, total) = {
+            let mut window = self.window.lock().unwrap();
+            window.1 += n;
+            *window
+        };
+        let rate = self.rate();
+        if rate == 0 {
+            return;
+        }
+        let due = Duration::from_secs_f64(total as f64 / rate as f64);
+        let elapsed = start.elapsed();
+        if due > elapsed {
+            std::thread::sleep(due - elapsed);
+        }
+    }
+}
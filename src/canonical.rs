@@ -0,0 +1,66 @@
+//! Canonical JSON, reproducible byte for byte.
+//!
+//! Object keys are sorted by their raw bytes and no whitespace is
+//! written. Floats are written in the shortest form that reads back to
+//! the same value, and whole floats below 10^21 without a fraction, so
+//! `1.0` and `1` are written alike, similar to RFC 8785.
+
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::{Number, Value};
+
+/// Serialize `value` as canonical JSON
+pub fn to_string<T: Serialize + ?Sized>(value: &T) -> Result<String> {
+    let mut out = String::new();
+    write_value(&serde_json::to_value(value)?, &mut out)?;
+    Ok(out)
+}
+
+fn write_value(value: &Value, out: &mut String) -> Result<()> {
+    match value {
+        Value::Null | Value::Bool(_) | Value::String(_) => {
+            out.push_str(&serde_json::to_string(value)?)
+        }
+        Value::Number(number) => write_number(number, out),
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_value(item, out)?;
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+            out.push('{');
+            for (i, (key, item)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&serde_json::to_string(key)?);
+                out.push(':');
+                write_value(item, out)?;
+            }
+            out.push('}');
+        }
+    }
+    Ok(())
+}
+
+fn write_number(number: &Number, out: &mut String) {
+    match number.as_f64() {
+        Some(float) if number.is_f64() => {
+            if float == float.trunc() && float.abs() < 1e21 {
+                // -0.0 is written as 0
+                let float = if float == 0.0 { 0.0 } else { float };
+                out.push_str(&format!("{:.0}", float));
+            } else {
+                out.push_str(&number.to_string());
+            }
+        }
+        _ => out.push_str(&number.to_string()),
+    }
+}
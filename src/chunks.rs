@@ -0,0 +1,124 @@
+//! Content-defined chunking of files with FastCDC. Files are cut where
+//! a rolling hash of the content before the cut matches, so that an
+//! insertion into a file moves only the cuts around it. The chunks two
+//! versions of a file share tell how much of it changed.
+
+use serde::{Deserialize, Serialize};
+
+use crate::algo::Hasher;
+use crate::config::Config;
+
+/// The chunking recorded in results: the algorithm and the minimum,
+/// average and maximum size of chunks
+pub const CHUNKING: &str = "fastcdc-16k-64k-256k";
+
+const MIN_SIZE: usize = 16 * 1024;
+const AVG_SIZE: usize = 64 * 1024;
+const MAX_SIZE: usize = 256 * 1024;
+
+// normalized chunking: cuts before the average size are less likely,
+// after it more, for sizes closer to the average
+const MASK_SMALL: u64 = high_bits(18);
+const MASK_LARGE: u64 = high_bits(14);
+
+/// The upper bits of the rolling hash depend on the last 64 bytes
+const fn high_bits(bits: u32) -> u64 {
+    !(u64::MAX >> bits)
+}
+
+/// A random value for each byte, from splitmix64 with a fixed seed so
+/// that cuts are the same everywhere
+static GEAR: [u64; 256] = gear();
+
+const fn gear() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x6664_7375_6d00_0000;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// A chunk of a file, in the order of the file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Chunk {
+    pub size: u64,
+    pub hash: String,
+}
+
+/// Cuts content fed to it in blocks of any size into chunks and
+/// hashes each
+pub struct Chunker<'a> {
+    config: &'a Config,
+    hasher: Box<dyn Hasher>,
+    /// Bytes in the current chunk
+    size: usize,
+    fingerprint: u64,
+    chunks: Vec<Chunk>,
+}
+
+impl<'a> Chunker<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        Self {
+            config,
+            hasher: config.hasher(),
+            size: 0,
+            fingerprint: 0,
+            chunks: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while let Some(end) = self.find_cut(data) {
+            self.hasher.update(&data[..end]);
+            self.cut();
+            data = &data[end..];
+        }
+        self.hasher.update(data);
+    }
+
+    /// The chunks of the content, the last ending with it
+    pub fn finish(mut self) -> Vec<Chunk> {
+        if self.size > 0 {
+            self.cut();
+        }
+        self.chunks
+    }
+
+    /// The end of the current chunk in `data`, or `None` if it
+    /// continues past it
+    fn find_cut(&mut self, data: &[u8]) -> Option<usize> {
+        // no cuts before the minimum size
+        let skip = MIN_SIZE.saturating_sub(self.size).min(data.len());
+        self.size += skip;
+        for (i, &byte) in data.iter().enumerate().skip(skip) {
+            self.size += 1;
+            self.fingerprint = (self.fingerprint << 1).wrapping_add(GEAR[byte as usize]);
+            let mask = if self.size < AVG_SIZE {
+                MASK_SMALL
+            } else {
+                MASK_LARGE
+            };
+            if self.fingerprint & mask == 0 || self.size >= MAX_SIZE {
+                return Some(i + 1);
+            }
+        }
+        None
+    }
+
+    fn cut(&mut self) {
+        let hasher = std::mem::replace(&mut self.hasher, self.config.hasher());
+        self.chunks.push(Chunk {
+            size: self.size as u64,
+            hash: self.config.algorithm.encode(&hasher.finalize()),
+        });
+        self.size = 0;
+        self.fingerprint = 0;
+    }
+}
@@ -0,0 +1,42 @@
+//! Colors of the human-readable output
+
+use console::{StyledObject, style};
+use std::io::IsTerminal;
+
+use fdsum::config::ColorChoice;
+use fdsum::tree::DiffKind;
+
+/// Enable colors on stdout and stderr as chosen. With auto, colors are
+/// used on terminals unless NO_COLOR is set to a non-empty value.
+pub fn init(choice: ColorChoice) {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    let enabled = |terminal: bool| match choice {
+        ColorChoice::Auto => terminal && !no_color,
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+    };
+    console::set_colors_enabled(enabled(std::io::stdout().is_terminal()));
+    console::set_colors_enabled_stderr(enabled(std::io::stderr().is_terminal()));
+}
+
+pub fn ok<D>(text: D) -> StyledObject<D> {
+    style(text).green()
+}
+
+pub fn mismatch<D>(text: D) -> StyledObject<D> {
+    style(text).red().bold()
+}
+
+/// Marks a field that differs
+pub fn highlight<D>(text: D) -> StyledObject<D> {
+    style(text).yellow().bold()
+}
+
+/// `text` colored by the kind of difference it describes
+pub fn change<D>(kind: DiffKind, text: D) -> StyledObject<D> {
+    match kind {
+        DiffKind::OnlyLeft => style(text).red(),
+        DiffKind::OnlyRight => style(text).green(),
+        _ => style(text).yellow(),
+    }
+}
@@ -1,17 +1,99 @@
-use crate::{algo, stats::SharedStats};
-use anyhow::{Result, anyhow};
-use clap::{Parser, ValueEnum};
+use crate::{
+    affinity::{CpuSet, NumaNode},
+    algo,
+    blocksize::{BlockSizes, DEFAULT_BLOCK_SIZE},
+    bwlimit::BandwidthLimit,
+    canonical,
+    chunks::{CHUNKING, Chunk},
+    error::{ErrorInfo, FsError},
+    events::EventStream,
+    flags::{
+        FLAG_NAMES, FRAMING_VERSION, Flags, LATEST_FRAMING, TimeGranularity, all_types, parse_types,
+    },
+    formats::{ExportFormat, OutputFormat},
+    hash::{EntryMeta, type_tag},
+    idmap::IdMap,
+    job::CancelToken,
+    limits,
+    profile::{LARGE_FILE, Profiler, Span},
+    remote::Remote,
+    stats::{Phases, ProgressTemplates, SharedStats, WorkerStats},
+    vfs::{FileKind, OsFs, Stat, Vfs},
+};
+use anyhow::{Context, Result, anyhow};
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
+use std::ffi::OsStr;
 use std::fmt;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
-#[derive(ValueEnum, Clone, Debug)]
+#[derive(ValueEnum, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum HashAlgorithm {
     Sha256,
     Blake3,
+    /// KangarooTwelve, a fast Keccak-based (SHA-3 family) hash
+    K12,
+    /// SHA-1, broken: not collision resistant, for compatibility only
+    Sha1,
+    /// MD5, broken: not collision resistant, for compatibility only
+    Md5,
+    /// CRC-32 (IEEE), not collision resistant
+    Crc32,
+    /// CRC-32C (Castagnoli), not collision resistant
+    Crc32c,
+}
+
+impl HashAlgorithm {
+    /// Whether the algorithm is approved for use in FIPS mode
+    pub fn is_fips_approved(&self) -> bool {
+        matches!(self, HashAlgorithm::Sha256)
+    }
+
+    pub fn hasher(&self) -> Box<dyn algo::Hasher> {
+        match self {
+            HashAlgorithm::Sha256 => Box::new(Sha256::new()),
+            HashAlgorithm::Blake3 => Box::new(algo::Blake3Wrapper::new()),
+            HashAlgorithm::K12 => Box::new(algo::K12Wrapper::default()),
+            HashAlgorithm::Sha1 => Box::new(sha1::Sha1::new()),
+            HashAlgorithm::Md5 => Box::new(md5::Md5::new()),
+            HashAlgorithm::Crc32 => Box::new(crc32fast::Hasher::new()),
+            HashAlgorithm::Crc32c => Box::new(algo::Crc32c::default()),
+        }
+    }
+
+    /// The number of bytes of a digest. Shorter digests are padded
+    /// with zeros to 32 bytes when framing entries.
+    pub fn digest_len(&self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 | HashAlgorithm::Blake3 | HashAlgorithm::K12 => 32,
+            HashAlgorithm::Sha1 => 20,
+            HashAlgorithm::Md5 => 16,
+            HashAlgorithm::Crc32 | HashAlgorithm::Crc32c => 4,
+        }
+    }
+
+    /// Format a digest as hex
+    pub fn encode(&self, digest: &[u8]) -> String {
+        hex::encode(&digest[..self.digest_len()])
+    }
+
+    /// Read a digest formatted by [`HashAlgorithm::encode`]
+    pub fn decode(&self, digest: &str) -> Result<[u8; 32]> {
+        let bytes = hex::decode(digest).map_err(|_| anyhow!("Invalid digest: {}", digest))?;
+        if bytes.len() != self.digest_len() {
+            return Err(anyhow!("Invalid {} digest: {}", self, digest));
+        }
+        let mut out = [0u8; 32];
+        out[..bytes.len()].copy_from_slice(&bytes);
+        Ok(out)
+    }
 }
 
 impl fmt::Display for HashAlgorithm {
@@ -19,91 +101,994 @@ impl fmt::Display for HashAlgorithm {
         let s = match self {
             HashAlgorithm::Sha256 => "sha256",
             HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::K12 => "k12",
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Md5 => "md5",
+            HashAlgorithm::Crc32 => "crc32",
+            HashAlgorithm::Crc32c => "crc32c",
         };
         write!(f, "{}", s)
     }
 }
 
+/// Parse a size in bytes, optionally ending in K, M, G or T for
+/// multiples of 1024
+pub fn parse_size(value: &str) -> Result<u64> {
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let shift = match value[digits.len()..].to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => return Err(anyhow!("Invalid size: {}", value)),
+    };
+    let size: u64 = digits
+        .parse()
+        .map_err(|_| anyhow!("Invalid size: {}", value))?;
+    size.checked_mul(1 << shift)
+        .ok_or_else(|| anyhow!("Size too large: {}", value))
+}
+
+/// Parse a point in time into seconds since the epoch. Accepts RFC
+/// 3339 timestamps, `YYYY-MM-DD[ HH:MM:SS]` in UTC, `@SECONDS` and
+/// ages relative to now such as `30m`, `12h` or `7d`.
+pub fn parse_date(value: &str) -> Result<i64> {
+    use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+
+    if let Some(seconds) = value.strip_prefix('@') {
+        return seconds
+            .parse()
+            .map_err(|_| anyhow!("Invalid date: {}", value));
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.timestamp());
+    }
+    if let Ok(time) = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S") {
+        return Ok(time.and_utc().timestamp());
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(date
+            .and_hms_opt(0, 0, 0)
+            .unwrap_or_default()
+            .and_utc()
+            .timestamp());
+    }
+    let age = parse_duration(value).map_err(|_| anyhow!("Invalid date: {}", value))?;
+    Ok(Utc::now().timestamp() - age as i64)
+}
+
+/// Parse a duration such as `30m`, `12h` or `7d` into seconds
+pub fn parse_duration(value: &str) -> Result<u64> {
+    let unit = match value.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 60 * 60,
+        Some('d') => 24 * 60 * 60,
+        Some('w') => 7 * 24 * 60 * 60,
+        _ => return Err(anyhow!("Invalid duration: {}", value)),
+    };
+    let count: u64 = value[..value.len() - 1]
+        .parse()
+        .map_err(|_| anyhow!("Invalid duration: {}", value))?;
+    count
+        .checked_mul(unit)
+        .ok_or_else(|| anyhow!("Duration too long: {}", value))
+}
+
+/// The order in which the content of files is read. The digest does
+/// not depend on it.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Traversal {
+    /// Depth first, in name order, reading files while descending
+    #[default]
+    Dfs,
+    /// Breadth first, reading files closer to the root first
+    Bfs,
+    /// Largest files first, for better balance between threads
+    SizeDesc,
+    /// In the order of the location of their content on the disk, to
+    /// save seeking on rotating disks
+    Physical,
+}
+
+/// When to color the human-readable output
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorChoice {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// Which runs notifications are sent for
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NotifyOn {
+    /// Every run
+    #[default]
+    Complete,
+    /// Runs that found a mismatch or failed
+    Failure,
+}
+
+/// Read exclude patterns from `path`, one per line, skipping empty
+/// lines and lines starting with `#`
+pub fn read_exclude_file(path: &Path) -> Result<Vec<String>> {
+    let text = std::fs::read_to_string(path).with_context(|| path.display().to_string())?;
+    Ok(text
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
 #[derive(Parser)]
-#[command(version, about, long_about = None, max_term_width=100)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    max_term_width = 100,
+    disable_version_flag = true
+)]
 /// Calculate checksums on files and directories recursively
 pub struct Args {
-    /// Enable verbose output
+    /// Enable verbose output, including statistics of each thread
     #[arg(short, long, global = true)]
     verbose: bool,
 
-    /// The path to checksum
+    /// Write the times spent on each directory and on large files to
+    /// FILE, to be viewed with speedscope
+    #[arg(long, value_name = "FILE", global = true)]
+    profile: Option<PathBuf>,
+
+    /// Write an event for each entry started, finished or failed to
+    /// TARGET as a line of JSON, for frontends showing the status of
+    /// single files. TARGET is a file or FIFO, unix:PATH for a Unix
+    /// socket or fd:N for an inherited file descriptor.
+    #[arg(long, value_name = "TARGET", global = true)]
+    events: Option<String>,
+
+    /// Append a record of the run (command, tree, flags, digest,
+    /// status, duration and error) to FILE as a line of JSON
+    #[arg(long, value_name = "FILE", global = true, env = "FDSUM_HISTORY")]
+    history: Option<PathBuf>,
+
+    /// Append the result of hashing each tree to FILE as a line of
+    /// compact JSON
+    #[arg(long, value_name = "FILE", global = true)]
+    append_results: Option<PathBuf>,
+
+    /// Log the outcome of the run to the system journal, or syslog,
+    /// with the tree, digest and status as structured fields
+    #[arg(long, global = true)]
+    syslog: bool,
+
+    /// POST the record and result of the run as JSON to URL when it
+    /// completes. Its `text` field summarizes the outcome for chat
+    /// webhooks.
+    #[arg(long, value_name = "URL", global = true, env = "FDSUM_NOTIFY_URL")]
+    notify_url: Option<String>,
+
+    /// Notify of every run, or only of mismatches and errors
+    #[arg(
+        long,
+        value_enum,
+        global = true,
+        requires = "notify_url",
+        default_value_t = NotifyOn::Complete
+    )]
+    notify_on: NotifyOn,
+
+    /// Run CMD with the shell when the run completes, with the record
+    /// of the run as JSON on stdin (see --notify-url) and its status,
+    /// tree and digests in FDSUM_STATUS, FDSUM_PATH, FDSUM_DIGEST and
+    /// FDSUM_EXPECTED
+    #[arg(long, value_name = "CMD", global = true)]
+    on_complete: Option<String>,
+
+    /// Run CMD as --on-complete does, but only when verifying found a
+    /// mismatch
+    #[arg(long, value_name = "CMD", global = true)]
+    on_mismatch: Option<String>,
+
+    /// Wait for other runs on the same tree to finish, instead of
+    /// failing. Runs on a tree hold an advisory lock on its root.
+    #[arg(long, global = true)]
+    wait_lock: bool,
+
+    /// Do not lock the tree against other runs
+    #[arg(long, global = true, conflicts_with = "wait_lock")]
+    no_lock: bool,
+
+    /// Overwrite existing files given with -o/--output
+    #[arg(long, global = true)]
+    force: bool,
+
+    /// Replace the template of the bytes or entries progress bar, e.g.
+    /// 'bytes={wide_bar} {percent}%'. See the indicatif documentation
+    /// for the syntax. May be repeated.
+    #[arg(long, value_name = "BAR=TEMPLATE", global = true)]
+    progress_template: Vec<String>,
+
+    /// Accept commands on stdin, one JSON object per line, and answer
+    /// each on stderr: {"command":"pause"}, "resume", "status",
+    /// "cancel", or "set-bwlimit" with "kib" (0 for no limit)
+    #[arg(long, global = true)]
+    control: bool,
+
+    /// Print version and, with --json, the capabilities of this build:
+    /// accelerated backends in use, algorithms, framing and schema
+    /// versions, formats, commands and optional features
+    #[arg(short = 'V', long)]
+    pub version: bool,
+
+    /// Print machine-readable JSON (with --version and in verify mode)
+    #[arg(long, global = true)]
+    pub json: bool,
+
+    /// Write JSON canonically: sorted keys, fixed number formatting and
+    /// no whitespace, so that equal results are equal byte for byte
+    #[arg(long, global = true)]
+    canonical: bool,
+
+    /// Write JSON on a single line, e.g. to append to a JSONL file.
+    /// Default for results with per-entry digests.
+    #[arg(long, global = true, conflicts_with = "pretty")]
+    compact: bool,
+
+    /// Write JSON indented over several lines, also for results with
+    /// per-entry digests
+    #[arg(long, global = true)]
+    pretty: bool,
+
+    /// Color the human-readable output. auto colors output to a
+    /// terminal unless NO_COLOR is set.
+    #[arg(long, value_enum, global = true, default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+
+    /// Output format. hashdeep and bsd list the hash of each regular
+    /// file; hashdeep files can be audited with -c.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Json)]
+    format: OutputFormat,
+
+    /// The path to checksum. Objects in S3 can be given as
+    /// s3://bucket/prefix, using the AWS_* environment variables for
+    /// credentials, region and endpoint. WebDAV collections can be
+    /// given as http(s)://[user:password@]host/path. Several paths
+    /// can be given with --combine.
     #[arg(value_name = "PATH")]
-    path: Option<PathBuf>,
+    path: Vec<PathBuf>,
+
+    /// Hash each PATH and combine their digests, in the order given,
+    /// into one digest. The results of the paths are listed in `roots`.
+    #[arg(long, conflicts_with_all = ["remote", "detail", "detail_dirs", "record_files", "chunks"])]
+    combine: bool,
+
+    /// Hash a tree on another host given as [user@]host:/path by
+    /// running fdsum there via ssh
+    #[arg(long, value_name = "HOST:PATH", conflicts_with = "path")]
+    remote: Option<String>,
+
+    /// The fdsum command to run on the remote host
+    #[arg(
+        long,
+        value_name = "CMD",
+        default_value = "fdsum",
+        global = true,
+        env = "FDSUM_REMOTE_FDSUM"
+    )]
+    remote_fdsum: String,
 
-    /// Hash algorithm
-    #[arg(short = 'm', long, default_value = "sha256")]
-    algorithm: HashAlgorithm,
+    /// Treat PATH as an archive (zip, tar, tar.gz) and hash its members
+    /// as if extracted. Directories not listed take the owner, mtime
+    /// and permissions of their first entry. Metadata not recorded in
+    /// the archive must be excluded.
+    #[arg(short = 'A', long)]
+    archive: bool,
 
-    /// Block size for reading files in kiB.
-    #[arg(short = 'b', long, default_value_t = 128)]
-    block_size: usize,
+    /// Hash algorithm [default: sha256]
+    #[arg(short = 'm', long, global = true, env = "FDSUM_ALGORITHM")]
+    algorithm: Option<HashAlgorithm>,
 
-    /// Number of parallel threads [default: lesser of 8 and #cores]
-    #[arg(short = 't', long)]
+    /// Block size for reading files in kiB [default: chosen per file
+    /// from its size and device, up to 1024 for rotating disks and
+    /// network filesystems and 128 otherwise]
+    #[arg(short = 'b', long, global = true, env = "FDSUM_BLOCK_SIZE")]
+    block_size: Option<usize>,
+
+    /// Limit the combined read rate to KIB kiB per second
+    #[arg(long, value_name = "KIB", global = true, env = "FDSUM_BWLIMIT")]
+    bwlimit: Option<u64>,
+
+    /// Read up to N blocks of each file ahead on another thread while
+    /// hashing, to keep network filesystems busy [default: 0, off]
+    #[arg(long, value_name = "N", global = true, env = "FDSUM_PREFETCH")]
+    prefetch: Option<usize>,
+
+    /// Number of parallel threads [default: lesser of 8, #cores and the
+    /// CPU quota of the cgroup, fewer if its memory is short]
+    #[arg(short = 't', long, global = true, env = "FDSUM_THREADS")]
     num_threads: Option<usize>,
 
+    /// Run only on the CPUs listed, e.g. 0-3,8. Unless given, the
+    /// number of threads is at most the number of CPUs listed.
+    #[arg(long, value_name = "LIST", global = true, env = "FDSUM_CPUS", value_parser = CpuSet::parse)]
+    cpus: Option<CpuSet>,
+
+    /// Run only on the CPUs of NUMA node N, or with auto of the node
+    /// the storage holding the tree is attached to, so that reads stay
+    /// on one node. As --cpus.
+    #[arg(
+        long,
+        value_name = "auto|N",
+        global = true,
+        env = "FDSUM_NUMA_NODE",
+        value_parser = NumaNode::parse,
+        conflicts_with = "cpus"
+    )]
+    numa_node: Option<NumaNode>,
+
+    /// List directories and stat entries on a separate pool of N
+    /// threads, handing the files found to the hashing threads through
+    /// a bounded queue. The tree is held in memory. [default: 0, list
+    /// and hash on the same threads]
+    #[arg(long, value_name = "N", global = true, env = "FDSUM_SCAN_THREADS")]
+    scan_threads: Option<usize>,
+
+    /// Order in which file contents are read. Except for dfs the tree
+    /// is listed first and held in memory. [default: dfs]
+    #[arg(
+        long,
+        alias = "order",
+        value_enum,
+        global = true,
+        env = "FDSUM_TRAVERSAL"
+    )]
+    traversal: Option<Traversal>,
+
     /// Exclude file contents
-    #[arg(short = 'C', long)]
+    #[arg(short = 'C', long, global = true)]
     no_content: bool,
 
     /// Exclude size
-    #[arg(short = 'S', long)]
+    #[arg(short = 'S', long, global = true)]
     no_size: bool,
 
     /// Exclude all permissions (equal to -MOG)
-    #[arg(short = 'P', long)]
+    #[arg(short = 'P', long, global = true)]
     no_perms: bool,
 
     /// Exclude mode bits
-    #[arg(short = 'M', long)]
+    #[arg(short = 'M', long, global = true)]
     no_mode: bool,
 
     /// Exclude owner UID
-    #[arg(short = 'O', long)]
+    #[arg(short = 'O', long, global = true)]
     no_owner: bool,
 
     /// Exclude owner GID
-    #[arg(short = 'G', long)]
+    #[arg(short = 'G', long, global = true)]
     no_group: bool,
 
     /// Exclude mtime (last data modification)
-    #[arg(short = 'T', long)]
+    #[arg(short = 'T', long, global = true)]
     no_mtime: bool,
 
     /// Include atime (last access). We may cause a change to the
     /// atime ourselves while reading files.
-    #[arg(long)]
+    #[arg(long, global = true)]
     atime: bool,
 
+    /// Include inode numbers. Not portable: copies of a tree, even on
+    /// the same filesystem, and restores from backup get other inode
+    /// numbers. Only for checking that no entry of a tree was replaced,
+    /// against results of the same tree in place.
+    #[arg(long, global = true)]
+    inode: bool,
+
+    /// Include the number of hard links of entries other than
+    /// directories, so that links broken or added are detected. The
+    /// link count of directories depends on the filesystem.
+    #[arg(long, global = true)]
+    nlink: bool,
+
+    /// Include the immutable, append-only and nodump attributes of
+    /// files and directories, as set by chattr (Linux)
+    #[arg(long, global = true)]
+    attributes: bool,
+
+    /// Include the project IDs of files and directories, as assigned
+    /// for project quotas on XFS and ext4 (Linux)
+    #[arg(long, global = true)]
+    project: bool,
+
+    /// Include the file capabilities of regular files, as set by
+    /// setcap (the security.capability extended attribute)
+    #[arg(long, global = true)]
+    capabilities: bool,
+
+    /// Include the NFSv4 ACLs of regular files and directories, as
+    /// exposed in the system.nfs4_acl extended attribute (e.g. on NFSv4
+    /// mounts)
+    #[arg(long, global = true)]
+    nfs4_acl: bool,
+
+    /// Include the ETags of S3 objects. They depend on how an object
+    /// was uploaded, so trees no longer compare equal to local copies.
+    #[arg(long, global = true)]
+    etag: bool,
+
+    /// Include the storage classes of S3 objects (e.g. STANDARD or
+    /// GLACIER)
+    #[arg(long, global = true)]
+    storage_class: bool,
+
     /// Include ctime (last status change). The ctime cannot be set by
     /// tools such as rsync and may be updated unexpectedly (e.g. by
     /// creating a hard link on a file).
-    #[arg(long)]
+    #[arg(long, global = true)]
     ctime: bool,
 
-    /// Set via flags string. This overrides all other settings.
-    #[arg(long, value_name = "STRING")]
+    /// Record the digests of the entries down to depth N in the result,
+    /// so that a mismatch can be traced to the changed entries
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    detail: usize,
+
+    /// Record the digests of all directories in the result, so that a
+    /// mismatch can be traced to the deepest changed directory
+    #[arg(long)]
+    detail_dirs: bool,
+
+    /// Record the size and content digest of each regular file in the
+    /// result, so that the manifest can be exported to per-file
+    /// formats (see export)
+    #[arg(long)]
+    record_files: bool,
+
+    /// Record the content-defined chunks of each regular file as well,
+    /// so that versions of a file can be compared by the chunks they
+    /// share (implies --record-files)
+    #[arg(long)]
+    chunks: bool,
+
+    /// Hash a placeholder for entries that cannot be read for lack of
+    /// permission instead of failing, and list them in the result
+    #[arg(long)]
+    skip_unreadable: bool,
+
+    /// Version of the framing of entries in the hash. Version 2 also
+    /// hashes the type of each entry, so that e.g. a symlink cannot
+    /// collide with a file whose content is the link target. [default: 1]
+    #[arg(
+        long,
+        value_name = "N",
+        global = true,
+        value_parser = clap::value_parser!(u32).range(1..=LATEST_FRAMING as i64)
+    )]
+    framing: Option<u32>,
+
+    /// Exclude the metadata of the root itself, so that the hash does
+    /// not change when the tree is copied into a different directory
+    #[arg(long, global = true)]
+    no_root_meta: bool,
+
+    /// Include the names of entries. Combined with -CSPT only the
+    /// layout of the tree takes part in the hash.
+    #[arg(long, global = true)]
+    names: bool,
+
+    /// Leave out directories without entries, e.g. to compare against
+    /// object storage that cannot represent them
+    #[arg(long, global = true)]
+    no_empty_dirs: bool,
+
+    /// Leave out regular files of size zero
+    #[arg(long, global = true)]
+    no_empty_files: bool,
+
+    /// Leave out regular files smaller than SIZE bytes. SIZE may end
+    /// in K, M, G or T for multiples of 1024.
+    #[arg(long, value_name = "SIZE", global = true, value_parser = parse_size)]
+    min_size: Option<u64>,
+
+    /// Leave out regular files larger than SIZE bytes
+    #[arg(long, value_name = "SIZE", global = true, value_parser = parse_size)]
+    max_size: Option<u64>,
+
+    /// Leave out entries other than directories last modified before
+    /// DATE. DATE is a timestamp such as 2024-05-01 or an age such as
+    /// 7d, and is recorded as absolute time.
+    #[arg(long, value_name = "DATE", global = true, value_parser = parse_date)]
+    newer_than: Option<i64>,
+
+    /// Leave out entries other than directories last modified at or
+    /// after DATE
+    #[arg(long, value_name = "DATE", global = true, value_parser = parse_date)]
+    older_than: Option<i64>,
+
+    /// Include only entries of the given TYPES: f (regular file), d
+    /// (directory), l (symlink), v (device) and s (FIFO or socket).
+    /// Directories are always descended into, without d those with no
+    /// included entries are left out.
+    #[arg(long, value_name = "TYPES", global = true, value_parser = parse_types)]
+    types: Option<String>,
+
+    /// Round the included timestamps down to GRANULARITY before
+    /// hashing, so that a tree can be verified after copying it to a
+    /// filesystem with coarser timestamps (e.g. 2s for FAT) [default: 1s]
+    #[arg(long, value_name = "GRANULARITY", global = true)]
+    time_granularity: Option<TimeGranularity>,
+
+    /// Skip entries whose name matches the glob PATTERN. May be given
+    /// multiple times.
+    #[arg(long, value_name = "PATTERN", global = true)]
+    exclude: Vec<String>,
+
+    /// Skip entries whose name matches a pattern read from FILE, one
+    /// per line. Empty lines and lines starting with # are ignored.
+    #[arg(long, value_name = "FILE", global = true)]
+    exclude_from: Vec<PathBuf>,
+
+    /// Hash the owner UIDs and GIDs mapped by FILE, with lines `uid OLD
+    /// NEW` or `gid OLD NEW` from the IDs at the site a tree comes from
+    /// to those at the site it moves to. Verifying maps the other way,
+    /// so that a copy whose owners have other IDs verifies against the
+    /// manifest of the original.
+    #[arg(long, value_name = "FILE", global = true)]
+    idmap: Option<PathBuf>,
+
+    /// Skip entries whose name starts with a dot
+    #[arg(long, global = true)]
+    no_hidden: bool,
+
+    /// Do not read defaults from ~/.config/fdsum/config.toml and
+    /// PATH/.fdsum.toml
+    #[arg(
+        long,
+        global = true,
+        env = "FDSUM_NO_CONFIG",
+        value_parser = clap::builder::FalseyValueParser::new()
+    )]
+    no_config: bool,
+
+    /// Only allow FIPS approved algorithms (SHA-2 family) and mark
+    /// the result as made in FIPS mode
+    #[arg(
+        long,
+        global = true,
+        env = "FDSUM_FIPS",
+        value_parser = clap::builder::FalseyValueParser::new()
+    )]
+    fips: bool,
+
+    /// Set via flags string. This overrides all other settings, but
+    /// from FDSUM_FLAGS it is a default that other options override.
+    #[arg(long, value_name = "STRING", global = true, env = "FDSUM_FLAGS")]
     flags: Option<String>,
 
+    /// In verify mode, use the flags of the manifest even if they
+    /// conflict with flags given on the command line
+    #[arg(long, requires = "verify")]
+    force_flags: bool,
+
     /// Verify mode: provide fdsum json to validate
     #[arg(long, short = 'c', value_name = "FILE")]
     pub verify: Option<String>,
+
+    /// In diff and verify mode, write the paths that differ to FILE for
+    /// use with `rsync --files-from` to repair the right tree or the
+    /// verified tree
+    #[arg(long, value_name = "FILE", global = true)]
+    output_changed_files: Option<PathBuf>,
+
+    /// Separate the paths in --output-changed-files with NUL bytes
+    /// (for `rsync --from0`)
+    #[arg(short = '0', long, global = true, requires = "output_changed_files")]
+    null: bool,
+
+    /// In diff and verify mode, write the paths that differ, as listed
+    /// by --output-changed-files, each followed by a NUL byte to the
+    /// file descriptor FD, e.g. `--print0-changed 3 3>&1 >/dev/null |
+    /// xargs -0 ...`
+    #[arg(long, value_name = "FD", global = true)]
+    print0_changed: Option<i32>,
+
+    /// In verify mode, list only the entries that differ. This is the
+    /// default unless --verbose is given, which lists all compared
+    /// entries.
+    #[arg(long, requires = "verify")]
+    only_mismatches: bool,
+
+    /// On mismatch, ask for each changed entry whether to accept its
+    /// current state and rewrite the manifest with the accepted ones
+    #[arg(long, requires = "verify")]
+    update: bool,
+
+    /// Review mismatches interactively, choosing to accept, inspect or
+    /// fail each changed entry. Decisions are logged to
+    /// FILE.audit.jsonl.
+    #[arg(short = 'i', long, requires = "verify", conflicts_with = "yes")]
+    interactive: bool,
+
+    /// Accept all changes without asking (with --update)
+    #[arg(short = 'y', long, requires = "update")]
+    yes: bool,
+
+    /// Verify only the entry at PATH below the root of the manifest
+    /// against its recorded digest, so that the rest of the tree need
+    /// not be available. The manifest must record the entry (see
+    /// --detail and --detail-dirs). Without -c, hash the entry as it
+    /// is hashed as part of the tree.
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["update", "interactive", "combine", "remote"]
+    )]
+    subtree: Option<PathBuf>,
+
+    /// Verify only the entries recorded in the manifest whose path
+    /// matches the glob PATTERN, or whose name does if the pattern has
+    /// no slash. May be given multiple times (see --detail).
+    #[arg(
+        long,
+        value_name = "PATTERN",
+        requires = "verify",
+        conflicts_with_all = ["update", "interactive", "combine", "remote", "subtree"]
+    )]
+    only: Vec<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Ids of the arguments whose values were taken from the environment
+    #[arg(skip)]
+    from_env: Vec<String>,
+}
+
+impl Args {
+    /// Parse the command line, noting which values came from
+    /// environment variables
+    pub fn parse_noting_env() -> Self {
+        let matches = Self::command().get_matches();
+        let mut args = Self::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        args.from_env = matches
+            .ids()
+            .filter(|id| matches.value_source(id.as_str()) == Some(ValueSource::EnvVariable))
+            .map(|id| id.to_string())
+            .collect();
+        args
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Compare two trees entry by entry. Each may be a directory, an
+    /// archive (zip, tar, tar.gz), s3://bucket/prefix, a WebDAV URL or
+    /// [user@]host:/path.
+    Diff {
+        #[arg(value_name = "LEFT")]
+        left: PathBuf,
+
+        #[arg(value_name = "RIGHT")]
+        right: PathBuf,
+
+        /// LEFT and RIGHT are snapshots of a btrfs or ZFS filesystem,
+        /// LEFT the older. Only the entries the filesystem lists as
+        /// changed between them are read (with btrfs send or zfs diff).
+        #[arg(long)]
+        snapshots: bool,
+    },
+
+    /// Estimate how much of the content of RIGHT is found in LEFT, by
+    /// the content-defined chunks of their files, and list the files
+    /// moved, changed and removed. Each may be a directory or a
+    /// manifest recording the chunks of its files (see --chunks).
+    Similarity {
+        #[arg(value_name = "LEFT")]
+        left: PathBuf,
+
+        #[arg(value_name = "RIGHT")]
+        right: PathBuf,
+    },
+
+    /// Hash the layers and flattened root filesystem of a container
+    /// image (OCI layout directory or docker-save tarball)
+    Image {
+        /// The OCI layout directory or docker-save tarball
+        #[arg(value_name = "IMAGE")]
+        image: PathBuf,
+    },
+
+    /// Print the digests needed to check a single entry against the
+    /// hash in MANIFEST, so that it can be audited without the rest of
+    /// the tree
+    Prove {
+        #[arg(value_name = "MANIFEST")]
+        manifest: String,
+
+        /// The entry, relative to the root of the tree
+        #[arg(value_name = "ENTRY")]
+        entry: PathBuf,
+    },
+
+    /// Merge the manifests of disjoint subtrees, made with the same
+    /// flags and --detail 1 or more, into a manifest of their closest
+    /// common ancestor. The directories between the ancestor and the
+    /// subtrees are read from the local filesystem, without the content
+    /// of files, and must not hold other entries taking part in the
+    /// hash.
+    Merge {
+        #[arg(value_name = "MANIFEST", required = true, num_args = 2..)]
+        manifests: Vec<String>,
+
+        /// Write the merged manifest to FILE instead of stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+
+    /// Hash a tree on storage shared with other hosts by handing its
+    /// entries at a depth below the root to fdsum on those hosts, run
+    /// via ssh, and combining their digests. The directories above
+    /// are read on this host. A host that cannot be reached leaves its
+    /// entries to the others.
+    Shard {
+        /// The tree, at the same path on all hosts
+        #[arg(value_name = "PATH")]
+        path: PathBuf,
+
+        /// The hosts to run fdsum on, as [user@]host
+        #[arg(long, value_name = "HOSTS", required = true, value_delimiter = ',')]
+        workers: Vec<String>,
+
+        /// Depth of the entries handed to the workers
+        #[arg(long, value_name = "N", default_value_t = 1)]
+        depth: usize,
+
+        /// Write the manifest to FILE instead of stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+
+    /// Remove the entries of MANIFEST that no longer exist and update
+    /// the digests of the directories that held them, without reading
+    /// the remaining entries again. The manifest must record the
+    /// entries of those directories (see --detail).
+    Prune {
+        #[arg(value_name = "MANIFEST")]
+        manifest: String,
+
+        /// The tree the manifest was made from [default: the tree named
+        /// in the manifest]
+        #[arg(value_name = "ROOT")]
+        root: Option<PathBuf>,
+    },
+
+    /// Write a manifest for each directory at a depth below the root
+    /// of MANIFEST, named after the manifest and the directory, so that
+    /// the subtrees can be verified separately. The manifest must
+    /// record the entries below that depth (see --detail).
+    Split {
+        #[arg(value_name = "MANIFEST")]
+        manifest: String,
+
+        /// Depth of the directories below the root
+        #[arg(long, value_name = "N", default_value_t = 1)]
+        by_depth: usize,
+
+        /// Write the manifests into DIR [default: next to MANIFEST]
+        #[arg(short, long, value_name = "DIR")]
+        output: Option<PathBuf>,
+    },
+
+    /// Convert the files recorded in MANIFEST to the format of another
+    /// tool, without reading them again. The manifest must record its
+    /// files (see --record-files).
+    Export {
+        #[arg(value_name = "MANIFEST")]
+        manifest: String,
+
+        #[arg(long, value_enum, value_name = "FORMAT")]
+        to: ExportFormat,
+
+        /// Write to FILE instead of stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+
+    /// Write the changes from the manifest OLD to NEW: the entries and
+    /// files added, changed and removed, and the rest of NEW. Keeping
+    /// the deltas of a series of manifests takes less space than the
+    /// manifests (see apply).
+    Delta {
+        #[arg(value_name = "OLD")]
+        old: String,
+
+        #[arg(value_name = "NEW")]
+        new: String,
+
+        /// Write the delta to FILE instead of stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+
+    /// Rebuild the manifest a DELTA was made to from the manifest it
+    /// was made from (see delta)
+    Apply {
+        #[arg(value_name = "OLD")]
+        old: String,
+
+        #[arg(value_name = "DELTA")]
+        delta: PathBuf,
+
+        /// Write the manifest to FILE instead of stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+
+    /// Build a manifest of the content of the regular files listed in
+    /// checksum files, as written by sha256sum and the like or
+    /// hashdeep, without reading the files. Metadata and entries other
+    /// than regular files are left out.
+    Import {
+        #[arg(value_name = "CHECKSUMS", required = true)]
+        lists: Vec<PathBuf>,
+
+        /// The tree the files are listed from [default: the directory
+        /// of the first checksum file]
+        #[arg(long, value_name = "DIR")]
+        root: Option<PathBuf>,
+
+        /// Write the manifest to FILE instead of stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+
+    /// Verify each MANIFEST, or each .json file in a directory given,
+    /// against the tree named in it, and summarize the outcomes
+    VerifyAll {
+        #[arg(value_name = "MANIFEST", required = true)]
+        manifests: Vec<PathBuf>,
+
+        /// Read the roots to verify manifests against from FILE, as
+        /// `manifest,root` lines naming manifests by path or file name
+        #[arg(long, value_name = "FILE")]
+        roots_from: Option<PathBuf>,
+    },
+
+    /// Check a transfer of SRC to another host by hashing SRC here and
+    /// DEST there at the same time, with fdsum run via ssh and the same
+    /// flags, and list the entries that differ. All entries are
+    /// compared unless limited with --detail.
+    XferVerify {
+        #[arg(value_name = "SRC")]
+        source: PathBuf,
+
+        #[arg(value_name = "[USER@]HOST:DEST")]
+        dest: String,
+    },
+
+    /// Verify the entries directly below each ROOT in turn, oldest
+    /// verification first, so that each is verified once per period
+    /// over repeated runs (e.g. daily from cron). New entries are
+    /// recorded, and a mismatch is reported until it is accepted.
+    Scrub {
+        #[arg(value_name = "ROOT", required = true)]
+        roots: Vec<PathBuf>,
+
+        /// Keep the digests and times of verification in DIR
+        #[arg(long, value_name = "DIR")]
+        state: PathBuf,
+
+        /// Verify each entry at least once per DURATION, such as 30d
+        #[arg(long, value_name = "DURATION", default_value = "30d", value_parser = parse_duration)]
+        period: u64,
+
+        /// Start no further entries after DURATION
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+        max_time: Option<u64>,
+
+        /// Start no further entries after reading SIZE bytes
+        #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+        max_bytes: Option<u64>,
+
+        /// Record the current digests of mismatching entries
+        #[arg(long)]
+        accept: bool,
+    },
+
+    /// Answer gRPC calls to hash, verify and diff trees, streaming
+    /// their progress (see proto/fdsum.proto). Calls run with the
+    /// threads and bandwidth limit given here. Needs a build with the
+    /// grpc feature.
+    Serve {
+        /// Listen on ADDR, e.g. 127.0.0.1:50051
+        #[arg(long, value_name = "ADDR")]
+        grpc: std::net::SocketAddr,
+    },
+
+    /// Hash a built-in test tree and compare against known digests
+    Selftest,
+
+    /// Print the built-in test trees and their digests as JSON, for
+    /// checking other implementations. Files with a pattern of N bytes
+    /// hold byte i % 251 at position i.
+    Vectors {
+        /// Also create the trees below DIR, each in a directory named
+        /// after the tree
+        #[arg(value_name = "DIR")]
+        dir: Option<PathBuf>,
+    },
+
+    /// Print a completion script for SHELL
+    Completions {
+        #[arg(value_name = "SHELL")]
+        shell: clap_complete::Shell,
+    },
 }
 
-#[derive(Debug)]
+/// The size, content digest and chunks of regular files by path
+/// relative to the root
+pub type FileDigests = BTreeMap<PathBuf, (u64, [u8; 32], Option<Vec<Chunk>>)>;
+
+#[derive(Debug, Clone)]
 pub struct Config {
     pub path: Option<PathBuf>,
+    /// Roots whose digests are combined into one, in order, instead of
+    /// hashing `path`
+    pub combine: Vec<PathBuf>,
     pub verbose: bool,
+    /// Print machine-readable JSON
+    pub json: bool,
+    /// Write JSON canonically, see [`crate::canonical`]
+    pub canonical: bool,
+    /// Write JSON on a single line, or indented if false. By default
+    /// only results with per-entry digests are compact.
+    pub compact: Option<bool>,
+    pub format: OutputFormat,
     pub algorithm: HashAlgorithm,
+    /// Block size for reading files in bytes, if not chosen per file
     pub block_size: usize,
+    pub auto_block_size: bool,
+    pub block_sizes: Arc<BlockSizes>,
     pub threads: usize,
+    /// The CPUs all threads run on
+    pub cpus: Option<CpuSet>,
+    /// Threads listing the tree apart from those hashing, if not 0
+    pub scan_threads: usize,
+    pub traversal: Traversal,
+    pub bwlimit: Option<Arc<BandwidthLimit>>,
+    /// Blocks of a file read ahead while hashing, none if 0
+    pub prefetch: usize,
+    /// Entries with matching names are skipped
+    pub exclude: Vec<glob::Pattern>,
+    /// Include entries whose name starts with a dot
+    pub include_hidden: bool,
+    pub fips: bool,
     pub verify: Option<String>,
+    /// Mapping of the owner IDs hashed
+    pub idmap: Option<IdMap>,
+    /// The entry below the root of the manifest that is verified alone
+    pub subtree: Option<PathBuf>,
+    /// Only the recorded entries matching any of these are verified
+    pub only: Vec<glob::Pattern>,
+    pub update: bool,
+    pub assume_yes: bool,
+    pub interactive: bool,
+    pub explicit_flags: ExplicitFlags,
+    pub force_flags: bool,
+    /// List only differing entries when verifying
+    pub only_mismatches: bool,
+    /// Where to write the list of changed paths
+    pub changed_files: Option<PathBuf>,
+    pub changed_files_nul: bool,
+    /// Where to write the paths that differ, NUL-separated
+    pub print0_changed: Option<Arc<File>>,
+    pub archive: bool,
+    pub remote: Option<Remote>,
+    pub remote_fdsum: String,
+    pub command: Option<Command>,
 
     pub include_file_content: bool,
     pub include_size: bool,
@@ -113,104 +1098,963 @@ pub struct Config {
     pub include_ctime: bool,
     pub include_mtime: bool,
     pub include_atime: bool,
+    pub include_inode: bool,
+    pub include_nlink: bool,
+    pub include_attributes: bool,
+    pub include_project: bool,
+    pub include_capabilities: bool,
+    pub include_nfs4_acl: bool,
+    pub include_etag: bool,
+    pub include_storage_class: bool,
+    pub include_names: bool,
+    /// Include the metadata of the root entry itself
+    pub include_root_meta: bool,
+    /// Version of the framing of entries
+    pub framing: u32,
+    /// Include directories without entries
+    pub include_empty_dirs: bool,
+    /// Include regular files of size zero
+    pub include_empty_files: bool,
+    /// Bounds of the size in bytes of included regular files
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// Bounds of the modification time of included entries other than
+    /// directories, in seconds since the epoch
+    pub newer_than: Option<i64>,
+    pub older_than: Option<i64>,
+    /// Letters of the included entry types
+    pub types: String,
+    /// Resolution of the hashed timestamps
+    pub time_granularity: TimeGranularity,
+
+    /// Depth down to which per-entry digests are recorded
+    pub detail_depth: usize,
+    /// Record the digests of directories at any depth
+    pub detail_dirs: bool,
+    /// The digests recorded during the run, by path relative to the root
+    pub detail_digests: Arc<Mutex<BTreeMap<PathBuf, [u8; 32]>>>,
+    /// Record the size and content digest of each regular file
+    pub record_files: bool,
+    /// The files recorded during the run, by path relative to the root
+    pub file_digests: Arc<Mutex<FileDigests>>,
+    /// Record the chunks of each regular file recorded
+    pub record_chunks: bool,
+    /// The chunks of the files hashed, by path, until they are recorded
+    pub file_chunks: Arc<Mutex<HashMap<PathBuf, Vec<Chunk>>>>,
+    /// Hash a placeholder for entries that cannot be read for lack of
+    /// permission
+    pub skip_unreadable: bool,
+    /// The entries skipped during the run and why, by path relative to
+    /// the root
+    pub skipped: Arc<Mutex<BTreeMap<PathBuf, ErrorInfo>>>,
 
     pub stats: Arc<SharedStats>,
+    pub cancel: CancelToken,
+    /// The filesystem local paths are read from
+    pub vfs: Arc<dyn Vfs>,
+    /// Where the profile is written
+    pub profile: Option<PathBuf>,
+    /// Where a record of the run is appended
+    pub history: Option<PathBuf>,
+    /// Where the results of hashing are appended
+    pub append_results: Option<PathBuf>,
+    /// Log the outcome of the run to the system journal
+    pub syslog: bool,
+    /// Where the record of the run is posted
+    pub notify_url: Option<String>,
+    pub notify_on: NotifyOn,
+    /// Commands run when the run completes, or found a mismatch
+    pub on_complete: Option<String>,
+    pub on_mismatch: Option<String>,
+    /// Lock the trees hashed against other runs, and wait for the
+    /// lock if held
+    pub lock: bool,
+    pub wait_lock: bool,
+    /// Overwrite existing output files
+    pub force: bool,
+    /// Accept commands on stdin
+    pub control: bool,
+    pub profiler: Option<Arc<Profiler>>,
+    /// Where the events on single entries are written
+    pub events: Option<Arc<EventStream>>,
+    /// Templates replacing the defaults of the progress bars
+    pub bytes_template: Option<String>,
+    pub entries_template: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            path: None,
+            combine: Vec::new(),
+            verbose: false,
+            json: false,
+            canonical: false,
+            compact: None,
+            format: OutputFormat::Json,
+            algorithm: HashAlgorithm::Sha256,
+            block_size: DEFAULT_BLOCK_SIZE,
+            auto_block_size: true,
+            block_sizes: Arc::default(),
+            threads: limits::default_threads(),
+            cpus: None,
+            scan_threads: 0,
+            traversal: Traversal::Dfs,
+            bwlimit: None,
+            prefetch: 0,
+            exclude: Vec::new(),
+            include_hidden: true,
+            fips: false,
+            verify: None,
+            idmap: None,
+            subtree: None,
+            only: Vec::new(),
+            update: false,
+            assume_yes: false,
+            interactive: false,
+            explicit_flags: ExplicitFlags::default(),
+            force_flags: false,
+            only_mismatches: true,
+            changed_files: None,
+            changed_files_nul: false,
+            print0_changed: None,
+            archive: false,
+            remote: None,
+            remote_fdsum: "fdsum".to_string(),
+            command: None,
+            include_file_content: true,
+            include_size: true,
+            include_mode: true,
+            include_uid: true,
+            include_gid: true,
+            include_ctime: false,
+            include_mtime: true,
+            include_atime: false,
+            include_inode: false,
+            include_nlink: false,
+            include_attributes: false,
+            include_project: false,
+            include_capabilities: false,
+            include_nfs4_acl: false,
+            include_etag: false,
+            include_storage_class: false,
+            include_names: false,
+            include_root_meta: true,
+            framing: FRAMING_VERSION,
+            include_empty_dirs: true,
+            include_empty_files: true,
+            min_size: None,
+            max_size: None,
+            newer_than: None,
+            older_than: None,
+            types: all_types(),
+            time_granularity: TimeGranularity::default(),
+
+            detail_depth: 0,
+            detail_dirs: false,
+            detail_digests: Arc::default(),
+            record_files: false,
+            file_digests: Arc::default(),
+            record_chunks: false,
+            file_chunks: Arc::default(),
+            skip_unreadable: false,
+            skipped: Arc::default(),
+
+            stats: Arc::new(SharedStats::new()),
+            cancel: CancelToken::default(),
+            vfs: Arc::new(OsFs),
+            profile: None,
+            history: None,
+            append_results: None,
+            syslog: false,
+            notify_url: None,
+            notify_on: NotifyOn::Complete,
+            on_complete: None,
+            on_mismatch: None,
+            lock: true,
+            wait_lock: false,
+            force: false,
+            control: false,
+            profiler: None,
+            events: None,
+            bytes_template: None,
+            entries_template: None,
+        }
+    }
 }
 
 impl Config {
     pub fn hasher(&self) -> Box<dyn algo::Hasher> {
-        match self.algorithm {
-            HashAlgorithm::Blake3 => Box::new(Sha256::new()),
-            HashAlgorithm::Sha256 => Box::new(algo::Blake3Wrapper::new()),
+        self.algorithm.hasher()
+    }
+
+    /// Apply the settings of a configuration file on top of the
+    /// current ones
+    pub fn apply_file_config(&mut self, file: FileConfig) -> Result<()> {
+        if let Some(flags) = &file.flags {
+            self.set_flags_from_string(flags)?;
+        }
+        if let Some(algorithm) = file.algorithm {
+            self.algorithm = algorithm;
+        }
+        if let Some(threads) = file.threads {
+            self.threads = threads;
+        }
+        if let Some(scan_threads) = file.scan_threads {
+            self.scan_threads = scan_threads;
+        }
+        if let Some(traversal) = file.traversal {
+            self.traversal = traversal;
+        }
+        if let Some(block_size) = file.block_size {
+            self.block_size = block_size * 1024;
+            self.auto_block_size = false;
+        }
+        if let Some(bwlimit) = file.bwlimit {
+            self.set_bwlimit(bwlimit.saturating_mul(1024));
         }
+        if let Some(prefetch) = file.prefetch {
+            self.prefetch = prefetch;
+        }
+        if let Some(fips) = file.fips {
+            self.fips = fips;
+        }
+        if let Some(history) = file.history {
+            self.history = Some(history);
+        }
+        if let Some(append_results) = file.append_results {
+            self.append_results = Some(append_results);
+        }
+        self.add_excludes(&file.exclude)
     }
 
-    pub fn flags_string(&self) -> String {
-        let mut flags = String::new();
-        if self.include_file_content {
-            flags.push('c');
+    /// Fail if the algorithm is not allowed in the current mode
+    pub fn check_algorithm(&self) -> Result<()> {
+        if self.fips && !self.algorithm.is_fips_approved() {
+            return Err(anyhow!("{} is not approved in FIPS mode", self.algorithm));
+        }
+        Ok(())
+    }
+
+    /// Limit reading to `rate` bytes per second, or lift the limit if
+    /// `rate` is zero
+    pub fn set_bwlimit(&mut self, rate: u64) {
+        self.bwlimit = (rate > 0).then(|| Arc::new(BandwidthLimit::new(rate)));
+    }
+
+    pub fn add_excludes(&mut self, patterns: &[String]) -> Result<()> {
+        for pattern in patterns {
+            self.exclude.push(
+                glob::Pattern::new(pattern)
+                    .with_context(|| format!("Invalid exclude pattern: {}", pattern))?,
+            );
+        }
+        Ok(())
+    }
+
+    /// Whether the entry at `rel` below the root is selected by `only`
+    pub fn is_selected(&self, rel: &Path) -> bool {
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+        self.only.iter().any(|pattern| {
+            if pattern.as_str().contains('/') {
+                pattern.matches_path_with(rel, options)
+            } else {
+                rel.file_name()
+                    .is_some_and(|name| pattern.matches_with(&name.to_string_lossy(), options))
+            }
+        })
+    }
+
+    /// Whether an entry named `name` is skipped
+    pub fn is_excluded(&self, name: &OsStr) -> bool {
+        if !self.include_hidden && name.as_encoded_bytes().starts_with(b".") {
+            return true;
+        }
+        if self.exclude.is_empty() {
+            return false;
+        }
+        let name = name.to_string_lossy();
+        self.exclude.iter().any(|pattern| pattern.matches(&name))
+    }
+
+    /// Whether the entry at `rel` below the root is left out of the
+    /// hash by its type, the size bounds of regular files or the
+    /// modification time bounds. The root is never left out.
+    pub fn skips_entry(&self, rel: &Path, kind: FileKind, meta: &EntryMeta) -> bool {
+        if rel.as_os_str().is_empty() || kind == FileKind::Dir {
+            return false;
+        }
+        if !self.types.contains(type_tag(kind) as char) {
+            return true;
+        }
+        if kind == FileKind::File
+            && let Some(size) = meta.size
+            && ((size == 0 && !self.include_empty_files)
+                || self.min_size.is_some_and(|min| size < min)
+                || self.max_size.is_some_and(|max| size > max))
+        {
+            return true;
+        }
+        meta.mtime.is_some_and(|mtime| {
+            self.newer_than.is_some_and(|t| mtime < t)
+                || self.older_than.is_some_and(|t| mtime >= t)
+        })
+    }
+
+    /// Open a profile frame for the entry at `rel` below the root if
+    /// profiling and it is a directory or a large file
+    pub fn profile_span(&self, rel: &Path, kind: FileKind, size: u64) -> Option<Span<'_>> {
+        let profiler = self.profiler.as_ref()?;
+        (kind == FileKind::Dir || (kind == FileKind::File && size >= LARGE_FILE))
+            .then(|| profiler.span(rel))
+    }
+
+    /// Whether the paths that differ are to be written out
+    pub fn lists_changed(&self) -> bool {
+        self.changed_files.is_some() || self.print0_changed.is_some()
+    }
+
+    /// Whether the directory at `rel` below the root is left out of the
+    /// hash for having no included entries
+    pub fn skips_empty_dir(&self, rel: &Path, entries: usize) -> bool {
+        (!self.include_empty_dirs || !self.types.contains('d'))
+            && !rel.as_os_str().is_empty()
+            && entries == 0
+    }
+
+    /// Report that the listing of the directory or the reading of the
+    /// file at `rel` below the root starts
+    pub fn entry_started(&self, rel: &Path) {
+        if let Some(events) = &self.events {
+            events.started(rel);
         }
-        if self.include_size {
-            flags.push('s');
+    }
+
+    /// Report that the run on the tree at `root` failed with `err`, at
+    /// the entry the error names
+    pub fn report_failure(&self, root: &Path, err: &anyhow::Error) {
+        let Some(events) = &self.events else {
+            return;
+        };
+        let rel = err
+            .chain()
+            .find_map(|e| e.downcast_ref::<FsError>())
+            .and_then(|fs| fs.path.strip_prefix(root).ok())
+            .unwrap_or(Path::new(""));
+        events.error(rel, err);
+    }
+
+    /// Report the digest of the entry at `rel` below the root, and
+    /// record it if it is within the detail depth or a directory to be
+    /// recorded
+    pub fn record_detail(&self, rel: &Path, is_dir: bool, digest: &[u8; 32]) {
+        if let Some(events) = &self.events {
+            events.finished(rel, &self.algorithm.encode(digest));
+        }
+        let depth = rel.components().count();
+        if depth == 0 || (depth > self.detail_depth && !(is_dir && self.detail_dirs)) {
+            return;
+        }
+        self.detail_digests
+            .lock()
+            .unwrap()
+            .insert(rel.to_path_buf(), *digest);
+    }
+
+    /// Whether `err` in reading the entry at `rel` below the root is
+    /// for lack of permission and the entry is to be skipped, and if so
+    /// record it. The root itself is never skipped.
+    pub fn skips_unreadable(&self, rel: &Path, err: &anyhow::Error) -> bool {
+        let denied = err
+            .chain()
+            .filter_map(|e| e.downcast_ref::<std::io::Error>())
+            .any(|e| e.kind() == std::io::ErrorKind::PermissionDenied);
+        if !self.skip_unreadable || !denied || rel.as_os_str().is_empty() {
+            return false;
+        }
+        self.skipped
+            .lock()
+            .unwrap()
+            .insert(rel.to_path_buf(), ErrorInfo::from_error(err));
+        if let Some(events) = &self.events {
+            events.error(rel, err);
+        }
+        true
+    }
+
+    /// The metadata of the entry at `path`, not following symlinks,
+    /// with the attributes, project IDs and ACLs of files and
+    /// directories and the capabilities of files if included
+    pub fn stat(&self, path: &Path) -> Result<Stat> {
+        let mut stat = self.vfs.symlink_metadata(path)?;
+        let file_or_dir = matches!(stat.kind, FileKind::File | FileKind::Dir);
+        if self.include_attributes && file_or_dir {
+            stat.meta.attributes = self.vfs.attributes(path)?;
+        }
+        if self.include_project && file_or_dir {
+            stat.meta.project = self.vfs.project(path)?;
+        }
+        if self.include_nfs4_acl && file_or_dir {
+            stat.meta.nfs4_acl = self.vfs.nfs4_acl(path)?;
+        }
+        if self.include_capabilities && stat.kind == FileKind::File {
+            stat.meta.capabilities = self.vfs.capabilities(path)?;
+        }
+        Ok(stat)
+    }
+
+    /// The size of the reads of the file at `path`: the block size
+    /// given, or one chosen for the file
+    pub fn read_size(&self, path: &Path) -> usize {
+        if !self.auto_block_size {
+            return self.block_size;
         }
-        if self.include_mode {
-            flags.push('p');
+        self.block_sizes.for_file(path).unwrap_or(self.block_size)
+    }
+
+    /// Record the size and content digest of the regular file at `rel`
+    /// below the root, read from `path`, if files are recorded, with
+    /// the chunks found in hashing it
+    pub fn record_file(&self, path: &Path, rel: &Path, size: u64, digest: &[u8; 32]) {
+        if !self.record_files {
+            return;
         }
-        if self.include_uid {
-            flags.push('u');
+        let chunks = if self.record_chunks {
+            self.file_chunks.lock().unwrap().remove(path)
+        } else {
+            None
+        };
+        self.file_digests
+            .lock()
+            .unwrap()
+            .insert(rel.to_path_buf(), (size, *digest, chunks));
+    }
+
+    /// Whether the metadata named by a flag letter is included
+    pub fn flag(&self, letter: char) -> bool {
+        match letter {
+            'c' => self.include_file_content,
+            's' => self.include_size,
+            'p' => self.include_mode,
+            'u' => self.include_uid,
+            'g' => self.include_gid,
+            't' => self.include_ctime,
+            'm' => self.include_mtime,
+            'a' => self.include_atime,
+            'i' => self.include_inode,
+            'h' => self.include_nlink,
+            'x' => self.include_attributes,
+            'q' => self.include_project,
+            'k' => self.include_capabilities,
+            'e' => self.include_nfs4_acl,
+            'o' => self.include_etag,
+            'r' => self.include_storage_class,
+            'n' => self.include_names,
+            _ => false,
         }
-        if self.include_gid {
-            flags.push('g');
+    }
+
+    /// Describe how the current settings differ from the flags given
+    /// explicitly on the command line
+    pub fn explicit_flag_conflicts(&self) -> Vec<String> {
+        let mut conflicts = Vec::new();
+        if let Some(algorithm) = &self.explicit_flags.algorithm
+            && *algorithm != self.algorithm
+        {
+            conflicts.push(format!(
+                "algorithm: {} on the command line, {} in the manifest",
+                algorithm, self.algorithm
+            ));
         }
-        if self.include_ctime {
-            flags.push('t');
+        let describe = |included| if included { "included" } else { "excluded" };
+        for &(letter, included) in &self.explicit_flags.include {
+            if self.flag(letter) != included {
+                let name = FLAG_NAMES
+                    .iter()
+                    .find(|(l, _)| *l == letter)
+                    .map_or("", |(_, name)| name);
+                conflicts.push(format!(
+                    "{} ({}): {} on the command line, {} in the manifest",
+                    name,
+                    letter,
+                    describe(included),
+                    describe(self.flag(letter))
+                ));
+            }
         }
-        if self.include_mtime {
-            flags.push('m');
+        conflicts.dedup();
+        conflicts
+    }
+
+    pub fn flags(&self) -> Flags {
+        Flags {
+            algorithm: self.algorithm.clone(),
+            include: FLAG_NAMES
+                .iter()
+                .map(|&(letter, _)| letter)
+                .filter(|&letter| self.flag(letter))
+                .collect(),
+            exclude: self
+                .exclude
+                .iter()
+                .map(|p| p.as_str().to_string())
+                .collect(),
+            hidden: self.include_hidden,
+            root_meta: self.include_root_meta,
+            framing: self.framing,
+            empty_dirs: self.include_empty_dirs,
+            empty_files: self.include_empty_files,
+            min_size: self.min_size,
+            max_size: self.max_size,
+            newer_than: self.newer_than,
+            older_than: self.older_than,
+            types: self.types.clone(),
+            time_granularity: self.time_granularity,
         }
-        if self.include_atime {
-            flags.push('a');
+    }
+
+    /// Adopt all settings recorded in `flags`
+    pub fn set_flags(&mut self, flags: &Flags) -> Result<()> {
+        self.algorithm = flags.algorithm.clone();
+        self.include_file_content = flags.includes('c');
+        self.include_size = flags.includes('s');
+        self.include_mode = flags.includes('p');
+        self.include_uid = flags.includes('u');
+        self.include_gid = flags.includes('g');
+        self.include_ctime = flags.includes('t');
+        self.include_mtime = flags.includes('m');
+        self.include_atime = flags.includes('a');
+        self.include_inode = flags.includes('i');
+        self.include_nlink = flags.includes('h');
+        self.include_attributes = flags.includes('x');
+        self.include_project = flags.includes('q');
+        self.include_capabilities = flags.includes('k');
+        self.include_nfs4_acl = flags.includes('e');
+        self.include_etag = flags.includes('o');
+        self.include_storage_class = flags.includes('r');
+        self.include_names = flags.includes('n');
+        self.include_root_meta = flags.root_meta;
+        self.framing = flags.framing;
+        self.include_empty_dirs = flags.empty_dirs;
+        self.include_empty_files = flags.empty_files;
+        self.min_size = flags.min_size;
+        self.max_size = flags.max_size;
+        self.newer_than = flags.newer_than;
+        self.older_than = flags.older_than;
+        self.types = flags.types.clone();
+        self.time_granularity = flags.time_granularity;
+        self.include_hidden = flags.hidden;
+        self.exclude.clear();
+        self.add_excludes(&flags.exclude)
+    }
+
+    /// The templates of the progress bars for the terminal on stderr
+    pub fn progress_templates(&self) -> ProgressTemplates {
+        let (_, width) = console::Term::stderr().size();
+        let defaults = ProgressTemplates::for_width(width);
+        ProgressTemplates {
+            bytes: self.bytes_template.clone().unwrap_or(defaults.bytes),
+            entries: self.entries_template.clone().unwrap_or(defaults.entries),
         }
+    }
+
+    /// Serialize `value` as JSON for output, canonically or compact if
+    /// requested
+    pub fn to_json<T: Serialize + ?Sized>(&self, value: &T) -> Result<String> {
+        self.json_string(value, false)
+    }
 
-        format!("v1:{}:{}", self.algorithm, flags)
+    /// Serialize a result for output, compact by default if it records
+    /// per-entry digests or files
+    pub fn result_json(&self, result: &HashResultJson) -> Result<String> {
+        self.json_string(result, result.detail.is_some() || result.files.is_some())
+    }
+
+    fn json_string<T: Serialize + ?Sized>(&self, value: &T, compact: bool) -> Result<String> {
+        if self.canonical {
+            canonical::to_string(value)
+        } else if self.compact.unwrap_or(compact) {
+            Ok(serde_json::to_string(value)?)
+        } else {
+            Ok(serde_json::to_string_pretty(value)?)
+        }
+    }
+
+    pub fn flags_string(&self) -> String {
+        self.flags().to_string()
     }
 
     pub fn set_flags_from_string(&mut self, flags: &str) -> Result<()> {
-        let parts: Vec<&str> = flags.split(':').collect();
-        if parts.len() != 3 || parts[0] != "v1" {
-            return Err(anyhow!("Unsupported config flags string format"));
+        self.set_flags(&Flags::parse(flags)?)
+    }
+}
+
+/// Flag settings given explicitly on the command line, checked against
+/// the flags of a manifest in verify mode
+#[derive(Debug, Clone, Default)]
+pub struct ExplicitFlags {
+    pub algorithm: Option<HashAlgorithm>,
+    /// Flag letters and whether they were included or excluded
+    pub include: Vec<(char, bool)>,
+}
+
+impl ExplicitFlags {
+    /// Values from environment variables are defaults like those of
+    /// configuration files and do not count as explicit
+    fn from_args(args: &Args) -> Result<Self> {
+        let from_env = |id: &str| args.from_env.iter().any(|e| e == id);
+        if let Some(flags) = args.flags.as_ref().filter(|_| !from_env("flags")) {
+            let mut config = Config::default();
+            config.set_flags_from_string(flags)?;
+            return Ok(Self {
+                algorithm: Some(config.algorithm.clone()),
+                include: FLAG_NAMES
+                    .iter()
+                    .map(|&(letter, _)| (letter, config.flag(letter)))
+                    .collect(),
+            });
         }
+        let mut include = Vec::new();
+        for (given, letters, value) in [
+            (args.no_content, "c", false),
+            (args.no_size, "s", false),
+            (args.no_perms, "pug", false),
+            (args.no_mode, "p", false),
+            (args.no_owner, "u", false),
+            (args.no_group, "g", false),
+            (args.no_mtime, "m", false),
+            (args.ctime, "t", true),
+            (args.atime, "a", true),
+            (args.inode, "i", true),
+            (args.nlink, "h", true),
+            (args.attributes, "x", true),
+            (args.project, "q", true),
+            (args.capabilities, "k", true),
+            (args.nfs4_acl, "e", true),
+            (args.etag, "o", true),
+            (args.storage_class, "r", true),
+            (args.names, "n", true),
+        ] {
+            if given {
+                include.extend(letters.chars().map(|letter| (letter, value)));
+            }
+        }
+        Ok(Self {
+            algorithm: args.algorithm.clone().filter(|_| !from_env("algorithm")),
+            include,
+        })
+    }
+}
 
-        self.algorithm = HashAlgorithm::from_str(parts[1], false)
-            .expect("unknown algorithm in config flag string");
+/// Defaults read from a configuration file. Settings not given in the
+/// file keep their previous value, exclude patterns accumulate.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    pub algorithm: Option<HashAlgorithm>,
+    pub flags: Option<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    pub threads: Option<usize>,
+    pub scan_threads: Option<usize>,
+    pub traversal: Option<Traversal>,
+    /// In kiB
+    pub block_size: Option<usize>,
+    /// In kiB per second
+    pub bwlimit: Option<u64>,
+    pub prefetch: Option<usize>,
+    pub fips: Option<bool>,
+    pub history: Option<PathBuf>,
+    pub append_results: Option<PathBuf>,
+}
 
-        self.include_file_content = parts[2].contains('c');
-        self.include_size = parts[2].contains('s');
-        self.include_mode = parts[2].contains('p');
-        self.include_uid = parts[2].contains('u');
-        self.include_gid = parts[2].contains('g');
-        self.include_ctime = parts[2].contains('t');
-        self.include_mtime = parts[2].contains('m');
-        self.include_atime = parts[2].contains('a');
+impl FileConfig {
+    /// Read `path`, returning `None` if it does not exist
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| path.display().to_string()),
+        };
+        let config = toml::from_str(&text).with_context(|| path.display().to_string())?;
+        Ok(Some(config))
+    }
 
-        Ok(())
+    /// The per-user configuration file
+    pub fn user_path() -> Option<PathBuf> {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+            .map(|dir| dir.join("fdsum").join("config.toml"))
     }
 }
 
 impl TryFrom<Args> for Config {
     type Error = anyhow::Error;
 
-    fn try_from(args: Args) -> Result<Self> {
+    fn try_from(mut args: Args) -> Result<Self> {
+        let explicit_flags = ExplicitFlags::from_args(&args)?;
+        let flags_from_env = args.from_env.iter().any(|id| id == "flags");
+        let (path, combine) = if args.combine {
+            (None, std::mem::take(&mut args.path))
+        } else if args.path.len() > 1 {
+            return Err(anyhow!("Several paths can only be hashed with --combine"));
+        } else {
+            (args.path.pop(), Vec::new())
+        };
         let mut obj = Self {
-            path: args.path,
+            explicit_flags,
+            force_flags: args.force_flags,
+            only_mismatches: args.only_mismatches || !args.verbose,
+            changed_files: args.output_changed_files,
+            profiler: args.profile.as_ref().map(|_| Arc::new(Profiler::new())),
+            events: match &args.events {
+                Some(target) => Some(Arc::new(EventStream::open(target)?)),
+                None => None,
+            },
+            profile: args.profile,
+            syslog: args.syslog,
+            notify_url: args.notify_url,
+            notify_on: args.notify_on,
+            on_complete: args.on_complete,
+            on_mismatch: args.on_mismatch,
+            lock: !args.no_lock,
+            wait_lock: args.wait_lock,
+            force: args.force,
+            control: args.control,
+            changed_files_nul: args.null,
+            print0_changed: match args.print0_changed {
+                Some(fd) if args.events.as_deref() == Some(&format!("fd:{}", fd)) => {
+                    return Err(anyhow!(
+                        "--print0-changed and --events cannot both write to file descriptor {}",
+                        fd
+                    ));
+                }
+                Some(fd) => Some(Arc::new(
+                    crate::job::open_fd(fd).context("--print0-changed")?,
+                )),
+                None => None,
+            },
+            path,
+            combine,
             verbose: args.verbose,
-            algorithm: args.algorithm,
-            block_size: args.block_size * 1024,
-            threads: args.num_threads.unwrap_or(num_cpus::get().min(8)),
+            json: args.json,
+            canonical: args.canonical,
+            compact: match (args.compact, args.pretty) {
+                (false, false) => None,
+                (compact, _) => Some(compact),
+            },
+            format: args.format,
             verify: args.verify,
-            include_file_content: !args.no_content,
-            include_size: !args.no_size,
-            include_mode: !args.no_perms && !args.no_mode,
-            include_uid: !args.no_perms && !args.no_owner,
-            include_gid: !args.no_perms && !args.no_group,
-            include_mtime: !args.no_mtime,
-            include_ctime: args.ctime,
-            include_atime: args.atime,
+            subtree: args.subtree,
+            update: args.update || args.interactive,
+            assume_yes: args.yes,
+            interactive: args.interactive,
+            archive: args.archive,
+            remote: args
+                .remote
+                .map(|spec| Remote::parse(&spec).ok_or_else(|| anyhow!("Invalid remote: {}", spec)))
+                .transpose()?,
+            remote_fdsum: args.remote_fdsum,
+            command: args.command,
+            detail_depth: args.detail,
+            detail_dirs: args.detail_dirs,
+            record_files: args.record_files || args.chunks,
+            record_chunks: args.chunks,
+            skip_unreadable: args.skip_unreadable,
+            ..Self::default()
+        };
 
-            stats: Arc::new(SharedStats::new()),
+        if !args.no_config {
+            let mut files: Vec<PathBuf> = FileConfig::user_path().into_iter().collect();
+            if let Some(path) = obj.path.as_ref().filter(|path| path.is_dir()) {
+                files.push(path.join(".fdsum.toml"));
+            }
+            for file in files {
+                if let Some(file) = FileConfig::load(&file)? {
+                    obj.apply_file_config(file)?;
+                }
+            }
+        }
+        // a default like those of configuration files
+        if let Some(flags) = args.flags.as_ref().filter(|_| flags_from_env) {
+            obj.set_flags_from_string(flags)?;
+        }
+
+        if let Some(algorithm) = args.algorithm {
+            obj.algorithm = algorithm;
+        }
+        if let Some(history) = args.history {
+            obj.history = Some(history);
+        }
+        if let Some(append_results) = args.append_results {
+            obj.append_results = Some(append_results);
+        }
+        if let Some(block_size) = args.block_size {
+            obj.block_size = block_size * 1024;
+            obj.auto_block_size = false;
+        }
+        obj.cpus = match (args.cpus, args.numa_node) {
+            (Some(cpus), _) => Some(cpus),
+            (None, Some(node)) => {
+                let root = obj
+                    .path
+                    .as_deref()
+                    .or(obj.combine.first().map(PathBuf::as_path));
+                let cpus = root.map(|root| node.cpus(root)).transpose()?.flatten();
+                if cpus.is_none() {
+                    eprintln!(
+                        "Note: the NUMA node of the storage is not known, running on all CPUs"
+                    );
+                }
+                cpus
+            }
+            (None, None) => None,
         };
-        if let Some(flags) = args.flags {
+        if let Some(threads) = args.num_threads {
+            obj.threads = threads;
+        } else if let Some(cpus) = &obj.cpus {
+            obj.threads = obj.threads.min(cpus.len());
+        }
+        if let Some(scan_threads) = args.scan_threads {
+            obj.scan_threads = scan_threads;
+        }
+        if let Some(traversal) = args.traversal {
+            obj.traversal = traversal;
+        }
+        if let Some(bwlimit) = args.bwlimit {
+            obj.set_bwlimit(bwlimit.saturating_mul(1024));
+        }
+        if obj.control {
+            if obj.verify.as_deref() == Some("-") {
+                return Err(anyhow!(
+                    "--control reads commands from stdin, not a manifest"
+                ));
+            }
+            // to be set while running
+            obj.bwlimit
+                .get_or_insert_with(|| Arc::new(BandwidthLimit::new(0)));
+        }
+        if let Some(prefetch) = args.prefetch {
+            obj.prefetch = prefetch;
+        }
+        for spec in &args.progress_template {
+            let (bar, template) = spec
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Expected BAR=TEMPLATE: {}", spec))?;
+            ProgressTemplates::validate(template)?;
+            match bar {
+                "bytes" => obj.bytes_template = Some(template.to_string()),
+                "entries" => obj.entries_template = Some(template.to_string()),
+                _ => {
+                    return Err(anyhow!(
+                        "Unknown progress bar: {}, use bytes or entries",
+                        bar
+                    ));
+                }
+            }
+        }
+        obj.add_excludes(&args.exclude)?;
+        for pattern in &args.only {
+            obj.only.push(
+                glob::Pattern::new(pattern)
+                    .with_context(|| format!("Invalid pattern: {}", pattern))?,
+            );
+        }
+        for path in &args.exclude_from {
+            obj.add_excludes(&read_exclude_file(path)?)?;
+        }
+        if let Some(path) = &args.idmap {
+            let idmap = IdMap::read(path)?;
+            obj.idmap = Some(match obj.verify {
+                Some(_) => idmap
+                    .inverse()
+                    .with_context(|| path.display().to_string())?,
+                None => idmap,
+            });
+        }
+        obj.include_hidden &= !args.no_hidden;
+        obj.include_file_content &= !args.no_content;
+        obj.include_size &= !args.no_size;
+        obj.include_mode &= !args.no_perms && !args.no_mode;
+        obj.include_uid &= !args.no_perms && !args.no_owner;
+        obj.include_gid &= !args.no_perms && !args.no_group;
+        obj.include_mtime &= !args.no_mtime;
+        obj.include_ctime |= args.ctime;
+        obj.include_atime |= args.atime;
+        obj.include_inode |= args.inode;
+        obj.include_nlink |= args.nlink;
+        obj.include_attributes |= args.attributes;
+        obj.include_project |= args.project;
+        obj.include_capabilities |= args.capabilities;
+        obj.include_nfs4_acl |= args.nfs4_acl;
+        obj.include_etag |= args.etag;
+        obj.include_storage_class |= args.storage_class;
+        obj.include_names |= args.names;
+        obj.include_root_meta &= !args.no_root_meta;
+        obj.include_empty_dirs &= !args.no_empty_dirs;
+        obj.include_empty_files &= !args.no_empty_files;
+        obj.min_size = args.min_size.or(obj.min_size);
+        obj.max_size = args.max_size.or(obj.max_size);
+        obj.newer_than = args.newer_than.or(obj.newer_than);
+        obj.older_than = args.older_than.or(obj.older_than);
+        if let Some(types) = args.types {
+            obj.types = types;
+        }
+        if let Some(granularity) = args.time_granularity {
+            obj.time_granularity = granularity;
+        }
+        if let Some(framing) = args.framing {
+            obj.framing = framing;
+        }
+        obj.fips |= args.fips;
+
+        if let Some(flags) = args.flags.filter(|_| !flags_from_env) {
             obj.set_flags_from_string(flags.as_str())?;
         }
-        if obj.path.is_none() && obj.verify.is_none() {
+        obj.check_algorithm()?;
+        if obj.record_files && !obj.include_file_content {
+            return Err(anyhow!(
+                "Files can only be recorded with the content of files included"
+            ));
+        }
+        if obj.skip_unreadable && (obj.traversal != Traversal::Dfs || obj.scan_threads > 0) {
+            return Err(anyhow!(
+                "Unreadable entries can only be skipped with --traversal dfs \
+                 and without --scan-threads"
+            ));
+        }
+        if obj.path.is_none()
+            && obj.combine.is_empty()
+            && obj.remote.is_none()
+            && obj.verify.is_none()
+            && obj.command.is_none()
+        {
             return Err(anyhow!("Neither PATH nor verify FILE specified"));
         }
         Ok(obj)
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The version of the layout of [`HashResultJson`] written
+pub const SCHEMA_VERSION: u32 = 1;
+
+fn schema_v1() -> u32 {
+    1
+}
+
+/// A result as written to manifests. Read manifests with
+/// [`HashResultJson::parse`], which accepts all known layouts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HashResultJson {
+    /// Version of the layout. Manifests without it use version 1, or
+    /// were made before 0.3.0 if they have no provenance either.
+    #[serde(default = "schema_v1")]
+    pub schema: u32,
     pub name: PathBuf,
     pub hash: String,
     pub flags: String,
@@ -223,21 +2067,239 @@ pub struct HashResultJson {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub elapsed_seconds: Option<f64>,
+
+    /// Made in FIPS mode
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub fips: bool,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<Detail>,
+
+    /// Time spent in each phase of the run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phases: Option<Phases>,
+
+    /// Statistics of each thread of the run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub workers: Option<Vec<WorkerStats>>,
+
+    /// The results of the roots of a combined result, in the order
+    /// their digests are combined
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub roots: Option<Vec<HashResultJson>>,
+
+    /// The regular files by path relative to the root
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<BTreeMap<String, FileRecord>>,
+
+    /// The chunking of the chunks of files recorded (see --chunks)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunking: Option<String>,
+
+    /// The entries that could not be read and were hashed as a
+    /// placeholder, by path relative to the root (see --skip-unreadable)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<Vec<String>>,
+
+    /// Why the entries skipped could not be read
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<ErrorInfo>,
+}
+
+/// The size and content digest of a regular file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileRecord {
+    pub size: u64,
+    pub hash: String,
+    /// The content-defined chunks of the file, in order
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<Vec<Chunk>>,
+}
+
+impl FileRecord {
+    /// Collect the files recorded during the run, if requested
+    pub fn from_config(config: &Config) -> Option<BTreeMap<String, Self>> {
+        if !config.record_files {
+            return None;
+        }
+        let files = config
+            .file_digests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, (size, digest, chunks))| {
+                let record = Self {
+                    size: *size,
+                    hash: config.algorithm.encode(digest),
+                    chunks: chunks.clone(),
+                };
+                (path.to_string_lossy().into_owned(), record)
+            })
+            .collect();
+        Some(files)
+    }
+}
+
+/// The digests of the entries down to a depth below the root
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Detail {
+    pub depth: usize,
+    /// Directories below `depth` are recorded as well
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dirs: bool,
+    /// Hex digests by path relative to the root
+    pub entries: BTreeMap<String, String>,
+}
+
+impl Detail {
+    /// Collect the digests recorded during the run, if any were requested
+    pub fn from_config(config: &Config) -> Option<Self> {
+        if config.detail_depth == 0 && !config.detail_dirs {
+            return None;
+        }
+        let entries = config
+            .detail_digests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, digest)| {
+                let digest = config.algorithm.encode(digest);
+                (path.to_string_lossy().into_owned(), digest)
+            })
+            .collect();
+        Some(Self {
+            depth: config.detail_depth,
+            dirs: config.detail_dirs,
+            entries,
+        })
+    }
+}
+
+/// How and where a result was produced
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Provenance {
+    pub fdsum_version: String,
+    /// Version of the framing of entries in the hash
+    pub format_version: u32,
+    pub hostname: Option<String>,
+    pub user: Option<String>,
+    /// The absolute path of the tree, for local trees
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root: Option<PathBuf>,
+    pub started: String,
+    pub finished: String,
+}
+
+impl Provenance {
+    /// Describe a run on this host that took `elapsed` until now
+    pub fn current(name: &Path, framing: u32, elapsed: std::time::Duration) -> Self {
+        let finished = chrono::Utc::now();
+        let started = finished - chrono::Duration::from_std(elapsed).unwrap_or_default();
+        Self {
+            fdsum_version: env!("CARGO_PKG_VERSION").to_string(),
+            format_version: framing,
+            hostname: gethostname::gethostname().into_string().ok(),
+            user: std::env::var("USER")
+                .or_else(|_| std::env::var("LOGNAME"))
+                .ok(),
+            root: std::fs::canonicalize(name).ok(),
+            started: started.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            finished: finished.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        }
+    }
 }
 
 impl HashResultJson {
-    pub fn from_result(config: &Config, hash: &[u8]) -> Self {
+    /// Read a result in any known layout, converting older layouts to
+    /// the current one
+    pub fn parse(json: &str) -> Result<Self> {
+        Self::from_value(serde_json::from_str(json)?)
+    }
+
+    pub fn from_value(value: serde_json::Value) -> Result<Self> {
+        let schema = match value.get("schema") {
+            // the layout before schema versions, made with the sha256 and
+            // blake3 hashers swapped
+            None if value.get("provenance").is_none() => {
+                return Err(anyhow!(
+                    "Made by fdsum before 0.3.0, which hashed sha256 with BLAKE3 and blake3 \
+                     with SHA-256; hash the tree again to verify it"
+                ));
+            }
+            None => 1,
+            Some(schema) => schema
+                .as_u64()
+                .ok_or_else(|| anyhow!("Invalid manifest schema version: {}", schema))?,
+        };
+        match schema {
+            1 => Ok(serde_json::from_value(value)?),
+            _ => Err(anyhow!(
+                "Unsupported manifest schema version {} (supported up to {}), upgrade fdsum",
+                schema,
+                SCHEMA_VERSION
+            )),
+        }
+    }
+
+    pub fn from_result(config: &Config, name: PathBuf, hash: &[u8]) -> Self {
+        config.stats.flush();
         let stats = config.stats.snapshot();
         let elapsed = (stats.elapsed.as_secs_f64() * 100.0).round() / 100.0;
 
         HashResultJson {
-            name: config.path.clone().unwrap(),
-            hash: hex::encode(hash),
+            schema: SCHEMA_VERSION,
+            provenance: Some(Provenance::current(&name, config.framing, stats.elapsed)),
+            name,
+            hash: config.algorithm.encode(hash),
             flags: config.flags_string(),
 
             entries: Some(stats.entries_total),
             bytes: Some(stats.bytes_total),
             elapsed_seconds: Some(elapsed),
+            fips: config.fips,
+            detail: Detail::from_config(config),
+            phases: Some(config.stats.phases()),
+            workers: Some(config.stats.workers()),
+            roots: None,
+            files: FileRecord::from_config(config),
+            chunking: config.record_chunks.then(|| CHUNKING.to_string()),
+            skipped: config.skip_unreadable.then(|| {
+                config
+                    .skipped
+                    .lock()
+                    .unwrap()
+                    .keys()
+                    .map(|rel| rel.to_string_lossy().into_owned())
+                    .collect()
+            }),
+            errors: config.skipped.lock().unwrap().values().cloned().collect(),
+        }
+    }
+
+    /// A result for a part of a run, without run statistics
+    pub fn from_part(config: &Config, name: PathBuf, hash: &[u8]) -> Self {
+        HashResultJson {
+            schema: SCHEMA_VERSION,
+            name,
+            hash: config.algorithm.encode(hash),
+            flags: config.flags_string(),
+
+            entries: None,
+            bytes: None,
+            elapsed_seconds: None,
+            fips: config.fips,
+            provenance: None,
+            detail: None,
+            phases: None,
+            workers: None,
+            roots: None,
+            files: None,
+            chunking: None,
+            skipped: None,
+            errors: Vec::new(),
         }
     }
 }
@@ -0,0 +1,67 @@
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::io::BufRead;
+
+use fdsum::config::Config;
+
+/// A command read from stdin with --control
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+enum Command {
+    Pause,
+    Resume,
+    /// Limit the read rate to `kib` kiB per second, or lift the limit
+    /// if 0
+    SetBwlimit {
+        kib: u64,
+    },
+    Status,
+    Cancel,
+}
+
+/// Follow the commands on stdin on a thread of its own, until stdin is
+/// closed. Each is answered with a line of JSON on stderr.
+pub fn spawn(config: &Config) {
+    let config = config.clone();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let reply = match serde_json::from_str(&line) {
+                Ok(command) => execute(&config, command),
+                Err(err) => json!({"ok": false, "error": err.to_string()}),
+            };
+            eprintln!("{}", reply);
+        }
+    });
+}
+
+fn execute(config: &Config, command: Command) -> Value {
+    match command {
+        Command::Pause => config.cancel.pause(),
+        Command::Resume => config.cancel.resume(),
+        Command::SetBwlimit { kib } => match &config.bwlimit {
+            Some(limit) => limit.set_rate(kib.saturating_mul(1024)),
+            None => return json!({"ok": false, "error": "No bandwidth limit to set"}),
+        },
+        Command::Status => {}
+        Command::Cancel => config.cancel.cancel(),
+    }
+    let stats = config.stats.snapshot();
+    let rate = config.bwlimit.as_ref().map_or(0, |limit| limit.rate());
+    json!({
+        "ok": true,
+        "paused": config.cancel.is_paused(),
+        "cancelled": config.cancel.is_cancelled(),
+        "bwlimit_kib": (rate > 0).then_some(rate / 1024),
+        "entries_done": stats.entries_done,
+        "entries_total": stats.entries_total,
+        "bytes_done": stats.bytes_done,
+        "bytes_total": stats.bytes_total,
+        "elapsed_seconds": stats.elapsed.as_secs_f64(),
+    })
+}
@@ -0,0 +1,143 @@
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use fdsum::canonical;
+use fdsum::config::{Config, FileRecord, HashAlgorithm, HashResultJson};
+use fdsum::job;
+
+/// The changes from one manifest to another: the new manifest without
+/// its per-entry digests and files, and the entries and files that
+/// differ from the old one
+#[derive(Debug, Serialize, Deserialize)]
+struct Delta {
+    /// The SHA-256 of the canonical JSON of the old manifest
+    base: String,
+    /// The SHA-256 of the canonical JSON of the new manifest
+    target: String,
+    manifest: HashResultJson,
+    /// Digests of entries added or changed, by path
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    entries: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    removed_entries: Vec<String>,
+    /// Files added or changed, by path
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    files: BTreeMap<String, FileRecord>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    removed_files: Vec<String>,
+}
+
+/// Identify a manifest by its content, whatever the layout of its JSON
+fn digest(manifest: &HashResultJson) -> Result<String> {
+    let mut hasher = HashAlgorithm::Sha256.hasher();
+    hasher.update(canonical::to_string(manifest)?.as_bytes());
+    Ok(HashAlgorithm::Sha256.encode(&hasher.finalize()))
+}
+
+/// The values of `new` added or changed from `old`, and the keys of
+/// `old` removed
+fn changes<V: Clone + PartialEq>(
+    old: &BTreeMap<String, V>,
+    new: &BTreeMap<String, V>,
+) -> (BTreeMap<String, V>, Vec<String>) {
+    let changed = new
+        .iter()
+        .filter(|(key, value)| old.get(*key) != Some(value))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    let removed = old
+        .keys()
+        .filter(|key| !new.contains_key(*key))
+        .cloned()
+        .collect();
+    (changed, removed)
+}
+
+fn patch<V>(map: &mut BTreeMap<String, V>, changed: BTreeMap<String, V>, removed: &[String]) {
+    for key in removed {
+        map.remove(key);
+    }
+    map.extend(changed);
+}
+
+/// Write the changes from the manifest `old` to `new` to `output`, or
+/// to stdout
+pub fn run(config: &Config, old: &str, new: &str, output: Option<&Path>) -> Result<()> {
+    if let Some(path) = output {
+        job::check_output(config, path)?;
+    }
+    let old = job::read_manifest(old)?;
+    let mut manifest = job::read_manifest(new)?;
+    let target = digest(&manifest)?;
+    let empty = BTreeMap::new();
+    let (mut entries, mut removed_entries) = (BTreeMap::new(), Vec::new());
+    if let Some(detail) = &mut manifest.detail {
+        let before = old.detail.as_ref().map_or(&empty, |detail| &detail.entries);
+        (entries, removed_entries) = changes(before, &detail.entries);
+        detail.entries.clear();
+    }
+    let (mut files, mut removed_files) = (BTreeMap::new(), Vec::new());
+    if let Some(records) = &mut manifest.files {
+        let empty = BTreeMap::new();
+        (files, removed_files) = changes(old.files.as_ref().unwrap_or(&empty), records);
+        records.clear();
+    }
+    let delta = Delta {
+        base: digest(&old)?,
+        target,
+        manifest,
+        entries,
+        removed_entries,
+        files,
+        removed_files,
+    };
+
+    let json = config.to_json(&delta)? + "\n";
+    match output {
+        Some(path) => std::fs::write(path, json)
+            .with_context(|| format!("Failed to write {}", path.display())),
+        None => {
+            print!("{}", json);
+            Ok(())
+        }
+    }
+}
+
+/// Rebuild the new manifest from the manifest `old` and the changes in
+/// `delta`, and write it to `output`, or to stdout
+pub fn apply(config: &Config, old: &str, delta: &Path, output: Option<&Path>) -> Result<()> {
+    if let Some(path) = output {
+        job::check_output(config, path)?;
+    }
+    let old = job::read_manifest(old)?;
+    let json = std::fs::read_to_string(delta)
+        .with_context(|| format!("Failed to read {}", delta.display()))?;
+    let delta: Delta = serde_json::from_str(&json).with_context(|| delta.display().to_string())?;
+    if digest(&old)? != delta.base {
+        return Err(anyhow!("The delta was made from another manifest"));
+    }
+
+    let mut manifest = delta.manifest;
+    if let Some(detail) = &mut manifest.detail {
+        detail.entries = old.detail.map(|detail| detail.entries).unwrap_or_default();
+        patch(&mut detail.entries, delta.entries, &delta.removed_entries);
+    }
+    if let Some(files) = &mut manifest.files {
+        *files = old.files.unwrap_or_default();
+        patch(files, delta.files, &delta.removed_files);
+    }
+    if digest(&manifest)? != delta.target {
+        return Err(anyhow!(
+            "The manifest rebuilt from the delta does not match"
+        ));
+    }
+    match output {
+        Some(path) => job::write_manifest(config, path, &manifest),
+        None => {
+            println!("{}", config.result_json(&manifest)?);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use anyhow::{Result, anyhow};
+use std::path::Path;
+
+use fdsum::config::Config;
+use fdsum::job;
+use fdsum::tree::DiffKind;
+
+use crate::color;
+
+/// Compare the trees at `left` and `right` and print each differing
+/// entry. LEFT is taken as the source when listing changed files.
+/// Snapshots are compared by the entries changed between them.
+pub fn run(config: &Config, left: &Path, right: &Path, snapshots: bool) -> Result<()> {
+    let differences = if snapshots {
+        job::diff_snapshots(config, left, right)?
+    } else {
+        job::diff_trees(config, left, right)?
+    };
+    if config.lists_changed() {
+        crate::write_changed_files(config, &differences)?;
+    }
+    for difference in &differences {
+        match difference.kind {
+            DiffKind::OnlyLeft => println!(
+                "{}: {}",
+                difference.path.display(),
+                color::change(difference.kind, format!("only in {}", left.display()))
+            ),
+            DiffKind::OnlyRight => println!(
+                "{}: {}",
+                difference.path.display(),
+                color::change(difference.kind, format!("only in {}", right.display()))
+            ),
+            _ => println!(
+                "{}: {}",
+                difference.path.display(),
+                color::change(difference.kind, difference.kind.description())
+            ),
+        }
+    }
+
+    if differences.is_empty() {
+        println!("{}: {}", left.display(), color::ok("Ok"));
+        Ok(())
+    } else {
+        Err(anyhow!("{} entries differ", differences.len()))
+    }
+}
@@ -0,0 +1,147 @@
+//! Errors of reading a tree, classified by codes that automation can
+//! match on instead of the messages
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// Opening a file for reading
+    #[serde(rename = "E_OPEN")]
+    Open,
+    /// Reading the content of a file
+    #[serde(rename = "E_READ")]
+    Read,
+    /// Reading the metadata of an entry
+    #[serde(rename = "E_STAT")]
+    Stat,
+    /// Listing a directory
+    #[serde(rename = "E_READDIR")]
+    ReadDir,
+    /// Reading the target of a symlink
+    #[serde(rename = "E_READLINK")]
+    ReadLink,
+    /// An entry of a type that cannot be hashed
+    #[serde(rename = "E_TYPE")]
+    Type,
+    /// Too many levels of symlinks
+    #[serde(rename = "E_LOOP")]
+    Loop,
+    /// Any other error
+    #[serde(rename = "E_OTHER")]
+    Other,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Open => "E_OPEN",
+            Self::Read => "E_READ",
+            Self::Stat => "E_STAT",
+            Self::ReadDir => "E_READDIR",
+            Self::ReadLink => "E_READLINK",
+            Self::Type => "E_TYPE",
+            Self::Loop => "E_LOOP",
+            Self::Other => "E_OTHER",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A failure to read an entry of a tree
+#[derive(Debug)]
+pub struct FsError {
+    pub code: ErrorCode,
+    pub path: PathBuf,
+    pub source: Option<io::Error>,
+}
+
+impl FsError {
+    /// The error of `op` on `path`. Symlink loops are reported as
+    /// such, whatever the operation.
+    pub fn new(op: ErrorCode, path: &Path, source: io::Error) -> Self {
+        let code = if source.raw_os_error() == Some(libc::ELOOP) {
+            ErrorCode::Loop
+        } else {
+            op
+        };
+        Self {
+            code,
+            path: path.to_path_buf(),
+            source: Some(source),
+        }
+    }
+
+    pub fn errno(&self) -> Option<i32> {
+        self.source.as_ref()?.raw_os_error()
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let what = match self.code {
+            ErrorCode::Open => "Failed to open file",
+            ErrorCode::Read => "Failed to read file",
+            ErrorCode::Stat => "Failed to stat",
+            ErrorCode::ReadDir => "Failed to read directory",
+            ErrorCode::ReadLink => "Failed to read symlink",
+            ErrorCode::Type => "File type unknown",
+            ErrorCode::Loop => "Too many levels of symlinks",
+            ErrorCode::Other => "Failed",
+        };
+        write!(f, "{}: {} ({}", what, self.path.display(), self.code)?;
+        if let Some(errno) = self.errno() {
+            write!(f, ", errno {}", errno)?;
+        }
+        // the cause follows as the source of the error
+        f.write_str(")")
+    }
+}
+
+impl std::error::Error for FsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_ref()
+            .map(|e| e as &(dyn std::error::Error + 'static))
+    }
+}
+
+/// An error as reported in JSON output and logs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorInfo {
+    pub code: ErrorCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub errno: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    pub message: String,
+}
+
+impl ErrorInfo {
+    /// Classify `err` by the first [`FsError`] in its chain, or by the
+    /// OS error it was caused by
+    pub fn from_error(err: &anyhow::Error) -> Self {
+        let message = format!("{:#}", err);
+        if let Some(fs) = err.chain().find_map(|e| e.downcast_ref::<FsError>()) {
+            return Self {
+                code: fs.code,
+                errno: fs.errno(),
+                path: Some(fs.path.clone()),
+                message,
+            };
+        }
+        let errno = err
+            .chain()
+            .find_map(|e| e.downcast_ref::<io::Error>())
+            .and_then(io::Error::raw_os_error);
+        Self {
+            code: ErrorCode::Other,
+            errno,
+            path: None,
+            message,
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! A stream of events on single entries for frontends that show the
+//! status of each file as it is hashed, one line of JSON per event:
+//!
+//! - `{"event":"started","path":P}` when the listing of a directory or
+//!   the reading of a file starts
+//! - `{"event":"finished","path":P,"digest":D}` when the digest of an
+//!   entry is known
+//! - `{"event":"error","path":P,"error":{"code":C,...}}` when an entry
+//!   cannot be read, whether it is skipped or the run fails
+//!
+//! Paths are relative to the root of the tree, the root itself is `.`.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::error::ErrorInfo;
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum Event<'a> {
+    Started { path: &'a str },
+    Finished { path: &'a str, digest: &'a str },
+    Error { path: &'a str, error: ErrorInfo },
+}
+
+/// Writes the events, until the reader goes away
+pub struct EventStream {
+    out: Mutex<Option<Box<dyn Write + Send>>>,
+}
+
+impl std::fmt::Debug for EventStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventStream").finish_non_exhaustive()
+    }
+}
+
+impl EventStream {
+    /// Open the stream to `target`: `unix:PATH` connects to a Unix
+    /// socket, `fd:N` writes to an inherited file descriptor, and any
+    /// other target is a file or FIFO opened for appending
+    pub fn open(target: &str) -> Result<Self> {
+        let out: Box<dyn Write + Send> = if let Some(path) = target.strip_prefix("unix:") {
+            Box::new(UnixStream::connect(path).with_context(|| path.to_string())?)
+        } else if let Some(fd) = target.strip_prefix("fd:") {
+            let fd = fd
+                .parse()
+                .with_context(|| format!("Invalid file descriptor: {}", fd))?;
+            Box::new(crate::job::open_fd(fd)?)
+        } else {
+            Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(target)
+                    .with_context(|| target.to_string())?,
+            )
+        };
+        Ok(Self {
+            out: Mutex::new(Some(out)),
+        })
+    }
+
+    pub fn started(&self, rel: &Path) {
+        self.send(&Event::Started {
+            path: &display(rel),
+        });
+    }
+
+    pub fn finished(&self, rel: &Path, digest: &str) {
+        self.send(&Event::Finished {
+            path: &display(rel),
+            digest,
+        });
+    }
+
+    pub fn error(&self, rel: &Path, err: &anyhow::Error) {
+        self.send(&Event::Error {
+            path: &display(rel),
+            error: ErrorInfo::from_error(err),
+        });
+    }
+
+    /// Write `event` as a line, and stop writing once that fails, as
+    /// the run does not depend on the frontend
+    fn send(&self, event: &Event) {
+        let mut line = serde_json::to_vec(event).expect("events serialize");
+        line.push(b'\n');
+        let mut out = self.out.lock().unwrap();
+        let Some(writer) = out.as_mut() else {
+            return;
+        };
+        if let Err(err) = writer.write_all(&line).and_then(|()| writer.flush()) {
+            eprintln!("Note: no longer writing events: {}", err);
+            *out = None;
+        }
+    }
+}
+
+fn display(rel: &Path) -> String {
+    if rel.as_os_str().is_empty() {
+        ".".to_string()
+    } else {
+        rel.to_string_lossy().into_owned()
+    }
+}
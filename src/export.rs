@@ -0,0 +1,57 @@
+use anyhow::{Context, Result, anyhow};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use fdsum::config::Config;
+use fdsum::formats::{self, ExportFormat, FileEntry};
+use fdsum::job;
+
+/// Write the files recorded in `manifest` in `format` to `output`, or
+/// to stdout
+pub fn run(
+    config: &mut Config,
+    manifest: &str,
+    format: ExportFormat,
+    output: Option<&Path>,
+) -> Result<()> {
+    if let Some(path) = output {
+        job::check_output(config, path)?;
+    }
+    let result = job::read_manifest(manifest)?;
+    job::apply_manifest(config, &result)?;
+    let records = result.files.as_ref().ok_or_else(|| {
+        anyhow!(
+            "{} records no files, hash with --record-files to export it",
+            manifest
+        )
+    })?;
+    // mtree lists paths relative to the root, the others as fdsum
+    // --format does
+    let files: Vec<FileEntry> = records
+        .iter()
+        .map(|(rel, record)| FileEntry {
+            path: match format {
+                ExportFormat::Mtree => PathBuf::from(rel),
+                _ => result.name.join(rel),
+            },
+            size: record.size,
+            hash: record.hash.clone(),
+        })
+        .collect();
+
+    let mut out: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(
+            std::fs::File::create(path).with_context(|| path.display().to_string())?,
+        )),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    match format {
+        ExportFormat::Sum => formats::write_sum(&files, &mut out)?,
+        ExportFormat::Bsd => formats::write_bsd(config, &files, &mut out)?,
+        ExportFormat::Hashdeep => formats::write_hashdeep(config, &files, &mut out)?,
+        ExportFormat::Mtree => formats::write_mtree(config, &files, &mut out)?,
+        ExportFormat::Csv => formats::write_csv(config, &files, &mut out)?,
+    }
+    out.flush()?;
+    Ok(())
+}
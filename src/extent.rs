@@ -0,0 +1,60 @@
+//! Where the content of files lies on the disk, so that they can be
+//! read in the order of their location. On rotating disks reading the
+//! files of a tree by location rather than by name saves most seeks.
+
+use std::path::Path;
+
+/// The position on the device of the first extent of the file at
+/// `path`, or `None` if the filesystem does not tell it (e.g. network
+/// filesystems, files without extents, or files inlined in metadata)
+#[cfg(target_os = "linux")]
+pub fn physical_offset(path: &Path) -> Option<u64> {
+    use std::os::fd::AsRawFd;
+
+    // struct fiemap and struct fiemap_extent of linux/fiemap.h
+    #[repr(C)]
+    #[derive(Default)]
+    struct Extent {
+        logical: u64,
+        physical: u64,
+        length: u64,
+        reserved64: [u64; 2],
+        flags: u32,
+        reserved: [u32; 3],
+    }
+    #[repr(C)]
+    #[derive(Default)]
+    struct Fiemap {
+        start: u64,
+        length: u64,
+        flags: u32,
+        mapped_extents: u32,
+        extent_count: u32,
+        reserved: u32,
+        extents: [Extent; 1],
+    }
+    // _IOWR('f', 11, struct fiemap)
+    const FS_IOC_FIEMAP: libc::c_ulong = 0xc020_660b;
+    const FIEMAP_FLAG_SYNC: u32 = 0x1;
+    const FIEMAP_EXTENT_UNKNOWN: u32 = 0x2;
+
+    let file = std::fs::File::open(path).ok()?;
+    let mut map = Fiemap {
+        length: u64::MAX,
+        // delayed allocations have no location until written
+        flags: FIEMAP_FLAG_SYNC,
+        extent_count: 1,
+        ..Fiemap::default()
+    };
+    // SAFETY: map is a struct fiemap with room for extent_count extents
+    if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut map) } != 0 {
+        return None;
+    }
+    let extent = &map.extents[0];
+    (map.mapped_extents > 0 && extent.flags & FIEMAP_EXTENT_UNKNOWN == 0).then_some(extent.physical)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn physical_offset(_path: &Path) -> Option<u64> {
+    None
+}
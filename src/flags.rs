@@ -0,0 +1,434 @@
+use anyhow::{Result, anyhow};
+use clap::ValueEnum;
+use std::fmt;
+
+use crate::config::HashAlgorithm;
+
+/// The letters of the flags string and the metadata they include
+pub const FLAG_NAMES: [(char, &str); 17] = [
+    ('c', "content"),
+    ('s', "size"),
+    ('p', "mode"),
+    ('u', "uid"),
+    ('g', "gid"),
+    ('t', "ctime"),
+    ('m', "mtime"),
+    ('a', "atime"),
+    ('i', "inode"),
+    ('h', "nlink"),
+    ('x', "attributes"),
+    ('q', "project"),
+    ('k', "capabilities"),
+    ('e', "nfs4acl"),
+    ('o', "etag"),
+    ('r', "storageclass"),
+    ('n', "name"),
+];
+
+/// The flag letters and algorithms that versions before the v2 flags
+/// string know, and so the only ones written as v1
+const V1_LETTERS: &str = "cspugtma";
+const V1_ALGORITHMS: [HashAlgorithm; 2] = [HashAlgorithm::Sha256, HashAlgorithm::Blake3];
+
+/// The letters of the entry types, as in the type tags of framing
+/// version 2
+pub const TYPE_NAMES: [(char, &str); 5] = [
+    ('f', "file"),
+    ('d', "directory"),
+    ('l', "symlink"),
+    ('v', "device"),
+    ('s', "special"),
+];
+
+/// Normalize a list of entry type letters, separated by commas or not,
+/// to the order of [`TYPE_NAMES`]
+pub fn parse_types(value: &str) -> Result<String> {
+    let letters: Vec<char> = value.chars().filter(|&c| c != ',').collect();
+    if let Some(c) = letters
+        .iter()
+        .find(|c| !TYPE_NAMES.iter().any(|(letter, _)| letter == *c))
+    {
+        return Err(anyhow!("Unknown entry type: {}", c));
+    }
+    Ok(TYPE_NAMES
+        .iter()
+        .map(|&(letter, _)| letter)
+        .filter(|letter| letters.contains(letter))
+        .collect())
+}
+
+/// The version of the framing of entries in the hash used by default.
+///
+/// In all versions the entries of a directory are hashed in the order
+/// of the raw bytes of their names, independent of the locale. The
+/// `vectors` command prints test trees with their digests.
+pub const FRAMING_VERSION: u32 = 1;
+
+/// The latest version of the framing. Version 2 hashes the type of
+/// each entry along with its metadata.
+pub const LATEST_FRAMING: u32 = 2;
+
+/// The resolution at which timestamps are hashed. Choose the coarsest
+/// resolution of the filesystems a tree is verified on.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimeGranularity {
+    /// Whole seconds
+    #[default]
+    #[value(name = "1s")]
+    Second,
+    /// Even seconds, as FAT and exFAT record modification times
+    #[value(name = "2s")]
+    TwoSeconds,
+    /// Microseconds, as SMB shares may record them
+    #[value(name = "1us")]
+    Microsecond,
+    /// Nanoseconds, as ext4, XFS and btrfs record them
+    #[value(name = "1ns")]
+    Nanosecond,
+}
+
+impl TimeGranularity {
+    /// Whether timestamps are hashed with their nanoseconds
+    pub fn sub_second(self) -> bool {
+        matches!(self, Self::Microsecond | Self::Nanosecond)
+    }
+
+    /// Round the time `secs` and `nsec` since the epoch down to the
+    /// granularity
+    pub fn round(self, secs: i64, nsec: u32) -> (i64, u32) {
+        match self {
+            Self::Second => (secs, 0),
+            Self::TwoSeconds => (secs - secs.rem_euclid(2), 0),
+            Self::Microsecond => (secs, nsec - nsec % 1000),
+            Self::Nanosecond => (secs, nsec),
+        }
+    }
+}
+
+impl fmt::Display for TimeGranularity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = self.to_possible_value().expect("no skipped variants");
+        write!(f, "{}", value.get_name())
+    }
+}
+
+/// Everything that determines a hash besides the tree itself.
+///
+/// Serialized as `v1:ALGORITHM:LETTERS` where possible so that older
+/// versions can verify the result, i.e. with the algorithms and flag
+/// letters they know and no options. Otherwise it is
+/// `v2:ALGORITHM:LETTERS:NAME=VALUE:...`, with `%`, `:` and `,` in
+/// values percent-encoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Flags {
+    pub algorithm: HashAlgorithm,
+    /// Letters of the included metadata, see [`FLAG_NAMES`]
+    pub include: String,
+    /// Glob patterns of names of skipped entries
+    pub exclude: Vec<String>,
+    /// Whether entries whose name starts with a dot are included
+    pub hidden: bool,
+    /// Whether the metadata of the root entry is included
+    pub root_meta: bool,
+    pub framing: u32,
+    /// Whether directories without entries are included
+    pub empty_dirs: bool,
+    /// Whether regular files of size zero are included
+    pub empty_files: bool,
+    /// Bounds of the size in bytes of included regular files
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// Bounds of the modification time in seconds since the epoch of
+    /// included entries other than directories
+    pub newer_than: Option<i64>,
+    pub older_than: Option<i64>,
+    /// Letters of the included entry types, see [`TYPE_NAMES`]
+    pub types: String,
+    pub time_granularity: TimeGranularity,
+}
+
+fn encode(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '%' | ':' | ',' => out.push_str(&format!("%{:02X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn decode(value: &str) -> Result<String> {
+    let mut out = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+            let byte = std::str::from_utf8(&hex)
+                .ok()
+                .filter(|h| h.len() == 2 && h.bytes().all(|b| b.is_ascii_hexdigit()))
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+                .ok_or_else(|| anyhow!("Invalid escape in flags string: {}", value))?;
+            out.push(byte);
+        } else {
+            out.push(b);
+        }
+    }
+    Ok(String::from_utf8(out)?)
+}
+
+fn parse_bool(name: &str, value: &str) -> Result<bool> {
+    match value {
+        "0" => Ok(false),
+        "1" => Ok(true),
+        _ => Err(anyhow!(
+            "Invalid value for {} in flags string: {}",
+            name,
+            value
+        )),
+    }
+}
+
+fn parse_u64(name: &str, value: &str) -> Result<u64> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("Invalid value for {} in flags string: {}", name, value))
+}
+
+/// The letters of all entry types
+pub fn all_types() -> String {
+    TYPE_NAMES.iter().map(|&(letter, _)| letter).collect()
+}
+
+fn parse_i64(name: &str, value: &str) -> Result<i64> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("Invalid value for {} in flags string: {}", name, value))
+}
+
+impl Flags {
+    pub fn parse(flags: &str) -> Result<Self> {
+        let parts: Vec<&str> = flags.split(':').collect();
+        let options = match parts.as_slice() {
+            ["v1", _, _] => &[][..],
+            ["v2", _, _, options @ ..] => options,
+            _ => return Err(anyhow!("Unsupported flags string format: {}", flags)),
+        };
+
+        let algorithm = HashAlgorithm::from_str(parts[1], false)
+            .map_err(|_| anyhow!("Unknown algorithm in flags string: {}", parts[1]))?;
+        let unknown: String = parts[2]
+            .chars()
+            .filter(|c| !FLAG_NAMES.iter().any(|(letter, _)| letter == c))
+            .collect();
+        if !unknown.is_empty() {
+            return Err(anyhow!("Unknown flags in flags string: {}", unknown));
+        }
+
+        let mut obj = Self {
+            algorithm,
+            include: parts[2].to_string(),
+            exclude: Vec::new(),
+            hidden: true,
+            root_meta: true,
+            framing: FRAMING_VERSION,
+            empty_dirs: true,
+            empty_files: true,
+            min_size: None,
+            max_size: None,
+            newer_than: None,
+            older_than: None,
+            types: all_types(),
+            time_granularity: TimeGranularity::default(),
+        };
+        for option in options {
+            let (name, value) = option.split_once('=').unwrap_or((option, ""));
+            match name {
+                "exclude" => {
+                    obj.exclude = value.split(',').map(decode).collect::<Result<_>>()?;
+                }
+                "hidden" => {
+                    obj.hidden = parse_bool(name, value)?;
+                }
+                "rootmeta" => {
+                    obj.root_meta = parse_bool(name, value)?;
+                }
+                "framing" => {
+                    obj.framing = value
+                        .parse()
+                        .ok()
+                        .filter(|v| (1..=LATEST_FRAMING).contains(v))
+                        .ok_or_else(|| anyhow!("Unsupported hash framing version: {}", value))?;
+                }
+                "emptydirs" => {
+                    obj.empty_dirs = parse_bool(name, value)?;
+                }
+                "emptyfiles" => {
+                    obj.empty_files = parse_bool(name, value)?;
+                }
+                "minsize" => {
+                    obj.min_size = Some(parse_u64(name, value)?);
+                }
+                "maxsize" => {
+                    obj.max_size = Some(parse_u64(name, value)?);
+                }
+                "newer" => {
+                    obj.newer_than = Some(parse_i64(name, value)?);
+                }
+                "older" => {
+                    obj.older_than = Some(parse_i64(name, value)?);
+                }
+                "types" => {
+                    obj.types = parse_types(value)?;
+                }
+                "time" => {
+                    obj.time_granularity = TimeGranularity::from_str(value, false)
+                        .map_err(|_| anyhow!("Unknown time granularity: {}", value))?;
+                }
+                _ => return Err(anyhow!("Unknown option in flags string: {}", name)),
+            }
+        }
+        Ok(obj)
+    }
+
+    /// Whether the metadata named by a flag letter is included
+    pub fn includes(&self, letter: char) -> bool {
+        self.include.contains(letter)
+    }
+
+    fn options(&self) -> Vec<String> {
+        let mut options = Vec::new();
+        if !self.exclude.is_empty() {
+            let patterns: Vec<String> = self.exclude.iter().map(|p| encode(p)).collect();
+            options.push(format!("exclude={}", patterns.join(",")));
+        }
+        if !self.hidden {
+            options.push("hidden=0".to_string());
+        }
+        if !self.root_meta {
+            options.push("rootmeta=0".to_string());
+        }
+        if self.framing != FRAMING_VERSION {
+            options.push(format!("framing={}", self.framing));
+        }
+        if !self.empty_dirs {
+            options.push("emptydirs=0".to_string());
+        }
+        if !self.empty_files {
+            options.push("emptyfiles=0".to_string());
+        }
+        if let Some(min_size) = self.min_size {
+            options.push(format!("minsize={}", min_size));
+        }
+        if let Some(max_size) = self.max_size {
+            options.push(format!("maxsize={}", max_size));
+        }
+        if let Some(newer_than) = self.newer_than {
+            options.push(format!("newer={}", newer_than));
+        }
+        if let Some(older_than) = self.older_than {
+            options.push(format!("older={}", older_than));
+        }
+        if self.types != all_types() {
+            options.push(format!("types={}", self.types));
+        }
+        if self.time_granularity != TimeGranularity::default() {
+            options.push(format!("time={}", self.time_granularity));
+        }
+        options
+    }
+}
+
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let options = self.options();
+        if options.is_empty()
+            && V1_ALGORITHMS.contains(&self.algorithm)
+            && self.include.chars().all(|c| V1_LETTERS.contains(c))
+        {
+            return write!(f, "v1:{}:{}", self.algorithm, self.include);
+        }
+        write!(f, "v2:{}:{}", self.algorithm, self.include)?;
+        for option in options {
+            write!(f, ":{}", option)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(flags: &str) {
+        assert_eq!(Flags::parse(flags).unwrap().to_string(), flags);
+    }
+
+    #[test]
+    fn v1_round_trip() {
+        round_trip("v1:sha256:cspugm");
+        round_trip("v1:blake3:cspugtma");
+        round_trip("v1:sha256:");
+    }
+
+    #[test]
+    fn v2_round_trip() {
+        round_trip("v2:sha256:cspugmi");
+        round_trip("v2:blake3:cn");
+        round_trip("v2:crc32:cspugm");
+        round_trip("v2:k12:c:hidden=0:rootmeta=0");
+        round_trip("v2:sha256:c:exclude=*.tmp,a%3Ab%2Cc%25:framing=2");
+        round_trip("v2:sha256:cs:emptydirs=0:emptyfiles=0:minsize=1:maxsize=1024");
+        round_trip("v2:sha256:cm:newer=-5:older=1700000000:types=fd:time=2s");
+    }
+
+    #[test]
+    fn v1_only_for_letters_and_algorithms_older_versions_know() {
+        let mut flags = Flags::parse("v1:sha256:cspugm").unwrap();
+        flags.include.push('x');
+        assert_eq!(flags.to_string(), "v2:sha256:cspugmx");
+        let mut flags = Flags::parse("v1:sha256:cspugm").unwrap();
+        flags.algorithm = HashAlgorithm::Md5;
+        assert_eq!(flags.to_string(), "v2:md5:cspugm");
+    }
+
+    #[test]
+    fn v1_accepts_later_letters() {
+        let flags = Flags::parse("v1:sha256:cspugmi").unwrap();
+        assert!(flags.includes('i'));
+        assert_eq!(flags.to_string(), "v2:sha256:cspugmi");
+    }
+
+    #[test]
+    fn excludes_decoded() {
+        let flags = Flags::parse("v2:sha256:c:exclude=a%3Ab,%25%2C").unwrap();
+        assert_eq!(flags.exclude, ["a:b", "%,"]);
+    }
+
+    #[test]
+    fn invalid_rejected() {
+        for flags in [
+            "v3:sha256:c",
+            "v1:sha256",
+            "v1:sha256:c:hidden=0",
+            "v1:sha512:c",
+            "v1:sha256:cz",
+            "v2:sha256:c:unknown=1",
+            "v2:sha256:c:hidden=2",
+            "v2:sha256:c:framing=9",
+            "v2:sha256:c:minsize=-1",
+            "v2:sha256:c:types=fz",
+            "v2:sha256:c:time=3s",
+        ] {
+            assert!(Flags::parse(flags).is_err(), "{}", flags);
+        }
+    }
+
+    #[test]
+    fn truncated_or_signed_escapes_rejected() {
+        for value in ["%4", "a%", "%+1", "%-1", "%4g", "% 4"] {
+            assert!(decode(value).is_err(), "{}", value);
+        }
+        assert_eq!(decode("%4a%41").unwrap(), "JA");
+    }
+}
@@ -0,0 +1,359 @@
+//! Per-file checksum listings in the formats of other tools
+
+use anyhow::{Context, Result, anyhow};
+use clap::ValueEnum;
+use rayon::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::{Config, HashAlgorithm};
+use crate::hash::hash_file;
+use crate::tree::{DiffKind, Difference};
+use crate::vfs::FileKind;
+
+/// How results are printed
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// The aggregate hash of the tree as JSON
+    #[default]
+    Json,
+    /// A hashdeep file set listing each regular file
+    Hashdeep,
+    /// `ALGORITHM (path) = digest` for each regular file, as written by
+    /// `sha256sum --tag`
+    Bsd,
+}
+
+/// The formats a manifest recording its files can be exported to
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// `digest  path` for each regular file, as written by `sha256sum`
+    Sum,
+    /// `ALGORITHM (path) = digest` for each regular file, as written by
+    /// `sha256sum --tag`
+    Bsd,
+    /// A hashdeep file set
+    Hashdeep,
+    /// An mtree specification of the regular files, relative to the root
+    Mtree,
+    /// `path,size,digest` with a header line
+    Csv,
+}
+
+/// A regular file with the digest of its content
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    pub path: PathBuf,
+    pub size: u64,
+    pub hash: String,
+}
+
+/// List the regular files below `root`, skipping excluded entries and
+/// files left out of the hash, sorted by path
+pub fn list_files(config: &Config, root: &Path) -> Result<Vec<FileEntry>> {
+    let mut out = Vec::new();
+    collect_files(config, root, Path::new(""), &mut out)?;
+    out.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(out)
+}
+
+fn collect_files(config: &Config, path: &Path, rel: &Path, out: &mut Vec<FileEntry>) -> Result<()> {
+    config.cancel.check()?;
+    let stat = config
+        .stats
+        .time_stat(|| config.vfs.symlink_metadata(path))?;
+    match stat.kind {
+        FileKind::Dir => {
+            let names: Vec<_> = config
+                .stats
+                .time_stat(|| config.vfs.read_dir(path))?
+                .into_iter()
+                .filter(|name| !config.is_excluded(name))
+                .collect();
+            config.stats.add_entries(names.len() as u64);
+            let children = names
+                .par_iter()
+                .map(|name| {
+                    let mut files = Vec::new();
+                    collect_files(config, &path.join(name), &rel.join(name), &mut files)?;
+                    Ok(files)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            out.extend(children.into_iter().flatten());
+        }
+        FileKind::File if config.skips_entry(rel, stat.kind, &stat.meta) => {}
+        FileKind::File => {
+            let size = stat.meta.size.unwrap_or(0);
+            config.stats.add_bytes(size);
+            out.push(FileEntry {
+                path: path.to_path_buf(),
+                size,
+                hash: config.algorithm.encode(&hash_file(config, path, size)?),
+            });
+        }
+        _ => {}
+    }
+    config.stats.done_entries(1);
+    Ok(())
+}
+
+const HASHDEEP_HEADER: &str = "%%%% HASHDEEP-1.0";
+
+/// Write `files` as a hashdeep file set
+pub fn write_hashdeep(config: &Config, files: &[FileEntry], out: &mut dyn Write) -> Result<()> {
+    writeln!(out, "{}", HASHDEEP_HEADER)?;
+    writeln!(out, "%%%% size,{},filename", config.algorithm)?;
+    writeln!(
+        out,
+        "## Invoked from: {}",
+        std::env::current_dir()?.display()
+    )?;
+    let args: Vec<String> = std::env::args().collect();
+    writeln!(out, "## $ {}", args.join(" "))?;
+    writeln!(out, "##")?;
+    for file in files {
+        writeln!(out, "{},{},{}", file.size, file.hash, file.path.display())?;
+    }
+    Ok(())
+}
+
+/// Write `files` in the BSD tag style
+pub fn write_bsd(config: &Config, files: &[FileEntry], out: &mut dyn Write) -> Result<()> {
+    let tag = config.algorithm.to_string().to_uppercase();
+    for file in files {
+        writeln!(out, "{} ({}) = {}", tag, file.path.display(), file.hash)?;
+    }
+    Ok(())
+}
+
+/// Write `files` as `sha256sum` does. Lines of paths holding a newline
+/// or backslash start with a backslash and have those escaped.
+pub fn write_sum(files: &[FileEntry], out: &mut dyn Write) -> Result<()> {
+    for file in files {
+        let path = file.path.to_string_lossy();
+        if path.contains(['\\', '\n']) {
+            let path = path.replace('\\', "\\\\").replace('\n', "\\n");
+            writeln!(out, "\\{}  {}", file.hash, path)?;
+        } else {
+            writeln!(out, "{}  {}", file.hash, path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write `files`, with paths relative to the root, as an mtree
+/// specification
+pub fn write_mtree(config: &Config, files: &[FileEntry], out: &mut dyn Write) -> Result<()> {
+    let keyword = match config.algorithm {
+        HashAlgorithm::Sha256 => "sha256digest",
+        HashAlgorithm::Sha1 => "sha1digest",
+        HashAlgorithm::Md5 => "md5digest",
+        _ => {
+            return Err(anyhow!(
+                "mtree has no keyword for {} digests",
+                config.algorithm
+            ));
+        }
+    };
+    writeln!(out, "#mtree")?;
+    for file in files {
+        writeln!(
+            out,
+            "./{} type=file size={} {}={}",
+            mtree_escape(&file.path),
+            file.size,
+            keyword,
+            file.hash
+        )?;
+    }
+    Ok(())
+}
+
+/// Octal escapes for the bytes of `path` that mtree does not take as is
+fn mtree_escape(path: &Path) -> String {
+    let mut out = String::new();
+    for &byte in path.as_os_str().as_encoded_bytes() {
+        if byte.is_ascii_graphic() && byte != b'\\' && byte != b'#' {
+            out.push(byte as char);
+        } else {
+            out.push_str(&format!("\\{:03o}", byte));
+        }
+    }
+    out
+}
+
+/// Write `files` as CSV, quoting paths as RFC 4180 requires
+pub fn write_csv(config: &Config, files: &[FileEntry], out: &mut dyn Write) -> Result<()> {
+    writeln!(out, "path,size,{}", config.algorithm)?;
+    for file in files {
+        let path = file.path.to_string_lossy();
+        if path.contains([',', '"', '\n', '\r']) {
+            let path = path.replace('"', "\"\"");
+            writeln!(out, "\"{}\",{},{}", path, file.size, file.hash)?;
+        } else {
+            writeln!(out, "{},{},{}", path, file.size, file.hash)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether the file at `path` is a hashdeep file set
+pub fn is_hashdeep(path: &Path) -> Result<bool> {
+    if path.as_os_str() == "-" {
+        return Ok(false);
+    }
+    let file = std::fs::File::open(path).with_context(|| path.display().to_string())?;
+    let mut first = String::new();
+    std::io::BufReader::new(file).read_line(&mut first)?;
+    Ok(first.trim_end() == HASHDEEP_HEADER)
+}
+
+/// Read a hashdeep file set. Of the hashes it lists, the one for
+/// `preferred` is used if present, else the first supported one.
+pub fn read_hashdeep(
+    reader: impl BufRead,
+    preferred: &HashAlgorithm,
+) -> Result<(HashAlgorithm, Vec<FileEntry>)> {
+    let mut lines = reader.lines();
+    if lines.next().transpose()?.as_deref().map(str::trim_end) != Some(HASHDEEP_HEADER) {
+        return Err(anyhow!("Not a hashdeep file"));
+    }
+    let columns_line = lines
+        .next()
+        .transpose()?
+        .ok_or_else(|| anyhow!("Missing hashdeep column header"))?;
+    let columns: Vec<&str> = columns_line
+        .strip_prefix("%%%% ")
+        .ok_or_else(|| anyhow!("Invalid hashdeep column header: {}", columns_line))?
+        .trim_end()
+        .split(',')
+        .collect();
+    let position = |name: &str| columns.iter().position(|c| *c == name);
+    let (Some(size_col), Some(name_col)) = (position("size"), position("filename")) else {
+        return Err(anyhow!("Invalid hashdeep column header: {}", columns_line));
+    };
+    let supported: Vec<(usize, HashAlgorithm)> = columns
+        .iter()
+        .enumerate()
+        .filter_map(|(i, c)| Some((i, HashAlgorithm::from_str(c, true).ok()?)))
+        .collect();
+    let (hash_col, algorithm) = supported
+        .iter()
+        .find(|(_, a)| a == preferred)
+        .or_else(|| supported.first())
+        .cloned()
+        .ok_or_else(|| anyhow!("No supported algorithm in hashdeep file: {}", columns_line))?;
+
+    let mut files = Vec::new();
+    for line in lines {
+        let line = line?;
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.splitn(columns.len(), ',').collect();
+        if fields.len() != columns.len() {
+            return Err(anyhow!("Malformed hashdeep line: {}", line));
+        }
+        files.push(FileEntry {
+            path: PathBuf::from(fields[name_col]),
+            size: fields[size_col]
+                .parse()
+                .with_context(|| format!("Malformed hashdeep line: {}", line))?,
+            hash: fields[hash_col].to_ascii_lowercase(),
+        });
+    }
+    Ok((algorithm, files))
+}
+
+/// Read a checksum list as written by `sha256sum` and the like, with
+/// or without `--tag`. The algorithm of untagged lines is `preferred`
+/// if the length of their digests fits, else the first that fits.
+pub fn read_sums(
+    reader: impl BufRead,
+    preferred: &HashAlgorithm,
+) -> Result<(HashAlgorithm, Vec<(PathBuf, String)>)> {
+    let mut algorithm: Option<HashAlgorithm> = None;
+    let mut files = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        // paths holding a newline or backslash are escaped
+        let (escaped, rest) = match line.strip_prefix('\\') {
+            Some(rest) => (true, rest),
+            None => (false, line.as_str()),
+        };
+        let malformed = || anyhow!("Malformed checksum line: {}", line);
+        let (line_algorithm, path, hash) = match rest.split_once(" (") {
+            Some((tag, tail)) if !tag.contains(' ') => {
+                let (path, hash) = tail.rsplit_once(") = ").ok_or_else(malformed)?;
+                let tagged = HashAlgorithm::from_str(tag, true)
+                    .map_err(|_| anyhow!("Unsupported algorithm: {}", tag))?;
+                (tagged, path, hash)
+            }
+            _ => {
+                let (hash, path) = rest.split_once(' ').ok_or_else(malformed)?;
+                // a space for text mode or an asterisk for binary mode
+                let path = path
+                    .strip_prefix(' ')
+                    .or_else(|| path.strip_prefix('*'))
+                    .ok_or_else(malformed)?;
+                let fits = |a: &HashAlgorithm| hash.len() == 2 * a.digest_len();
+                let untagged = std::iter::once(preferred)
+                    .chain(HashAlgorithm::value_variants())
+                    .find(|a| fits(a))
+                    .cloned()
+                    .ok_or_else(malformed)?;
+                (untagged, path, hash)
+            }
+        };
+        match &algorithm {
+            Some(algorithm) if *algorithm != line_algorithm => {
+                return Err(anyhow!(
+                    "Mixed {} and {} digests in one checksum list",
+                    algorithm,
+                    line_algorithm
+                ));
+            }
+            Some(_) => {}
+            None => algorithm = Some(line_algorithm.clone()),
+        }
+        line_algorithm.decode(hash).map_err(|_| malformed())?;
+        let path = if escaped {
+            path.replace("\\\\", "\0")
+                .replace("\\n", "\n")
+                .replace('\0', "\\")
+        } else {
+            path.to_string()
+        };
+        files.push((PathBuf::from(path), hash.to_ascii_lowercase()));
+    }
+    let algorithm = algorithm.ok_or_else(|| anyhow!("No checksums found"))?;
+    Ok((algorithm, files))
+}
+
+/// Compare the current files against a known set. Known files that
+/// are missing are reported as only left, unknown files as only right.
+pub fn audit(known: &[FileEntry], current: &[FileEntry]) -> Vec<Difference> {
+    let known: BTreeMap<&Path, &FileEntry> = known.iter().map(|f| (f.path.as_path(), f)).collect();
+    let current: BTreeMap<&Path, &FileEntry> =
+        current.iter().map(|f| (f.path.as_path(), f)).collect();
+    let paths: BTreeSet<&Path> = known.keys().chain(current.keys()).copied().collect();
+
+    let mut out = Vec::new();
+    for path in paths {
+        let kind = match (known.get(path), current.get(path)) {
+            (Some(a), Some(b)) if a.size == b.size && a.hash == b.hash => continue,
+            (Some(_), Some(_)) => DiffKind::Content,
+            (Some(_), None) => DiffKind::OnlyLeft,
+            (None, _) => DiffKind::OnlyRight,
+        };
+        out.push(Difference {
+            path: path.to_path_buf(),
+            kind,
+        });
+    }
+    out
+}
@@ -0,0 +1,306 @@
+//! The gRPC service of `fdsum serve --grpc`, as described in
+//! proto/fdsum.proto, for running fdsum as a service next to the
+//! storage it checks. Each call runs a [`HashJob`] of its own, with the
+//! threads and bandwidth limit the service was started with.
+
+use anyhow::Result;
+use std::net::SocketAddr;
+use std::path::Path;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+
+use crate::config::{Config, HashResultJson};
+use crate::job::{CancelToken, HashJob, HashJobBuilder};
+use crate::stats::StatsSnapshot;
+use crate::tree::{DiffKind, Difference as TreeDifference};
+
+mod generated {
+    include!(concat!(env!("OUT_DIR"), "/fdsum.v1.Fdsum.rs"));
+}
+
+pub use generated::fdsum_client::FdsumClient;
+pub use generated::fdsum_server::{Fdsum, FdsumServer};
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HashRequest {
+    #[prost(string, tag = "1")]
+    pub path: String,
+    #[prost(string, tag = "2")]
+    pub flags: String,
+    #[prost(uint32, tag = "3")]
+    pub detail: u32,
+    #[prost(bool, tag = "4")]
+    pub archive: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct VerifyRequest {
+    #[prost(string, tag = "1")]
+    pub manifest: String,
+    #[prost(string, tag = "2")]
+    pub path: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DiffRequest {
+    #[prost(string, tag = "1")]
+    pub left: String,
+    #[prost(string, tag = "2")]
+    pub right: String,
+    #[prost(string, tag = "3")]
+    pub flags: String,
+}
+
+#[derive(Clone, Copy, PartialEq, prost::Message)]
+pub struct Progress {
+    #[prost(uint64, tag = "1")]
+    pub entries_done: u64,
+    #[prost(uint64, tag = "2")]
+    pub entries_total: u64,
+    #[prost(uint64, tag = "3")]
+    pub bytes_done: u64,
+    #[prost(uint64, tag = "4")]
+    pub bytes_total: u64,
+    #[prost(double, tag = "5")]
+    pub elapsed_seconds: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Change {
+    Unspecified = 0,
+    OnlyLeft = 1,
+    OnlyRight = 2,
+    Type = 3,
+    Metadata = 4,
+    Content = 5,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Difference {
+    #[prost(string, tag = "1")]
+    pub path: String,
+    #[prost(enumeration = "Change", tag = "2")]
+    pub change: i32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Verification {
+    #[prost(bool, tag = "1")]
+    pub matches: bool,
+    #[prost(string, tag = "2")]
+    pub expected: String,
+    #[prost(string, tag = "3")]
+    pub hash: String,
+    #[prost(message, repeated, tag = "4")]
+    pub changed: Vec<Difference>,
+    #[prost(string, tag = "5")]
+    pub manifest: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Differences {
+    #[prost(message, repeated, tag = "1")]
+    pub differences: Vec<Difference>,
+}
+
+/// The progress of a call, or its result
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Update {
+    #[prost(oneof = "update::Update", tags = "1, 2, 3, 4")]
+    pub update: Option<update::Update>,
+}
+
+pub mod update {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Update {
+        #[prost(message, tag = "1")]
+        Progress(super::Progress),
+        #[prost(string, tag = "2")]
+        Manifest(String),
+        #[prost(message, tag = "3")]
+        Verification(super::Verification),
+        #[prost(message, tag = "4")]
+        Differences(super::Differences),
+    }
+}
+
+impl From<&StatsSnapshot> for Progress {
+    fn from(stats: &StatsSnapshot) -> Self {
+        Self {
+            entries_done: stats.entries_done,
+            entries_total: stats.entries_total,
+            bytes_done: stats.bytes_done,
+            bytes_total: stats.bytes_total,
+            elapsed_seconds: stats.elapsed.as_secs_f64(),
+        }
+    }
+}
+
+impl From<TreeDifference> for Difference {
+    fn from(difference: TreeDifference) -> Self {
+        let change = match difference.kind {
+            DiffKind::OnlyLeft => Change::OnlyLeft,
+            DiffKind::OnlyRight => Change::OnlyRight,
+            DiffKind::Type => Change::Type,
+            DiffKind::Metadata => Change::Metadata,
+            DiffKind::Content => Change::Content,
+        };
+        Self {
+            path: difference.path.to_string_lossy().into_owned(),
+            change: change as i32,
+        }
+    }
+}
+
+type UpdateStream = ReceiverStream<Result<Update, Status>>;
+
+/// Progress updates waiting to be sent before newer ones are dropped
+const QUEUED_UPDATES: usize = 16;
+
+/// Answers the calls with the settings of `config`
+pub struct Service {
+    config: Config,
+}
+
+impl Service {
+    pub fn new(config: Config) -> Self {
+        Self { config }
+    }
+
+    /// Run `task` with a job of the service on the blocking thread pool,
+    /// streaming its progress followed by its result
+    fn start(
+        &self,
+        configure: impl FnOnce(HashJobBuilder) -> HashJobBuilder,
+        task: impl FnOnce(&HashJob) -> Result<update::Update> + Send + 'static,
+    ) -> Result<UpdateStream> {
+        let (sender, receiver) = mpsc::channel(QUEUED_UPDATES);
+        let cancel = CancelToken::default();
+        let progress = sender.clone();
+        let abandoned = cancel.clone();
+        let mut builder = HashJob::builder()
+            .threads(self.config.threads)
+            .cancel(cancel)
+            .progress(move |stats| {
+                let update = Update {
+                    update: Some(update::Update::Progress(stats.into())),
+                };
+                // a full queue only drops this update
+                if let Err(mpsc::error::TrySendError::Closed(_)) = progress.try_send(Ok(update)) {
+                    abandoned.cancel();
+                }
+            });
+        if let Some(limit) = &self.config.bwlimit {
+            builder = builder.bwlimit(limit.rate());
+        }
+        let job = configure(builder).build()?;
+        tokio::task::spawn_blocking(move || {
+            let update = match task(&job) {
+                Ok(update) => Ok(Update {
+                    update: Some(update),
+                }),
+                Err(_) if job.cancel_token().is_cancelled() => Err(Status::cancelled("Cancelled")),
+                Err(err) => Err(Status::internal(format!("{:#}", err))),
+            };
+            let _ = sender.blocking_send(update);
+        });
+        Ok(ReceiverStream::new(receiver))
+    }
+}
+
+/// The response to a call with invalid arguments
+fn invalid(err: anyhow::Error) -> Status {
+    Status::invalid_argument(format!("{:#}", err))
+}
+
+/// Set `flags` on `builder` unless empty
+fn with_flags(builder: HashJobBuilder, flags: &str) -> HashJobBuilder {
+    if flags.is_empty() {
+        builder
+    } else {
+        builder.flags(flags)
+    }
+}
+
+#[tonic::async_trait]
+impl Fdsum for Service {
+    type HashStream = UpdateStream;
+    type VerifyStream = UpdateStream;
+    type DiffStream = UpdateStream;
+
+    async fn hash(&self, request: Request<HashRequest>) -> Result<Response<UpdateStream>, Status> {
+        let HashRequest {
+            path,
+            flags,
+            detail,
+            archive,
+        } = request.into_inner();
+        self.start(
+            |builder| {
+                with_flags(builder, &flags)
+                    .detail(detail as usize)
+                    .archive(archive)
+            },
+            move |job| {
+                let result = job.run(&path)?;
+                Ok(update::Update::Manifest(serde_json::to_string(&result)?))
+            },
+        )
+        .map(Response::new)
+        .map_err(invalid)
+    }
+
+    async fn verify(
+        &self,
+        request: Request<VerifyRequest>,
+    ) -> Result<Response<UpdateStream>, Status> {
+        let request = request.into_inner();
+        let reference = HashResultJson::parse(&request.manifest).map_err(invalid)?;
+        self.start(
+            |builder| builder,
+            move |job| {
+                let path = Some(Path::new(&request.path)).filter(|p| !p.as_os_str().is_empty());
+                let verification = job.verify(reference, path)?;
+                Ok(update::Update::Verification(Verification {
+                    matches: verification.is_match(),
+                    expected: verification.reference.hash.clone(),
+                    hash: verification.result.hash.clone(),
+                    changed: verification
+                        .changed_entries()
+                        .into_iter()
+                        .map(Difference::from)
+                        .collect(),
+                    manifest: serde_json::to_string(&verification.result)?,
+                }))
+            },
+        )
+        .map(Response::new)
+        .map_err(invalid)
+    }
+
+    async fn diff(&self, request: Request<DiffRequest>) -> Result<Response<UpdateStream>, Status> {
+        let DiffRequest { left, right, flags } = request.into_inner();
+        self.start(
+            |builder| with_flags(builder, &flags),
+            move |job| {
+                let differences = job.diff(&left, &right)?;
+                Ok(update::Update::Differences(Differences {
+                    differences: differences.into_iter().map(Difference::from).collect(),
+                }))
+            },
+        )
+        .map(Response::new)
+        .map_err(invalid)
+    }
+}
+
+/// Answer calls on `addr` until the process is stopped
+pub async fn serve(config: Config, addr: SocketAddr) -> Result<()> {
+    tonic::transport::Server::builder()
+        .add_service(FdsumServer::new(Service::new(config)))
+        .serve(addr)
+        .await?;
+    Ok(())
+}
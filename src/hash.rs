@@ -1,120 +1,635 @@
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, anyhow};
 use byteorder::{LittleEndian, WriteBytesExt};
 use rayon::prelude::*;
-use std::fs;
-use std::io::{BufReader, Cursor, Read};
-use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::io::{self, Cursor, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
+use std::sync::mpsc;
+use std::time::Instant;
 
+use crate::chunks::Chunker;
 use crate::config::Config;
+use crate::error::{ErrorCode, FsError};
+use crate::vfs::FileKind;
 
 pub fn hash_entry(config: &Config, path: &Path) -> Result<[u8; 32]> {
-    let meta = std::fs::symlink_metadata(path)?;
-    let filetype = meta.file_type();
+    hash_entry_at(config, path, Path::new(""))
+}
+
+/// The bytes hashed before the data of the entry at `path`, found at
+/// `rel` below the root, and the data. The digest of the entry is the
+/// hash of both.
+pub fn hash_entry_parts(config: &Config, path: &Path, rel: &Path) -> Result<(Vec<u8>, Vec<u8>)> {
+    let (kind, meta, data) = entry_parts(config, path, rel)?.ok_or_else(|| skipped_error(path))?;
+    Ok((entry_prefix(config, kind, &meta), data))
+}
+
+fn skipped_error(path: &Path) -> anyhow::Error {
+    anyhow!("Left out of the hash: {}", path.display())
+}
+
+/// The type of an entry and the digest of its metadata as hashed
+/// before its data. The type takes part from framing version 2 on.
+pub fn entry_prefix(config: &Config, kind: FileKind, meta: &[u8; 32]) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(33);
+    if config.framing >= 2 {
+        prefix.push(type_tag(kind));
+    }
+    prefix.extend_from_slice(meta);
+    prefix
+}
+
+/// The byte identifying the type of an entry
+pub fn type_tag(kind: FileKind) -> u8 {
+    match kind {
+        FileKind::File => b'f',
+        FileKind::Dir => b'd',
+        FileKind::Symlink => b'l',
+        FileKind::Device(_) => b'v',
+        FileKind::Special => b's',
+    }
+}
+
+/// Hash the entry at `path`, found at `rel` below the root
+pub fn hash_entry_at(config: &Config, path: &Path, rel: &Path) -> Result<[u8; 32]> {
+    hash_child(config, path, rel)?.ok_or_else(|| skipped_error(path))
+}
+
+/// Hash the entry at `path`, found at `rel` below the root, or `None`
+/// if it is left out of the hash (e.g. an empty directory)
+pub fn hash_child(config: &Config, path: &Path, rel: &Path) -> Result<Option<[u8; 32]>> {
+    let parts = match entry_parts(config, path, rel) {
+        Err(err) if config.skips_unreadable(rel, &err) => {
+            config.stats.done_entries(1);
+            let digest = unreadable_digest(config);
+            config.record_detail(rel, false, &digest);
+            return Ok(Some(digest));
+        }
+        parts => parts?,
+    };
+    let Some((kind, meta, data)) = parts else {
+        return Ok(None);
+    };
+    let mut hasher = config.hasher();
+    hasher.update(&entry_prefix(config, kind, &meta));
+    hasher.update(&data);
+
+    let digest = hasher.finalize();
+    config.record_detail(rel, kind == FileKind::Dir, &digest);
+    Ok(Some(digest))
+}
+
+/// The digest standing in for an entry that could not be read
+pub fn unreadable_digest(config: &Config) -> [u8; 32] {
     let mut hasher = config.hasher();
-    hasher.update(&hash_meta(config, &meta)?);
-
-    if filetype.is_dir() {
-        hasher.update(&hash_dir(config, path)?);
-    } else if filetype.is_file() {
-        config.stats.add_bytes(meta.size());
-        if config.include_file_content {
-            hasher.update(&hash_file(config, path)?);
+    hasher.update(b"fdsum:unreadable");
+    hasher.finalize()
+}
+
+type EntryParts = (FileKind, [u8; 32], Vec<u8>);
+
+fn entry_parts(config: &Config, path: &Path, rel: &Path) -> Result<Option<EntryParts>> {
+    config.cancel.check()?;
+    let stat = config.stats.time_stat(|| config.stat(path))?;
+    if config.skips_entry(rel, stat.kind, &stat.meta) {
+        config.stats.done_entries(1);
+        return Ok(None);
+    }
+    config.stats.count_kind(stat.kind);
+    let _span = config.profile_span(rel, stat.kind, stat.meta.size.unwrap_or(0));
+    let meta = hash_meta_at(config, &stat.meta, rel).with_context(|| path.display().to_string())?;
+
+    let data = match stat.kind {
+        FileKind::Dir => {
+            config.entry_started(rel);
+            let digests = dir_digests(config, path, rel)?;
+            if config.skips_empty_dir(rel, digests.len()) {
+                config.stats.done_entries(1);
+                return Ok(None);
+            }
+            let mut hasher = config.hasher();
+            for digest in digests {
+                hasher.update(&digest);
+            }
+            hasher.finalize().to_vec()
+        }
+        FileKind::File => {
+            let size = stat.meta.size.unwrap_or(0);
+            config.stats.add_bytes(size);
+            if config.include_file_content {
+                config.entry_started(rel);
+                let digest = hash_file(config, path, size)?;
+                config.record_file(path, rel, size, &digest);
+                digest.to_vec()
+            } else {
+                Vec::new()
+            }
+        }
+        FileKind::Symlink => {
+            let target = config.stats.time_stat(|| config.vfs.read_link(path))?;
+            target.into_os_string().into_encoded_bytes()
+        }
+        FileKind::Device(rdev) => rdev.to_le_bytes().to_vec(),
+        FileKind::Special => {
+            // this block intentionally left blank
+            Vec::new()
+        }
+    };
+    config.stats.done_entries(1);
+    Ok(Some((stat.kind, meta, data)))
+}
+
+/// The metadata of an entry that may take part in the hash. Sources
+/// other than the local filesystem (e.g. archives) may not record all
+/// fields, in which case they are `None`.
+#[derive(Debug, Clone, Default)]
+pub struct EntryMeta {
+    pub is_file: bool,
+    pub is_dir: bool,
+    pub mode: Option<u32>,
+    pub size: Option<u64>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub ctime: Option<i64>,
+    pub mtime: Option<i64>,
+    pub atime: Option<i64>,
+    /// Nanoseconds of the timestamps, where the source records them
+    pub ctime_nsec: Option<u32>,
+    pub mtime_nsec: Option<u32>,
+    pub atime_nsec: Option<u32>,
+    pub ino: Option<u64>,
+    /// The device holding the entry, to order reads by; never hashed
+    pub dev: Option<u64>,
+    /// Hard links, of entries other than directories
+    pub nlink: Option<u64>,
+    /// Attributes of files and directories, see [`crate::vfs::ATTRIBUTES`]
+    pub attributes: Option<u32>,
+    /// The project ID of files and directories
+    pub project: Option<u32>,
+    /// The security.capability attribute of regular files, empty if
+    /// not set
+    pub capabilities: Option<Vec<u8>>,
+    /// The NFSv4 ACL of regular files and directories in canonical
+    /// form, empty if not set
+    pub nfs4_acl: Option<Vec<u8>>,
+    /// The ETag of S3 objects, as listed
+    pub etag: Option<String>,
+    /// The storage class of S3 objects, as listed
+    pub storage_class: Option<String>,
+}
+
+impl From<&std::fs::Metadata> for EntryMeta {
+    fn from(meta: &std::fs::Metadata) -> Self {
+        Self {
+            is_file: meta.file_type().is_file(),
+            is_dir: meta.is_dir(),
+            mode: Some(meta.mode()),
+            size: Some(meta.size()),
+            uid: Some(meta.uid()),
+            gid: Some(meta.gid()),
+            ctime: Some(meta.ctime()),
+            mtime: Some(meta.mtime()),
+            atime: Some(meta.atime()),
+            ctime_nsec: Some(meta.ctime_nsec() as u32),
+            mtime_nsec: Some(meta.mtime_nsec() as u32),
+            atime_nsec: Some(meta.atime_nsec() as u32),
+            ino: Some(meta.ino()),
+            dev: Some(meta.dev()),
+            nlink: Some(meta.nlink()),
+            // read separately, if included
+            attributes: None,
+            project: None,
+            capabilities: None,
+            nfs4_acl: None,
+            etag: None,
+            storage_class: None,
         }
-    } else if filetype.is_symlink() {
-        let target = fs::read_link(path)?;
-        hasher.update(target.as_os_str().as_encoded_bytes());
-    } else if filetype.is_block_device() || filetype.is_char_device() {
-        let rdev = meta.rdev();
-        hasher.update(&rdev.to_le_bytes());
-    } else if filetype.is_fifo() || filetype.is_socket() {
-        // this block intentionally left blank
+    }
+}
+
+/// The metadata of the entry at `rel` below the root that the flags
+/// include but `meta` lacks, as the name of each field and how to leave
+/// it out of the hash
+pub fn missing_meta(
+    config: &Config,
+    meta: &EntryMeta,
+    rel: &Path,
+) -> Vec<(&'static str, &'static str)> {
+    if !config.include_root_meta && rel.as_os_str().is_empty() {
+        return Vec::new();
+    }
+    let file_or_dir = meta.is_file || meta.is_dir;
+    [
+        (
+            config.include_mode,
+            meta.mode.is_some(),
+            "mode",
+            "exclude with -M",
+        ),
+        (
+            config.include_size && meta.is_file,
+            meta.size.is_some(),
+            "size",
+            "exclude with -S",
+        ),
+        (
+            config.include_uid,
+            meta.uid.is_some(),
+            "owner UID",
+            "exclude with -O",
+        ),
+        (
+            config.include_gid,
+            meta.gid.is_some(),
+            "owner GID",
+            "exclude with -G",
+        ),
+        (
+            config.include_ctime,
+            meta.ctime.is_some(),
+            "ctime",
+            "omit --ctime",
+        ),
+        (
+            config.include_mtime,
+            meta.mtime.is_some(),
+            "mtime",
+            "exclude with -T",
+        ),
+        (
+            config.include_atime,
+            meta.atime.is_some(),
+            "atime",
+            "omit --atime",
+        ),
+        (
+            config.include_inode,
+            meta.ino.is_some(),
+            "inode",
+            "omit --inode",
+        ),
+        (
+            config.include_nlink && !meta.is_dir,
+            meta.nlink.is_some(),
+            "link count",
+            "omit --nlink",
+        ),
+        (
+            config.include_attributes && file_or_dir,
+            meta.attributes.is_some(),
+            "attributes",
+            "omit --attributes",
+        ),
+        (
+            config.include_project && file_or_dir,
+            meta.project.is_some(),
+            "project ID",
+            "omit --project",
+        ),
+        (
+            config.include_capabilities && meta.is_file,
+            meta.capabilities.is_some(),
+            "capabilities",
+            "omit --capabilities",
+        ),
+        (
+            config.include_nfs4_acl && file_or_dir,
+            meta.nfs4_acl.is_some(),
+            "NFSv4 ACL",
+            "omit --nfs4-acl",
+        ),
+        (
+            config.include_etag && meta.is_file,
+            meta.etag.is_some(),
+            "ETag",
+            "omit --etag",
+        ),
+        (
+            config.include_storage_class && meta.is_file,
+            meta.storage_class.is_some(),
+            "storage class",
+            "omit --storage-class",
+        ),
+    ]
+    .into_iter()
+    .filter(|&(included, available, _, _)| included && !available)
+    .map(|(_, _, what, hint)| (what, hint))
+    .collect()
+}
+
+fn require<T>(value: Option<T>, what: &str, hint: &str) -> Result<T> {
+    value.ok_or_else(|| anyhow!("{} not available ({})", what, hint))
+}
+
+/// Write the timestamp `secs` and `nsec` rounded to the time
+/// granularity, with the nanoseconds only for sub-second granularity
+fn write_time(
+    config: &Config,
+    cursor: &mut Cursor<&mut [u8]>,
+    (secs, nsec): (Option<i64>, Option<u32>),
+    what: &str,
+    hint: &str,
+) -> Result<()> {
+    let granularity = config.time_granularity;
+    let secs = require(secs, what, hint)?;
+    let nsec = if granularity.sub_second() {
+        let what = format!("sub-second {}", what);
+        require(nsec, &what, "use a coarser --time-granularity")?
     } else {
-        anyhow::bail!("file type unknown: {}", path.display());
+        0
+    };
+    let (secs, nsec) = granularity.round(secs, nsec);
+    cursor.write_i64::<LittleEndian>(secs)?;
+    if granularity.sub_second() {
+        cursor.write_u32::<LittleEndian>(nsec)?;
     }
-    config.stats.done_entries(1);
+    Ok(())
+}
 
-    Ok(hasher.finalize())
+/// Hash the metadata of the entry at `rel` below the root. The
+/// metadata of the root is left out unless included by the flags.
+pub fn hash_meta_at(config: &Config, meta: &EntryMeta, rel: &Path) -> Result<[u8; 32]> {
+    if !config.include_root_meta && rel.as_os_str().is_empty() {
+        return Ok(config.hasher().finalize());
+    }
+    let name = rel
+        .file_name()
+        .filter(|_| config.include_names)
+        .map(|name| name.as_bytes());
+    hash_meta_named(config, meta, name)
+}
+
+pub fn hash_meta(config: &Config, meta: &EntryMeta) -> Result<[u8; 32]> {
+    hash_meta_named(config, meta, None)
 }
 
-pub fn hash_meta(config: &Config, meta: &std::fs::Metadata) -> Result<[u8; 32]> {
-    let mut buf = [0u8; 64];
+/// Hash the metadata followed by the length and bytes of the
+/// capabilities, of the NFSv4 ACL, of the ETag, of the storage class
+/// and of `name`
+fn hash_meta_named(config: &Config, meta: &EntryMeta, name: Option<&[u8]>) -> Result<[u8; 32]> {
+    let mut buf = [0u8; 80];
     let mut cursor = Cursor::new(&mut buf[..]);
 
     if config.include_mode {
         // mode includes the file type as well, but we
         // don't really care
-        cursor.write_u32::<LittleEndian>(meta.mode())?;
+        cursor.write_u32::<LittleEndian>(require(meta.mode, "mode", "exclude with -M")?)?;
     }
-    if config.include_size && meta.file_type().is_file() {
+    if config.include_size && meta.is_file {
         // size used only for regular files since it may vary between
         // file system implementations for other type
-        cursor.write_u64::<LittleEndian>(meta.size())?;
+        cursor.write_u64::<LittleEndian>(require(meta.size, "size", "exclude with -S")?)?;
     }
     if config.include_uid {
-        cursor.write_u32::<LittleEndian>(meta.uid())?;
+        let uid = require(meta.uid, "owner UID", "exclude with -O")?;
+        let uid = config.idmap.as_ref().map_or(uid, |idmap| idmap.uid(uid));
+        cursor.write_u32::<LittleEndian>(uid)?;
     }
     if config.include_gid {
-        cursor.write_u32::<LittleEndian>(meta.gid())?;
+        let gid = require(meta.gid, "owner GID", "exclude with -G")?;
+        let gid = config.idmap.as_ref().map_or(gid, |idmap| idmap.gid(gid));
+        cursor.write_u32::<LittleEndian>(gid)?;
     }
     if config.include_ctime {
-        cursor.write_i64::<LittleEndian>(meta.ctime())?;
+        let ctime = (meta.ctime, meta.ctime_nsec);
+        write_time(config, &mut cursor, ctime, "ctime", "omit --ctime")?;
     }
     if config.include_mtime {
-        cursor.write_i64::<LittleEndian>(meta.mtime())?;
+        let mtime = (meta.mtime, meta.mtime_nsec);
+        write_time(config, &mut cursor, mtime, "mtime", "exclude with -T")?;
     }
     if config.include_atime {
-        cursor.write_i64::<LittleEndian>(meta.atime())?;
+        let atime = (meta.atime, meta.atime_nsec);
+        write_time(config, &mut cursor, atime, "atime", "omit --atime")?;
+    }
+    if config.include_inode {
+        cursor.write_u64::<LittleEndian>(require(meta.ino, "inode", "omit --inode")?)?;
+    }
+    if config.include_nlink && !meta.is_dir {
+        cursor.write_u64::<LittleEndian>(require(meta.nlink, "link count", "omit --nlink")?)?;
+    }
+    if config.include_attributes && (meta.is_file || meta.is_dir) {
+        let attributes = require(meta.attributes, "attributes", "omit --attributes")?;
+        cursor.write_u32::<LittleEndian>(attributes)?;
+    }
+    if config.include_project && (meta.is_file || meta.is_dir) {
+        let project = require(meta.project, "project ID", "omit --project")?;
+        cursor.write_u32::<LittleEndian>(project)?;
     }
 
     let mut hasher = config.hasher();
     let len = cursor.position() as usize;
     hasher.update(&buf[..len]);
+    if config.include_capabilities && meta.is_file {
+        let capabilities = require(
+            meta.capabilities.as_deref(),
+            "capabilities",
+            "omit --capabilities",
+        )?;
+        hasher.update(&(capabilities.len() as u32).to_le_bytes());
+        hasher.update(capabilities);
+    }
+    if config.include_nfs4_acl && (meta.is_file || meta.is_dir) {
+        let acl = require(meta.nfs4_acl.as_deref(), "NFSv4 ACL", "omit --nfs4-acl")?;
+        hasher.update(&(acl.len() as u32).to_le_bytes());
+        hasher.update(acl);
+    }
+    if config.include_etag && meta.is_file {
+        let etag = require(meta.etag.as_deref(), "ETag", "omit --etag")?;
+        hasher.update(&(etag.len() as u32).to_le_bytes());
+        hasher.update(etag.as_bytes());
+    }
+    if config.include_storage_class && meta.is_file {
+        let class = require(
+            meta.storage_class.as_deref(),
+            "storage class",
+            "omit --storage-class",
+        )?;
+        hasher.update(&(class.len() as u32).to_le_bytes());
+        hasher.update(class.as_bytes());
+    }
+    if let Some(name) = name {
+        hasher.update(&(name.len() as u32).to_le_bytes());
+        hasher.update(name);
+    }
     Ok(hasher.finalize())
 }
 
-pub fn hash_file(config: &Config, path: &Path) -> Result<[u8; 32]> {
-    let file = fs::File::open(path).map_err(|e| {
-        let errno = e.raw_os_error().unwrap_or(-1);
-        anyhow::anyhow!(
-            "Failed to open file: {} (errno {}): {}",
-            path.display(),
-            errno,
-            e
-        )
+/// Hash the content of the file at `path`, of `size` bytes as stat
+/// reported it
+pub fn hash_file(config: &Config, path: &Path, size: u64) -> Result<[u8; 32]> {
+    let started = Instant::now();
+    let mut reader = config.vfs.open(path)?;
+    let block_size = config.read_size(path);
+    // a file read in one block gains nothing from another thread
+    let prefetch = config.prefetch > 0 && size > block_size as u64;
+    let mut chunker = config.record_chunks.then(|| Chunker::new(config));
+    let digest = if prefetch {
+        hash_prefetched(config, &mut *reader, block_size, chunker.as_mut())
+    } else {
+        hash_reads(config, &mut reader, block_size, chunker.as_mut())
+    };
+    let digest = digest.map_err(|err| match err.downcast::<io::Error>() {
+        Ok(e) => FsError::new(ErrorCode::Read, path, e).into(),
+        Err(err) => err,
     })?;
-    let mut reader = BufReader::new(file);
+    if let Some(chunker) = chunker {
+        let chunks = chunker.finish();
+        config
+            .file_chunks
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), chunks);
+    }
+    config.stats.time_file(path, started.elapsed());
+    Ok(digest)
+}
+
+/// Hash the content read from `reader` in blocks of the configured size
+pub fn hash_stream(config: &Config, reader: &mut dyn Read) -> Result<[u8; 32]> {
+    hash_reads(config, reader, config.block_size, None)
+}
+
+/// Hash the content read from `reader` in blocks of `block_size`,
+/// cutting it into chunks with `chunker` if given
+fn hash_reads(
+    config: &Config,
+    reader: &mut dyn Read,
+    block_size: usize,
+    mut chunker: Option<&mut Chunker>,
+) -> Result<[u8; 32]> {
     let mut hasher = config.hasher();
-    let mut buf = vec![0u8; config.block_size];
+    let mut buf = vec![0u8; block_size];
 
     loop {
+        config.cancel.check()?;
+        let started = Instant::now();
         let n = reader.read(&mut buf)?;
         if n == 0 {
             break;
         }
+        let read = started.elapsed();
         hasher.update(&buf[..n]);
+        if let Some(chunker) = chunker.as_mut() {
+            chunker.update(&buf[..n]);
+        }
+        config.stats.add_times(read, started.elapsed() - read);
         config.stats.done_bytes(n as u64);
+        if let Some(limit) = &config.bwlimit {
+            limit.consume(n as u64);
+        }
     }
     Ok(hasher.finalize())
 }
 
-pub fn hash_dir(config: &Config, path: &Path) -> Result<[u8; 32]> {
-    let mut entries = Vec::new();
-    for entry in fs::read_dir(path)? {
-        entries.push(entry?.path());
+/// Hash the content read from `reader` in blocks of `block_size`,
+/// reading up to `config.prefetch` blocks ahead on another thread. The
+/// time spent waiting for blocks counts as reading.
+fn hash_prefetched(
+    config: &Config,
+    reader: &mut (dyn Read + Send),
+    block_size: usize,
+    mut chunker: Option<&mut Chunker>,
+) -> Result<[u8; 32]> {
+    std::thread::scope(|scope| {
+        let (full_tx, full_rx) =
+            mpsc::sync_channel::<io::Result<(Vec<u8>, usize)>>(config.prefetch);
+        let (empty_tx, empty_rx) = mpsc::channel::<Vec<u8>>();
+        scope.spawn(move || {
+            loop {
+                let mut buf = empty_rx
+                    .try_recv()
+                    .unwrap_or_else(|_| vec![0u8; block_size]);
+                let block = match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => Ok((buf, n)),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e),
+                };
+                let failed = block.is_err();
+                // the receiver is gone once hashing failed
+                if full_tx.send(block).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        let mut hasher = config.hasher();
+        loop {
+            config.cancel.check()?;
+            let started = Instant::now();
+            let Ok(block) = full_rx.recv() else {
+                break;
+            };
+            let (buf, n) = block?;
+            let read = started.elapsed();
+            hasher.update(&buf[..n]);
+            if let Some(chunker) = chunker.as_mut() {
+                chunker.update(&buf[..n]);
+            }
+            config.stats.add_times(read, started.elapsed() - read);
+            config.stats.done_bytes(n as u64);
+            if let Some(limit) = &config.bwlimit {
+                limit.consume(n as u64);
+            }
+            let _ = empty_tx.send(buf);
+        }
+        Ok(hasher.finalize())
+    })
+}
+
+/// The digest of the directory at `rel` below the root from its
+/// metadata and the digests of its included entries in name order, or
+/// `None` if it is left out of the hash
+pub fn dir_digest(
+    config: &Config,
+    rel: &Path,
+    meta: &EntryMeta,
+    digests: &[[u8; 32]],
+) -> Result<Option<[u8; 32]>> {
+    if config.skips_empty_dir(rel, digests.len()) {
+        return Ok(None);
+    }
+    let mut data = config.hasher();
+    for digest in digests {
+        data.update(digest);
+    }
+    let mut hasher = config.hasher();
+    hasher.update(&entry_prefix(
+        config,
+        FileKind::Dir,
+        &hash_meta_at(config, meta, rel)?,
+    ));
+    hasher.update(&data.finalize());
+    Ok(Some(hasher.finalize()))
+}
+
+pub fn hash_dir(config: &Config, path: &Path, rel: &Path) -> Result<[u8; 32]> {
+    let mut hasher = config.hasher();
+    for digest in dir_digests(config, path, rel)? {
+        hasher.update(&digest);
     }
-    entries.sort();
+    Ok(hasher.finalize())
+}
+
+/// The digests of the entries of a directory that take part in the
+/// hash, sorted by name
+fn dir_digests(config: &Config, path: &Path, rel: &Path) -> Result<Vec<[u8; 32]>> {
+    let mut entries: Vec<_> = config
+        .stats
+        .time_stat(|| config.vfs.read_dir(path))?
+        .into_iter()
+        .filter(|name| !config.is_excluded(name))
+        .collect();
+    // raw bytes of the names, independent of the locale
+    entries.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
     config.stats.add_entries(entries.len() as u64);
 
-    let hashes: Vec<[u8; 32]> = entries
+    let hashes: Vec<Option<[u8; 32]>> = entries
         .par_iter()
-        .map(|entry| hash_entry(config, entry))
+        .map(|name| hash_child(config, &path.join(name), &rel.join(name)))
         .collect::<Result<_>>()?;
 
-    let mut hasher = config.hasher();
-    for h in hashes {
-        hasher.update(&h);
-    }
-
-    Ok(hasher.finalize())
+    Ok(hashes.into_iter().flatten().collect())
 }
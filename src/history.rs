@@ -0,0 +1,216 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use fdsum::config::{HashResultJson, NotifyOn};
+use fdsum::error::ErrorInfo;
+
+/// A record of a run as appended to the history log
+#[derive(Debug, Serialize)]
+pub struct RunRecord {
+    pub started: String,
+    pub command: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flags: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// The digest recorded in the manifest, when verifying
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected: Option<String>,
+    /// "hashed", "ok", "mismatch", "updated" or "error"
+    pub status: &'static str,
+    pub elapsed_seconds: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The error classified, for automation
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<ErrorInfo>,
+}
+
+impl RunRecord {
+    /// The fields describing the outcome, by their names in the journal
+    /// and the environment of hooks
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![("FDSUM_STATUS", self.status.to_string())];
+        let optional = [
+            (
+                "FDSUM_PATH",
+                self.name.as_ref().map(|n| n.display().to_string()),
+            ),
+            ("FDSUM_DIGEST", self.hash.clone()),
+            ("FDSUM_EXPECTED", self.expected.clone()),
+            ("FDSUM_FLAGS", self.flags.clone()),
+            ("FDSUM_ERROR", self.error.clone()),
+            (
+                "FDSUM_ERROR_CODE",
+                self.errors.first().map(|e| e.code.to_string()),
+            ),
+            (
+                "FDSUM_ERRNO",
+                self.errors
+                    .first()
+                    .and_then(|e| e.errno)
+                    .map(|n| n.to_string()),
+            ),
+            (
+                "FDSUM_ERROR_PATH",
+                self.errors
+                    .first()
+                    .and_then(|e| e.path.as_ref())
+                    .map(|p| p.display().to_string()),
+            ),
+        ];
+        fields.extend(
+            optional
+                .into_iter()
+                .filter_map(|(key, value)| Some((key, value?))),
+        );
+        fields
+    }
+
+    /// A line summarizing the outcome
+    pub fn message(&self) -> String {
+        match (&self.name, &self.error) {
+            (Some(name), Some(error)) if self.status == "error" => {
+                format!("{}: {}", name.display(), error)
+            }
+            (Some(name), _) => format!("{}: {}", name.display(), self.status),
+            (None, Some(error)) => format!("Error: {}", error),
+            (None, None) => format!("{}: {}", self.command.join(" "), self.status),
+        }
+    }
+}
+
+/// Collects what a run did, to be logged, appended to the history log
+/// and posted as configured
+pub struct Run {
+    start: Instant,
+    /// Where the record is appended
+    pub history: Option<PathBuf>,
+    /// Log the record to the system journal as well
+    pub syslog: bool,
+    /// Post the record to this URL
+    pub notify_url: Option<String>,
+    pub notify_on: NotifyOn,
+    /// Commands run with the payload on stdin after every run, and
+    /// after runs that found a mismatch
+    pub on_complete: Option<String>,
+    pub on_mismatch: Option<String>,
+    pub record: RunRecord,
+    /// The result of hashing the tree, as posted
+    pub result: Option<serde_json::Value>,
+}
+
+impl Run {
+    pub fn start() -> Self {
+        Self {
+            start: Instant::now(),
+            history: None,
+            syslog: false,
+            notify_url: None,
+            notify_on: NotifyOn::Complete,
+            on_complete: None,
+            on_mismatch: None,
+            result: None,
+            record: RunRecord {
+                started: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+                command: std::env::args().collect(),
+                name: None,
+                flags: None,
+                hash: None,
+                expected: None,
+                status: "ok",
+                elapsed_seconds: 0.0,
+                error: None,
+                errors: Vec::new(),
+            },
+        }
+    }
+
+    /// Note the result of hashing the tree, and the manifest it was
+    /// verified against
+    pub fn hashed(&mut self, result: &HashResultJson, reference: Option<&HashResultJson>) {
+        self.record.name = Some(result.name.clone());
+        self.record.flags = Some(result.flags.clone());
+        self.record.hash = Some(result.hash.clone());
+        self.record.expected = reference.map(|reference| reference.hash.clone());
+        self.record.status = if reference.is_some() { "ok" } else { "hashed" };
+        self.result = serde_json::to_value(result).ok();
+    }
+
+    /// The record with the result of hashing the tree if any and a
+    /// `text` field summarizing the outcome as Slack and Teams webhooks
+    /// expect
+    fn payload(&self) -> Result<serde_json::Value> {
+        let mut payload = serde_json::to_value(&self.record)?;
+        payload["text"] = self.record.message().into();
+        if let Some(result) = &self.result {
+            payload["result"] = result.clone();
+        }
+        Ok(payload)
+    }
+
+    /// Complete the record with the outcome of the run, then log, post
+    /// and append it and run the hooks, as configured
+    pub fn finish(mut self, outcome: &Result<()>) -> Result<()> {
+        self.record.elapsed_seconds = (self.start.elapsed().as_secs_f64() * 100.0).round() / 100.0;
+        if let Err(err) = outcome {
+            self.record.error = Some(format!("{:#}", err));
+            self.record.errors = vec![ErrorInfo::from_error(err)];
+            if matches!(self.record.status, "ok" | "hashed") {
+                self.record.status = "error";
+            }
+        }
+        if self.syslog
+            && let Err(err) = crate::syslog::log(&self.record)
+        {
+            eprintln!("Warning: not logged to the journal: {:#}", err);
+        }
+        let payload = self.payload()?;
+        if let Some(url) = &self.notify_url
+            && (self.notify_on == NotifyOn::Complete
+                || matches!(self.record.status, "mismatch" | "error"))
+            && let Err(err) = crate::notify::post(url, &payload)
+        {
+            eprintln!("Warning: notification not sent: {:#}", err);
+        }
+        let hooks = [
+            self.on_complete.as_ref(),
+            self.on_mismatch
+                .as_ref()
+                .filter(|_| self.record.status == "mismatch"),
+        ];
+        for command in hooks.into_iter().flatten() {
+            if let Err(err) = crate::hooks::run(command, &self.record, &payload) {
+                eprintln!("Warning: {}: {:#}", command, err);
+            }
+        }
+        match &self.history {
+            Some(history) => append(history, &self.record),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Append `value` to `path` as one line, written at once so that
+/// concurrent runs do not interleave
+pub fn append(path: &Path, value: &impl Serialize) -> Result<()> {
+    let inner = || -> Result<()> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut line = serde_json::to_string(value)?;
+        line.push('\n');
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(line.as_bytes())?;
+        Ok(())
+    };
+    inner().with_context(|| path.display().to_string())
+}
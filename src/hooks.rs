@@ -0,0 +1,31 @@
+use anyhow::{Result, anyhow};
+use serde_json::Value;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use crate::history::RunRecord;
+
+/// Run `command` with the shell, with `payload` as JSON on stdin and
+/// the outcome of the run in FDSUM_* environment variables
+pub fn run(command: &str, record: &RunRecord, payload: &Value) -> Result<()> {
+    let mut child = Command::new("sh");
+    // stdout carries the result of the run only
+    child
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(std::io::stderr());
+    for (key, value) in record.fields() {
+        child.env(key, value);
+    }
+    let mut child = child.spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    // the command need not read all of it
+    let _ = stdin.write_all(serde_json::to_string(payload)?.as_bytes());
+    drop(stdin);
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(anyhow!("{}", status));
+    }
+    Ok(())
+}
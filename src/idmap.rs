@@ -0,0 +1,80 @@
+//! Mappings of numeric user and group IDs between sites, so that a tree
+//! migrated to a site where the same owners have other IDs verifies.
+//!
+//! The file lists one mapping per line, `uid OLD NEW` or `gid OLD NEW`,
+//! from the IDs at the site the tree comes from to those at the site it
+//! moved to. Empty lines and lines starting with `#` are ignored.
+
+use anyhow::{Context, Result, anyhow};
+use std::collections::HashMap;
+use std::path::Path;
+
+#[derive(Debug, Clone, Default)]
+pub struct IdMap {
+    uids: HashMap<u32, u32>,
+    gids: HashMap<u32, u32>,
+}
+
+impl IdMap {
+    pub fn read(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| path.display().to_string())?;
+        Self::parse(&text).with_context(|| path.display().to_string())
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut map = Self::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || anyhow!("line {}: expected uid|gid OLD NEW: {}", number + 1, line);
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let [kind, old, new] = words.as_slice() else {
+                return Err(invalid());
+            };
+            let (Ok(old), Ok(new)) = (old.parse(), new.parse()) else {
+                return Err(invalid());
+            };
+            let ids = match *kind {
+                "uid" => &mut map.uids,
+                "gid" => &mut map.gids,
+                _ => return Err(invalid()),
+            };
+            if ids.insert(old, new).is_some_and(|other| other != new) {
+                return Err(anyhow!(
+                    "line {}: {} {} mapped twice",
+                    number + 1,
+                    kind,
+                    old
+                ));
+            }
+        }
+        Ok(map)
+    }
+
+    /// The mapping from the new IDs back to the old ones
+    pub fn inverse(&self) -> Result<Self> {
+        fn invert(ids: &HashMap<u32, u32>, kind: &str) -> Result<HashMap<u32, u32>> {
+            let mut inverse = HashMap::with_capacity(ids.len());
+            for (&old, &new) in ids {
+                if inverse.insert(new, old).is_some() {
+                    return Err(anyhow!("Several IDs are mapped to {} {}", kind, new));
+                }
+            }
+            Ok(inverse)
+        }
+        Ok(Self {
+            uids: invert(&self.uids, "uid")?,
+            gids: invert(&self.gids, "gid")?,
+        })
+    }
+
+    pub fn uid(&self, uid: u32) -> u32 {
+        self.uids.get(&uid).copied().unwrap_or(uid)
+    }
+
+    pub fn gid(&self, gid: u32) -> u32 {
+        self.gids.get(&gid).copied().unwrap_or(gid)
+    }
+}
@@ -0,0 +1,213 @@
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+
+use fdsum::archive;
+use fdsum::config::{Config, HashResultJson};
+use fdsum::tree::VirtualTree;
+
+const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+const DOCKER_MANIFEST_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+
+#[derive(Debug, Deserialize)]
+struct Descriptor {
+    #[serde(rename = "mediaType", default)]
+    media_type: String,
+    digest: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct OciIndex {
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OciManifest {
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerManifest {
+    layers: Vec<PathBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImageResultJson {
+    pub name: PathBuf,
+    pub layers: Vec<HashResultJson>,
+    pub rootfs: HashResultJson,
+}
+
+/// Hash each layer of the image at `path` and the root filesystem
+/// obtained by applying the layers in order
+pub fn run(config: &Config, path: &Path) -> Result<()> {
+    let layers = if path.is_dir() {
+        read_oci_layout(config, path)
+    } else {
+        read_docker_archive(config, path)
+    }
+    .with_context(|| path.display().to_string())?;
+
+    let mut rootfs = VirtualTree::new();
+    let mut results = Vec::new();
+    for (name, layer) in &layers {
+        let hash = layer.digest(config, name)?;
+        results.push(HashResultJson::from_part(config, name.clone(), &hash));
+        rootfs.overlay(layer);
+    }
+    config.stats.done_entries(1);
+    let hash = rootfs.digest(config, path)?;
+
+    let result = ImageResultJson {
+        name: path.to_path_buf(),
+        layers: results,
+        rootfs: HashResultJson::from_part(config, path.to_path_buf(), &hash),
+    };
+    println!("{}", config.to_json(&result)?);
+    Ok(())
+}
+
+fn read_layer(config: &Config, reader: impl Read) -> Result<VirtualTree> {
+    config.stats.add_entries(1);
+    let tree = archive::read_tar_stream(config, BufReader::new(reader))?;
+    config.stats.done_entries(1);
+    Ok(tree)
+}
+
+fn blob_path(layout: &Path, digest: &str) -> Result<PathBuf> {
+    let (algorithm, hex) = digest
+        .split_once(':')
+        .ok_or_else(|| anyhow!("malformed digest: {}", digest))?;
+    if algorithm.contains('/') || hex.contains('/') {
+        return Err(anyhow!("malformed digest: {}", digest));
+    }
+    Ok(layout.join("blobs").join(algorithm).join(hex))
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    serde_json::from_reader(BufReader::new(file))
+        .with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+/// Read the layers of an image in an OCI image layout directory. The
+/// layout must reference exactly one image manifest.
+fn read_oci_layout(config: &Config, layout: &Path) -> Result<Vec<(PathBuf, VirtualTree)>> {
+    let mut index: OciIndex = read_json(&layout.join("index.json"))?;
+    let manifest = loop {
+        let [descriptor] = index.manifests.as_slice() else {
+            return Err(anyhow!(
+                "image index lists {} manifests, expected exactly one",
+                index.manifests.len()
+            ));
+        };
+        let path = blob_path(layout, &descriptor.digest)?;
+        if descriptor.media_type == OCI_INDEX || descriptor.media_type == DOCKER_MANIFEST_LIST {
+            index = read_json(&path)?;
+        } else {
+            break read_json::<OciManifest>(&path)?;
+        }
+    };
+
+    manifest
+        .layers
+        .iter()
+        .map(|layer| {
+            let path = blob_path(layout, &layer.digest)?;
+            let file = File::open(&path)
+                .with_context(|| format!("Failed to open layer {}", layer.digest))?;
+            let tree = read_layer(config, file)
+                .with_context(|| format!("Failed to read layer {}", layer.digest))?;
+            Ok((PathBuf::from(&layer.digest), tree))
+        })
+        .collect()
+}
+
+/// Resolve a symlink inside the tarball relative to the link's directory
+fn resolve_link(link: &Path, target: &Path) -> PathBuf {
+    normalize(&link.parent().unwrap_or(Path::new("")).join(target))
+}
+
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::ParentDir => {
+                out.pop();
+            }
+            Component::Normal(name) => out.push(name),
+            _ => {}
+        }
+    }
+    out
+}
+
+/// Read the layers of a `docker save` tarball. The tarball is read
+/// twice, first for `manifest.json`, then for the layers themselves.
+fn read_docker_archive(config: &Config, path: &Path) -> Result<Vec<(PathBuf, VirtualTree)>> {
+    let open = || -> Result<tar::Archive<BufReader<File>>> {
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        Ok(tar::Archive::new(BufReader::new(file)))
+    };
+
+    let mut manifests: Option<Vec<DockerManifest>> = None;
+    let mut links = HashMap::new();
+    for entry in open()?.entries()? {
+        let mut entry = entry?;
+        let name = normalize(&entry.path()?);
+        if entry.header().entry_type().is_symlink() {
+            if let Some(target) = entry.link_name()? {
+                links.insert(name.clone(), resolve_link(&name, &target));
+            }
+        } else if name == Path::new("manifest.json") {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            manifests =
+                Some(serde_json::from_slice(&data).context("Failed to parse manifest.json")?);
+        }
+    }
+    let manifests = manifests.ok_or_else(|| anyhow!("manifest.json not found"))?;
+    let [manifest] = manifests.as_slice() else {
+        return Err(anyhow!(
+            "tarball contains {} images, expected exactly one",
+            manifests.len()
+        ));
+    };
+
+    let resolve = |layer: &Path| {
+        let mut layer = normalize(layer);
+        while let Some(target) = links.get(&layer) {
+            layer = target.clone();
+        }
+        layer
+    };
+    let wanted: Vec<PathBuf> = manifest.layers.iter().map(|l| resolve(l)).collect();
+
+    let mut trees = HashMap::new();
+    for entry in open()?.entries()? {
+        let entry = entry?;
+        let name = normalize(&entry.path()?);
+        if wanted.contains(&name) && !trees.contains_key(&name) {
+            let tree = read_layer(config, entry)
+                .with_context(|| format!("Failed to read layer {}", name.display()))?;
+            trees.insert(name, tree);
+        }
+    }
+
+    manifest
+        .layers
+        .iter()
+        .zip(&wanted)
+        .map(|(layer, resolved)| {
+            let tree = trees
+                .get(resolved)
+                .ok_or_else(|| anyhow!("layer not found: {}", layer.display()))?;
+            Ok((layer.clone(), tree.clone()))
+        })
+        .collect()
+}
@@ -0,0 +1,254 @@
+use anyhow::{Context, Result, anyhow};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use fdsum::config::{Config, Detail, FileRecord, HashAlgorithm, HashResultJson};
+use fdsum::formats;
+use fdsum::hash::{EntryMeta, dir_digest, entry_prefix, hash_meta_at};
+use fdsum::job;
+use fdsum::vfs::FileKind;
+
+/// A file listed in a checksum file: its path relative to the root,
+/// its size if listed and its content digest
+type Listed = (PathBuf, Option<u64>, String);
+
+fn read_list(config: &Config, list: &Path) -> Result<(HashAlgorithm, Vec<Listed>)> {
+    let reader = || -> Result<_> {
+        Ok(BufReader::new(
+            File::open(list).with_context(|| list.display().to_string())?,
+        ))
+    };
+    if formats::is_hashdeep(list)? {
+        let (algorithm, files) = formats::read_hashdeep(reader()?, &config.algorithm)?;
+        // relative paths are relative to where hashdeep was run
+        let base = invoked_from(reader()?)?;
+        let files = files
+            .into_iter()
+            .map(|file| {
+                let path = match &base {
+                    Some(base) => base.join(file.path),
+                    None => file.path,
+                };
+                (path, Some(file.size), file.hash)
+            })
+            .collect();
+        Ok((algorithm, files))
+    } else {
+        let (algorithm, files) = formats::read_sums(reader()?, &config.algorithm)?;
+        let files = files
+            .into_iter()
+            .map(|(path, hash)| (path, None, hash))
+            .collect();
+        Ok((algorithm, files))
+    }
+}
+
+/// The directory a hashdeep file set was written from, if recorded
+fn invoked_from(reader: impl BufRead) -> Result<Option<PathBuf>> {
+    for line in reader.lines() {
+        let line = line?;
+        if !line.starts_with('%') && !line.starts_with('#') {
+            break;
+        }
+        if let Some(dir) = line.strip_prefix("## Invoked from: ") {
+            return Ok(Some(PathBuf::from(dir)));
+        }
+    }
+    Ok(None)
+}
+
+/// `path` relative to `root`. Relative paths are taken as relative to
+/// the root already.
+fn relative(root: &Path, path: &Path) -> Result<PathBuf> {
+    let rel = if path.is_absolute() {
+        let root = std::path::absolute(root)?;
+        path.strip_prefix(&root)
+            .map_err(|_| anyhow!("Outside of {}: {}", root.display(), path.display()))?
+            .to_path_buf()
+    } else {
+        path.to_path_buf()
+    };
+    let rel: PathBuf = rel
+        .components()
+        .filter(|c| *c != Component::CurDir)
+        .collect();
+    if rel.as_os_str().is_empty() || rel.components().any(|c| c == Component::ParentDir) {
+        return Err(anyhow!("Not a file below the root: {}", path.display()));
+    }
+    Ok(rel)
+}
+
+/// Build a manifest of the content of the regular files listed in the
+/// checksum files `lists`, found below `root`, without reading them,
+/// and write it to `output`, or to stdout
+pub fn run(
+    config: &mut Config,
+    lists: &[PathBuf],
+    root: Option<&Path>,
+    output: Option<&Path>,
+) -> Result<()> {
+    if !config.include_file_content {
+        return Err(anyhow!("Checksum files can only be imported with content"));
+    }
+    if config.record_chunks {
+        return Err(anyhow!("Checksum files record no chunks of files"));
+    }
+    if let Some(path) = output {
+        job::check_output(config, path)?;
+    }
+    if config.newer_than.is_some() || config.older_than.is_some() {
+        return Err(anyhow!(
+            "Checksum files record no modification times to select files by"
+        ));
+    }
+    // checksum files record the content of regular files only
+    config.include_size = false;
+    config.include_mode = false;
+    config.include_uid = false;
+    config.include_gid = false;
+    config.include_mtime = false;
+    config.include_ctime = false;
+    config.include_atime = false;
+    config.include_inode = false;
+    config.include_nlink = false;
+    config.include_attributes = false;
+    config.include_project = false;
+    config.include_capabilities = false;
+    config.include_nfs4_acl = false;
+    config.include_etag = false;
+    config.include_storage_class = false;
+    config.types = "f".to_string();
+
+    let root = match root {
+        Some(root) => root.to_path_buf(),
+        None => match lists[0].parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        },
+    };
+
+    let mut algorithm: Option<HashAlgorithm> = None;
+    let mut files: BTreeMap<PathBuf, (Option<u64>, String)> = BTreeMap::new();
+    for list in lists {
+        let (list_algorithm, listed) =
+            read_list(config, list).with_context(|| list.display().to_string())?;
+        if algorithm.as_ref().is_some_and(|a| *a != list_algorithm) {
+            return Err(anyhow!(
+                "{} lists {} digests, the others {}",
+                list.display(),
+                list_algorithm,
+                algorithm.unwrap()
+            ));
+        }
+        algorithm = Some(list_algorithm);
+        for (path, size, hash) in listed {
+            let rel = relative(&root, &path).with_context(|| list.display().to_string())?;
+            match files.get(&rel) {
+                Some((_, known)) if *known != hash => {
+                    return Err(anyhow!("Listed with different digests: {}", path.display()));
+                }
+                _ => files.insert(rel, (size, hash)),
+            };
+        }
+    }
+    config.algorithm = algorithm.expect("at least one checksum file");
+    config.check_algorithm()?;
+
+    let mut digests: BTreeMap<PathBuf, [u8; 32]> = BTreeMap::new();
+    let mut bytes = Some(0);
+    for (rel, (size, hash)) in &files {
+        if rel.iter().any(|name| config.is_excluded(name)) {
+            continue;
+        }
+        let meta = EntryMeta {
+            is_file: true,
+            size: *size,
+            ..EntryMeta::default()
+        };
+        if size.is_none()
+            && (config.min_size.is_some()
+                || config.max_size.is_some()
+                || !config.include_empty_files)
+        {
+            return Err(anyhow!(
+                "{} lists no sizes to select files by",
+                lists[0].display()
+            ));
+        }
+        if config.skips_entry(rel, FileKind::File, &meta) {
+            continue;
+        }
+        let content = config.algorithm.decode(hash)?;
+        let mut hasher = config.hasher();
+        hasher.update(&entry_prefix(
+            config,
+            FileKind::File,
+            &hash_meta_at(config, &meta, rel)?,
+        ));
+        hasher.update(&content);
+        let digest = hasher.finalize();
+        config.record_detail(rel, false, &digest);
+        match size {
+            Some(size) => config.record_file(rel, rel, *size, &content),
+            None if config.record_files => {
+                return Err(anyhow!(
+                    "{} lists no sizes to record files with",
+                    lists[0].display()
+                ));
+            }
+            None => {}
+        }
+        bytes = bytes.zip(*size).map(|(a, b)| a + b);
+        digests.insert(rel.clone(), digest);
+    }
+
+    // the directories holding the files, deepest first
+    let dirs: BTreeSet<PathBuf> = digests
+        .keys()
+        .flat_map(|rel| rel.ancestors().skip(1).map(Path::to_path_buf))
+        .collect();
+    if let Some(dir) = dirs.iter().find(|dir| digests.contains_key(*dir)) {
+        return Err(anyhow!(
+            "Listed as a file and holding files: {}",
+            root.join(dir).display()
+        ));
+    }
+    let mut dirs: Vec<PathBuf> = dirs.into_iter().collect();
+    dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+    let mut hash = None;
+    for dir in dirs {
+        let mut children: Vec<(&PathBuf, &[u8; 32])> = digests
+            .iter()
+            .filter(|(rel, _)| rel.parent() == Some(dir.as_path()))
+            .collect();
+        children.sort_by(|a, b| {
+            let name = |rel: &Path| rel.file_name().unwrap_or_default().as_bytes().to_vec();
+            name(a.0).cmp(&name(b.0))
+        });
+        let children: Vec<[u8; 32]> = children.into_iter().map(|(_, digest)| *digest).collect();
+        let digest = dir_digest(config, &dir, &EntryMeta::default(), &children)?
+            .expect("directories holding files are never left out");
+        if dir.as_os_str().is_empty() {
+            hash = Some(digest);
+        } else {
+            config.record_detail(&dir, true, &digest);
+            digests.insert(dir, digest);
+        }
+    }
+    let hash = hash.ok_or_else(|| anyhow!("No files to import"))?;
+
+    let mut result = HashResultJson::from_part(config, root, &hash);
+    result.bytes = bytes;
+    result.detail = Detail::from_config(config);
+    result.files = FileRecord::from_config(config);
+    match output {
+        Some(path) => job::write_manifest(config, path, &result),
+        None => {
+            println!("{}", config.result_json(&result)?);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,1195 @@
+use anyhow::{Context, Result, anyhow};
+use rayon::ThreadPoolBuilder;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::{BorrowedFd, RawFd};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use crate::config::{Config, Detail, HashAlgorithm, HashResultJson, Traversal};
+use crate::error::ErrorInfo;
+use crate::events::EventStream;
+use crate::flags::parse_types;
+use crate::profile::Profiler;
+use crate::stats::{Phases, SharedStats, StatsSnapshot, WorkerStats};
+use crate::tree::{DiffKind, Difference, VirtualTree};
+use crate::vfs::Vfs;
+use crate::{archive, hash, remote, s3, snapshot, webdav};
+
+/// A flag shared between a job and its controller to pause or stop
+/// the job. Once cancelled, all runs using the token fail.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<TokenState>);
+
+#[derive(Debug, Default)]
+struct TokenState {
+    cancelled: AtomicBool,
+    paused: AtomicBool,
+    /// Held while changing either flag, for waiting on `changed`
+    lock: Mutex<()>,
+    changed: Condvar,
+}
+
+impl TokenState {
+    fn set(&self, flag: &AtomicBool, value: bool) {
+        let _lock = self.lock.lock().unwrap();
+        flag.store(value, Ordering::Relaxed);
+        self.changed.notify_all();
+    }
+}
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.set(&self.0.cancelled, true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Hold runs using the token at their next check until resumed
+    pub fn pause(&self) {
+        self.0.set(&self.0.paused, true);
+    }
+
+    pub fn resume(&self) {
+        self.0.set(&self.0.paused, false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.0.paused.load(Ordering::Relaxed)
+    }
+
+    /// Wait while the token is paused, and fail if it has been
+    /// cancelled
+    pub fn check(&self) -> Result<()> {
+        if self.is_paused() {
+            let mut lock = self.0.lock.lock().unwrap();
+            while self.is_paused() && !self.is_cancelled() {
+                lock = self.0.changed.wait(lock).unwrap();
+            }
+        }
+        if self.is_cancelled() {
+            Err(anyhow!("Cancelled"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Hash the tree at `path`. Besides local paths this accepts
+/// `s3://bucket/prefix` and WebDAV `http(s)://` URLs, and archives if
+/// `config.archive` is set.
+pub fn hash_path(config: &Config, path: &Path) -> Result<[u8; 32]> {
+    let digest = hash_tree(config, path);
+    if let Err(err) = &digest {
+        config.report_failure(path, err);
+    }
+    digest
+}
+
+fn hash_tree(config: &Config, path: &Path) -> Result<[u8; 32]> {
+    if let Some(url) = s3::S3Url::parse(path) {
+        s3::hash_url(config, &url)
+    } else if let Some(url) = webdav::DavUrl::parse(path) {
+        webdav::hash_url(config, &url)
+    } else if config.archive {
+        archive::hash_archive(config, path)
+    } else if config.traversal != Traversal::Dfs {
+        VirtualTree::from_dir_ordered(config, path, config.traversal)?.digest(config, path)
+    } else if config.scan_threads > 0 {
+        VirtualTree::from_dir_pipelined(config, path)?.digest(config, path)
+    } else {
+        hash::hash_entry(config, path)
+    }
+}
+
+/// Take an advisory lock on the local tree at `root`, so that runs on
+/// the same tree do not overlap. Fails if another run holds it, unless
+/// `config.wait_lock` is set. The lock is held until the file returned
+/// is dropped.
+pub fn lock_root(config: &Config, root: &Path) -> Result<Option<File>> {
+    if !config.lock
+        || config.archive
+        || s3::S3Url::parse(root).is_some()
+        || webdav::DavUrl::parse(root).is_some()
+    {
+        return Ok(None);
+    }
+    // trees that cannot be opened fail with their own error when hashed
+    let Ok(file) = File::open(root) else {
+        return Ok(None);
+    };
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(std::fs::TryLockError::WouldBlock) if config.wait_lock => {
+            eprintln!("Note: waiting for another run on {}", root.display());
+            file.lock().with_context(|| root.display().to_string())?;
+        }
+        Err(std::fs::TryLockError::WouldBlock) => {
+            return Err(anyhow!(
+                "Another run is hashing {} (see --wait-lock)",
+                root.display()
+            ));
+        }
+        Err(std::fs::TryLockError::Error(err)) => {
+            return Err(anyhow::Error::from(err).context(root.display().to_string()));
+        }
+    }
+    Ok(Some(file))
+}
+
+/// Lock the trees `config` hashes (see [`lock_root`])
+pub fn lock_target(config: &Config) -> Result<Vec<File>> {
+    if config.remote.is_some() {
+        return Ok(Vec::new());
+    }
+    let roots = if config.combine.is_empty() {
+        config.path.iter().cloned().collect()
+    } else {
+        config.combine.clone()
+    };
+    let mut locks = Vec::new();
+    for root in roots {
+        locks.extend(lock_root(config, &root)?);
+    }
+    Ok(locks)
+}
+
+/// Load a tree from a directory, archive, S3 prefix, WebDAV collection
+/// or remote host
+pub fn read_tree(config: &Config, spec: &Path) -> Result<VirtualTree> {
+    if let Some(url) = s3::S3Url::parse(spec) {
+        s3::read_url(config, &url)
+    } else if let Some(url) = webdav::DavUrl::parse(spec) {
+        webdav::read_url(config, &url)
+    } else if let Some(remote) = remote::parse_source(spec) {
+        remote::read_tree(config, &remote)
+    } else if spec.is_dir() {
+        VirtualTree::from_dir(config, spec)
+    } else {
+        archive::read_archive(config, spec)
+    }
+}
+
+/// Compare the trees at `left` and `right` entry by entry
+pub fn diff_trees(config: &Config, left: &Path, right: &Path) -> Result<Vec<Difference>> {
+    // account for the second root up front so progress does not
+    // finish after the first tree
+    config.stats.add_entries(1);
+    let left = read_tree(config, left)?;
+    let right = read_tree(config, right)?;
+    left.diff(config, &right)
+}
+
+/// Compare two snapshots of a filesystem, reading only the entries the
+/// filesystem lists as changed between them, or all entries if it
+/// cannot list them
+pub fn diff_snapshots(config: &Config, left: &Path, right: &Path) -> Result<Vec<Difference>> {
+    let changed = match snapshot::changed_paths(left, right) {
+        Ok(changed) => changed,
+        Err(err) => {
+            eprintln!("Note: {:#}, comparing all entries", err);
+            return diff_trees(config, left, right);
+        }
+    };
+    config.stats.add_entries(1);
+    let left = VirtualTree::from_dir_changed(config, left, &changed)?;
+    let right = VirtualTree::from_dir_changed(config, right, &changed)?;
+    left.diff(config, &right)
+}
+
+/// Hash the remote tree or path selected by `config`
+pub fn hash_target(config: &Config) -> Result<HashResultJson> {
+    if let Some(remote) = &config.remote {
+        return remote::hash_remote(config, remote);
+    }
+    if !config.combine.is_empty() {
+        return hash_combined(config, &config.combine);
+    }
+    if let Some(subtree) = &config.subtree {
+        return hash_subtree(config, subtree);
+    }
+    let path = config
+        .path
+        .clone()
+        .ok_or_else(|| anyhow!("No path to hash"))?;
+    let hash = hash_path(config, &path)?;
+    Ok(HashResultJson::from_result(config, path, &hash))
+}
+
+/// Hash each of `roots` and hash their digests, in order, into one.
+/// The name of the result lists the roots.
+pub fn hash_combined(config: &Config, roots: &[PathBuf]) -> Result<HashResultJson> {
+    // one entry per root is expected already
+    config
+        .stats
+        .add_entries(roots.len().saturating_sub(1) as u64);
+    let mut hasher = config.hasher();
+    let mut results = Vec::with_capacity(roots.len());
+    for root in roots {
+        let hash = hash_path(config, root)?;
+        hasher.update(&hash);
+        results.push(HashResultJson::from_part(config, root.clone(), &hash));
+    }
+    let name: Vec<String> = roots
+        .iter()
+        .map(|root| root.display().to_string())
+        .collect();
+    let mut result =
+        HashResultJson::from_result(config, name.join(" + ").into(), &hasher.finalize());
+    result.roots = Some(results);
+    Ok(result)
+}
+
+/// Hash the entry at `subtree` below the root as it is hashed as part
+/// of the tree, recording per-entry digests by their paths relative to
+/// the root
+pub fn hash_subtree(config: &Config, subtree: &Path) -> Result<HashResultJson> {
+    let root = config
+        .path
+        .clone()
+        .ok_or_else(|| anyhow!("No path to hash"))?;
+    if config.archive || s3::S3Url::parse(&root).is_some() || webdav::DavUrl::parse(&root).is_some()
+    {
+        return Err(anyhow!("Subtrees can only be verified in local trees"));
+    }
+    let rel = subtree_rel(subtree)?;
+    let path = root.join(&rel);
+    let hash = hash::hash_entry_at(config, &path, &rel)?;
+    let mut result = HashResultJson::from_result(config, path, &hash);
+    // recorded as an entry, but the root of the result
+    if let Some(detail) = &mut result.detail {
+        detail.entries.remove(rel.to_string_lossy().as_ref());
+    }
+    Ok(result)
+}
+
+/// `subtree` as a path relative to the root of a manifest
+fn subtree_rel(subtree: &Path) -> Result<PathBuf> {
+    let rel: PathBuf = subtree
+        .components()
+        .filter(|c| *c != Component::CurDir)
+        .collect();
+    if rel.as_os_str().is_empty() || rel.components().any(|c| !matches!(c, Component::Normal(_))) {
+        return Err(anyhow!(
+            "Not an entry below the root: {}",
+            subtree.display()
+        ));
+    }
+    Ok(rel)
+}
+
+/// The part of `manifest` recording the entry at `subtree` below its
+/// root, to verify a result of [`hash_subtree`] against
+pub fn subtree_reference(manifest: &HashResultJson, subtree: &Path) -> Result<HashResultJson> {
+    let rel = subtree_rel(subtree)?;
+    let key = rel.to_string_lossy();
+    let detail = manifest.detail.as_ref();
+    let hash = detail
+        .and_then(|detail| detail.entries.get(key.as_ref()))
+        .ok_or_else(|| {
+            anyhow!(
+                "The manifest records no digest of {}, hash with --detail {}",
+                key,
+                rel.components().count()
+            )
+        })?;
+    let detail = detail.map(|detail| Detail {
+        depth: detail.depth,
+        dirs: detail.dirs,
+        entries: detail
+            .entries
+            .iter()
+            .filter(|(entry, _)| Path::new(entry).starts_with(&rel) && **entry != key)
+            .map(|(entry, digest)| (entry.clone(), digest.clone()))
+            .collect(),
+    });
+    Ok(HashResultJson {
+        schema: manifest.schema,
+        name: manifest.name.join(&rel),
+        hash: hash.clone(),
+        flags: manifest.flags.clone(),
+        entries: None,
+        bytes: None,
+        elapsed_seconds: None,
+        fips: manifest.fips,
+        provenance: None,
+        detail,
+        phases: None,
+        workers: None,
+        roots: None,
+        files: None,
+        chunking: None,
+        skipped: None,
+        errors: Vec::new(),
+    })
+}
+
+/// The digest of the digests of `entries` in order, standing in for
+/// the hash of a tree when only these entries are verified
+fn selection_digest(config: &Config, entries: &BTreeMap<String, String>) -> Result<[u8; 32]> {
+    let mut hasher = config.hasher();
+    for digest in entries.values() {
+        hasher.update(&config.algorithm.decode(digest)?);
+    }
+    Ok(hasher.finalize())
+}
+
+/// The part of `manifest` recording the entries selected by
+/// `config.only`, to verify a result of [`hash_recorded`] against
+pub fn selected_reference(config: &Config, manifest: &HashResultJson) -> Result<HashResultJson> {
+    let detail = manifest.detail.as_ref().ok_or_else(|| {
+        anyhow!("The manifest records no digests of its entries, hash with --detail")
+    })?;
+    let entries: BTreeMap<String, String> = detail
+        .entries
+        .iter()
+        .filter(|(entry, _)| config.is_selected(Path::new(entry)))
+        .map(|(entry, digest)| (entry.clone(), digest.clone()))
+        .collect();
+    if entries.is_empty() {
+        return Err(anyhow!("The manifest records no entries matching --only"));
+    }
+    Ok(HashResultJson {
+        schema: manifest.schema,
+        name: manifest.name.clone(),
+        hash: config
+            .algorithm
+            .encode(&selection_digest(config, &entries)?),
+        flags: manifest.flags.clone(),
+        entries: None,
+        bytes: None,
+        elapsed_seconds: None,
+        fips: manifest.fips,
+        provenance: None,
+        detail: Some(Detail {
+            depth: detail.depth,
+            dirs: detail.dirs,
+            entries,
+        }),
+        phases: None,
+        workers: None,
+        roots: None,
+        files: None,
+        chunking: None,
+        skipped: None,
+        errors: Vec::new(),
+    })
+}
+
+/// Hash the entries recorded in `reference` below the local root.
+/// Entries that no longer exist or are left out of the hash are left
+/// out of the result.
+pub fn hash_recorded(config: &Config, reference: &HashResultJson) -> Result<HashResultJson> {
+    let root = config
+        .path
+        .clone()
+        .ok_or_else(|| anyhow!("Entries can only be verified in local trees"))?;
+    if config.archive || s3::S3Url::parse(&root).is_some() || webdav::DavUrl::parse(&root).is_some()
+    {
+        return Err(anyhow!("Entries can only be verified in local trees"));
+    }
+    let recorded: Vec<&String> = reference
+        .detail
+        .as_ref()
+        .map(|detail| detail.entries.keys().collect())
+        .unwrap_or_default();
+    // the entries are counted as they are hashed
+    config
+        .stats
+        .add_entries(recorded.len().saturating_sub(1) as u64);
+    let mut entries = BTreeMap::new();
+    for rel in recorded {
+        let path = root.join(rel);
+        if let Err(err) = config.vfs.symlink_metadata(&path) {
+            match err.chain().find_map(|e| e.downcast_ref::<io::Error>()) {
+                Some(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory
+                    ) =>
+                {
+                    config.stats.done_entries(1);
+                    continue;
+                }
+                _ => return Err(err.context(path.display().to_string())),
+            }
+        }
+        if let Some(digest) = hash::hash_child(config, &path, Path::new(rel))? {
+            entries.insert(rel.clone(), config.algorithm.encode(&digest));
+        }
+    }
+    let hash = selection_digest(config, &entries)?;
+    let mut result = HashResultJson::from_result(config, root, &hash);
+    result.detail = reference.detail.as_ref().map(|detail| Detail {
+        depth: detail.depth,
+        dirs: detail.dirs,
+        entries,
+    });
+    Ok(result)
+}
+
+/// Read a manifest from `path`, or from stdin if `path` is `-`
+pub fn read_manifest(path: &str) -> Result<HashResultJson> {
+    let mut reader: Box<dyn Read> = if path == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(path)?)
+    };
+    let mut json = String::new();
+    reader.read_to_string(&mut json)?;
+    HashResultJson::parse(&json).with_context(|| path.to_string())
+}
+
+/// Write `paths` to `file` one per line, or separated by NUL bytes if
+/// `nul` is set, as read by `rsync --files-from` (with `--from0`).
+/// Repeated paths are written once.
+pub fn write_file_list<'a>(
+    file: &Path,
+    paths: impl IntoIterator<Item = &'a Path>,
+    nul: bool,
+) -> Result<()> {
+    std::fs::write(file, file_list(paths, nul))
+        .with_context(|| format!("Failed to write {}", file.display()))
+}
+
+/// The bytes of a list of `paths` as written by [`write_file_list`]
+pub fn file_list<'a>(paths: impl IntoIterator<Item = &'a Path>, nul: bool) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut last = None;
+    for path in paths {
+        let path = path.strip_prefix(".").unwrap_or(path);
+        let path = if path.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            path
+        };
+        // an entry may differ in several ways
+        if last == Some(path) {
+            continue;
+        }
+        last = Some(path);
+        out.extend_from_slice(path.as_os_str().as_encoded_bytes());
+        out.push(if nul { b'\0' } else { b'\n' });
+    }
+    out
+}
+
+/// A duplicate of the file descriptor `fd` the process was started
+/// with, e.g. given as `3>file` in the shell. The original stays open
+/// for the rest of the process, as for stdout and stderr.
+pub fn open_fd(fd: RawFd) -> Result<File> {
+    // SAFETY: fcntl only checks that fd is open
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+        let err = io::Error::last_os_error();
+        return Err(err).with_context(|| format!("File descriptor {}", fd));
+    }
+    // SAFETY: fd is open and never closed by this process
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) }
+        .try_clone_to_owned()
+        .with_context(|| format!("File descriptor {}", fd))?;
+    Ok(File::from(fd))
+}
+
+/// Replace the manifest at `path` with `result`
+pub fn write_manifest(config: &Config, path: &Path, result: &HashResultJson) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let json = config.result_json(result)? + "\n";
+    std::fs::write(&tmp, json).with_context(|| format!("Failed to write {}", path.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+/// Refuse to overwrite the existing file `path` unless `config.force`
+/// is set
+pub fn check_output(config: &Config, path: &Path) -> Result<()> {
+    if !config.force && path.exists() {
+        return Err(anyhow!(
+            "{} exists (use --force to overwrite it)",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Adopt the flags of a manifest and, unless a target is set already,
+/// the tree it was made from
+pub fn apply_manifest(config: &mut Config, manifest: &HashResultJson) -> Result<()> {
+    config
+        .set_flags_from_string(&manifest.flags)
+        .with_context(|| format!("Manifest for {}", manifest.name.display()))?;
+    let conflicts = config.explicit_flag_conflicts();
+    if !conflicts.is_empty() {
+        let list = conflicts.join("\n  ");
+        if !config.force_flags {
+            return Err(anyhow!(
+                "Flags given on the command line conflict with the manifest \
+                 (use --force-flags to verify with the manifest's flags):\n  {}",
+                list
+            ));
+        }
+        eprintln!("Warning: using the manifest's flags over:\n  {}", list);
+    }
+    config.fips |= manifest.fips;
+    config.check_algorithm()?;
+    if let Some(detail) = &manifest.detail {
+        config.detail_depth = config.detail_depth.max(detail.depth);
+        config.detail_dirs |= detail.dirs;
+    }
+    config.record_files |= manifest.files.is_some();
+    config.skip_unreadable |= manifest.skipped.is_some();
+    if let Some(roots) = &manifest.roots {
+        if config.path.is_some() || config.remote.is_some() {
+            return Err(anyhow!(
+                "The manifest combines several roots, give them with --combine"
+            ));
+        }
+        if config.combine.is_empty() {
+            config.combine = roots.iter().map(|root| root.name.clone()).collect();
+        }
+    } else if !config.combine.is_empty() {
+        return Err(anyhow!("The manifest does not combine several roots"));
+    } else if config.path.is_none() && config.remote.is_none() {
+        match remote::parse_source(&manifest.name) {
+            Some(remote) => config.remote = Some(remote),
+            None => config.path = Some(manifest.name.clone()),
+        }
+    }
+    Ok(())
+}
+
+/// The outcome of verifying a tree against a manifest
+#[derive(Debug)]
+pub struct Verification {
+    pub reference: HashResultJson,
+    pub result: HashResultJson,
+}
+
+impl Verification {
+    pub fn is_match(&self) -> bool {
+        self.reference.hash == self.result.hash
+    }
+
+    /// The deepest entries recorded in both results whose digests
+    /// differ, and the topmost entries recorded in only one of them
+    pub fn changed_entries(&self) -> Vec<Difference> {
+        changed_entries(&self.reference, &self.result)
+    }
+
+    /// The entries recorded in both results, with how they differ
+    pub fn compared_entries(&self) -> Vec<(&Path, Option<DiffKind>)> {
+        compared_entries(&self.reference, &self.result)
+    }
+
+    /// Summarize the verification with the given status
+    pub fn report(&self, status: &'static str) -> VerifyReport {
+        let compared = compared_entries(&self.reference, &self.result);
+        let differing = compared.iter().filter(|(_, kind)| kind.is_some()).count()
+            + usize::from(!self.is_match());
+        VerifyReport {
+            name: self.result.name.clone(),
+            status,
+            expected: self.reference.hash.clone(),
+            hash: self.result.hash.clone(),
+            flags: self.result.flags.clone(),
+            matched: compared.len() + 1 - differing,
+            mismatched: differing,
+            changed: self
+                .changed_entries()
+                .into_iter()
+                .map(|d| ChangedEntry {
+                    path: d.path,
+                    change: change_name(d.kind),
+                })
+                .collect(),
+            updated: Vec::new(),
+            entries: self.result.entries,
+            bytes: self.result.bytes,
+            elapsed_seconds: self.result.elapsed_seconds,
+            phases: self.result.phases,
+            workers: self.result.workers.clone(),
+            errors: self.result.errors.clone(),
+        }
+    }
+}
+
+/// Compare the per-entry digests of two results, descending as far as
+/// both recorded them. Changed entries with a changed descendant are
+/// left out, as are added or removed entries below one that was added
+/// or removed as a whole. Removed entries are reported as only in
+/// the left tree, added ones as only in the right.
+pub fn changed_entries(reference: &HashResultJson, result: &HashResultJson) -> Vec<Difference> {
+    let changed: Vec<(&Path, DiffKind)> = compared_entries(reference, result)
+        .into_iter()
+        .filter_map(|(path, kind)| Some((path, kind?)))
+        .collect();
+    changed
+        .iter()
+        .filter(|(path, kind)| {
+            !changed.iter().any(|(other, other_kind)| {
+                if *kind == DiffKind::Content {
+                    other != path && other.starts_with(path)
+                } else {
+                    other_kind == kind && other != path && path.starts_with(other)
+                }
+            })
+        })
+        .map(|(path, kind)| Difference {
+            path: path.to_path_buf(),
+            kind: *kind,
+        })
+        .collect()
+}
+
+/// The entries recorded in either result that can be compared, with
+/// how they differ, in order. The roots of combined results are
+/// compared by position.
+fn compared_entries<'a>(
+    reference: &'a HashResultJson,
+    result: &'a HashResultJson,
+) -> Vec<(&'a Path, Option<DiffKind>)> {
+    if let (Some(old), Some(new)) = (&reference.roots, &result.roots) {
+        let mut out: Vec<_> = old
+            .iter()
+            .zip(new)
+            .map(|(a, b)| {
+                (
+                    a.name.as_path(),
+                    (a.hash != b.hash).then_some(DiffKind::Content),
+                )
+            })
+            .collect();
+        out.extend(
+            old.iter()
+                .skip(new.len())
+                .map(|a| (a.name.as_path(), Some(DiffKind::OnlyLeft))),
+        );
+        out.extend(
+            new.iter()
+                .skip(old.len())
+                .map(|b| (b.name.as_path(), Some(DiffKind::OnlyRight))),
+        );
+        return out;
+    }
+    let (Some(old), Some(new)) = (&reference.detail, &result.detail) else {
+        return Vec::new();
+    };
+    let depth = old.depth.min(new.depth);
+    let dirs = old.dirs && new.dirs;
+    let within = |path: &String| dirs || Path::new(path).components().count() <= depth;
+
+    let paths: BTreeSet<&String> = old
+        .entries
+        .keys()
+        .chain(new.entries.keys())
+        .filter(|p| within(p))
+        .collect();
+    paths
+        .into_iter()
+        .map(|path| {
+            let kind = match (old.entries.get(path), new.entries.get(path)) {
+                (Some(a), Some(b)) if a == b => None,
+                (Some(_), Some(_)) => Some(DiffKind::Content),
+                (Some(_), None) => Some(DiffKind::OnlyLeft),
+                (None, _) => Some(DiffKind::OnlyRight),
+            };
+            (Path::new(path.as_str()), kind)
+        })
+        .collect()
+}
+
+/// How an entry changed between a manifest and the verified tree
+pub fn change_name(kind: DiffKind) -> &'static str {
+    match kind {
+        DiffKind::OnlyLeft => "removed",
+        DiffKind::OnlyRight => "added",
+        _ => "changed",
+    }
+}
+
+/// An entry listed in a [`VerifyReport`]
+#[derive(Debug, Serialize)]
+pub struct ChangedEntry {
+    pub path: PathBuf,
+    pub change: &'static str,
+}
+
+/// The outcome of verify mode in machine-readable form
+#[derive(Debug, Serialize)]
+pub struct VerifyReport {
+    pub name: PathBuf,
+    /// "ok", "mismatch" or "updated"
+    pub status: &'static str,
+    pub expected: String,
+    pub hash: String,
+    pub flags: String,
+    /// Compared entries, the root and those recorded with --detail,
+    /// whose digests match or differ
+    pub matched: usize,
+    pub mismatched: usize,
+    /// The entries the mismatch was narrowed down to
+    pub changed: Vec<ChangedEntry>,
+    /// The changed entries accepted into the manifest with --update
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub updated: Vec<ChangedEntry>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entries: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub elapsed_seconds: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phases: Option<Phases>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub workers: Option<Vec<WorkerStats>>,
+    /// Why the entries skipped in the verified tree could not be read
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<ErrorInfo>,
+}
+
+type ProgressCallback = Box<dyn Fn(&StatsSnapshot) + Send + Sync>;
+
+/// A configured hashing job. Each call to [`HashJob::run`],
+/// [`HashJob::verify`] or [`HashJob::diff`] uses its own thread pool
+/// and statistics.
+pub struct HashJob {
+    config: Config,
+    progress: Option<ProgressCallback>,
+    progress_interval: Duration,
+}
+
+impl HashJob {
+    pub fn builder() -> HashJobBuilder {
+        HashJobBuilder::default()
+    }
+
+    /// A token to cancel running and future runs of this job
+    pub fn cancel_token(&self) -> CancelToken {
+        self.config.cancel.clone()
+    }
+
+    /// The settings the job runs with
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Hash the tree at `path`
+    pub fn run(&self, path: impl AsRef<Path>) -> Result<HashResultJson> {
+        let mut config = self.config.clone();
+        config.path = Some(path.as_ref().to_path_buf());
+        self.execute(config, hash_target)
+    }
+
+    /// Hash the tree at `path` with the flags of `reference` and
+    /// compare the result. If `path` is `None`, the tree named in the
+    /// manifest is hashed.
+    pub fn verify(&self, reference: HashResultJson, path: Option<&Path>) -> Result<Verification> {
+        let mut config = self.config.clone();
+        config.path = path.map(Path::to_path_buf);
+        apply_manifest(&mut config, &reference)?;
+        let result = self.execute(config, hash_target)?;
+        Ok(Verification { reference, result })
+    }
+
+    /// Compare the trees at `left` and `right` entry by entry. Either
+    /// may be any source [`HashJob::run`] accepts.
+    pub fn diff(&self, left: impl AsRef<Path>, right: impl AsRef<Path>) -> Result<Vec<Difference>> {
+        let (left, right) = (left.as_ref(), right.as_ref());
+        self.execute(self.config.clone(), |config| {
+            diff_trees(config, left, right)
+        })
+    }
+
+    /// Start hashing the tree at `path` on tokio's blocking thread
+    /// pool. The returned handle can be awaited for the result.
+    /// Must be called from within a tokio runtime.
+    #[cfg(feature = "async")]
+    pub fn spawn(mut self, path: impl Into<std::path::PathBuf>) -> RunningJob {
+        let (sender, receiver) = tokio::sync::watch::channel(None);
+        let callback = self.progress.take();
+        self.progress = Some(Box::new(move |stats| {
+            if let Some(callback) = &callback {
+                callback(stats);
+            }
+            sender.send_replace(Some(*stats));
+        }));
+        let cancel = self.cancel_token();
+        let path = path.into();
+        RunningJob {
+            handle: tokio::task::spawn_blocking(move || self.run(path)),
+            progress: receiver,
+            cancel,
+        }
+    }
+
+    fn execute<T: Send>(
+        &self,
+        mut config: Config,
+        task: impl FnOnce(&Config) -> Result<T> + Send,
+    ) -> Result<T> {
+        config.stats = Arc::new(SharedStats::new());
+        config.detail_digests = Arc::default();
+        config.file_digests = Arc::default();
+        config.file_chunks = Arc::default();
+        config.skipped = Arc::default();
+        if let Some(limit) = &config.bwlimit {
+            config.set_bwlimit(limit.rate());
+        }
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(config.threads)
+            .build()?;
+
+        let Some(progress) = &self.progress else {
+            return pool.install(|| task(&config));
+        };
+        let finished = AtomicBool::new(false);
+        let result = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                while !finished.load(Ordering::Relaxed) {
+                    std::thread::sleep(self.progress_interval);
+                    progress(&config.stats.snapshot());
+                }
+            });
+            let result = pool.install(|| task(&config));
+            finished.store(true, Ordering::Relaxed);
+            result
+        });
+        progress(&config.stats.snapshot());
+        result
+    }
+}
+
+/// A job started with [`HashJob::spawn`]. Resolves to the result of
+/// the job; dropping it does not stop the job, cancel it for that.
+#[cfg(feature = "async")]
+pub struct RunningJob {
+    handle: tokio::task::JoinHandle<Result<HashResultJson>>,
+    progress: tokio::sync::watch::Receiver<Option<StatsSnapshot>>,
+    cancel: CancelToken,
+}
+
+#[cfg(feature = "async")]
+impl RunningJob {
+    /// Statistics as reported at the progress interval. The stream
+    /// skips updates that are not consumed in time and ends with the
+    /// job.
+    pub fn progress(&self) -> impl tokio_stream::Stream<Item = StatsSnapshot> + use<> {
+        use tokio_stream::StreamExt;
+        tokio_stream::wrappers::WatchStream::new(self.progress.clone()).filter_map(|stats| stats)
+    }
+
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+}
+
+#[cfg(feature = "async")]
+impl std::future::Future for RunningJob {
+    type Output = Result<HashResultJson>;
+
+    fn poll(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        std::pin::Pin::new(&mut self.handle)
+            .poll(cx)
+            .map(|joined| joined.map_err(|e| anyhow!("Job failed: {}", e))?)
+    }
+}
+
+/// Builder for [`HashJob`]. Defaults match the command line defaults.
+pub struct HashJobBuilder {
+    config: Config,
+    flags: Option<String>,
+    exclude: Vec<String>,
+    types: Option<String>,
+    progress: Option<ProgressCallback>,
+    progress_interval: Duration,
+}
+
+impl Default for HashJobBuilder {
+    fn default() -> Self {
+        Self {
+            config: Config::default(),
+            flags: None,
+            exclude: Vec::new(),
+            types: None,
+            progress: None,
+            progress_interval: Duration::from_millis(200),
+        }
+    }
+}
+
+impl HashJobBuilder {
+    pub fn algorithm(mut self, algorithm: HashAlgorithm) -> Self {
+        self.config.algorithm = algorithm;
+        self
+    }
+
+    /// Set all included metadata and the algorithm from a flags string
+    /// such as `v1:sha256:cspugm`. Overrides the individual settings.
+    pub fn flags(mut self, flags: &str) -> Self {
+        self.flags = Some(flags.to_string());
+        self
+    }
+
+    /// Block size for reading files in bytes
+    pub fn block_size(mut self, bytes: usize) -> Self {
+        self.config.block_size = bytes;
+        self.config.auto_block_size = false;
+        self
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.config.threads = threads;
+        self
+    }
+
+    /// Limit the combined read rate to `rate` bytes per second
+    pub fn bwlimit(mut self, rate: u64) -> Self {
+        self.config.set_bwlimit(rate);
+        self
+    }
+
+    /// Read up to `blocks` blocks of each file ahead while hashing
+    pub fn prefetch(mut self, blocks: usize) -> Self {
+        self.config.prefetch = blocks;
+        self
+    }
+
+    /// Skip entries whose name matches the glob `pattern`
+    pub fn exclude(mut self, pattern: &str) -> Self {
+        self.exclude.push(pattern.to_string());
+        self
+    }
+
+    /// Order in which file contents are read
+    pub fn traversal(mut self, traversal: Traversal) -> Self {
+        self.config.traversal = traversal;
+        self
+    }
+
+    /// Whether entries whose name starts with a dot are included
+    pub fn hidden(mut self, include: bool) -> Self {
+        self.config.include_hidden = include;
+        self
+    }
+
+    /// Record the digests of entries down to `depth` in the result
+    pub fn detail(mut self, depth: usize) -> Self {
+        self.config.detail_depth = depth;
+        self
+    }
+
+    /// Record the digests of all directories in the result
+    pub fn detail_dirs(mut self, dirs: bool) -> Self {
+        self.config.detail_dirs = dirs;
+        self
+    }
+
+    /// Only allow FIPS approved algorithms
+    pub fn fips(mut self, fips: bool) -> Self {
+        self.config.fips = fips;
+        self
+    }
+
+    /// Treat paths as archives and hash their members
+    pub fn archive(mut self, archive: bool) -> Self {
+        self.config.archive = archive;
+        self
+    }
+
+    pub fn content(mut self, include: bool) -> Self {
+        self.config.include_file_content = include;
+        self
+    }
+
+    pub fn size(mut self, include: bool) -> Self {
+        self.config.include_size = include;
+        self
+    }
+
+    pub fn mode(mut self, include: bool) -> Self {
+        self.config.include_mode = include;
+        self
+    }
+
+    pub fn uid(mut self, include: bool) -> Self {
+        self.config.include_uid = include;
+        self
+    }
+
+    pub fn gid(mut self, include: bool) -> Self {
+        self.config.include_gid = include;
+        self
+    }
+
+    pub fn mtime(mut self, include: bool) -> Self {
+        self.config.include_mtime = include;
+        self
+    }
+
+    pub fn ctime(mut self, include: bool) -> Self {
+        self.config.include_ctime = include;
+        self
+    }
+
+    pub fn atime(mut self, include: bool) -> Self {
+        self.config.include_atime = include;
+        self
+    }
+
+    pub fn inode(mut self, include: bool) -> Self {
+        self.config.include_inode = include;
+        self
+    }
+
+    pub fn nlink(mut self, include: bool) -> Self {
+        self.config.include_nlink = include;
+        self
+    }
+
+    pub fn attributes(mut self, include: bool) -> Self {
+        self.config.include_attributes = include;
+        self
+    }
+
+    pub fn project(mut self, include: bool) -> Self {
+        self.config.include_project = include;
+        self
+    }
+
+    pub fn capabilities(mut self, include: bool) -> Self {
+        self.config.include_capabilities = include;
+        self
+    }
+
+    pub fn nfs4_acl(mut self, include: bool) -> Self {
+        self.config.include_nfs4_acl = include;
+        self
+    }
+
+    pub fn etag(mut self, include: bool) -> Self {
+        self.config.include_etag = include;
+        self
+    }
+
+    pub fn storage_class(mut self, include: bool) -> Self {
+        self.config.include_storage_class = include;
+        self
+    }
+
+    pub fn names(mut self, include: bool) -> Self {
+        self.config.include_names = include;
+        self
+    }
+
+    pub fn empty_dirs(mut self, include: bool) -> Self {
+        self.config.include_empty_dirs = include;
+        self
+    }
+
+    pub fn empty_files(mut self, include: bool) -> Self {
+        self.config.include_empty_files = include;
+        self
+    }
+
+    /// Leave out regular files smaller than `size` bytes
+    pub fn min_size(mut self, size: u64) -> Self {
+        self.config.min_size = Some(size);
+        self
+    }
+
+    /// Leave out regular files larger than `size` bytes
+    pub fn max_size(mut self, size: u64) -> Self {
+        self.config.max_size = Some(size);
+        self
+    }
+
+    /// Include only entries of the types given by letters as in
+    /// [`crate::flags::TYPE_NAMES`]
+    pub fn types(mut self, types: &str) -> Self {
+        self.types = Some(types.to_string());
+        self
+    }
+
+    /// Leave out entries other than directories last modified before
+    /// `time`, in seconds since the epoch
+    pub fn newer_than(mut self, time: i64) -> Self {
+        self.config.newer_than = Some(time);
+        self
+    }
+
+    /// Leave out entries other than directories last modified at or
+    /// after `time`, in seconds since the epoch
+    pub fn older_than(mut self, time: i64) -> Self {
+        self.config.older_than = Some(time);
+        self
+    }
+
+    /// Record the times spent on directories and large files in
+    /// `profiler`
+    pub fn profiler(mut self, profiler: Arc<Profiler>) -> Self {
+        self.config.profiler = Some(profiler);
+        self
+    }
+
+    /// Share `token` with the job, e.g. to cancel several jobs at once
+    pub fn cancel(mut self, token: CancelToken) -> Self {
+        self.config.cancel = token;
+        self
+    }
+
+    /// Write the events on single entries to `events`
+    pub fn events(mut self, events: Arc<EventStream>) -> Self {
+        self.config.events = Some(events);
+        self
+    }
+
+    /// Read local paths through `vfs` instead of the OS filesystem
+    pub fn vfs(mut self, vfs: impl Vfs + 'static) -> Self {
+        self.config.vfs = Arc::new(vfs);
+        self
+    }
+
+    /// Call `callback` with the current statistics periodically while
+    /// a job runs, and once more when it finishes
+    pub fn progress(mut self, callback: impl Fn(&StatsSnapshot) + Send + Sync + 'static) -> Self {
+        self.progress = Some(Box::new(callback));
+        self
+    }
+
+    pub fn progress_interval(mut self, interval: Duration) -> Self {
+        self.progress_interval = interval;
+        self
+    }
+
+    pub fn build(mut self) -> Result<HashJob> {
+        if let Some(flags) = &self.flags {
+            self.config.set_flags_from_string(flags)?;
+        }
+        self.config.add_excludes(&self.exclude)?;
+        if let Some(types) = &self.types {
+            self.config.types = parse_types(types)?;
+        }
+        self.config.check_algorithm()?;
+        if self.config.block_size == 0 || self.config.threads == 0 {
+            return Err(anyhow!("Block size and thread count must be positive"));
+        }
+        Ok(HashJob {
+            config: self.config,
+            progress: self.progress,
+            progress_interval: self.progress_interval,
+        })
+    }
+}
@@ -0,0 +1,59 @@
+//! Calculate checksums on files and directories recursively.
+//!
+//! The checksum of a tree is computed as a Merkle tree over file
+//! contents, file metadata and directory contents. Which metadata
+//! takes part is configurable and recorded in a flags string so that
+//! a result can later be verified with the same settings.
+//!
+//! ```no_run
+//! use fdsum::{HashAlgorithm, HashJob};
+//!
+//! let job = HashJob::builder()
+//!     .algorithm(HashAlgorithm::Blake3)
+//!     .mtime(false)
+//!     .progress(|stats| eprintln!("{}/{} bytes", stats.bytes_done, stats.bytes_total))
+//!     .build()?;
+//! let result = job.run("/data/set1")?;
+//! println!("{} {}", result.hash, result.flags);
+//!
+//! let verification = job.verify(result, None)?;
+//! assert!(verification.is_match());
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+pub mod accel;
+pub mod acl;
+pub mod affinity;
+pub mod algo;
+pub mod archive;
+pub mod blocksize;
+pub mod bwlimit;
+pub mod canonical;
+pub mod chunks;
+pub mod config;
+pub mod error;
+pub mod events;
+pub mod extent;
+pub mod flags;
+pub mod formats;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod hash;
+pub mod idmap;
+pub mod job;
+pub mod limits;
+pub mod profile;
+pub mod proof;
+pub mod remote;
+pub mod s3;
+pub mod snapshot;
+pub mod stats;
+pub mod tree;
+pub mod vfs;
+pub mod webdav;
+
+pub use config::{Config, Detail, HashAlgorithm, HashResultJson, Provenance};
+#[cfg(feature = "async")]
+pub use job::RunningJob;
+pub use job::{CancelToken, HashJob, HashJobBuilder, Verification};
+pub use stats::{Phases, StatsSnapshot, WorkerStats};
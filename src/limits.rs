@@ -0,0 +1,116 @@
+//! The share of the host a run may use. Inside a container the CPUs and
+//! memory of the host are not the allocation: the cgroup the process
+//! runs in limits its CPU time and memory, and more threads than the
+//! quota allows only get throttled.
+
+use std::fs;
+use std::num::NonZero;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The most threads run by default
+pub const MAX_DEFAULT_THREADS: usize = 8;
+
+/// The memory to allow for each thread: its read buffers, the blocks
+/// prefetched and its share of the tree
+pub const MEMORY_PER_THREAD: u64 = 32 * 1024 * 1024;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// The number of threads to run: at most [`MAX_DEFAULT_THREADS`], the
+/// CPUs available, the CPU quota of the cgroup, and as many as the
+/// memory left in the cgroup holds
+pub fn default_threads() -> usize {
+    let mut threads = std::thread::available_parallelism()
+        .map_or(1, NonZero::get)
+        .min(MAX_DEFAULT_THREADS);
+    if let Some(cpus) = cpu_quota() {
+        threads = threads.min(cpus);
+    }
+    if let Some(memory) = memory_available() {
+        threads = threads.min(usize::try_from(memory / MEMORY_PER_THREAD).unwrap_or(usize::MAX));
+    }
+    threads.max(1)
+}
+
+/// The CPUs the cgroup may use, rounded up, or `None` if unlimited
+pub fn cpu_quota() -> Option<usize> {
+    cgroup_dirs("cpu")
+        .iter()
+        .filter_map(|dir| {
+            // cgroup v2: "max 100000" or "<quota> <period>"
+            if let Ok(max) = fs::read_to_string(dir.join("cpu.max")) {
+                let (quota, period) = max.trim().split_once(' ')?;
+                return cpus(quota.parse().ok()?, period.parse().ok()?);
+            }
+            // cgroup v1: a quota of -1 is unlimited
+            cpus(
+                read_number(&dir.join("cpu.cfs_quota_us"))?,
+                read_number(&dir.join("cpu.cfs_period_us"))?,
+            )
+        })
+        .min()
+}
+
+fn cpus(quota: i64, period: i64) -> Option<usize> {
+    if quota <= 0 || period <= 0 {
+        return None;
+    }
+    usize::try_from((quota as u64).div_ceil(period as u64)).ok()
+}
+
+/// The memory the cgroup may still allocate, or `None` if unlimited
+pub fn memory_available() -> Option<u64> {
+    cgroup_dirs("memory")
+        .iter()
+        .filter_map(|dir| {
+            let (limit, usage) = if dir.join("memory.max").exists() {
+                ("memory.max", "memory.current")
+            } else {
+                ("memory.limit_in_bytes", "memory.usage_in_bytes")
+            };
+            // "max" on cgroup v2, a number near 2^63 on v1
+            let limit: u64 = read_number(&dir.join(limit))?;
+            let usage: u64 = read_number(&dir.join(usage)).unwrap_or(0);
+            Some(limit.saturating_sub(usage))
+        })
+        .min()
+}
+
+fn read_number<T: FromStr>(path: &Path) -> Option<T> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// The directories of the cgroups of this process holding the files of
+/// `controller`, from its own up to the root of the hierarchy, each of
+/// which may limit it
+fn cgroup_dirs(controller: &str) -> Vec<PathBuf> {
+    let Ok(list) = fs::read_to_string("/proc/self/cgroup") else {
+        return Vec::new();
+    };
+    let mut dirs = Vec::new();
+    for line in list.lines() {
+        // "<id>:<controllers>:<path>", with no controllers on cgroup v2
+        let mut parts = line.splitn(3, ':');
+        let (Some(_), Some(controllers), Some(path)) = (parts.next(), parts.next(), parts.next())
+        else {
+            continue;
+        };
+        let mount = if controllers.is_empty() {
+            PathBuf::from(CGROUP_ROOT)
+        } else if controllers.split(',').any(|c| c == controller) {
+            Path::new(CGROUP_ROOT).join(controller)
+        } else {
+            continue;
+        };
+        // with a cgroup namespace, the own cgroup is mounted as the root
+        let own = mount.join(path.trim_start_matches('/'));
+        let own = if own.is_dir() { own } else { mount.clone() };
+        dirs.extend(
+            own.ancestors()
+                .take_while(|dir| dir.starts_with(&mount))
+                .map(Path::to_path_buf),
+        );
+    }
+    dirs
+}
@@ -1,78 +1,497 @@
-use anyhow::{Result, anyhow};
-use clap::Parser;
+use anyhow::{Context, Result, anyhow};
+use clap::{CommandFactory, ValueEnum};
 use rayon::ThreadPoolBuilder;
-use std::fs::File;
-use std::io::{self, Read};
-use std::{io::IsTerminal, process::ExitCode};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::{IsTerminal, Write};
+use std::path::Path;
+use std::process::ExitCode;
 
-mod algo;
-mod config;
-mod hash;
-mod stats;
+mod audit;
+mod color;
+mod control;
+mod delta;
+mod diff;
+mod export;
+mod history;
+mod hooks;
+mod image;
+mod import;
+mod merge;
+mod notify;
+mod prove;
+mod prune;
+mod review;
+mod scrub;
+mod selftest;
+mod serve;
+mod shard;
+mod similarity;
+mod split;
+mod syslog;
+mod vectors;
+mod verify_all;
+mod xfer_verify;
 
-use config::HashResultJson;
+use fdsum::accel::Backends;
+use fdsum::formats::{self, OutputFormat};
+use fdsum::stats::WorkerStats;
+use fdsum::tree::{DiffKind, Difference};
+use fdsum::{config, flags, job};
 
 fn main() -> ExitCode {
-    match run() {
+    let mut history = history::Run::start();
+    let outcome = run(&mut history);
+    let logged = history.finish(&outcome);
+    match outcome.and(logged) {
         Ok(()) => ExitCode::SUCCESS,
         Err(err) => {
-            eprintln!("Error: {}", err);
+            eprintln!("{} {:#}", color::mismatch("Error:").for_stderr(), err);
             ExitCode::FAILURE
         }
     }
 }
 
-fn run() -> Result<()> {
-    let args = config::Args::parse();
+#[derive(Serialize)]
+struct VersionInfo {
+    name: &'static str,
+    version: &'static str,
+    backends: Backends,
+    /// Versions of the result document and of the framing of entries
+    schema: u32,
+    framing: FramingInfo,
+    algorithms: Vec<AlgorithmInfo>,
+    /// The letters of the flags string and of entry types, by name
+    flags: BTreeMap<&'static str, char>,
+    types: BTreeMap<&'static str, char>,
+    output_formats: Vec<String>,
+    export_formats: Vec<String>,
+    commands: Vec<String>,
+    /// Optional features compiled in
+    features: Vec<&'static str>,
+}
+
+#[derive(Serialize)]
+struct FramingInfo {
+    default: u32,
+    latest: u32,
+}
+
+#[derive(Serialize)]
+struct AlgorithmInfo {
+    name: String,
+    fips_approved: bool,
+}
+
+/// The names of the values of `T` as given on the command line
+fn value_names<T: ValueEnum>() -> Vec<String> {
+    T::value_variants()
+        .iter()
+        .filter_map(|value| Some(value.to_possible_value()?.get_name().to_string()))
+        .collect()
+}
+
+fn print_version(json: bool) -> Result<()> {
+    let version = env!("CARGO_PKG_VERSION");
+    if json {
+        let info = VersionInfo {
+            name: "fdsum",
+            version,
+            backends: Backends::detect(),
+            schema: config::SCHEMA_VERSION,
+            framing: FramingInfo {
+                default: flags::FRAMING_VERSION,
+                latest: flags::LATEST_FRAMING,
+            },
+            algorithms: config::HashAlgorithm::value_variants()
+                .iter()
+                .map(|algorithm| AlgorithmInfo {
+                    name: algorithm.to_string(),
+                    fips_approved: algorithm.is_fips_approved(),
+                })
+                .collect(),
+            flags: flags::FLAG_NAMES
+                .iter()
+                .map(|&(c, name)| (name, c))
+                .collect(),
+            types: flags::TYPE_NAMES
+                .iter()
+                .map(|&(c, name)| (name, c))
+                .collect(),
+            output_formats: value_names::<OutputFormat>(),
+            export_formats: value_names::<formats::ExportFormat>(),
+            commands: config::Args::command()
+                .get_subcommands()
+                .map(|command| command.get_name().to_string())
+                .collect(),
+            features: [
+                ("async", cfg!(feature = "async")),
+                ("grpc", cfg!(feature = "grpc")),
+            ]
+            .into_iter()
+            .filter_map(|(feature, enabled)| enabled.then_some(feature))
+            .collect(),
+        };
+        println!("{}", serde_json::to_string_pretty(&info)?);
+    } else {
+        println!("fdsum {}", version);
+    }
+    Ok(())
+}
+
+/// Print the statistics of each thread to stderr
+fn print_workers(workers: &[WorkerStats]) {
+    eprintln!(
+        "{:>8} {:>10} {:>14} {:>8} {:>8} {:>8} {:>8}",
+        "worker", "entries", "bytes", "stat s", "read s", "hash s", "idle s"
+    );
+    for w in workers {
+        eprintln!(
+            "{:>8} {:>10} {:>14} {:>8.2} {:>8.2} {:>8.2} {:>8.2}",
+            w.worker,
+            w.entries,
+            w.bytes,
+            w.stat_seconds,
+            w.read_seconds,
+            w.hash_seconds,
+            w.idle_seconds
+        );
+    }
+}
+
+/// Print the entries of a mismatching tree that differ from the
+/// manifest, or all compared entries with --verbose
+fn print_mismatch(
+    config: &config::Config,
+    verification: &job::Verification,
+    changed: &[Difference],
+) {
+    let (reference, result) = (&verification.reference, &verification.result);
+    println!("{}: {}", result.name.display(), color::mismatch("Mismatch"));
+    if reference.detail.is_none() && reference.roots.is_none() {
+        eprintln!(
+            "Note: {} records no intermediate digests, hash with --detail-dirs \
+             to locate changes",
+            config.verify.as_deref().unwrap_or("the manifest")
+        );
+    }
+    if config.only_mismatches {
+        for entry in changed {
+            println!(
+                "  {}: {}",
+                entry.path.display(),
+                color::change(entry.kind, job::change_name(entry.kind))
+            );
+        }
+    } else {
+        for (path, kind) in verification.compared_entries() {
+            match kind {
+                Some(kind) => println!(
+                    "  {}: {}",
+                    path.display(),
+                    color::change(kind, job::change_name(kind))
+                ),
+                None => println!("  {}: {}", path.display(), color::ok("Ok")),
+            }
+        }
+    }
+}
+
+/// Write the paths to copy from the reference to repair the checked
+/// tree to the file and file descriptor given for them. Entries that
+/// exist only in the checked tree are left out.
+pub fn write_changed_files(config: &config::Config, differences: &[Difference]) -> Result<()> {
+    let paths: Vec<&Path> = differences
+        .iter()
+        .filter(|d| d.kind != DiffKind::OnlyRight)
+        .map(|d| d.path.as_path())
+        .collect();
+    if let Some(file) = &config.changed_files {
+        job::write_file_list(file, paths.iter().copied(), config.changed_files_nul)?;
+    }
+    if let Some(out) = &config.print0_changed {
+        (&**out)
+            .write_all(&job::file_list(paths, true))
+            .context("Failed to write the paths that differ (--print0-changed)")?;
+    }
+    Ok(())
+}
+
+fn run(history: &mut history::Run) -> Result<()> {
+    let args = config::Args::parse_noting_env();
+    color::init(args.color);
+    if args.version {
+        return print_version(args.json);
+    }
     let mut config = config::Config::try_from(args)?;
+    history.history = config.history.clone();
+    history.syslog = config.syslog;
+    history.notify_url = config.notify_url.clone();
+    history.notify_on = config.notify_on;
+    history.on_complete = config.on_complete.clone();
+    history.on_mismatch = config.on_mismatch.clone();
 
-    if config.verbose {
-        todo!("verbose mode not implemented");
+    if let Some(config::Command::Completions { shell }) = config.command {
+        let mut command = config::Args::command();
+        clap_complete::generate(shell, &mut command, "fdsum", &mut std::io::stdout());
+        return Ok(());
     }
 
+    // before any threads are started, so that all inherit it
+    if let Some(cpus) = &config.cpus {
+        fdsum::affinity::pin(cpus)?;
+    }
     ThreadPoolBuilder::new()
         .num_threads(config.threads)
         .build_global()?;
 
-    if std::io::stdout().is_terminal() {
-        config.stats.clone().spawn_display_thread();
+    if let Some(config::Command::Selftest) = config.command {
+        return selftest::run();
+    }
+    if let Some(config::Command::Vectors { dir }) = &config.command {
+        return vectors::run(&config, dir.as_deref());
     }
 
-    let reference: Option<HashResultJson> = match config.verify.as_deref() {
-        Some(verify) => {
-            let reader: Box<dyn Read> = if verify == "-" {
-                Box::new(io::stdin())
-            } else {
-                Box::new(File::open(verify)?)
-            };
+    if config.control {
+        control::spawn(&config);
+    }
 
-            let json: HashResultJson = serde_json::from_reader(reader)?;
-            config.set_flags_from_string(&json.flags)?;
+    let display = std::io::stdout().is_terminal().then(|| {
+        config
+            .stats
+            .clone()
+            .spawn_display_thread(&config.progress_templates())
+    });
 
-            if config.path.is_none() {
-                config.path = Some(json.name.clone());
+    if let Some(command) = config.command.take() {
+        return match command {
+            config::Command::Diff {
+                left,
+                right,
+                snapshots,
+            } => diff::run(&config, &left, &right, snapshots),
+            config::Command::Similarity { left, right } => {
+                similarity::run(&mut config, &left, &right)
+            }
+            config::Command::Image { image } => image::run(&config, &image),
+            config::Command::Merge { manifests, output } => {
+                merge::run(&mut config, &manifests, output.as_deref())
+            }
+            config::Command::Shard {
+                path,
+                workers,
+                depth,
+                output,
+            } => shard::run(&config, &path, &workers, depth, output.as_deref()),
+            config::Command::Prune { manifest, root } => {
+                prune::run(&mut config, &manifest, root.as_deref())
             }
+            config::Command::Split {
+                manifest,
+                by_depth,
+                output,
+            } => split::run(&mut config, &manifest, by_depth, output.as_deref()),
+            config::Command::Export {
+                manifest,
+                to,
+                output,
+            } => export::run(&mut config, &manifest, to, output.as_deref()),
+            config::Command::Delta { old, new, output } => {
+                delta::run(&config, &old, &new, output.as_deref())
+            }
+            config::Command::Apply { old, delta, output } => {
+                delta::apply(&config, &old, &delta, output.as_deref())
+            }
+            config::Command::Import {
+                lists,
+                root,
+                output,
+            } => import::run(&mut config, &lists, root.as_deref(), output.as_deref()),
+            config::Command::VerifyAll {
+                manifests,
+                roots_from,
+            } => verify_all::run(&config, &manifests, roots_from.as_deref()),
+            config::Command::XferVerify { source, dest } => {
+                xfer_verify::run(&config, &source, &dest)
+            }
+            config::Command::Scrub {
+                roots,
+                state,
+                period,
+                max_time,
+                max_bytes,
+                accept,
+            } => {
+                let options = scrub::Options {
+                    period,
+                    max_seconds: max_time,
+                    max_bytes,
+                    accept,
+                };
+                scrub::run(&config, &roots, &state, &options)
+            }
+            config::Command::Prove { manifest, entry } => {
+                prove::run(&mut config, &manifest, &entry)
+            }
+            config::Command::Serve { grpc } => serve::run(&config, grpc),
+            config::Command::Selftest
+            | config::Command::Vectors { .. }
+            | config::Command::Completions { .. } => {
+                unreachable!("handled above")
+            }
+        };
+    }
+
+    if let Some(verify) = config.verify.clone()
+        && formats::is_hashdeep(Path::new(&verify))?
+    {
+        return audit::run(&mut config, &verify);
+    }
 
-            Some(json)
+    let reference = match config.verify.as_deref() {
+        Some(verify) => {
+            let json = job::read_manifest(verify)?;
+            job::apply_manifest(&mut config, &json)?;
+            if let Some(subtree) = &config.subtree {
+                Some(job::subtree_reference(&json, subtree)?)
+            } else if !config.only.is_empty() {
+                Some(job::selected_reference(&config, &json)?)
+            } else {
+                Some(json)
+            }
         }
         None => None,
     };
 
-    let hash = hash::hash_entry(&config, &config.path.clone().unwrap())?;
-    let result = HashResultJson::from_result(&config, &hash);
+    if config.format != OutputFormat::Json && reference.is_none() {
+        let path = match (&config.path, &config.remote) {
+            (Some(path), None) if !config.archive => path.clone(),
+            _ => return Err(anyhow!("Per-file listings can only be made of local paths")),
+        };
+        let files = formats::list_files(&config, &path)?;
+        let mut out = std::io::stdout().lock();
+        return match config.format {
+            OutputFormat::Hashdeep => formats::write_hashdeep(&config, &files, &mut out),
+            OutputFormat::Bsd => formats::write_bsd(&config, &files, &mut out),
+            OutputFormat::Json => unreachable!("handled below"),
+        };
+    }
+
+    // held until the manifest is updated
+    let _locks = job::lock_target(&config)?;
+    let result = match reference.as_ref().filter(|_| !config.only.is_empty()) {
+        Some(reference) => job::hash_recorded(&config, reference)?,
+        None => job::hash_target(&config)?,
+    };
+    if let (Some(profiler), Some(file)) = (&config.profiler, &config.profile) {
+        profiler.write(&result.name.display().to_string(), file)?;
+    }
+    if let Some(file) = &config.append_results {
+        history::append(file, &result)?;
+    }
+    // below the progress bars, once they are cleared
+    let shown = display.is_some();
+    config.stats.finish();
+    if let Some(display) = display {
+        let _ = display.join();
+    }
+    if config.verbose {
+        print_workers(result.workers.as_deref().unwrap_or_default());
+    }
+    if shown || config.verbose {
+        let skipped = config.skipped.lock().unwrap().len();
+        for line in config.stats.summary(skipped) {
+            eprintln!("{}", line);
+        }
+    }
 
     match reference {
         Some(reference) => {
-            if reference.hash == result.hash {
-                println!("{}: Ok", result.name.display());
-                Ok(())
+            let verification = job::Verification { reference, result };
+            let (reference, result) = (&verification.reference, &verification.result);
+            let review = match verification.is_match() {
+                false if config.update => Some(review::review(&config, &verification)?),
+                _ => None,
+            };
+            let mut updated = None;
+            if let Some(review) = review.as_ref().filter(|review| !review.accepted.is_empty()) {
+                let manifest = config.verify.as_deref().expect("update requires verify");
+                let rebaselined = review::rebaseline(&config, &verification, review)?;
+                job::write_manifest(&config, Path::new(manifest), &rebaselined)?;
+                updated = Some(rebaselined.hash);
+            }
+            let status = if verification.is_match() {
+                "ok"
+            } else if review
+                .as_ref()
+                .is_some_and(|review| review.refused.is_empty())
+            {
+                "updated"
             } else {
-                println!("{}: Mismatch", result.name.display());
+                "mismatch"
+            };
+            history.hashed(result, Some(reference));
+            history.record.status = status;
+
+            let (accepted, mut changed) = match review {
+                Some(review) => (review.accepted, review.refused),
+                None if status == "mismatch" => (Vec::new(), verification.changed_entries()),
+                None => (Vec::new(), Vec::new()),
+            };
+            if config.json {
+                let mut report = verification.report(status);
+                report.updated = accepted
+                    .iter()
+                    .map(|entry| job::ChangedEntry {
+                        path: entry.path.clone(),
+                        change: job::change_name(entry.kind),
+                    })
+                    .collect();
+                println!("{}", config.to_json(&report)?);
+            } else if status == "ok" {
+                println!("{}: {}", result.name.display(), color::ok("Ok"));
+            } else {
+                if let Some(hash) = &updated {
+                    println!(
+                        "{}: {} {} ({} -> {})",
+                        result.name.display(),
+                        color::ok("Updated"),
+                        config.verify.as_deref().unwrap_or_default(),
+                        reference.hash,
+                        hash
+                    );
+                    for entry in &accepted {
+                        println!(
+                            "  {}: {}",
+                            entry.path.display(),
+                            color::change(entry.kind, job::change_name(entry.kind))
+                        );
+                    }
+                }
+                if status == "mismatch" {
+                    print_mismatch(&config, &verification, &changed);
+                }
+            }
+
+            if config.lists_changed() {
+                if status == "mismatch" && changed.is_empty() {
+                    // nothing recorded to narrow it down
+                    changed.push(Difference {
+                        path: ".".into(),
+                        kind: DiffKind::Content,
+                    });
+                }
+                write_changed_files(&config, &changed)?;
+            }
+            if status == "mismatch" {
                 Err(anyhow!("Checksums did not match"))
+            } else {
+                Ok(())
             }
         }
         None => {
-            println!("{}", serde_json::to_string_pretty(&result)?);
+            history.hashed(&result, None);
+            println!("{}", config.result_json(&result)?);
             Ok(())
         }
     }
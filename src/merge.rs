@@ -0,0 +1,202 @@
+use anyhow::{Context, Result, anyhow};
+use std::collections::{BTreeMap, BTreeSet};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use fdsum::config::{Config, Detail, HashResultJson};
+use fdsum::hash::dir_digest;
+use fdsum::job;
+use fdsum::vfs::FileKind;
+
+/// Merge `manifests` into one of the closest common ancestor of their
+/// trees and write it to `output`, or to stdout
+pub fn run(config: &mut Config, manifests: &[String], output: Option<&Path>) -> Result<()> {
+    if let Some(path) = output {
+        job::check_output(config, path)?;
+    }
+    let manifests: Vec<HashResultJson> = manifests
+        .iter()
+        .map(|manifest| job::read_manifest(manifest))
+        .collect::<Result<_>>()?;
+    let merged = merge(config, &manifests)?;
+    match output {
+        Some(path) => job::write_manifest(config, path, &merged),
+        None => {
+            println!("{}", config.result_json(&merged)?);
+            Ok(())
+        }
+    }
+}
+
+/// The tree a manifest was made from, absolute if it was local
+fn tree_of(manifest: &HashResultJson) -> PathBuf {
+    manifest
+        .provenance
+        .as_ref()
+        .and_then(|provenance| provenance.root.clone())
+        .unwrap_or_else(|| manifest.name.clone())
+}
+
+fn common_ancestor(paths: &[PathBuf]) -> PathBuf {
+    let mut ancestor = paths[0].parent().unwrap_or(Path::new("")).to_path_buf();
+    for path in &paths[1..] {
+        while !path.starts_with(&ancestor) {
+            if !ancestor.pop() {
+                break;
+            }
+        }
+    }
+    ancestor
+}
+
+/// The digest of the root of a subtree as an entry at `rel` below the
+/// merged root. Its name and, unless included by the flags, its
+/// metadata were not part of the digest in its own manifest.
+fn subtree_digest(
+    config: &Config,
+    manifest: &HashResultJson,
+    path: &Path,
+    rel: &Path,
+) -> Result<Option<[u8; 32]>> {
+    if config.include_root_meta && !config.include_names {
+        return config.algorithm.decode(&manifest.hash).map(Some);
+    }
+    let detail = manifest
+        .detail
+        .as_ref()
+        .filter(|detail| detail.depth >= 1)
+        .ok_or_else(|| {
+            anyhow!(
+                "The manifest of {} records no digests of its entries, hash it with --detail 1",
+                manifest.name.display()
+            )
+        })?;
+    let stat = config.vfs.symlink_metadata(path)?;
+    if stat.kind != FileKind::Dir {
+        return Err(anyhow!(
+            "{} is not a directory, merge with the metadata of roots included",
+            path.display()
+        ));
+    }
+    // the keys are sorted by their bytes already
+    let digests: Vec<[u8; 32]> = detail
+        .entries
+        .iter()
+        .filter(|(entry, _)| Path::new(entry).components().count() == 1)
+        .map(|(_, digest)| config.algorithm.decode(digest))
+        .collect::<Result<_>>()?;
+    dir_digest(config, rel, &stat.meta, &digests)
+}
+
+fn merge(config: &mut Config, manifests: &[HashResultJson]) -> Result<HashResultJson> {
+    let first = &manifests[0];
+    job::apply_manifest(config, first)?;
+    for manifest in &manifests[1..] {
+        if manifest.flags != first.flags {
+            return Err(anyhow!(
+                "{} and {} were hashed with different flags ({} and {})",
+                first.name.display(),
+                manifest.name.display(),
+                first.flags,
+                manifest.flags
+            ));
+        }
+    }
+    let trees: Vec<PathBuf> = manifests.iter().map(tree_of).collect();
+    for (i, a) in trees.iter().enumerate() {
+        if let Some(b) = trees[i + 1..]
+            .iter()
+            .find(|b| a.starts_with(b) || b.starts_with(a))
+        {
+            return Err(anyhow!("{} and {} overlap", a.display(), b.display()));
+        }
+    }
+    let base = common_ancestor(&trees);
+
+    // digests by path relative to the merged root
+    let mut digests: BTreeMap<PathBuf, Option<[u8; 32]>> = BTreeMap::new();
+    let mut entries = BTreeMap::new();
+    let mut depth = usize::MAX;
+    for (manifest, tree) in manifests.iter().zip(&trees) {
+        let rel = tree.strip_prefix(&base)?.to_path_buf();
+        let digest = subtree_digest(config, manifest, tree, &rel)
+            .with_context(|| tree.display().to_string())?;
+        if let Some(detail) = &manifest.detail {
+            depth = depth.min(rel.components().count() + detail.depth);
+            for (entry, digest) in &detail.entries {
+                entries.insert(rel.join(entry), digest.clone());
+            }
+        } else {
+            depth = depth.min(rel.components().count());
+        }
+        digests.insert(rel, digest);
+    }
+
+    // the directories from the merged root down to the subtrees,
+    // deepest first
+    let dirs: BTreeSet<PathBuf> = digests
+        .keys()
+        .flat_map(|rel| rel.ancestors().skip(1).map(Path::to_path_buf))
+        .collect();
+    let mut dirs: Vec<PathBuf> = dirs.into_iter().collect();
+    dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+    let hashed = dirs.len() as u64;
+    for dir in dirs {
+        let path = base.join(&dir);
+        let mut names: Vec<_> = config
+            .vfs
+            .read_dir(&path)
+            .with_context(|| path.display().to_string())?
+            .into_iter()
+            .filter(|name| !config.is_excluded(name))
+            .collect();
+        names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        let mut children = Vec::new();
+        for name in names {
+            let rel = dir.join(&name);
+            match digests.get(&rel) {
+                Some(digest) => children.extend(*digest),
+                None => {
+                    let stat = config.vfs.symlink_metadata(&base.join(&rel))?;
+                    if !config.skips_entry(&rel, stat.kind, &stat.meta) {
+                        return Err(anyhow!(
+                            "Not covered by the manifests: {}",
+                            base.join(&rel).display()
+                        ));
+                    }
+                }
+            }
+        }
+        let stat = config.stat(&path)?;
+        let digest = dir_digest(config, &dir, &stat.meta, &children)?;
+        digests.insert(dir, digest);
+    }
+
+    let hash = digests
+        .remove(Path::new(""))
+        .flatten()
+        .ok_or_else(|| anyhow!("Nothing left to hash in {}", base.display()))?;
+    for (rel, digest) in digests {
+        if let Some(digest) = digest {
+            entries.insert(rel, config.algorithm.encode(&digest));
+        }
+    }
+    let mut merged = HashResultJson::from_part(config, base, &hash);
+    merged.entries = manifests
+        .iter()
+        .map(|m| m.entries)
+        .sum::<Option<u64>>()
+        .map(|entries| entries + hashed);
+    merged.bytes = manifests.iter().map(|m| m.bytes).sum::<Option<u64>>();
+    merged.detail = Some(Detail {
+        depth,
+        dirs: manifests
+            .iter()
+            .all(|m| m.detail.as_ref().is_some_and(|detail| detail.dirs)),
+        entries: entries
+            .into_iter()
+            .map(|(rel, digest)| (rel.to_string_lossy().into_owned(), digest))
+            .collect(),
+    });
+    Ok(merged)
+}
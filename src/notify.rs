@@ -0,0 +1,11 @@
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+/// POST `payload` as JSON to `url`
+pub fn post(url: &str, payload: &Value) -> Result<()> {
+    ureq::post(url)
+        .header("Content-Type", "application/json")
+        .send(serde_json::to_string(payload)?)
+        .with_context(|| format!("POST {}", url))?;
+    Ok(())
+}
@@ -0,0 +1,140 @@
+//! Timings of directories and large files in the speedscope format
+
+use anyhow::{Context, Result};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::stats::worker_name;
+
+/// Files of at least this size get their own frame
+pub const LARGE_FILE: u64 = 1024 * 1024;
+
+#[derive(Debug, Default)]
+struct Frames {
+    names: Vec<String>,
+    index: HashMap<String, usize>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Event {
+    open: bool,
+    frame: usize,
+    /// Milliseconds since the start of the profile
+    at: f64,
+}
+
+/// Records when each thread starts and finishes hashing directories
+/// and large files. Each thread becomes one evented profile, which
+/// nests since a thread only picks up other work while waiting for the
+/// entries of a directory.
+#[derive(Debug)]
+pub struct Profiler {
+    start: Instant,
+    frames: Mutex<Frames>,
+    events: Mutex<BTreeMap<String, Vec<Event>>>,
+}
+
+/// Closes its frame when dropped
+pub struct Span<'a> {
+    profiler: &'a Profiler,
+    frame: usize,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            frames: Mutex::default(),
+            events: Mutex::default(),
+        }
+    }
+
+    /// Open a frame for the entry at `rel` below the root on the
+    /// current thread
+    pub fn span(&self, rel: &Path) -> Span<'_> {
+        let name = if rel.as_os_str().is_empty() {
+            ".".to_string()
+        } else {
+            rel.display().to_string()
+        };
+        let frame = {
+            let mut frames = self.frames.lock().unwrap();
+            match frames.index.get(&name) {
+                Some(&frame) => frame,
+                None => {
+                    let frame = frames.names.len();
+                    frames.names.push(name.clone());
+                    frames.index.insert(name, frame);
+                    frame
+                }
+            }
+        };
+        self.push(true, frame);
+        Span {
+            profiler: self,
+            frame,
+        }
+    }
+
+    fn push(&self, open: bool, frame: usize) {
+        let at = self.start.elapsed().as_secs_f64() * 1000.0;
+        self.events
+            .lock()
+            .unwrap()
+            .entry(worker_name())
+            .or_default()
+            .push(Event { open, frame, at });
+    }
+
+    /// Write the profile for the run named `name` to `path`
+    pub fn write(&self, name: &str, path: &Path) -> Result<()> {
+        let frames = self.frames.lock().unwrap();
+        let events = self.events.lock().unwrap();
+        let profiles: Vec<_> = events
+            .iter()
+            .map(|(worker, events)| {
+                json!({
+                    "type": "evented",
+                    "name": format!("worker {}", worker),
+                    "unit": "milliseconds",
+                    "startValue": 0.0,
+                    "endValue": events.last().map_or(0.0, |e| e.at),
+                    "events": events
+                        .iter()
+                        .map(|e| json!({
+                            "type": if e.open { "O" } else { "C" },
+                            "frame": e.frame,
+                            "at": e.at,
+                        }))
+                        .collect::<Vec<_>>(),
+                })
+            })
+            .collect();
+        let profile = json!({
+            "$schema": "https://www.speedscope.app/file-format-schema.json",
+            "name": name,
+            "exporter": format!("fdsum {}", env!("CARGO_PKG_VERSION")),
+            "shared": {
+                "frames": frames.names.iter().map(|name| json!({ "name": name })).collect::<Vec<_>>(),
+            },
+            "profiles": profiles,
+        });
+        std::fs::write(path, serde_json::to_string(&profile)?)
+            .with_context(|| path.display().to_string())
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Span<'_> {
+    fn drop(&mut self) {
+        self.profiler.push(false, self.frame);
+    }
+}
@@ -0,0 +1,150 @@
+use anyhow::{Context, Result, anyhow};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use crate::config::Config;
+use crate::flags::Flags;
+use crate::hash::{entry_prefix, hash_child, hash_entry_parts, hash_meta_at};
+use crate::vfs::FileKind;
+
+/// A directory on the path from the proven entry to the root
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProofStep {
+    /// Digest of the metadata of the directory, preceded by its type
+    /// with framing version 2
+    pub meta: String,
+    /// Digests of the entries sorted before the one on the path
+    pub before: Vec<String>,
+    /// Digests of the entries sorted after the one on the path
+    pub after: Vec<String>,
+}
+
+/// The digests needed to check a single entry against the digest of
+/// the whole tree without access to the rest of the tree.
+///
+/// The digest of the entry is `H(meta || data)`. Going up one step,
+/// the digest of the directory is `H(step.meta || H(before || digest
+/// || after))`. After the last step this must equal `hash`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Proof {
+    pub name: PathBuf,
+    pub hash: String,
+    pub flags: String,
+    /// The proven entry, relative to the root of the tree
+    pub path: PathBuf,
+    /// Digest of the metadata of the entry, preceded by its type with
+    /// framing version 2
+    pub meta: String,
+    /// The data hashed after the metadata, for files the digest of
+    /// the content
+    pub data: String,
+    /// From the directory containing the entry up to the root
+    pub steps: Vec<ProofStep>,
+}
+
+impl Proof {
+    /// Compute the root digest implied by the proof
+    pub fn root_digest(&self) -> Result<[u8; 32]> {
+        let algorithm = Flags::parse(&self.flags)?.algorithm;
+        let mut hasher = algorithm.hasher();
+        hasher.update(&hex::decode(&self.meta)?);
+        hasher.update(&hex::decode(&self.data)?);
+        let mut digest = hasher.finalize();
+
+        for step in &self.steps {
+            let mut dir_hasher = algorithm.hasher();
+            for sibling in &step.before {
+                dir_hasher.update(&hex::decode(sibling)?);
+            }
+            dir_hasher.update(&digest);
+            for sibling in &step.after {
+                dir_hasher.update(&hex::decode(sibling)?);
+            }
+            let mut hasher = algorithm.hasher();
+            hasher.update(&hex::decode(&step.meta)?);
+            hasher.update(&dir_hasher.finalize());
+            digest = hasher.finalize();
+        }
+        Ok(digest)
+    }
+
+    /// Whether the proof leads to the digest it claims
+    pub fn is_valid(&self) -> Result<bool> {
+        let algorithm = Flags::parse(&self.flags)?.algorithm;
+        Ok(algorithm.encode(&self.root_digest()?) == self.hash)
+    }
+}
+
+/// Build the proof for the entry at `entry` below the tree at `root`
+pub fn prove(config: &Config, root: &Path, entry: &Path) -> Result<Proof> {
+    let mut steps = Vec::new();
+    let mut dir = root.to_path_buf();
+    let mut rel = PathBuf::new();
+    for component in entry.components() {
+        let name = match component {
+            Component::Normal(name) => name,
+            Component::CurDir => continue,
+            _ => {
+                return Err(anyhow!(
+                    "Not a path below the root of the tree: {}",
+                    entry.display()
+                ));
+            }
+        };
+        let stat = config.stat(&dir)?;
+        let meta =
+            hash_meta_at(config, &stat.meta, &rel).with_context(|| dir.display().to_string())?;
+
+        let mut names: Vec<_> = config
+            .vfs
+            .read_dir(&dir)?
+            .into_iter()
+            .filter(|name| !config.is_excluded(name))
+            .collect();
+        names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        let index = names
+            .iter()
+            .position(|n| n == name)
+            .ok_or_else(|| anyhow!("Not in the tree: {}", dir.join(name).display()))?;
+        // entries left out of the hash are left out of the proof too
+        let digests: Vec<(usize, String)> = names
+            .par_iter()
+            .enumerate()
+            .filter(|(i, _)| *i != index)
+            .map(|(i, n)| {
+                let digest = hash_child(config, &dir.join(n), &rel.join(n))?;
+                Ok(digest.map(|d| (i, hex::encode(d))))
+            })
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect();
+        let (before, after): (Vec<_>, Vec<_>) = digests.into_iter().partition(|(i, _)| *i < index);
+
+        steps.push(ProofStep {
+            meta: hex::encode(entry_prefix(config, FileKind::Dir, &meta)),
+            before: before.into_iter().map(|(_, d)| d).collect(),
+            after: after.into_iter().map(|(_, d)| d).collect(),
+        });
+        dir.push(name);
+        rel.push(name);
+    }
+    steps.reverse();
+
+    let (meta, data) = hash_entry_parts(config, &dir, &rel)?;
+    let proof = Proof {
+        name: root.to_path_buf(),
+        hash: String::new(),
+        flags: config.flags_string(),
+        path: entry.to_path_buf(),
+        meta: hex::encode(meta),
+        data: hex::encode(data),
+        steps,
+    };
+    Ok(Proof {
+        hash: config.algorithm.encode(&proof.root_digest()?),
+        ..proof
+    })
+}
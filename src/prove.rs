@@ -0,0 +1,27 @@
+use anyhow::{Result, anyhow};
+use std::path::Path;
+
+use fdsum::config::Config;
+use fdsum::{job, proof};
+
+/// Print the proof for `entry` of the tree recorded in `manifest`. The
+/// tree must still match the manifest.
+pub fn run(config: &mut Config, manifest: &str, entry: &Path) -> Result<()> {
+    let reference = job::read_manifest(manifest)?;
+    job::apply_manifest(config, &reference)?;
+    let root = match (&config.path, &config.remote) {
+        (Some(path), None) if path.is_dir() => path.clone(),
+        _ => return Err(anyhow!("Proofs can only be made for local directories")),
+    };
+
+    let proof = proof::prove(config, &root, entry)?;
+    if proof.hash != reference.hash {
+        return Err(anyhow!(
+            "{} does not match {} anymore",
+            root.display(),
+            manifest
+        ));
+    }
+    println!("{}", config.to_json(&proof)?);
+    Ok(())
+}
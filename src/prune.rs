@@ -0,0 +1,180 @@
+use anyhow::{Context, Result, anyhow};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::ErrorKind;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use fdsum::config::Config;
+use fdsum::hash::dir_digest;
+use fdsum::job;
+use fdsum::tree::DiffKind;
+
+use crate::color;
+
+fn is_missing(config: &Config, path: &Path) -> Result<bool> {
+    match config.vfs.symlink_metadata(path) {
+        Ok(_) => Ok(false),
+        Err(err) => match err.chain().find_map(|e| e.downcast_ref::<std::io::Error>()) {
+            Some(err) if matches!(err.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory) => {
+                Ok(true)
+            }
+            _ => Err(err.context(path.display().to_string())),
+        },
+    }
+}
+
+/// Remove the entries of `manifest` that no longer exist below `root`,
+/// or the tree named in the manifest, and recompute the digests of the
+/// directories that held them from the digests of their remaining
+/// entries. The data of the remaining entries is not read again.
+pub fn run(config: &mut Config, manifest: &str, root: Option<&Path>) -> Result<()> {
+    let mut result = job::read_manifest(manifest)?;
+    if result.roots.is_some() {
+        return Err(anyhow!("Manifests of combined roots cannot be pruned"));
+    }
+    config.path = root.map(Path::to_path_buf);
+    job::apply_manifest(config, &result)?;
+    let root = config
+        .path
+        .clone()
+        .ok_or_else(|| anyhow!("Only manifests of local trees can be pruned"))?;
+    let mut detail = result.detail.take().ok_or_else(|| {
+        anyhow!(
+            "{} records no digests of its entries, hash with --detail to prune it",
+            manifest
+        )
+    })?;
+
+    let mut digests: BTreeMap<PathBuf, [u8; 32]> = BTreeMap::new();
+    for (rel, digest) in &detail.entries {
+        digests.insert(PathBuf::from(rel), config.algorithm.decode(digest)?);
+    }
+    let mut removed = Vec::new();
+    for rel in digests.keys() {
+        if is_missing(config, &root.join(rel))? {
+            removed.push(rel.clone());
+        }
+    }
+    if removed.is_empty() {
+        println!("{}: {}", manifest, color::ok("Nothing to prune"));
+        return Ok(());
+    }
+    for rel in &removed {
+        digests.remove(rel);
+    }
+
+    // the remaining directories that held removed entries
+    let dirs: BTreeSet<PathBuf> = removed
+        .iter()
+        .flat_map(|rel| rel.ancestors().skip(1).map(Path::to_path_buf))
+        .filter(|dir| !removed.contains(dir))
+        .collect();
+    let hash = rehash_dirs(config, manifest, &root, detail.depth, &mut digests, dirs)?;
+
+    let hash = hash.ok_or_else(|| anyhow!("Nothing left to hash in {}", root.display()))?;
+    result.hash = config.algorithm.encode(&hash);
+    // no longer known without reading the tree
+    result.entries = None;
+    result.bytes = None;
+    detail.entries = digests
+        .iter()
+        .map(|(rel, digest)| {
+            (
+                rel.to_string_lossy().into_owned(),
+                config.algorithm.encode(digest),
+            )
+        })
+        .collect();
+    result.detail = Some(detail);
+    let is_removed = |rel: &str| removed.iter().any(|r| Path::new(rel).starts_with(r));
+    if let Some(files) = &mut result.files {
+        files.retain(|rel, _| !is_removed(rel));
+    }
+    if let Some(skipped) = &mut result.skipped {
+        skipped.retain(|rel| !is_removed(rel));
+    }
+    result.errors.retain(|error| {
+        !error
+            .path
+            .as_ref()
+            .is_some_and(|path| removed.iter().any(|rel| path.starts_with(root.join(rel))))
+    });
+    job::write_manifest(config, Path::new(manifest), &result)?;
+
+    println!(
+        "{}: {} {} entries",
+        manifest,
+        color::ok("Pruned"),
+        removed.len()
+    );
+    // entries below removed directories go without saying
+    for rel in &removed {
+        if !rel
+            .ancestors()
+            .skip(1)
+            .any(|dir| removed.iter().any(|r| r == dir))
+        {
+            println!(
+                "  {}: {}",
+                rel.display(),
+                color::change(DiffKind::OnlyLeft, job::change_name(DiffKind::OnlyLeft))
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Recompute the digests of `dirs` below `root` and the directories
+/// above them from the digests of their entries in `digests`, deepest
+/// first, and return the digest of the root. The manifest must record
+/// all entries of the directories, i.e. they must be above its detail
+/// `depth`.
+pub fn rehash_dirs(
+    config: &Config,
+    manifest: &str,
+    root: &Path,
+    depth: usize,
+    digests: &mut BTreeMap<PathBuf, [u8; 32]>,
+    dirs: BTreeSet<PathBuf>,
+) -> Result<Option<[u8; 32]>> {
+    let mut dirs: Vec<PathBuf> = dirs
+        .iter()
+        .flat_map(|dir| dir.ancestors().map(Path::to_path_buf))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+    let mut hash = None;
+    for dir in dirs {
+        if dir.components().count() >= depth {
+            return Err(anyhow!(
+                "{} does not record all entries of {}, hash with a greater --detail",
+                manifest,
+                root.join(&dir).display()
+            ));
+        }
+        let mut children: Vec<(&PathBuf, &[u8; 32])> = digests
+            .iter()
+            .filter(|(rel, _)| rel.parent() == Some(dir.as_path()))
+            .collect();
+        children.sort_by(|a, b| {
+            let name = |rel: &Path| rel.file_name().unwrap_or_default().as_bytes().to_vec();
+            name(a.0).cmp(&name(b.0))
+        });
+        let children: Vec<[u8; 32]> = children.into_iter().map(|(_, digest)| *digest).collect();
+        let path = root.join(&dir);
+        let stat = config
+            .stat(&path)
+            .with_context(|| path.display().to_string())?;
+        let digest = dir_digest(config, &dir, &stat.meta, &children)?;
+        if dir.as_os_str().is_empty() {
+            hash = digest;
+        } else {
+            match digest {
+                Some(digest) => digests.insert(dir, digest),
+                None => digests.remove(&dir),
+            };
+        }
+    }
+    Ok(hash)
+}
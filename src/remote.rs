@@ -0,0 +1,187 @@
+use anyhow::{Context, Result, anyhow};
+use std::fmt;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::archive;
+use crate::config::{Config, HashResultJson};
+use crate::tree::VirtualTree;
+
+/// A tree on another host given as `[user@]host:/path`, reached by
+/// running `ssh`
+#[derive(Debug, Clone)]
+pub struct Remote {
+    pub host: String,
+    pub path: String,
+}
+
+impl Remote {
+    pub fn parse(spec: &str) -> Option<Self> {
+        let (host, path) = spec.split_once(':')?;
+        if host.is_empty() || host.contains('/') || path.is_empty() {
+            return None;
+        }
+        Some(Self {
+            host: host.to_string(),
+            path: path.to_string(),
+        })
+    }
+
+    fn ssh(&self, command: &str) -> Command {
+        let mut ssh = Command::new("ssh");
+        ssh.arg("--")
+            .arg(&self.host)
+            .arg(command)
+            .stdin(Stdio::inherit())
+            .stderr(Stdio::inherit());
+        ssh
+    }
+}
+
+impl fmt::Display for Remote {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.host, self.path)
+    }
+}
+
+/// Quote a string for the POSIX shell ssh runs commands with
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// The command line of fdsum on a remote host hashing with the flags
+/// and options of `config`, up to the path
+fn fdsum_command(config: &Config) -> String {
+    let mut command = format!(
+        "{} --flags {}",
+        config.remote_fdsum,
+        shell_quote(&config.flags_string()),
+    );
+    if !config.auto_block_size {
+        command.push_str(&format!(" -b {}", config.block_size / 1024));
+    }
+    if config.prefetch > 0 {
+        command.push_str(&format!(" --prefetch {}", config.prefetch));
+    }
+    if config.detail_depth > 0 {
+        command.push_str(&format!(" --detail {}", config.detail_depth));
+    }
+    if config.detail_dirs {
+        command.push_str(" --detail-dirs");
+    }
+    if config.record_chunks {
+        command.push_str(" --chunks");
+    } else if config.record_files {
+        command.push_str(" --record-files");
+    }
+    if config.skip_unreadable {
+        command.push_str(" --skip-unreadable");
+    }
+    command
+}
+
+/// Hash the remote tree by running fdsum on the remote host with the
+/// same flags. If fdsum is not installed there, stream a tar of the
+/// tree over ssh and hash that locally instead.
+pub fn hash_remote(config: &Config, remote: &Remote) -> Result<HashResultJson> {
+    let command = format!("{} -- {}", fdsum_command(config), shell_quote(&remote.path));
+    // warnings of the remote fdsum go to stderr, the result to stdout
+    let output = remote
+        .ssh(&command)
+        .stderr(Stdio::inherit())
+        .output()
+        .context("Failed to run ssh")?;
+
+    match output.status.code() {
+        Some(0) => {
+            let mut result = HashResultJson::parse(&String::from_utf8_lossy(&output.stdout))
+                .with_context(|| format!("Malformed result from {}", remote.host))?;
+            result.name = PathBuf::from(remote.to_string());
+            Ok(result)
+        }
+        Some(127) => {
+            eprintln!(
+                "Note: {} not found on {}, hashing a tar stream of the tree",
+                config.remote_fdsum, remote.host
+            );
+            let name = PathBuf::from(remote.to_string());
+            let hash = read_tree(config, remote)?.digest(config, &name)?;
+            Ok(HashResultJson::from_result(config, name, &hash))
+        }
+        _ => Err(anyhow!(
+            "fdsum on {} failed: {}",
+            remote.host,
+            output.status
+        )),
+    }
+}
+
+/// Hash the entry at `rel` below `root` on `host` as it is hashed as
+/// part of the tree (see --subtree), with the tree on storage shared
+/// with `host`. `None` if `host` cannot be reached.
+pub fn hash_remote_entry(
+    config: &Config,
+    host: &str,
+    root: &Path,
+    rel: &Path,
+) -> Result<Option<HashResultJson>> {
+    let remote = Remote {
+        host: host.to_string(),
+        path: root.to_string_lossy().into_owned(),
+    };
+    // the caller holds the lock on the tree
+    let command = format!(
+        "{} --no-lock --subtree {} -- {}",
+        fdsum_command(config),
+        shell_quote(&rel.to_string_lossy()),
+        shell_quote(&remote.path)
+    );
+    let output = remote
+        .ssh(&command)
+        .stdin(Stdio::null())
+        .output()
+        .context("Failed to run ssh")?;
+    match output.status.code() {
+        Some(0) => HashResultJson::parse(&String::from_utf8_lossy(&output.stdout))
+            .with_context(|| format!("Malformed result from {}", host))
+            .map(Some),
+        // ssh itself failed
+        Some(255) => Ok(None),
+        Some(127) => Err(anyhow!("{} not found on {}", config.remote_fdsum, host)),
+        _ => Err(anyhow!(
+            "fdsum on {} failed for {}: {}",
+            host,
+            rel.display(),
+            output.status
+        )),
+    }
+}
+
+/// Load the remote tree from a tar stream produced on the remote host
+pub fn read_tree(config: &Config, remote: &Remote) -> Result<VirtualTree> {
+    let command = format!("tar -C {} -cf - .", shell_quote(&remote.path));
+    let mut child = remote
+        .ssh(&command)
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("Failed to run ssh")?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+
+    let tree = archive::read_tar_stream(config, BufReader::new(stdout))
+        .with_context(|| remote.to_string());
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(anyhow!("tar on {} failed: {}", remote.host, status));
+    }
+    config.stats.done_entries(1);
+    tree
+}
+
+/// Whether `spec` names a remote tree rather than a local path
+pub fn parse_source(spec: &Path) -> Option<Remote> {
+    if spec.exists() {
+        return None;
+    }
+    Remote::parse(spec.to_str()?)
+}
@@ -0,0 +1,363 @@
+use anyhow::{Context, Result, anyhow};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use fdsum::HashResultJson;
+use fdsum::config::{Config, FileRecord};
+use fdsum::error::ErrorInfo;
+use fdsum::flags::TYPE_NAMES;
+use fdsum::hash::type_tag;
+use fdsum::job::{self, Verification};
+use fdsum::tree::{DiffKind, Difference};
+
+use crate::{color, prune};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    Accept,
+    Fail,
+}
+
+/// A decision on a changed entry taken in an interactive review,
+/// appended to the audit trail next to the manifest
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    time: String,
+    user: Option<String>,
+    name: &'a str,
+    path: &'a Path,
+    change: &'static str,
+    manifest_digest: Option<&'a str>,
+    current_digest: Option<&'a str>,
+    decision: Decision,
+}
+
+fn prompt(question: &str) -> Result<String> {
+    eprint!("{} ", question);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim().to_lowercase())
+}
+
+fn print_field(name: &str, old: &dyn std::fmt::Display, new: &dyn std::fmt::Display) {
+    let line = format!("{:<16} {:<66} {}", name, old, new);
+    if old.to_string() == new.to_string() {
+        eprintln!("  {}", line);
+    } else {
+        eprintln!("* {}", color::highlight(line).for_stderr());
+    }
+}
+
+/// A field of the current state that the manifest does not record
+fn print_current(name: &str, new: &dyn std::fmt::Display) {
+    eprintln!("  {:<16} {:<66} {}", name, "-", new);
+}
+
+fn option<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(|| "-".to_string(), |v| v.to_string())
+}
+
+/// Show what the manifest records of a changed entry and its current
+/// state side by side. Manifests record the digests of entries and,
+/// with --record-files, the size and content digest of files; the
+/// other metadata is shown as it is now.
+fn inspect(config: &Config, verification: &Verification, change: &Difference) {
+    let (reference, result) = (&verification.reference, &verification.result);
+    let path = &change.path;
+    eprintln!("  {:<16} {:<66} current", "", "manifest");
+    print_field(
+        "digest",
+        &option(digest(reference, path)),
+        &option(digest(result, path)),
+    );
+    let (old, new) = (file(reference, path), file(result, path));
+    if old.is_some() || new.is_some() {
+        print_field(
+            "size",
+            &option(old.as_ref().map(|file| file.size)),
+            &option(new.as_ref().map(|file| file.size)),
+        );
+        print_field(
+            "content",
+            &option(old.map(|file| &file.hash)),
+            &option(new.map(|file| &file.hash)),
+        );
+    }
+    let Some(root) = config
+        .path
+        .as_ref()
+        .filter(|_| !config.archive && config.remote.is_none())
+    else {
+        return;
+    };
+    let Ok(stat) = config.stat(&root.join(path)) else {
+        return;
+    };
+    let kind = TYPE_NAMES
+        .iter()
+        .find(|&&(letter, _)| letter == type_tag(stat.kind) as char)
+        .map_or("-", |&(_, name)| name);
+    print_current("type", &kind);
+    if old.is_none() {
+        print_current("size", &option(stat.meta.size));
+    }
+    print_current(
+        "mode",
+        &option(stat.meta.mode.map(|mode| format!("{:o}", mode))),
+    );
+    print_current("uid", &option(stat.meta.uid));
+    print_current("gid", &option(stat.meta.gid));
+    let mtime = stat.meta.mtime.and_then(|secs| {
+        chrono::DateTime::from_timestamp(secs, stat.meta.mtime_nsec.unwrap_or_default())
+    });
+    print_current("mtime", &option(mtime.map(|mtime| mtime.to_rfc3339())));
+}
+
+fn append_audit(config: &Config, record: &AuditRecord) -> Result<()> {
+    let manifest = config.verify.as_deref().expect("review requires verify");
+    let path = format!("{}.audit.jsonl", manifest);
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to write {}", path))?;
+    writeln!(file, "{}", serde_json::to_string(record)?)?;
+    Ok(())
+}
+
+/// The changes of a mismatching tree accepted into the manifest and
+/// those refused
+#[derive(Debug, Default)]
+pub struct Review {
+    pub accepted: Vec<Difference>,
+    pub refused: Vec<Difference>,
+}
+
+impl Review {
+    fn decide(&mut self, change: Difference, decision: Decision) {
+        match decision {
+            Decision::Accept => self.accepted.push(change),
+            Decision::Fail => self.refused.push(change),
+        }
+    }
+}
+
+/// The changes to decide on: the entries the mismatch was narrowed
+/// down to, or the tree as a whole as `.`
+fn changes(verification: &Verification) -> Vec<Difference> {
+    let changed = match verification.reference.roots {
+        Some(_) => Vec::new(),
+        None => verification.changed_entries(),
+    };
+    if changed.is_empty() {
+        return vec![Difference {
+            path: ".".into(),
+            kind: DiffKind::Content,
+        }];
+    }
+    changed
+}
+
+/// The digest of the entry at `path` in `result`, that of the root for
+/// `.`
+fn digest<'a>(result: &'a HashResultJson, path: &Path) -> Option<&'a str> {
+    if path == Path::new(".") {
+        return Some(&result.hash);
+    }
+    let detail = result.detail.as_ref()?;
+    detail.entries.get(path.to_str()?).map(String::as_str)
+}
+
+/// The size and content digest of the file at `path` in `result`, if
+/// recorded
+fn file<'a>(result: &'a HashResultJson, path: &Path) -> Option<&'a FileRecord> {
+    let files = result.files.as_ref()?;
+    files.get(path.to_str()?)
+}
+
+/// Decide which changes of a mismatching tree to accept. With
+/// --interactive the user may inspect the differences first and each
+/// decision is recorded; with --update a yes/no question is asked for
+/// each changed entry unless --yes accepts all changes.
+pub fn review(config: &Config, verification: &Verification) -> Result<Review> {
+    if config.verify.as_deref() == Some("-") {
+        return Err(anyhow!("--update needs a manifest file, not stdin"));
+    }
+    let changes = changes(verification);
+    if config.assume_yes {
+        return Ok(Review {
+            accepted: changes,
+            refused: Vec::new(),
+        });
+    }
+    let (reference, result) = (&verification.reference, &verification.result);
+    let name = result.name.display().to_string();
+    eprintln!("{}: {}", name, color::mismatch("Mismatch").for_stderr());
+    let mut review = Review::default();
+
+    if !config.interactive {
+        for change in changes {
+            eprintln!(
+                "  manifest: {}",
+                digest(reference, &change.path).unwrap_or("-")
+            );
+            eprintln!(
+                "  current:  {}",
+                digest(result, &change.path).unwrap_or("-")
+            );
+            let answer = prompt(&format!(
+                "Accept {} ({})? [y/N]",
+                change.path.display(),
+                job::change_name(change.kind)
+            ))?;
+            let decision = if matches!(answer.as_str(), "y" | "yes") {
+                Decision::Accept
+            } else {
+                Decision::Fail
+            };
+            review.decide(change, decision);
+        }
+        return Ok(review);
+    }
+
+    for change in changes {
+        eprintln!(
+            "  {}: {}",
+            change.path.display(),
+            color::change(change.kind, job::change_name(change.kind)).for_stderr()
+        );
+        let decision = loop {
+            match prompt("[a]ccept, [i]nspect or [f]ail?")?.as_str() {
+                "a" | "accept" => break Decision::Accept,
+                "f" | "fail" | "" => break Decision::Fail,
+                "i" | "inspect" => inspect(config, verification, &change),
+                _ => {}
+            }
+        };
+        append_audit(
+            config,
+            &AuditRecord {
+                time: chrono::Utc::now().to_rfc3339(),
+                user: std::env::var("USER").ok(),
+                name: &name,
+                path: &change.path,
+                change: job::change_name(change.kind),
+                manifest_digest: digest(reference, &change.path),
+                current_digest: digest(result, &change.path),
+                decision,
+            },
+        )?;
+        review.decide(change, decision);
+    }
+    Ok(review)
+}
+
+/// The manifest with the changes accepted in `review`: the current
+/// digests of the accepted entries, the recorded ones of the others,
+/// and those of the directories above them recomputed as for prune
+pub fn rebaseline(
+    config: &Config,
+    verification: &Verification,
+    review: &Review,
+) -> Result<HashResultJson> {
+    let (reference, result) = (&verification.reference, &verification.result);
+    if review.refused.is_empty() {
+        return Ok(result.clone());
+    }
+    let manifest = config.verify.as_deref().expect("update requires verify");
+    let root = match (&config.path, &config.remote) {
+        (Some(path), None) if !config.archive => path.clone(),
+        _ => {
+            return Err(anyhow!(
+                "Only manifests of local trees can be updated in part"
+            ));
+        }
+    };
+    let (Some(detail), Some(current)) = (&reference.detail, &result.detail) else {
+        return Err(anyhow!("{} records no digests of its entries", manifest));
+    };
+
+    let accepted = |rel: &Path| {
+        review
+            .accepted
+            .iter()
+            .any(|change| rel.starts_with(&change.path))
+    };
+    let mut digests: BTreeMap<PathBuf, [u8; 32]> = BTreeMap::new();
+    let recorded = detail
+        .entries
+        .iter()
+        .filter(|(rel, _)| !accepted(Path::new(rel)));
+    let changed = current
+        .entries
+        .iter()
+        .filter(|(rel, _)| accepted(Path::new(rel)));
+    for (rel, digest) in recorded.chain(changed) {
+        digests.insert(PathBuf::from(rel), config.algorithm.decode(digest)?);
+    }
+    let dirs: BTreeSet<PathBuf> = review
+        .accepted
+        .iter()
+        .filter_map(|change| change.path.parent())
+        .map(Path::to_path_buf)
+        .collect();
+    let hash = prune::rehash_dirs(config, manifest, &root, detail.depth, &mut digests, dirs)?
+        .ok_or_else(|| anyhow!("Nothing left to hash in {}", root.display()))?;
+
+    let mut updated = reference.clone();
+    updated.hash = config.algorithm.encode(&hash);
+    // no longer known without reading the tree
+    updated.entries = None;
+    updated.bytes = None;
+    if let Some(detail) = &mut updated.detail {
+        detail.entries = digests
+            .iter()
+            .map(|(rel, digest)| {
+                (
+                    rel.to_string_lossy().into_owned(),
+                    config.algorithm.encode(digest),
+                )
+            })
+            .collect();
+    }
+    if let (Some(files), Some(current)) = (&mut updated.files, &result.files) {
+        files.retain(|rel, _| !accepted(Path::new(rel)));
+        files.extend(
+            current
+                .iter()
+                .filter(|(rel, _)| accepted(Path::new(rel)))
+                .map(|(rel, file)| (rel.clone(), file.clone())),
+        );
+    }
+    if let (Some(skipped), Some(current)) = (&mut updated.skipped, &result.skipped) {
+        skipped.retain(|rel| !accepted(Path::new(rel)));
+        skipped.extend(
+            current
+                .iter()
+                .filter(|rel| accepted(Path::new(rel)))
+                .cloned(),
+        );
+        skipped.sort();
+    }
+    let accepted_error = |error: &ErrorInfo| {
+        error
+            .path
+            .as_ref()
+            .and_then(|path| path.strip_prefix(&root).ok())
+            .is_some_and(accepted)
+    };
+    updated.errors.retain(|error| !accepted_error(error));
+    updated.errors.extend(
+        result
+            .errors
+            .iter()
+            .filter(|error| accepted_error(error))
+            .cloned(),
+    );
+    Ok(updated)
+}
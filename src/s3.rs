@@ -0,0 +1,397 @@
+use anyhow::{Context, Result, anyhow};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::hash::{EntryMeta, hash_stream};
+use crate::tree::{Node, NodeKind, VirtualTree};
+
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+/// A location given as `s3://bucket/prefix`
+#[derive(Debug, Clone)]
+pub struct S3Url {
+    pub bucket: String,
+    pub prefix: String,
+}
+
+impl S3Url {
+    pub fn parse(path: &Path) -> Option<Self> {
+        let rest = path.to_str()?.strip_prefix("s3://")?;
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        let mut prefix = prefix.trim_matches('/').to_string();
+        if !prefix.is_empty() {
+            prefix.push('/');
+        }
+        Some(Self {
+            bucket: bucket.to_string(),
+            prefix,
+        })
+    }
+}
+
+struct Credentials {
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+/// A minimal S3 client signing requests with AWS Signature Version 4.
+/// Configured from the usual `AWS_*` environment variables; requests
+/// are sent unsigned if no credentials are set.
+struct Client {
+    agent: ureq::Agent,
+    endpoint: Option<String>,
+    region: String,
+    credentials: Option<Credentials>,
+}
+
+struct Object {
+    key: String,
+    size: u64,
+    etag: Option<String>,
+    storage_class: Option<String>,
+}
+
+fn uri_encode(s: &str, encode_slash: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            b'/' if !encode_slash => out.push('/'),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+impl Client {
+    fn from_env() -> Self {
+        let credentials = match (
+            env::var("AWS_ACCESS_KEY_ID"),
+            env::var("AWS_SECRET_ACCESS_KEY"),
+        ) {
+            (Ok(access_key), Ok(secret_key)) => Some(Credentials {
+                access_key,
+                secret_key,
+                session_token: env::var("AWS_SESSION_TOKEN").ok(),
+            }),
+            _ => None,
+        };
+        Self {
+            agent: ureq::Agent::new_with_defaults(),
+            endpoint: env::var("AWS_ENDPOINT_URL_S3")
+                .or_else(|_| env::var("AWS_ENDPOINT_URL"))
+                .ok()
+                .map(|e| e.trim_end_matches('/').to_string()),
+            region: env::var("AWS_REGION")
+                .or_else(|_| env::var("AWS_DEFAULT_REGION"))
+                .unwrap_or_else(|_| "us-east-1".to_string()),
+            credentials,
+        }
+    }
+
+    fn get(
+        &self,
+        bucket: &str,
+        key: &str,
+        query: &[(&str, &str)],
+    ) -> Result<ureq::http::Response<ureq::Body>> {
+        self.request("GET", bucket, key, query)
+    }
+
+    /// Issue a GET or HEAD request. Custom endpoints are addressed
+    /// path-style, AWS itself virtual-hosted-style.
+    fn request(
+        &self,
+        method: &str,
+        bucket: &str,
+        key: &str,
+        query: &[(&str, &str)],
+    ) -> Result<ureq::http::Response<ureq::Body>> {
+        let (base, uri) = match &self.endpoint {
+            Some(endpoint) => (
+                endpoint.clone(),
+                format!("/{}/{}", uri_encode(bucket, true), uri_encode(key, false)),
+            ),
+            None => (
+                format!("https://{}.s3.{}.amazonaws.com", bucket, self.region),
+                format!("/{}", uri_encode(key, false)),
+            ),
+        };
+        let host = base
+            .split_once("://")
+            .map_or(base.as_str(), |(_, rest)| rest)
+            .to_string();
+
+        let mut query: Vec<(String, String)> = query
+            .iter()
+            .map(|(k, v)| (uri_encode(k, true), uri_encode(v, true)))
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let url = if query.is_empty() {
+            format!("{}{}", base, uri)
+        } else {
+            format!("{}{}?{}", base, uri, query)
+        };
+        let mut request = match method {
+            "HEAD" => self.agent.head(&url),
+            _ => self.agent.get(&url),
+        };
+        for (name, value) in self.sign(method, &host, &uri, &query) {
+            request = request.header(name, value);
+        }
+        request
+            .call()
+            .with_context(|| format!("{} s3://{}/{}", method, bucket, key))
+    }
+
+    fn sign(
+        &self,
+        method: &str,
+        host: &str,
+        uri: &str,
+        query: &str,
+    ) -> Vec<(&'static str, String)> {
+        let Some(credentials) = &self.credentials else {
+            return Vec::new();
+        };
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+
+        let mut headers = vec![
+            ("host", host.to_string()),
+            ("x-amz-content-sha256", EMPTY_SHA256.to_string()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(k, v)| format!("{}:{}\n", k, v.trim()))
+            .collect();
+        let signed_headers = headers
+            .iter()
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>()
+            .join(";");
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method, uri, query, canonical_headers, signed_headers, EMPTY_SHA256
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let key = hmac(format!("AWS4{}", credentials.secret_key).as_bytes(), &date);
+        let key = hmac(&key, &self.region);
+        let key = hmac(&key, "s3");
+        let key = hmac(&key, "aws4_request");
+        let signature = hex::encode(hmac(&key, &string_to_sign));
+
+        headers.retain(|(k, _)| *k != "host");
+        headers.push((
+            "authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                credentials.access_key, scope, signed_headers, signature
+            ),
+        ));
+        headers
+    }
+
+    fn list(&self, url: &S3Url) -> Result<Vec<Object>> {
+        let mut objects = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", url.prefix.as_str())];
+            if let Some(token) = &token {
+                query.push(("continuation-token", token.as_str()));
+            }
+            let body = self
+                .get(&url.bucket, "", &query)?
+                .body_mut()
+                .read_to_string()?;
+            let doc =
+                roxmltree::Document::parse(&body).context("Malformed ListObjectsV2 response")?;
+            let root = doc.root_element();
+            let child_text = |node: roxmltree::Node, name: &str| {
+                node.children()
+                    .find(|n| n.has_tag_name(name))
+                    .and_then(|n| n.text())
+                    .map(str::to_string)
+            };
+
+            for contents in root.children().filter(|n| n.has_tag_name("Contents")) {
+                let key =
+                    child_text(contents, "Key").ok_or_else(|| anyhow!("object without key"))?;
+                let size = child_text(contents, "Size")
+                    .and_then(|s| s.parse().ok())
+                    .ok_or_else(|| anyhow!("object without size: {}", key))?;
+                objects.push(Object {
+                    key,
+                    size,
+                    etag: child_text(contents, "ETag").map(|e| e.trim_matches('"').to_string()),
+                    storage_class: child_text(contents, "StorageClass"),
+                });
+            }
+
+            token = child_text(root, "NextContinuationToken");
+            if child_text(root, "IsTruncated").as_deref() != Some("true") || token.is_none() {
+                break;
+            }
+        }
+        Ok(objects)
+    }
+}
+
+/// Map the object metadata conventionally written by s3fs and rclone
+/// (`x-amz-meta-mode`, `-uid`, `-gid`, `-mtime`) to entry metadata
+fn object_meta(headers: &ureq::http::HeaderMap) -> EntryMeta {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    let mtime = header("x-amz-meta-mtime")
+        .and_then(|v| v.parse::<f64>().ok())
+        .map(|v| v.floor() as i64);
+    EntryMeta {
+        mode: header("x-amz-meta-mode").and_then(|v| v.parse().ok()),
+        uid: header("x-amz-meta-uid").and_then(|v| v.parse().ok()),
+        gid: header("x-amz-meta-gid").and_then(|v| v.parse().ok()),
+        mtime,
+        ..Default::default()
+    }
+}
+
+fn fetch(config: &Config, client: &Client, url: &S3Url, object: &Object) -> Result<Node> {
+    let is_marker = object.key.ends_with('/');
+    let wants_meta =
+        config.include_mode || config.include_uid || config.include_gid || config.include_mtime;
+    if !wants_meta && (is_marker || !config.include_file_content) {
+        let kind = if is_marker {
+            NodeKind::Dir(BTreeMap::new())
+        } else {
+            NodeKind::File(None)
+        };
+        return Ok(Node {
+            meta: EntryMeta {
+                is_file: !is_marker,
+                is_dir: is_marker,
+                size: Some(object.size),
+                etag: object.etag.clone(),
+                storage_class: object.storage_class.clone(),
+                ..Default::default()
+            },
+            kind,
+        });
+    }
+
+    // without content only symlinks need their body, for the target
+    let mut response = if is_marker || !config.include_file_content {
+        let response = client.request("HEAD", &url.bucket, &object.key, &[])?;
+        let mode = object_meta(response.headers()).mode;
+        if mode.is_some_and(|m| m & S_IFMT == S_IFLNK) && !is_marker {
+            client.get(&url.bucket, &object.key, &[])?
+        } else {
+            response
+        }
+    } else {
+        client.get(&url.bucket, &object.key, &[])?
+    };
+    let mut meta = object_meta(response.headers());
+    let mut reader = response.body_mut().as_reader();
+
+    let file_type = meta.mode.map(|m| m & S_IFMT).filter(|&t| t != 0);
+    if is_marker || file_type == Some(S_IFDIR) {
+        meta.mode = meta.mode.map(|m| m | S_IFDIR);
+        return Ok(Node {
+            meta,
+            kind: NodeKind::Dir(BTreeMap::new()),
+        });
+    }
+    if file_type == Some(S_IFLNK) {
+        let mut target = Vec::new();
+        std::io::Read::read_to_end(&mut reader, &mut target)?;
+        return Ok(Node {
+            meta,
+            kind: NodeKind::Symlink(target),
+        });
+    }
+
+    meta.is_file = true;
+    meta.size = Some(object.size);
+    meta.etag = object.etag.clone();
+    meta.storage_class = object.storage_class.clone();
+    meta.mode = meta
+        .mode
+        .map(|m| if m & S_IFMT == 0 { m | S_IFREG } else { m });
+    let content = if config.include_file_content {
+        Some(hash_stream(config, &mut reader)?)
+    } else {
+        None
+    };
+    Ok(Node {
+        meta,
+        kind: NodeKind::File(content),
+    })
+}
+
+/// Load the objects below an S3 prefix into a virtual tree, treating
+/// `/` in keys as directory separators. ETag and storage class describe
+/// how the store holds an object rather than its data, so they are
+/// hashed only with --etag and --storage-class. Both are taken from the
+/// listing, and without content the objects are only requested with
+/// HEAD for their metadata.
+pub fn read_url(config: &Config, url: &S3Url) -> Result<VirtualTree> {
+    let client = Client::from_env();
+    let objects = client.list(url)?;
+    if config.include_file_content {
+        config.stats.add_bytes(objects.iter().map(|o| o.size).sum());
+    }
+
+    let nodes = objects
+        .par_iter()
+        .map(|object| fetch(config, &client, url, object))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut tree = VirtualTree::new();
+    for (object, node) in objects.iter().zip(nodes) {
+        let name = PathBuf::from(&object.key[url.prefix.len()..]);
+        tree.insert(config, &name, node)?;
+    }
+    config.stats.done_entries(1);
+    Ok(tree)
+}
+
+pub fn hash_url(config: &Config, url: &S3Url) -> Result<[u8; 32]> {
+    let root = PathBuf::from(format!("s3://{}/{}", url.bucket, url.prefix));
+    read_url(config, url)?.digest(config, &root)
+}
@@ -0,0 +1,242 @@
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use fdsum::config::Config;
+use fdsum::hash::hash_child;
+use fdsum::vfs::FileKind;
+
+use crate::color;
+
+/// What is known about the entries below a root
+#[derive(Debug, Serialize, Deserialize)]
+struct State {
+    root: PathBuf,
+    flags: String,
+    /// When the root was last scrubbed, in seconds since the epoch
+    last_run: Option<i64>,
+    units: BTreeMap<String, Unit>,
+}
+
+/// An entry directly below a root, verified as a whole
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Unit {
+    hash: String,
+    bytes: u64,
+    /// When the entry was last verified, in seconds since the epoch
+    verified: i64,
+}
+
+/// How entries are scheduled and mismatches handled
+pub struct Options {
+    /// Verify each entry at least once per this many seconds
+    pub period: u64,
+    /// Limits on the work of one run
+    pub max_seconds: Option<u64>,
+    pub max_bytes: Option<u64>,
+    /// Record the current digests of mismatching entries
+    pub accept: bool,
+}
+
+/// The state file of `root` in `dir`, named after its path
+fn state_file(dir: &Path, root: &Path) -> PathBuf {
+    let name = root.to_string_lossy().trim_matches('/').replace('/', "_");
+    let name = if name.is_empty() { "root" } else { &name };
+    dir.join(format!("{}.json", name))
+}
+
+fn load(file: &Path, root: &Path, config: &Config) -> Result<State> {
+    if !file.exists() {
+        return Ok(State {
+            root: root.to_path_buf(),
+            flags: config.flags_string(),
+            last_run: None,
+            units: BTreeMap::new(),
+        });
+    }
+    let json = std::fs::read_to_string(file).with_context(|| file.display().to_string())?;
+    let state: State =
+        serde_json::from_str(&json).with_context(|| format!("Malformed {}", file.display()))?;
+    if state.root != root {
+        return Err(anyhow!(
+            "{} holds the state of {}",
+            file.display(),
+            state.root.display()
+        ));
+    }
+    Ok(state)
+}
+
+/// Write `state` to `file` through a temporary file, so that an
+/// interrupted run leaves the previous state
+fn save(file: &Path, state: &State) -> Result<()> {
+    let tmp = file.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_string_pretty(state)?)
+        .with_context(|| tmp.display().to_string())?;
+    std::fs::rename(&tmp, file).with_context(|| file.display().to_string())?;
+    Ok(())
+}
+
+/// The entries directly below `root` in name order, or the root itself
+/// if it is not a directory
+fn list_units(config: &Config, root: &Path) -> Result<Vec<String>> {
+    let stat = config.vfs.symlink_metadata(root)?;
+    if stat.kind != FileKind::Dir {
+        return Ok(vec![String::new()]);
+    }
+    let mut names: Vec<_> = config
+        .vfs
+        .read_dir(root)?
+        .into_iter()
+        .filter(|name| !config.is_excluded(name))
+        .collect();
+    names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+    Ok(names
+        .into_iter()
+        .map(|name| name.to_string_lossy().into_owned())
+        .collect())
+}
+
+/// Scrub each of `roots`, keeping state in `dir`
+pub fn run(config: &Config, roots: &[PathBuf], dir: &Path, options: &Options) -> Result<()> {
+    std::fs::create_dir_all(dir).with_context(|| dir.display().to_string())?;
+    let start = Instant::now();
+    let mut read = 0;
+    let mut mismatches = 0;
+    for root in roots {
+        let root = std::fs::canonicalize(root).with_context(|| root.display().to_string())?;
+        // each root is hashed with the flags it was first scrubbed with
+        let mut config = config.clone();
+        mismatches += scrub_root(&mut config, &root, dir, options, start, &mut read)
+            .with_context(|| root.display().to_string())?;
+    }
+    if mismatches > 0 {
+        Err(anyhow!("{} entries did not verify", mismatches))
+    } else {
+        Ok(())
+    }
+}
+
+fn scrub_root(
+    config: &mut Config,
+    root: &Path,
+    dir: &Path,
+    options: &Options,
+    start: Instant,
+    read: &mut u64,
+) -> Result<usize> {
+    let _lock = fdsum::job::lock_root(config, root)?;
+    let file = state_file(dir, root);
+    let mut state = load(&file, root, config)?;
+    config.set_flags_from_string(&state.flags)?;
+    let now = chrono::Utc::now().timestamp();
+    let period = options.period as i64;
+
+    let units = list_units(config, root)?;
+    for rel in state.units.keys() {
+        if !units.contains(rel) {
+            println!(
+                "{}: {}",
+                root.join(rel).display(),
+                color::change(fdsum::tree::DiffKind::OnlyLeft, "removed")
+            );
+        }
+    }
+    state.units.retain(|rel, _| units.contains(rel));
+
+    // to verify everything once per period, each run covers the share
+    // of the data for the time since the last one, and at least the
+    // entries overdue
+    let total: u64 = state.units.values().map(|unit| unit.bytes).sum();
+    let target = match state.last_run {
+        Some(last) => {
+            let share = ((now - last) as f64 / period.max(1) as f64).min(1.0);
+            (total as f64 * share).ceil() as u64
+        }
+        None => u64::MAX,
+    };
+    let mut order: Vec<&String> = units.iter().collect();
+    order.sort_by_key(|rel| state.units.get(*rel).map_or(i64::MIN, |unit| unit.verified));
+
+    let mut done = 0;
+    let mut verified = 0;
+    let mut mismatches = 0;
+    for rel in order {
+        let known = state.units.get(rel).cloned();
+        let overdue = known
+            .as_ref()
+            .is_none_or(|unit| now - unit.verified >= period);
+        if !overdue && done >= target {
+            break;
+        }
+        let over_time = options
+            .max_seconds
+            .is_some_and(|max| start.elapsed().as_secs() >= max);
+        let over_bytes = options.max_bytes.is_some_and(|max| *read >= max);
+        if over_time || over_bytes {
+            break;
+        }
+
+        config.stats.flush();
+        let before = config.stats.snapshot().bytes_total;
+        let path = if rel.is_empty() {
+            root.to_path_buf()
+        } else {
+            root.join(rel)
+        };
+        let digest = hash_child(config, &path, Path::new(rel))?;
+        config.stats.flush();
+        let bytes = config.stats.snapshot().bytes_total - before;
+        *read += bytes;
+        done += bytes;
+        verified += 1;
+
+        let Some(digest) = digest else {
+            // left out of the hash, e.g. an empty directory
+            state.units.remove(rel);
+            continue;
+        };
+        let hash = config.algorithm.encode(&digest);
+        let status = match &known {
+            None => color::ok("Recorded"),
+            Some(unit) if unit.hash == hash => color::ok("Ok"),
+            Some(_) if options.accept => color::ok("Accepted"),
+            Some(_) => {
+                mismatches += 1;
+                println!("{}: {}", path.display(), color::mismatch("Mismatch"));
+                continue;
+            }
+        };
+        if config.verbose || known.as_ref().is_none_or(|unit| unit.hash != hash) {
+            println!("{}: {}", path.display(), status);
+        }
+        state.units.insert(
+            rel.clone(),
+            Unit {
+                hash,
+                bytes,
+                verified: now,
+            },
+        );
+    }
+
+    state.last_run = Some(now);
+    save(&file, &state)?;
+    let overdue = state
+        .units
+        .values()
+        .filter(|unit| now - unit.verified >= period)
+        .count();
+    println!(
+        "{}: {} {} of {} entries, {} overdue",
+        root.display(),
+        color::ok("Verified"),
+        verified,
+        units.len(),
+        overdue
+    );
+    Ok(mismatches)
+}
@@ -0,0 +1,78 @@
+use anyhow::{Result, anyhow};
+
+use fdsum::config::Config;
+use fdsum::hash::hash_entry;
+use fdsum::tree::VirtualTree;
+
+use crate::color;
+use crate::vectors::{TREES, TestTree};
+
+/// Digests of single inputs, checking the algorithms themselves
+const ALGORITHM_VECTORS: &[(&str, &[u8], &str)] = &[
+    (
+        "sha256",
+        b"abc",
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+    ),
+    (
+        "blake3",
+        b"abc",
+        "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85",
+    ),
+    (
+        "k12",
+        b"",
+        "1ac2d450fc3b4205d19da7bfca1b37513c0803577ac7167f06fe2ce1f0ef39e5",
+    ),
+    ("sha1", b"abc", "a9993e364706816aba3e25717850c26c9cd0d89d"),
+    ("md5", b"abc", "900150983cd24fb0d6963f7d28e17f72"),
+    ("crc32", b"123456789", "cbf43926"),
+    ("crc32c", b"123456789", "e3069283"),
+];
+
+/// Check the hash algorithms and the framing of trees against
+/// embedded digests, printing the outcome of each check
+pub fn run() -> Result<()> {
+    let mut failed = 0;
+    let mut check = |name: &str, expected: &str, actual: String| {
+        if actual == expected {
+            println!("{}: {}", name, color::ok("Ok"));
+        } else {
+            println!("{}: {} (got {})", name, color::mismatch("Mismatch"), actual);
+            failed += 1;
+        }
+    };
+
+    for (algorithm, input, expected) in ALGORITHM_VECTORS {
+        let mut config = Config::default();
+        config.set_flags_from_string(&format!("v1:{}:", algorithm))?;
+        let mut hasher = config.hasher();
+        hasher.update(input);
+        check(
+            algorithm,
+            expected,
+            config.algorithm.encode(&hasher.finalize()),
+        );
+    }
+
+    for vectors in TREES {
+        let tree = TestTree::create(vectors)?;
+        for (flags, expected) in vectors.vectors {
+            let mut config = Config::default();
+            config.set_flags_from_string(flags)?;
+            let digest = hash_entry(&config, tree.path())?;
+            let name = format!("{} ({})", flags, vectors.name);
+            check(&name, expected, config.algorithm.encode(&digest));
+            let virtual_tree = VirtualTree::from_dir(&config, tree.path())?;
+            let name = format!("{} ({}, in memory)", flags, vectors.name);
+            let digest = virtual_tree.digest(&config, tree.path())?;
+            check(&name, expected, config.algorithm.encode(&digest));
+        }
+    }
+
+    if failed > 0 {
+        Err(anyhow!("{} self tests failed", failed))
+    } else {
+        Ok(())
+    }
+}
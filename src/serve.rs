@@ -0,0 +1,18 @@
+use anyhow::Result;
+use std::net::SocketAddr;
+
+use fdsum::config::Config;
+
+/// Answer gRPC calls on `addr` until stopped
+#[cfg(feature = "grpc")]
+pub fn run(config: &Config, addr: SocketAddr) -> Result<()> {
+    eprintln!("Listening on {}", addr);
+    tokio::runtime::Runtime::new()?.block_on(fdsum::grpc::serve(config.clone(), addr))
+}
+
+#[cfg(not(feature = "grpc"))]
+pub fn run(_config: &Config, _addr: SocketAddr) -> Result<()> {
+    Err(anyhow::anyhow!(
+        "This build of fdsum cannot serve gRPC (build with --features grpc)"
+    ))
+}
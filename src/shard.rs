@@ -0,0 +1,241 @@
+use anyhow::{Context, Result, anyhow};
+use std::collections::{BTreeMap, VecDeque};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use fdsum::config::{Config, HashResultJson};
+use fdsum::hash::dir_digest;
+use fdsum::job;
+use fdsum::remote;
+use fdsum::vfs::FileKind;
+
+/// The entries of a tree handed to the workers, and the directories
+/// above them with their included entries in name order
+struct Partition {
+    entries: Vec<PathBuf>,
+    dirs: BTreeMap<PathBuf, Vec<PathBuf>>,
+}
+
+/// Hash the tree at `root` on `workers` and write its manifest to
+/// `output`, or to stdout
+pub fn run(
+    config: &Config,
+    root: &Path,
+    workers: &[String],
+    depth: usize,
+    output: Option<&Path>,
+) -> Result<()> {
+    if depth == 0 {
+        return Err(anyhow!("The depth of the entries must be 1 or more"));
+    }
+    if config.archive || !root.is_dir() {
+        return Err(anyhow!(
+            "Only local directories can be sharded: {}",
+            root.display()
+        ));
+    }
+    if let Some(path) = output {
+        job::check_output(config, path)?;
+    }
+    let root = std::path::absolute(root)?;
+    let _lock = job::lock_root(config, &root)?;
+    let partition = partition(config, &root, depth)?;
+    let results = dispatch(config, &root, workers, &partition.entries)?;
+    let result = combine(config, &root, &partition, results)?;
+    match output {
+        Some(path) => job::write_manifest(config, path, &result),
+        None => {
+            println!("{}", config.result_json(&result)?);
+            Ok(())
+        }
+    }
+}
+
+/// Split the tree at `root` into the entries at `depth` below it and
+/// the entries above that are not directories
+fn partition(config: &Config, root: &Path, depth: usize) -> Result<Partition> {
+    let mut partition = Partition {
+        entries: Vec::new(),
+        dirs: BTreeMap::new(),
+    };
+    let mut pending = vec![PathBuf::new()];
+    while let Some(dir) = pending.pop() {
+        let path = root.join(&dir);
+        let mut names: Vec<_> = config
+            .vfs
+            .read_dir(&path)
+            .with_context(|| path.display().to_string())?
+            .into_iter()
+            .filter(|name| !config.is_excluded(name))
+            .collect();
+        names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        let mut children = Vec::new();
+        for name in names {
+            let rel = dir.join(&name);
+            let stat = config.vfs.symlink_metadata(&root.join(&rel))?;
+            if config.skips_entry(&rel, stat.kind, &stat.meta) {
+                continue;
+            }
+            if stat.kind == FileKind::Dir && rel.components().count() < depth {
+                pending.push(rel.clone());
+            } else if stat.kind == FileKind::Dir
+                && config.skips_empty_dir(&rel, 0)
+                && !holds_entries(config, root, &rel)?
+            {
+                continue;
+            } else {
+                partition.entries.push(rel.clone());
+            }
+            children.push(rel);
+        }
+        partition.dirs.insert(dir, children);
+    }
+    Ok(partition)
+}
+
+/// Whether the directory at `rel` below `root` holds an entry taking
+/// part in the hash, looking no further than the first
+fn holds_entries(config: &Config, root: &Path, rel: &Path) -> Result<bool> {
+    let path = root.join(rel);
+    let names = config
+        .vfs
+        .read_dir(&path)
+        .with_context(|| path.display().to_string())?;
+    for name in names.into_iter().filter(|name| !config.is_excluded(name)) {
+        let rel = rel.join(&name);
+        let stat = config.vfs.symlink_metadata(&root.join(&rel))?;
+        if config.skips_entry(&rel, stat.kind, &stat.meta) {
+            continue;
+        }
+        if stat.kind != FileKind::Dir || holds_entries(config, root, &rel)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Hash `entries` below `root` on the workers, each taking the next
+/// entry when done with one
+fn dispatch(
+    config: &Config,
+    root: &Path,
+    workers: &[String],
+    entries: &[PathBuf],
+) -> Result<Vec<HashResultJson>> {
+    config.stats.add_entries(entries.len() as u64);
+    let queue = Mutex::new((0..entries.len()).collect::<VecDeque<_>>());
+    let results: Mutex<Vec<Option<HashResultJson>>> = Mutex::new(
+        std::iter::repeat_with(|| None)
+            .take(entries.len())
+            .collect(),
+    );
+    let failure = Mutex::new(None);
+    std::thread::scope(|scope| {
+        for host in workers {
+            let (queue, results, failure) = (&queue, &results, &failure);
+            scope.spawn(move || {
+                while failure.lock().unwrap().is_none() {
+                    let Some(i) = queue.lock().unwrap().pop_front() else {
+                        break;
+                    };
+                    let result = config
+                        .cancel
+                        .check()
+                        .and_then(|()| remote::hash_remote_entry(config, host, root, &entries[i]));
+                    match result {
+                        Ok(Some(result)) => {
+                            config.stats.done_entries(1);
+                            results.lock().unwrap()[i] = Some(result);
+                        }
+                        Ok(None) => {
+                            eprintln!(
+                                "Note: {} cannot be reached, leaving its entries to the other \
+                                 workers",
+                                host
+                            );
+                            queue.lock().unwrap().push_back(i);
+                            break;
+                        }
+                        Err(err) => {
+                            failure.lock().unwrap().get_or_insert(err);
+                        }
+                    }
+                }
+            });
+        }
+    });
+    if let Some(err) = failure.into_inner().unwrap() {
+        return Err(err);
+    }
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .zip(entries)
+        .map(|(result, rel)| {
+            result.ok_or_else(|| anyhow!("No worker left to hash {}", root.join(rel).display()))
+        })
+        .collect()
+}
+
+/// The manifest of the tree at `root` from the results of the workers
+/// and the directories above their entries
+fn combine(
+    config: &Config,
+    root: &Path,
+    partition: &Partition,
+    results: Vec<HashResultJson>,
+) -> Result<HashResultJson> {
+    let mut digests: BTreeMap<&Path, Option<[u8; 32]>> = BTreeMap::new();
+    for (rel, result) in partition.entries.iter().zip(&results) {
+        let digest = config.algorithm.decode(&result.hash)?;
+        let kind = config.vfs.symlink_metadata(&root.join(rel))?.kind;
+        config.record_detail(rel, kind == FileKind::Dir, &digest);
+        digests.insert(rel, Some(digest));
+    }
+    // deepest first
+    let mut dirs: Vec<&PathBuf> = partition.dirs.keys().collect();
+    dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+    for dir in dirs {
+        let children: Vec<[u8; 32]> = partition.dirs[dir]
+            .iter()
+            .filter_map(|rel| digests[rel.as_path()])
+            .collect();
+        let stat = config.stat(&root.join(dir))?;
+        let digest = dir_digest(config, dir, &stat.meta, &children)?;
+        if let Some(digest) = &digest {
+            config.record_detail(dir, true, digest);
+        }
+        digests.insert(dir, digest);
+    }
+    let hash = digests[Path::new("")]
+        .ok_or_else(|| anyhow!("Nothing left to hash in {}", root.display()))?;
+
+    let mut combined = HashResultJson::from_result(config, root.to_path_buf(), &hash);
+    combined.entries = results
+        .iter()
+        .map(|result| result.entries)
+        .sum::<Option<u64>>()
+        .map(|entries| entries + partition.dirs.len() as u64);
+    combined.bytes = results.iter().map(|result| result.bytes).sum();
+    // the threads of this host only read the directories
+    combined.phases = None;
+    combined.workers = None;
+    for result in results {
+        if let (Some(detail), Some(part)) = (&mut combined.detail, result.detail) {
+            detail.entries.extend(part.entries);
+        }
+        if let (Some(files), Some(part)) = (&mut combined.files, result.files) {
+            files.extend(part);
+        }
+        if let (Some(skipped), Some(part)) = (&mut combined.skipped, result.skipped) {
+            skipped.extend(part);
+        }
+        combined.errors.extend(result.errors);
+    }
+    if let Some(skipped) = &mut combined.skipped {
+        skipped.sort();
+    }
+    Ok(combined)
+}
@@ -0,0 +1,261 @@
+use anyhow::{Result, anyhow};
+use indicatif::HumanBytes;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use fdsum::chunks::CHUNKING;
+use fdsum::config::{Config, FileRecord, HashAlgorithm};
+use fdsum::flags::Flags;
+use fdsum::job;
+
+use crate::color;
+
+/// The files of one of the trees compared
+struct Tree {
+    name: PathBuf,
+    files: BTreeMap<String, FileRecord>,
+}
+
+#[derive(Debug, Serialize)]
+struct Totals {
+    name: PathBuf,
+    files: usize,
+    bytes: u64,
+}
+
+/// A file of RIGHT found with the same content at another path of LEFT
+#[derive(Debug, Serialize)]
+struct Moved {
+    from: String,
+    to: String,
+    /// The content is still at its path in LEFT as well
+    copied: bool,
+    size: u64,
+}
+
+/// A file of RIGHT whose content is not in LEFT as a whole
+#[derive(Debug, Serialize)]
+struct Changed {
+    path: String,
+    /// There is a file at the path in LEFT
+    existed: bool,
+    size: u64,
+    /// Bytes in chunks found in LEFT
+    shared_bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    left: Totals,
+    right: Totals,
+    /// Bytes of RIGHT in chunks found in LEFT
+    shared_bytes: u64,
+    /// Files of RIGHT at the same path with the same content in LEFT
+    unchanged: usize,
+    moved: Vec<Moved>,
+    changed: Vec<Changed>,
+    /// Files of LEFT whose content is not in RIGHT under any path
+    removed: Vec<String>,
+}
+
+/// Read the files and their chunks from the manifest at `spec`, or
+/// hash the local tree at `spec` recording them
+fn read_tree(config: &Config, spec: &Path) -> Result<Tree> {
+    if spec.is_dir() {
+        let mut config = config.clone();
+        config.record_files = true;
+        config.record_chunks = true;
+        config.file_digests = Arc::default();
+        config.file_chunks = Arc::default();
+        job::hash_path(&config, spec)?;
+        return Ok(Tree {
+            name: spec.to_path_buf(),
+            files: FileRecord::from_config(&config).unwrap_or_default(),
+        });
+    }
+    let manifest = job::read_manifest(&spec.to_string_lossy())?;
+    match (&manifest.chunking, manifest.files) {
+        (Some(chunking), Some(files)) if chunking == CHUNKING => Ok(Tree {
+            name: manifest.name,
+            files,
+        }),
+        (Some(chunking), Some(_)) => Err(anyhow!(
+            "{} records chunks by {}, this version of fdsum compares {}",
+            spec.display(),
+            chunking,
+            CHUNKING
+        )),
+        _ => Err(anyhow!(
+            "{} records no chunks of its files, hash with --chunks to compare it",
+            spec.display()
+        )),
+    }
+}
+
+/// The algorithm of the manifest at `spec`, if it is one
+fn manifest_algorithm(spec: &Path) -> Result<Option<HashAlgorithm>> {
+    if spec.is_dir() {
+        return Ok(None);
+    }
+    let manifest = job::read_manifest(&spec.to_string_lossy())?;
+    Ok(Some(Flags::parse(&manifest.flags)?.algorithm))
+}
+
+fn compare(left: Tree, right: Tree) -> Report {
+    let chunks: HashSet<&str> = left
+        .files
+        .values()
+        .flat_map(|file| file.chunks.iter().flatten())
+        .map(|chunk| chunk.hash.as_str())
+        .collect();
+    // empty files have the same content everywhere
+    let mut by_content: HashMap<&str, Vec<&str>> = HashMap::new();
+    for (path, file) in &left.files {
+        if file.size > 0 {
+            by_content.entry(&file.hash).or_default().push(path);
+        }
+    }
+    let right_contents: HashSet<&str> = right.files.values().map(|f| f.hash.as_str()).collect();
+
+    let mut report = Report {
+        left: totals(&left),
+        right: totals(&right),
+        shared_bytes: 0,
+        unchanged: 0,
+        moved: Vec::new(),
+        changed: Vec::new(),
+        removed: Vec::new(),
+    };
+    for (path, file) in &right.files {
+        let shared_bytes = file
+            .chunks
+            .iter()
+            .flatten()
+            .filter(|chunk| chunks.contains(chunk.hash.as_str()))
+            .map(|chunk| chunk.size)
+            .sum();
+        report.shared_bytes += shared_bytes;
+        let before = left.files.get(path);
+        if before.is_some_and(|before| before.hash == file.hash) {
+            report.unchanged += 1;
+            continue;
+        }
+        // rather name a source whose content is gone from its path
+        // than one that was copied
+        let kept = |source: &str| right.files.get(source).is_some_and(|f| f.hash == file.hash);
+        let sources = by_content.get(file.hash.as_str());
+        let source = sources.and_then(|sources| {
+            sources
+                .iter()
+                .find(|source| !kept(source))
+                .or(sources.first())
+        });
+        match source {
+            Some(source) => report.moved.push(Moved {
+                from: source.to_string(),
+                to: path.clone(),
+                copied: kept(source),
+                size: file.size,
+            }),
+            None => report.changed.push(Changed {
+                path: path.clone(),
+                existed: before.is_some(),
+                size: file.size,
+                shared_bytes,
+            }),
+        }
+    }
+    report.removed = left
+        .files
+        .iter()
+        .filter(|(path, file)| {
+            !right.files.contains_key(*path) && !right_contents.contains(file.hash.as_str())
+        })
+        .map(|(path, _)| path.clone())
+        .collect();
+    report
+}
+
+fn totals(tree: &Tree) -> Totals {
+    Totals {
+        name: tree.name.clone(),
+        files: tree.files.len(),
+        bytes: tree.files.values().map(|file| file.size).sum(),
+    }
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        100.0
+    } else {
+        part as f64 * 100.0 / whole as f64
+    }
+}
+
+/// Estimate how much of the content of the tree `right` is found in
+/// the tree `left`, by the content-defined chunks of their files. Each
+/// may be a local directory, hashed here, or a manifest recording the
+/// chunks of its files (see --chunks).
+pub fn run(config: &mut Config, left: &Path, right: &Path) -> Result<()> {
+    // chunks are compared by digest, and directories are hashed with
+    // the algorithm of the manifests
+    let algorithms = [manifest_algorithm(left)?, manifest_algorithm(right)?];
+    if let [Some(a), Some(b)] = &algorithms
+        && a != b
+    {
+        return Err(anyhow!(
+            "The manifests were made with different algorithms ({} and {})",
+            a,
+            b
+        ));
+    }
+    if let Some(algorithm) = algorithms.into_iter().flatten().next() {
+        config.algorithm = algorithm;
+    }
+    // account for the second root up front so progress does not
+    // finish after the first tree
+    config.stats.add_entries(1);
+    let left = read_tree(config, left)?;
+    let right = read_tree(config, right)?;
+    let report = compare(left, right);
+
+    if config.json {
+        println!("{}", config.to_json(&report)?);
+        return Ok(());
+    }
+    for moved in &report.moved {
+        let what = if moved.copied {
+            "copied from"
+        } else {
+            "moved from"
+        };
+        println!("{}: {} {}", moved.to, color::highlight(what), moved.from);
+    }
+    for changed in &report.changed {
+        let what = if changed.existed { "changed" } else { "new" };
+        println!(
+            "{}: {} ({:.0}% shared)",
+            changed.path,
+            color::highlight(what),
+            percent(changed.shared_bytes, changed.size)
+        );
+    }
+    for removed in &report.removed {
+        println!("{}: {}", removed, color::mismatch("removed"));
+    }
+    println!(
+        "{}: {} of {} ({:.1}%) found in {}; {} files unchanged, {} moved or copied, {} changed or new, {} removed",
+        report.right.name.display(),
+        HumanBytes(report.shared_bytes),
+        HumanBytes(report.right.bytes),
+        percent(report.shared_bytes, report.right.bytes),
+        report.left.name.display(),
+        report.unchanged,
+        report.moved.len(),
+        report.changed.len(),
+        report.removed.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,234 @@
+//! The entries changed between two snapshots of a filesystem, as the
+//! filesystem records them, so that comparing the snapshots needs to
+//! read only those. Btrfs lists the changes from one snapshot to
+//! another in the stream of `btrfs send`, ZFS in `zfs diff`.
+
+use anyhow::{Context, Result, anyhow};
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+// magic numbers of statfs(2)
+const BTRFS: i64 = 0x9123683e;
+const ZFS: i64 = 0x2fc12fc1;
+
+/// The paths, relative to the roots of the snapshots, of the entries
+/// that may differ between the snapshots `left` and `right`, with
+/// their ancestors. Entries not listed are the same in both.
+pub fn changed_paths(left: &Path, right: &Path) -> Result<BTreeSet<PathBuf>> {
+    let changes = match (fs_type(left)?, fs_type(right)?) {
+        (BTRFS, BTRFS) => btrfs_changes(left, right)?,
+        (ZFS, ZFS) => zfs_changes(left, right)?,
+        _ => {
+            return Err(anyhow!(
+                "{} and {} are not snapshots of a btrfs or ZFS filesystem",
+                left.display(),
+                right.display()
+            ));
+        }
+    };
+    let mut paths = BTreeSet::new();
+    for path in changes {
+        paths.extend(path.ancestors().map(Path::to_path_buf));
+    }
+    Ok(paths)
+}
+
+fn fs_type(path: &Path) -> Result<i64> {
+    let name = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: name is NUL-terminated and stat is a valid statfs buffer
+    if unsafe { libc::statfs(name.as_ptr(), &mut stat) } != 0 {
+        let err = std::io::Error::last_os_error();
+        return Err(err).with_context(|| path.display().to_string());
+    }
+    Ok(stat.f_type as i64)
+}
+
+/// The paths named in the operations of the send stream from the
+/// snapshot `left` to `right`, without the data of files
+fn btrfs_changes(left: &Path, right: &Path) -> Result<Vec<PathBuf>> {
+    let mut send = Command::new("btrfs")
+        .args(["send", "--quiet", "--no-data", "-p"])
+        .arg(left)
+        .arg(right)
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("Failed to run btrfs send")?;
+    let dump = Command::new("btrfs")
+        .args(["receive", "--dump"])
+        .stdin(send.stdout.take().expect("stdout is piped"))
+        .stderr(Stdio::inherit())
+        .output()
+        .context("Failed to run btrfs receive")?;
+    let status = send.wait()?;
+    if !status.success() || !dump.status.success() {
+        return Err(anyhow!(
+            "btrfs send -p {} {} failed",
+            left.display(),
+            right.display()
+        ));
+    }
+    let mut paths = Vec::new();
+    for line in dump.stdout.split(|&b| b == b'\n') {
+        let words = split_escaped(line);
+        let Some((op, args)) = words.split_first() else {
+            continue;
+        };
+        // the snapshot itself
+        if op.as_slice() == b"snapshot" || op.as_slice() == b"subvol" {
+            continue;
+        }
+        // the path operated on, and the new one of renames and links
+        let named = args.iter().enumerate().filter_map(|(i, arg)| match i {
+            0 => Some(arg.as_slice()),
+            _ => arg.strip_prefix(b"dest="),
+        });
+        for arg in named {
+            // paths start with ./ and the name of the snapshot received
+            let path = PathBuf::from(OsString::from_vec(arg.to_vec()));
+            paths.push(path.components().skip(2).collect());
+        }
+    }
+    Ok(paths)
+}
+
+/// Split a line of `btrfs receive --dump` at whitespace, undoing the
+/// escapes of the paths in it
+fn split_escaped(line: &[u8]) -> Vec<Vec<u8>> {
+    let mut words = Vec::new();
+    let mut word = Vec::new();
+    let mut bytes = line.iter().copied().peekable();
+    while let Some(b) = bytes.next() {
+        match b {
+            b' ' | b'\t' => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            b'\\' => match bytes.next() {
+                Some(digit @ b'0'..=b'7') => {
+                    let mut value = digit - b'0';
+                    for _ in 0..2 {
+                        if let Some(digit @ b'0'..=b'7') = bytes.peek().copied() {
+                            value = value.wrapping_mul(8) + (digit - b'0');
+                            bytes.next();
+                        }
+                    }
+                    word.push(value);
+                }
+                Some(b'a') => word.push(0x07),
+                Some(b'b') => word.push(0x08),
+                Some(b'e') => word.push(0x1b),
+                Some(b'f') => word.push(0x0c),
+                Some(b'n') => word.push(b'\n'),
+                Some(b'r') => word.push(b'\r'),
+                Some(b't') => word.push(b'\t'),
+                Some(b'v') => word.push(0x0b),
+                Some(other) => word.push(other),
+                None => {}
+            },
+            _ => word.push(b),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+/// The paths listed by `zfs diff` between the snapshots mounted at
+/// `left` and `right`, both below `.zfs/snapshot` of the same dataset
+fn zfs_changes(left: &Path, right: &Path) -> Result<Vec<PathBuf>> {
+    let (mount, old) = zfs_snapshot(left)?;
+    let (other, new) = zfs_snapshot(right)?;
+    if mount != other {
+        return Err(anyhow!(
+            "{} and {} are snapshots of different datasets",
+            left.display(),
+            right.display()
+        ));
+    }
+    let list = Command::new("zfs")
+        .args(["list", "-H", "-o", "name"])
+        .arg(&mount)
+        .stderr(Stdio::inherit())
+        .output()
+        .context("Failed to run zfs list")?;
+    if !list.status.success() {
+        return Err(anyhow!("zfs list {} failed", mount.display()));
+    }
+    let dataset = String::from_utf8_lossy(&list.stdout).trim().to_string();
+    let diff = Command::new("zfs")
+        .args(["diff", "-H"])
+        .arg(format!("{}@{}", dataset, old))
+        .arg(format!("{}@{}", dataset, new))
+        .stderr(Stdio::inherit())
+        .output()
+        .context("Failed to run zfs diff")?;
+    if !diff.status.success() {
+        return Err(anyhow!("zfs diff {}@{} {} failed", dataset, old, new));
+    }
+    // "<change>\t<path>[\t<new path>]" with paths below the mountpoint
+    let mut paths = Vec::new();
+    for line in diff.stdout.split(|&b| b == b'\n') {
+        for field in line.split(|&b| b == b'\t').skip(1) {
+            let path = PathBuf::from(OsString::from_vec(unescape_zfs(field)));
+            if let Ok(rel) = path.strip_prefix(&mount) {
+                paths.push(rel.to_path_buf());
+            }
+        }
+    }
+    Ok(paths)
+}
+
+/// The mountpoint of the dataset and the name of the snapshot mounted
+/// at `path`
+fn zfs_snapshot(path: &Path) -> Result<(PathBuf, String)> {
+    let path = std::fs::canonicalize(path).with_context(|| path.display().to_string())?;
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned());
+    let snapshots = path.parent();
+    match (name, snapshots) {
+        (Some(name), Some(snapshots)) if snapshots.ends_with(".zfs/snapshot") => {
+            let mount = snapshots
+                .parent()
+                .and_then(Path::parent)
+                .unwrap_or(Path::new("/"));
+            Ok((mount.to_path_buf(), name))
+        }
+        _ => Err(anyhow!(
+            "{} is not a snapshot below .zfs/snapshot",
+            path.display()
+        )),
+    }
+}
+
+/// Undo the `\0ooo` escapes of `zfs diff`
+fn unescape_zfs(field: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(field.len());
+    let mut i = 0;
+    while i < field.len() {
+        let escape = field
+            .get(i + 1..i + 5)
+            .filter(|digits| field[i] == b'\\' && digits.iter().all(|d| (b'0'..=b'7').contains(d)));
+        match escape {
+            Some(digits) => {
+                out.push(
+                    digits
+                        .iter()
+                        .fold(0u8, |value, d| value.wrapping_mul(8) + (d - b'0')),
+                );
+                i += 5;
+            }
+            None => {
+                out.push(field[i]);
+                i += 1;
+            }
+        }
+    }
+    out
+}
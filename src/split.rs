@@ -0,0 +1,119 @@
+use anyhow::{Context, Result, anyhow};
+use std::path::{Path, PathBuf};
+
+use fdsum::config::{Config, Detail, HashResultJson};
+use fdsum::hash::{EntryMeta, dir_digest};
+use fdsum::job;
+
+/// The digest of the directory at `rel` in `manifest` as the root of
+/// its own tree, from the digests of its entries. Its name and, unless
+/// included by the flags, its metadata are left out as for any root.
+fn root_digest(
+    config: &Config,
+    manifest: &HashResultJson,
+    detail: &Detail,
+    rel: &str,
+) -> Result<[u8; 32]> {
+    let digest = config.algorithm.decode(&detail.entries[rel])?;
+    if config.include_root_meta && !config.include_names {
+        return Ok(digest);
+    }
+    let meta = if config.include_root_meta {
+        let path = manifest.name.join(rel);
+        config
+            .stat(&path)
+            .with_context(|| format!("Reading the metadata of {}", path.display()))?
+            .meta
+    } else {
+        EntryMeta::default()
+    };
+    let children: Vec<[u8; 32]> = detail
+        .entries
+        .iter()
+        .filter(|(entry, _)| Path::new(entry).parent() == Some(Path::new(rel)))
+        .map(|(_, digest)| config.algorithm.decode(digest))
+        .collect::<Result<_>>()?;
+    Ok(dir_digest(config, Path::new(""), &meta, &children)?.expect("the root is never left out"))
+}
+
+/// Write a manifest for each directory recorded at `depth` below the
+/// root of `manifest` into `output`, or next to the manifest
+pub fn run(config: &mut Config, manifest: &str, depth: usize, output: Option<&Path>) -> Result<()> {
+    let result = job::read_manifest(manifest)?;
+    if result.roots.is_some() {
+        return Err(anyhow!("Manifests of combined roots cannot be split"));
+    }
+    job::apply_manifest(config, &result)?;
+    let detail = result.detail.as_ref().ok_or_else(|| {
+        anyhow!(
+            "{} records no digests of its entries, hash with --detail to split it",
+            manifest
+        )
+    })?;
+    if depth == 0 || depth >= detail.depth {
+        return Err(anyhow!(
+            "{} records all entries down to depth {} only, split at a depth from 1 to {}",
+            manifest,
+            detail.depth,
+            detail.depth.saturating_sub(1)
+        ));
+    }
+
+    let path = Path::new(manifest);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let output = output.unwrap_or(path.parent().unwrap_or(Path::new("")));
+    // directories are the entries with entries recorded below them
+    let dirs: Vec<&String> = detail
+        .entries
+        .keys()
+        .filter(|rel| Path::new(rel).components().count() == depth)
+        .filter(|rel| {
+            detail
+                .entries
+                .keys()
+                .any(|entry| Path::new(entry).parent() == Some(Path::new(rel)))
+        })
+        .collect();
+    if dirs.is_empty() {
+        return Err(anyhow!(
+            "{} records no directories at depth {}",
+            manifest,
+            depth
+        ));
+    }
+
+    let mut written: Vec<PathBuf> = Vec::new();
+    for rel in dirs {
+        let hash = root_digest(config, &result, detail, rel)?;
+        let prefix = Path::new(rel);
+        let entries = detail
+            .entries
+            .iter()
+            .filter_map(|(entry, digest)| {
+                let entry = Path::new(entry).strip_prefix(prefix).ok()?;
+                (!entry.as_os_str().is_empty())
+                    .then(|| (entry.to_string_lossy().into_owned(), digest.clone()))
+            })
+            .collect();
+        let mut part = HashResultJson::from_part(config, result.name.join(rel), &hash);
+        part.provenance = result.provenance.clone().map(|mut provenance| {
+            provenance.root = provenance.root.map(|root| root.join(rel));
+            provenance
+        });
+        part.detail = Some(Detail {
+            depth: detail.depth - depth,
+            dirs: detail.dirs,
+            entries,
+        });
+
+        let file = output.join(format!("{}.{}.json", stem, rel.replace('/', "_")));
+        if written.contains(&file) {
+            return Err(anyhow!("{} would be written twice", file.display()));
+        }
+        job::check_output(config, &file)?;
+        job::write_manifest(config, &file, &part)?;
+        println!("{}: {}", file.display(), part.name.display());
+        written.push(file);
+    }
+    Ok(())
+}
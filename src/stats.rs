@@ -1,9 +1,17 @@
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use anyhow::{Result, anyhow};
+use indicatif::{HumanDuration, MultiProgress, ProgressBar, ProgressState, ProgressStyle};
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
 
+use crate::vfs::FileKind;
+
 #[derive(Debug, Clone, Copy)]
 pub struct StatsSnapshot {
     pub entries_total: u64,
@@ -13,6 +21,42 @@ pub struct StatsSnapshot {
     pub elapsed: Duration,
 }
 
+/// What a single thread did during a run. Time not spent in one of the
+/// phases, e.g. waiting for work or for the bandwidth limit, counts as
+/// idle.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkerStats {
+    /// The index of the thread in the pool, or its name outside of it
+    pub worker: String,
+    pub entries: u64,
+    pub bytes: u64,
+    #[serde(default)]
+    pub stat_seconds: f64,
+    pub read_seconds: f64,
+    pub hash_seconds: f64,
+    pub idle_seconds: f64,
+}
+
+/// Time spent in each phase of a run, summed over all threads
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Phases {
+    /// Discovering entries: reading metadata, directories and links
+    pub stat_seconds: f64,
+    /// Reading the content of files
+    pub read_seconds: f64,
+    /// Hashing the content of files
+    pub hash_seconds: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct WorkerTotals {
+    entries: u64,
+    bytes: u64,
+    stat_time: Duration,
+    read_time: Duration,
+    hash_time: Duration,
+}
+
 #[derive(Debug)]
 pub struct SharedStats {
     pub entries_total: AtomicU64,
@@ -20,6 +64,16 @@ pub struct SharedStats {
     pub bytes_total: AtomicU64,
     pub bytes_done: AtomicU64,
     pub start_time: Instant,
+    workers: Mutex<BTreeMap<String, WorkerTotals>>,
+    /// Entries noted in the summary of the run
+    symlinks: AtomicU64,
+    special: AtomicU64,
+    /// The file that took longest to read and hash, and the time in
+    /// nanoseconds to compare against without taking the lock
+    slowest: Mutex<Option<(PathBuf, Duration)>>,
+    slowest_nanos: AtomicU64,
+    /// Set when the run is over, to stop the progress display
+    finished: AtomicBool,
 }
 
 impl SharedStats {
@@ -30,9 +84,67 @@ impl SharedStats {
             bytes_total: AtomicU64::new(0),
             bytes_done: AtomicU64::new(0),
             start_time: Instant::now(),
+            workers: Mutex::default(),
+            symlinks: AtomicU64::new(0),
+            special: AtomicU64::new(0),
+            slowest: Mutex::default(),
+            slowest_nanos: AtomicU64::new(0),
+            finished: AtomicBool::new(false),
+        }
+    }
+
+    /// The statistics of each thread, ordered by worker
+    pub fn workers(&self) -> Vec<WorkerStats> {
+        let elapsed = self.start_time.elapsed();
+        let seconds = |d: Duration| (d.as_secs_f64() * 100.0).round() / 100.0;
+        let mut workers: Vec<_> = self
+            .workers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(worker, totals)| WorkerStats {
+                worker: worker.clone(),
+                entries: totals.entries,
+                bytes: totals.bytes,
+                stat_seconds: seconds(totals.stat_time),
+                read_seconds: seconds(totals.read_time),
+                hash_seconds: seconds(totals.hash_time),
+                idle_seconds: seconds(
+                    elapsed.saturating_sub(totals.stat_time + totals.read_time + totals.hash_time),
+                ),
+            })
+            .collect();
+        // numerically, with named threads last
+        workers.sort_by_key(|w| {
+            (
+                w.worker.parse::<usize>().unwrap_or(usize::MAX),
+                w.worker.clone(),
+            )
+        });
+        workers
+    }
+
+    /// The time spent in each phase by all threads
+    pub fn phases(&self) -> Phases {
+        let workers = self.workers.lock().unwrap();
+        let sum = |f: fn(&WorkerTotals) -> Duration| {
+            let total: Duration = workers.values().map(f).sum();
+            (total.as_secs_f64() * 100.0).round() / 100.0
+        };
+        Phases {
+            stat_seconds: sum(|t| t.stat_time),
+            read_seconds: sum(|t| t.read_time),
+            hash_seconds: sum(|t| t.hash_time),
         }
     }
 
+    /// Flush the counts of the current thread and of all threads of
+    /// the current thread pool. Call before the final snapshot.
+    pub fn flush(&self) {
+        rayon::broadcast(|_| LOCAL_STATS.with(|cell| cell.borrow_mut().flush(self)));
+        LOCAL_STATS.with(|cell| cell.borrow_mut().flush(self));
+    }
+
     pub fn snapshot(&self) -> StatsSnapshot {
         StatsSnapshot {
             entries_total: self.entries_total.load(Ordering::Relaxed),
@@ -88,29 +200,110 @@ impl SharedStats {
         });
     }
 
-    pub fn spawn_display_thread(self: Arc<Self>) {
+    /// Run `f`, accounting its time as spent discovering entries
+    pub fn time_stat<T>(&self, f: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let out = f();
+        LOCAL_STATS.with(|cell| {
+            let mut stats = cell.borrow_mut();
+            stats.stat_time += started.elapsed();
+            stats.maybe_flush(self);
+        });
+        out
+    }
+
+    /// Account time the current thread spent reading and hashing
+    pub fn add_times(&self, read: Duration, hash: Duration) {
+        LOCAL_STATS.with(|cell| {
+            let mut stats = cell.borrow_mut();
+            stats.read_time += read;
+            stats.hash_time += hash;
+            stats.maybe_flush(self);
+        });
+    }
+
+    /// Note an entry of `kind` for the summary of the run
+    pub fn count_kind(&self, kind: FileKind) {
+        match kind {
+            FileKind::Symlink => self.symlinks.fetch_add(1, Ordering::Relaxed),
+            FileKind::Device(_) | FileKind::Special => self.special.fetch_add(1, Ordering::Relaxed),
+            FileKind::File | FileKind::Dir => return,
+        };
+    }
+
+    /// Note the time taken to read and hash the file at `path`
+    pub fn time_file(&self, path: &Path, elapsed: Duration) {
+        let nanos = elapsed.as_nanos() as u64;
+        if self.slowest_nanos.fetch_max(nanos, Ordering::Relaxed) >= nanos {
+            return;
+        }
+        let mut slowest = self.slowest.lock().unwrap();
+        if slowest.as_ref().is_none_or(|(_, time)| *time < elapsed) {
+            *slowest = Some((path.to_path_buf(), elapsed));
+        }
+    }
+
+    /// Lines summarizing what is worth noting about the run, with
+    /// `skipped` entries that could not be read
+    pub fn summary(&self, skipped: usize) -> Vec<String> {
+        let mut notes = Vec::new();
+        let mut note = |count: u64, what: &str| {
+            if count > 0 {
+                notes.push(format!("{} {}", count, what));
+            }
+        };
+        note(skipped as u64, "unreadable skipped");
+        note(self.special.load(Ordering::Relaxed), "special files");
+        note(
+            self.symlinks.load(Ordering::Relaxed),
+            "symlinks (not followed)",
+        );
+        let mut lines = Vec::new();
+        if !notes.is_empty() {
+            lines.push(format!("Entries: {}", notes.join(", ")));
+        }
+        if let Some((path, time)) = &*self.slowest.lock().unwrap() {
+            lines.push(format!(
+                "Slowest file: {} ({:.2} s)",
+                path.display(),
+                time.as_secs_f64()
+            ));
+        }
+        lines
+    }
+
+    /// Stop the progress display, even if not all entries discovered
+    /// were done
+    pub fn finish(&self) {
+        self.finished.store(true, Ordering::Relaxed);
+    }
+
+    pub fn spawn_display_thread(self: Arc<Self>, templates: &ProgressTemplates) -> JoinHandle<()> {
         let m = MultiProgress::new();
 
-        let byte_bar = m.add(ProgressBar::new(0));
-        byte_bar.set_style(
-            ProgressStyle::with_template(
-                "Bytes   [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, ETA {eta})",
+        // {eta} in either template shows the estimate of the whole run
+        let eta = Arc::new(Mutex::new(Eta::default()));
+        let style = |template: &str| {
+            let eta = eta.clone();
+            ProgressStyle::with_template(template).unwrap().with_key(
+                "eta",
+                move |_: &ProgressState, w: &mut dyn fmt::Write| {
+                    let _ = write!(w, "{}", eta.lock().unwrap());
+                },
             )
-            .unwrap(),
-        );
+        };
+
+        let byte_bar = m.add(ProgressBar::new(0));
+        byte_bar.set_style(style(&templates.bytes));
 
         let entry_bar = m.add(ProgressBar::new(0));
-        entry_bar.set_style(
-            ProgressStyle::with_template(
-                "Entries [{bar:40.green/white}] {pos}/{len} ({per_sec} entries/s)",
-            )
-            .unwrap(),
-        );
+        entry_bar.set_style(style(&templates.entries));
 
         std::thread::spawn(move || {
             loop {
                 std::thread::sleep(Duration::from_millis(200));
                 let snap = self.snapshot();
+                eta.lock().unwrap().update(&snap);
 
                 byte_bar.set_length(snap.bytes_total);
                 byte_bar.set_position(snap.bytes_done);
@@ -118,13 +311,155 @@ impl SharedStats {
                 entry_bar.set_length(snap.entries_total);
                 entry_bar.set_position(snap.entries_done);
 
-                if snap.entries_done >= snap.entries_total {
+                if snap.entries_done >= snap.entries_total || self.finished.load(Ordering::Relaxed)
+                {
                     byte_bar.finish_and_clear();
                     entry_bar.finish_and_clear();
                     break;
                 }
             }
-        });
+        })
+    }
+}
+
+/// Time over which the progress of a run is weighted in the ETA
+const ETA_MEMORY: Duration = Duration::from_secs(30);
+
+/// An estimate of the time left in a run.
+///
+/// Small files cost time per entry and large files per byte, so each
+/// interval between updates is fitted as `seconds = a * entries + b *
+/// bytes`, weighting older intervals down over [`ETA_MEMORY`]. Unlike
+/// a single rate this does not swing between stretches of small and of
+/// large files. While entries are still discovered the totals grow and
+/// the estimate is only a lower bound, shown with a leading `>`.
+#[derive(Debug, Default)]
+pub struct Eta {
+    last: Option<StatsSnapshot>,
+    /// Weighted sums of products of entries, bytes and seconds of the
+    /// intervals
+    ee: f64,
+    eb: f64,
+    bb: f64,
+    et: f64,
+    bt: f64,
+    /// When the totals last grew, in time since the start of the run
+    grown: Option<Duration>,
+}
+
+impl Eta {
+    pub fn update(&mut self, snap: &StatsSnapshot) {
+        let Some(last) = self.last.replace(*snap) else {
+            return;
+        };
+        if snap.bytes_total > last.bytes_total || snap.entries_total > last.entries_total {
+            self.grown = Some(snap.elapsed);
+        }
+        let t = snap.elapsed.saturating_sub(last.elapsed).as_secs_f64();
+        let e = snap.entries_done.saturating_sub(last.entries_done) as f64;
+        let b = snap.bytes_done.saturating_sub(last.bytes_done) as f64;
+        let decay = (-t / ETA_MEMORY.as_secs_f64()).exp();
+        for (sum, value) in [
+            (&mut self.ee, e * e),
+            (&mut self.eb, e * b),
+            (&mut self.bb, b * b),
+            (&mut self.et, e * t),
+            (&mut self.bt, b * t),
+        ] {
+            *sum = *sum * decay + value;
+        }
+    }
+
+    /// Seconds per entry and per byte
+    fn costs(&self) -> Option<(f64, f64)> {
+        let det = self.ee * self.bb - self.eb * self.eb;
+        if det > 1e-9 * self.ee * self.bb {
+            let a = (self.et * self.bb - self.bt * self.eb) / det;
+            let b = (self.bt * self.ee - self.et * self.eb) / det;
+            if a >= 0.0 && b >= 0.0 {
+                return Some((a, b));
+            }
+        }
+        // entries and bytes went in step, one of them explains the time
+        if self.bb > 0.0 {
+            Some((0.0, self.bt / self.bb))
+        } else if self.ee > 0.0 {
+            Some((self.et / self.ee, 0.0))
+        } else {
+            None
+        }
+    }
+
+    /// Whether the totals grew within the last second
+    pub fn growing(&self) -> bool {
+        match (self.last, self.grown) {
+            (Some(snap), Some(grown)) => snap.elapsed - grown < Duration::from_secs(1),
+            _ => false,
+        }
+    }
+
+    /// The estimated time left, if there was progress to go by
+    pub fn remaining(&self) -> Option<Duration> {
+        let snap = self.last?;
+        let (a, b) = self.costs()?;
+        let entries = snap.entries_total.saturating_sub(snap.entries_done) as f64;
+        let bytes = snap.bytes_total.saturating_sub(snap.bytes_done) as f64;
+        Some(Duration::from_secs_f64(a * entries + b * bytes))
+    }
+}
+
+impl fmt::Display for Eta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.remaining() {
+            None => write!(f, "-"),
+            Some(left) if self.growing() => write!(f, ">{:#}", HumanDuration(left)),
+            Some(left) => write!(f, "{:#}", HumanDuration(left)),
+        }
+    }
+}
+
+/// Terminals narrower than this get progress bars without rates
+const NARROW_TERMINAL: u16 = 72;
+
+/// The templates of the byte and entry progress bars, in the syntax of
+/// indicatif
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgressTemplates {
+    pub bytes: String,
+    pub entries: String,
+}
+
+impl ProgressTemplates {
+    /// The default templates for a terminal `width` columns wide. The
+    /// bars take up the columns left by the text.
+    pub fn for_width(width: u16) -> Self {
+        if width < NARROW_TERMINAL {
+            Self {
+                bytes: "Bytes   {wide_bar:.cyan/blue} {bytes}/{total_bytes}".to_string(),
+                entries: "Entries {wide_bar:.green/white} {pos}/{len}".to_string(),
+            }
+        } else {
+            Self {
+                bytes: "Bytes   [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} \
+                        ({bytes_per_sec}, ETA {eta})"
+                    .to_string(),
+                entries: "Entries [{wide_bar:.green/white}] {pos}/{len} ({per_sec} entries/s)"
+                    .to_string(),
+            }
+        }
+    }
+
+    /// Check that `template` is a valid template
+    pub fn validate(template: &str) -> Result<()> {
+        ProgressStyle::with_template(template)
+            .map(|_| ())
+            .map_err(|err| anyhow!("Invalid progress template {}: {}", template, err))
+    }
+}
+
+impl Default for SharedStats {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -134,6 +469,9 @@ pub struct LocalStats {
     pub entries_completed: u64,
     pub bytes_discovered: u64,
     pub bytes_completed: u64,
+    pub stat_time: Duration,
+    pub read_time: Duration,
+    pub hash_time: Duration,
     pub last_flush: Instant,
 }
 
@@ -144,6 +482,9 @@ impl LocalStats {
             entries_completed: 0,
             bytes_discovered: 0,
             bytes_completed: 0,
+            stat_time: Duration::ZERO,
+            read_time: Duration::ZERO,
+            hash_time: Duration::ZERO,
             last_flush: Instant::now(),
         }
     }
@@ -151,24 +492,62 @@ impl LocalStats {
     fn maybe_flush(&mut self, stats: &SharedStats) {
         let now = Instant::now();
         if now.duration_since(self.last_flush).as_millis() >= 100 {
-            if self.entries_discovered > 0 {
-                stats._add_entries(self.entries_discovered);
-                self.entries_discovered = 0;
-            }
-            if self.entries_completed > 0 {
-                stats._complete_entries(self.entries_completed);
-                self.entries_completed = 0;
-            }
-            if self.bytes_discovered > 0 {
-                stats._add_bytes(self.bytes_discovered);
-                self.bytes_discovered = 0;
-            }
-            if self.bytes_completed > 0 {
-                stats._complete_bytes(self.bytes_completed);
-                self.bytes_completed = 0
-            }
+            self.flush(stats);
+            self.last_flush = now;
         }
     }
+
+    fn flush(&mut self, stats: &SharedStats) {
+        if self.entries_discovered > 0 {
+            stats._add_entries(self.entries_discovered);
+            self.entries_discovered = 0;
+        }
+        if self.entries_completed == 0
+            && self.bytes_completed == 0
+            && self.bytes_discovered == 0
+            && self.stat_time.is_zero()
+            && self.read_time.is_zero()
+            && self.hash_time.is_zero()
+        {
+            return;
+        }
+        stats._complete_entries(self.entries_completed);
+        stats._add_bytes(self.bytes_discovered);
+        stats._complete_bytes(self.bytes_completed);
+
+        let mut workers = stats.workers.lock().unwrap();
+        let totals = workers.entry(worker_name()).or_default();
+        totals.entries += self.entries_completed;
+        totals.bytes += self.bytes_completed;
+        totals.stat_time += self.stat_time;
+        totals.read_time += self.read_time;
+        totals.hash_time += self.hash_time;
+
+        self.entries_completed = 0;
+        self.bytes_discovered = 0;
+        self.bytes_completed = 0;
+        self.stat_time = Duration::ZERO;
+        self.read_time = Duration::ZERO;
+        self.hash_time = Duration::ZERO;
+    }
+}
+
+impl Default for LocalStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The index of the current thread in its pool, or its name outside
+/// of a pool
+pub fn worker_name() -> String {
+    let thread = std::thread::current();
+    match (rayon::current_thread_index(), thread.name()) {
+        // threads of pools other than the hashing one are named
+        (Some(_), Some(name)) => name.to_string(),
+        (Some(index), None) => index.to_string(),
+        (None, name) => name.unwrap_or("other").to_string(),
+    }
 }
 
 thread_local! {
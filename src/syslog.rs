@@ -0,0 +1,93 @@
+//! Logging the outcome of runs to the system journal, or to syslog
+//! where there is no journal
+
+use anyhow::{Result, anyhow};
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+
+use crate::history::RunRecord;
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// The example enterprise number of RFC 5424, naming the structured
+/// data element of fdsum
+const SD_ID: &str = "fdsum@32473";
+
+/// Severities of RFC 5424
+const ERR: u8 = 3;
+const INFO: u8 = 6;
+
+/// The facility of user-level messages
+const USER: u8 = 1;
+
+fn severity(record: &RunRecord) -> u8 {
+    if record.error.is_some() { ERR } else { INFO }
+}
+
+/// Log `record` to the journal with its fields, or to syslog with
+/// them as structured data
+pub fn log(record: &RunRecord) -> Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    if Path::new(JOURNAL_SOCKET).exists() {
+        socket.send_to(&journal_entry(record), JOURNAL_SOCKET)?;
+    } else if Path::new(SYSLOG_SOCKET).exists() {
+        socket.send_to(syslog_line(record).as_bytes(), SYSLOG_SOCKET)?;
+    } else {
+        return Err(anyhow!("Neither the journal nor syslog is available"));
+    }
+    Ok(())
+}
+
+/// An entry in the native protocol of the journal. Values holding a
+/// newline are written with their length instead.
+fn journal_entry(record: &RunRecord) -> Vec<u8> {
+    let mut fields = vec![
+        ("MESSAGE", record.message()),
+        ("PRIORITY", severity(record).to_string()),
+        ("SYSLOG_IDENTIFIER", "fdsum".to_string()),
+    ];
+    fields.extend(record.fields());
+    let mut out = Vec::new();
+    for (key, value) in fields {
+        out.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            out.push(b'\n');
+            out.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            out.push(b'=');
+        }
+        out.extend_from_slice(value.as_bytes());
+        out.push(b'\n');
+    }
+    out
+}
+
+/// A line in the format of RFC 5424
+fn syslog_line(record: &RunRecord) -> String {
+    let params: Vec<String> = record
+        .fields()
+        .into_iter()
+        .map(|(key, value)| {
+            let name = key.trim_start_matches("FDSUM_").to_ascii_lowercase();
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace(']', "\\]");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    let hostname = gethostname::gethostname()
+        .into_string()
+        .unwrap_or_else(|_| "-".to_string());
+    format!(
+        "<{}>1 {} {} fdsum {} - [{} {}] {}",
+        USER * 8 + severity(record),
+        chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        hostname,
+        std::process::id(),
+        SD_ID,
+        params.join(" "),
+        record.message().replace('\n', " ")
+    )
+}
@@ -0,0 +1,674 @@
+use anyhow::{Context, Result, anyhow};
+use rayon::ThreadPoolBuilder;
+use rayon::prelude::*;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, SyncSender};
+
+use crate::config::{Config, Traversal};
+use crate::extent::physical_offset;
+use crate::hash::{EntryMeta, entry_prefix, hash_file, hash_meta_at, missing_meta};
+use crate::vfs::FileKind;
+
+#[derive(Clone)]
+pub enum NodeKind {
+    File(Option<[u8; 32]>),
+    /// Entries ordered by the raw bytes of their names, as `OsString`
+    /// compares them on Unix
+    Dir(BTreeMap<OsString, Node>),
+    Symlink(Vec<u8>),
+    Device(u64),
+    Special,
+}
+
+#[derive(Clone)]
+pub struct Node {
+    pub meta: EntryMeta,
+    pub kind: NodeKind,
+}
+
+impl Node {
+    fn implicit_dir() -> Self {
+        Node {
+            meta: EntryMeta::default(),
+            kind: NodeKind::Dir(BTreeMap::new()),
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        matches!(self.kind, NodeKind::Dir(_))
+    }
+
+    fn file_kind(&self) -> FileKind {
+        match &self.kind {
+            NodeKind::File(_) => FileKind::File,
+            NodeKind::Dir(_) => FileKind::Dir,
+            NodeKind::Symlink(_) => FileKind::Symlink,
+            NodeKind::Device(rdev) => FileKind::Device(*rdev),
+            NodeKind::Special => FileKind::Special,
+        }
+    }
+}
+
+/// An in-memory directory tree holding the metadata and content
+/// digests of each entry. Used for sources that cannot be traversed
+/// like a directory (e.g. archives) and for per-entry comparisons.
+/// Directories not listed explicitly by the source have no metadata.
+#[derive(Clone)]
+pub struct VirtualTree {
+    root: Node,
+}
+
+impl VirtualTree {
+    pub fn new() -> Self {
+        Self {
+            root: Node::implicit_dir(),
+        }
+    }
+
+    /// Load the tree below `path` from the filesystem
+    pub fn from_dir(config: &Config, path: &Path) -> Result<Self> {
+        let content = if config.include_file_content {
+            Content::Hash
+        } else {
+            Content::Skip
+        };
+        Ok(Self {
+            root: scan(config, path, Path::new(""), content)?,
+        })
+    }
+
+    /// Load the entries of the tree below `path` listed in `changed`
+    /// from the filesystem. The files and directories not listed are
+    /// left without content and entries, to compare equal to those of
+    /// another snapshot of the tree.
+    pub fn from_dir_changed(
+        config: &Config,
+        path: &Path,
+        changed: &BTreeSet<PathBuf>,
+    ) -> Result<Self> {
+        Ok(Self {
+            root: scan(config, path, Path::new(""), Content::Changed(changed))?,
+        })
+    }
+
+    /// Load the tree below `path` from the filesystem, listing it first
+    /// and then reading the content of the files in the given order
+    pub fn from_dir_ordered(config: &Config, path: &Path, traversal: Traversal) -> Result<Self> {
+        let mut root = if config.scan_threads > 0 {
+            scan_pool(config)?.install(|| {
+                let root = scan(config, path, Path::new(""), Content::Skip);
+                config.stats.flush();
+                root
+            })?
+        } else {
+            scan(config, path, Path::new(""), Content::Skip)?
+        };
+        if config.include_file_content {
+            let mut files = Vec::new();
+            collect_files(config, &mut root, path, Path::new(""), &mut files);
+            match traversal {
+                Traversal::Dfs => {}
+                Traversal::Bfs => files.sort_by_key(|f| f.rel.components().count()),
+                Traversal::SizeDesc => files.sort_by_key(|f| std::cmp::Reverse(f.size)),
+                Traversal::Physical => {
+                    // by device, files of unknown location last
+                    let keys: Vec<(u64, u64)> = files
+                        .par_iter()
+                        .map(|f| (f.dev, physical_offset(&f.path).unwrap_or(u64::MAX)))
+                        .collect();
+                    let mut keyed: Vec<_> = keys.into_iter().zip(files).collect();
+                    keyed.sort_by_key(|(key, _)| *key);
+                    files = keyed.into_iter().map(|(_, file)| file).collect();
+                }
+            }
+            // par_bridge hands out the files in order as threads get idle
+            files.into_iter().par_bridge().try_for_each(|file| {
+                let _span = config.profile_span(&file.rel, FileKind::File, file.size);
+                config.entry_started(&file.rel);
+                *file.content = Some(hash_file(config, &file.path, file.size)?);
+                Ok::<_, anyhow::Error>(())
+            })?;
+        }
+        Ok(Self { root })
+    }
+
+    /// Load the tree below `path` from the filesystem, listing it on a
+    /// pool of `config.scan_threads` threads while the current pool
+    /// reads the content of the files as they are found
+    pub fn from_dir_pipelined(config: &Config, path: &Path) -> Result<Self> {
+        let pool = scan_pool(config)?;
+        let contents = Mutex::new(HashMap::new());
+        let (scanned, hashed) = std::thread::scope(|scope| {
+            let (queue, found) = mpsc::sync_channel::<FoundFile>(SCAN_QUEUE);
+            let scanner = scope.spawn(move || {
+                pool.install(|| {
+                    let root = scan(config, path, Path::new(""), Content::Queue(&queue));
+                    config.stats.flush();
+                    root
+                })
+            });
+            let hashed = found.into_iter().par_bridge().try_for_each(|file| {
+                let _span = config.profile_span(&file.rel, FileKind::File, file.size);
+                config.entry_started(&file.rel);
+                let digest = hash_file(config, &file.path, file.size)?;
+                contents.lock().unwrap().insert(file.rel, digest);
+                Ok::<_, anyhow::Error>(())
+            });
+            (scanner.join().expect("scanning does not panic"), hashed)
+        });
+        // a failure to hash stops the scan, not the other way round
+        hashed?;
+        let mut root = scanned?;
+
+        let mut contents = contents.into_inner().unwrap();
+        if config.include_file_content {
+            let mut files = Vec::new();
+            collect_files(config, &mut root, path, Path::new(""), &mut files);
+            for file in files {
+                let digest = contents
+                    .remove(&file.rel)
+                    .ok_or_else(|| anyhow!("Not hashed: {}", file.path.display()))?;
+                *file.content = Some(digest);
+            }
+        }
+        Ok(Self { root })
+    }
+
+    fn lookup(&self, path: &Path) -> Option<&Node> {
+        let mut node = &self.root;
+        for name in normal_components(path) {
+            let NodeKind::Dir(children) = &node.kind else {
+                return None;
+            };
+            node = children.get(&name)?;
+        }
+        Some(node)
+    }
+
+    /// Insert an entry, creating missing parent directories
+    pub fn insert(&mut self, config: &Config, path: &Path, node: Node) -> Result<()> {
+        let names = normal_components(path);
+        let Some((name, parents)) = names.split_last() else {
+            // an entry for the root itself
+            if node.is_dir() {
+                self.root.meta = node.meta;
+            }
+            return Ok(());
+        };
+
+        let mut dir = &mut self.root;
+        for parent in parents {
+            let NodeKind::Dir(children) = &mut dir.kind else {
+                return Err(anyhow!("not a directory: {}", path.display()));
+            };
+            dir = children.entry(parent.clone()).or_insert_with(|| {
+                config.stats.add_entries(1);
+                config.stats.done_entries(1);
+                Node::implicit_dir()
+            });
+        }
+        let NodeKind::Dir(children) = &mut dir.kind else {
+            return Err(anyhow!("not a directory: {}", path.display()));
+        };
+        match children.get_mut(name) {
+            // a directory may be listed after its contents
+            Some(existing) if existing.is_dir() && node.is_dir() => existing.meta = node.meta,
+            Some(existing) => *existing = node,
+            None => {
+                config.stats.add_entries(1);
+                config.stats.done_entries(1);
+                children.insert(name.clone(), node);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill in the metadata that the source does not record of the
+    /// directories, such as the parents of entries it does not list,
+    /// from their first entry: its owner and modification time, and its
+    /// permissions with search permission wherever it is readable
+    pub fn fill_dir_meta(&mut self) {
+        fill_dir_meta(&mut self.root);
+    }
+
+    /// The metadata included by the flags that entries taking part in
+    /// the hash lack, as the name of each field and how to leave it out
+    pub fn missing_meta(&self, config: &Config) -> Vec<(&'static str, &'static str)> {
+        let mut missing = Vec::new();
+        missing_node_meta(config, &self.root, Path::new(""), &mut missing);
+        missing
+    }
+
+    /// Insert a hard link as a copy of the node it links to
+    pub fn insert_hard_link(&mut self, config: &Config, path: &Path, target: &Path) -> Result<()> {
+        let node = self
+            .lookup(target)
+            .ok_or_else(|| anyhow!("hard link to unknown entry: {}", target.display()))?
+            .clone();
+        self.insert(config, path, node)
+    }
+
+    /// Apply a container image layer on top of this tree, honouring
+    /// the OCI whiteout files (`.wh.NAME` and `.wh..wh..opq`)
+    pub fn overlay(&mut self, layer: &VirtualTree) {
+        overlay_node(&mut self.root, &layer.root);
+    }
+
+    /// Compute the digest of the tree, framed exactly as `hash_entry`
+    /// frames the equivalent tree on disk
+    pub fn digest(&self, config: &Config, path: &Path) -> Result<[u8; 32]> {
+        digest_node(config, &self.root, path, Path::new(""))?
+            .ok_or_else(|| anyhow!("Left out of the hash: {}", path.display()))
+    }
+
+    /// List the entries that differ between two trees
+    pub fn diff(&self, config: &Config, other: &VirtualTree) -> Result<Vec<Difference>> {
+        let mut out = Vec::new();
+        diff_node(
+            config,
+            &self.root,
+            &other.root,
+            Path::new("."),
+            Path::new(""),
+            &mut out,
+        )?;
+        Ok(out)
+    }
+}
+
+impl Default for VirtualTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn fill_dir_meta(node: &mut Node) {
+    let NodeKind::Dir(children) = &mut node.kind else {
+        return;
+    };
+    children.values_mut().for_each(fill_dir_meta);
+    let Some(first) = children.values().next().map(|child| &child.meta) else {
+        return;
+    };
+    let meta = &mut node.meta;
+    meta.is_dir = true;
+    meta.mode = meta.mode.or_else(|| {
+        let perm = first.mode? & 0o777;
+        Some(libc::S_IFDIR | perm | (perm & 0o444) >> 2)
+    });
+    meta.uid = meta.uid.or(first.uid);
+    meta.gid = meta.gid.or(first.gid);
+    if meta.mtime.is_none() {
+        meta.mtime = first.mtime;
+        meta.mtime_nsec = first.mtime_nsec;
+    }
+}
+
+fn missing_node_meta(
+    config: &Config,
+    node: &Node,
+    rel: &Path,
+    out: &mut Vec<(&'static str, &'static str)>,
+) {
+    if config.skips_entry(rel, node.file_kind(), &node.meta) {
+        return;
+    }
+    for field in missing_meta(config, &node.meta, rel) {
+        if !out.contains(&field) {
+            out.push(field);
+        }
+    }
+    if let NodeKind::Dir(children) = &node.kind {
+        for (name, child) in children {
+            missing_node_meta(config, child, &rel.join(name), out);
+        }
+    }
+}
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const WHITEOUT_OPAQUE: &str = ".wh..wh..opq";
+
+fn overlay_node(lower: &mut Node, upper: &Node) {
+    let (NodeKind::Dir(lower_children), NodeKind::Dir(upper_children)) =
+        (&mut lower.kind, &upper.kind)
+    else {
+        *lower = upper.clone();
+        return;
+    };
+    lower.meta = upper.meta.clone();
+
+    if upper_children.contains_key(OsStr::new(WHITEOUT_OPAQUE)) {
+        lower_children.clear();
+    }
+    for (name, child) in upper_children {
+        if name == WHITEOUT_OPAQUE {
+            continue;
+        } else if let Some(hidden) = name.as_bytes().strip_prefix(WHITEOUT_PREFIX.as_bytes()) {
+            lower_children.remove(OsStr::from_bytes(hidden));
+        } else if let Some(existing) = lower_children.get_mut(name) {
+            overlay_node(existing, child);
+        } else {
+            lower_children.insert(name.clone(), child.clone());
+        }
+    }
+}
+
+fn normal_components(path: &Path) -> Vec<OsString> {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_os_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Files found while scanning that may be waiting to be hashed
+const SCAN_QUEUE: usize = 1024;
+
+/// The pool listing trees apart from the hashing threads
+fn scan_pool(config: &Config) -> Result<rayon::ThreadPool> {
+    Ok(ThreadPoolBuilder::new()
+        .num_threads(config.scan_threads)
+        .thread_name(|index| format!("scan{}", index))
+        .build()?)
+}
+
+/// What to do with the content of the regular files found scanning
+#[derive(Clone, Copy)]
+enum Content<'a> {
+    Skip,
+    Hash,
+    /// Hand the files to be hashed to another pool
+    Queue(&'a SyncSender<FoundFile>),
+    /// Hash only the files listed, and list only the directories
+    /// listed, leaving the others empty
+    Changed(&'a BTreeSet<PathBuf>),
+}
+
+/// A regular file found scanning, to be hashed
+struct FoundFile {
+    path: PathBuf,
+    rel: PathBuf,
+    size: u64,
+}
+
+/// A regular file whose content is still to be read
+struct PendingFile<'a> {
+    path: PathBuf,
+    rel: PathBuf,
+    size: u64,
+    /// The device holding the file, 0 if unknown
+    dev: u64,
+    content: &'a mut Option<[u8; 32]>,
+}
+
+/// Collect the files below `node` in depth first name order, leaving
+/// out those left out of the hash
+fn collect_files<'a>(
+    config: &Config,
+    node: &'a mut Node,
+    path: &Path,
+    rel: &Path,
+    out: &mut Vec<PendingFile<'a>>,
+) {
+    match &mut node.kind {
+        NodeKind::Dir(children) => {
+            for (name, child) in children {
+                collect_files(config, child, &path.join(name), &rel.join(name), out);
+            }
+        }
+        NodeKind::File(content) if !config.skips_entry(rel, FileKind::File, &node.meta) => {
+            out.push(PendingFile {
+                path: path.to_path_buf(),
+                rel: rel.to_path_buf(),
+                size: node.meta.size.unwrap_or(0),
+                dev: node.meta.dev.unwrap_or(0),
+                content,
+            });
+        }
+        _ => {}
+    }
+}
+
+fn scan(config: &Config, path: &Path, rel: &Path, content: Content) -> Result<Node> {
+    config.cancel.check()?;
+    let stat = config.stats.time_stat(|| config.stat(path))?;
+    let _span = config.profile_span(rel, stat.kind, stat.meta.size.unwrap_or(0));
+    config.stats.count_kind(stat.kind);
+
+    let kind = match stat.kind {
+        FileKind::Dir if matches!(content, Content::Changed(changed) if !changed.contains(rel)) => {
+            NodeKind::Dir(BTreeMap::new())
+        }
+        FileKind::Dir => {
+            config.entry_started(rel);
+            let mut names = config.stats.time_stat(|| config.vfs.read_dir(path))?;
+            names.retain(|name| !config.is_excluded(name));
+            config.stats.add_entries(names.len() as u64);
+            let children = names
+                .into_par_iter()
+                .map(|name| {
+                    let node = scan(config, &path.join(&name), &rel.join(&name), content)?;
+                    Ok((name, node))
+                })
+                .collect::<Result<_>>()?;
+            NodeKind::Dir(children)
+        }
+        // left out of the hash, as hash_entry leaves them unread
+        FileKind::File if config.skips_entry(rel, FileKind::File, &stat.meta) => {
+            NodeKind::File(None)
+        }
+        FileKind::File => {
+            let size = stat.meta.size.unwrap_or(0);
+            config.stats.add_bytes(size);
+            match content {
+                Content::Hash => {
+                    config.entry_started(rel);
+                    NodeKind::File(Some(hash_file(config, path, size)?))
+                }
+                Content::Queue(queue) => {
+                    if config.include_file_content {
+                        let file = FoundFile {
+                            path: path.to_path_buf(),
+                            rel: rel.to_path_buf(),
+                            size,
+                        };
+                        // the receiver is gone once hashing failed
+                        queue.send(file).map_err(|_| anyhow!("Hashing stopped"))?;
+                    }
+                    NodeKind::File(None)
+                }
+                Content::Changed(changed)
+                    if config.include_file_content && changed.contains(rel) =>
+                {
+                    config.entry_started(rel);
+                    NodeKind::File(Some(hash_file(config, path, size)?))
+                }
+                Content::Skip | Content::Changed(_) => NodeKind::File(None),
+            }
+        }
+        FileKind::Symlink => NodeKind::Symlink(
+            config
+                .stats
+                .time_stat(|| config.vfs.read_link(path))?
+                .into_os_string()
+                .into_encoded_bytes(),
+        ),
+        FileKind::Device(rdev) => NodeKind::Device(rdev),
+        FileKind::Special => NodeKind::Special,
+    };
+    config.stats.done_entries(1);
+
+    Ok(Node {
+        meta: stat.meta,
+        kind,
+    })
+}
+
+/// The digest of `node`, or `None` if it is left out of the hash
+fn digest_node(config: &Config, node: &Node, path: &Path, rel: &Path) -> Result<Option<[u8; 32]>> {
+    let kind = node.file_kind();
+    if config.skips_entry(rel, kind, &node.meta) {
+        return Ok(None);
+    }
+    let meta = hash_meta_at(config, &node.meta, rel).with_context(|| path.display().to_string())?;
+    let mut hasher = config.hasher();
+    hasher.update(&entry_prefix(config, kind, &meta));
+
+    match &node.kind {
+        NodeKind::Dir(children) => {
+            let mut dir_hasher = config.hasher();
+            let mut entries = 0;
+            for (name, child) in children {
+                if let Some(digest) = digest_node(config, child, &path.join(name), &rel.join(name))?
+                {
+                    dir_hasher.update(&digest);
+                    entries += 1;
+                }
+            }
+            if config.skips_empty_dir(rel, entries) {
+                return Ok(None);
+            }
+            hasher.update(&dir_hasher.finalize());
+        }
+        NodeKind::File(content) => {
+            if let Some(content) = content {
+                config.record_file(path, rel, node.meta.size.unwrap_or(0), content);
+                hasher.update(content);
+            }
+        }
+        NodeKind::Symlink(target) => {
+            hasher.update(target);
+        }
+        NodeKind::Device(rdev) => {
+            hasher.update(&rdev.to_le_bytes());
+        }
+        NodeKind::Special => {}
+    }
+
+    let digest = hasher.finalize();
+    config.record_detail(rel, node.is_dir(), &digest);
+    Ok(Some(digest))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffKind {
+    OnlyLeft,
+    OnlyRight,
+    Type,
+    Metadata,
+    Content,
+}
+
+#[derive(Debug)]
+pub struct Difference {
+    pub path: PathBuf,
+    pub kind: DiffKind,
+}
+
+impl DiffKind {
+    pub fn description(self) -> &'static str {
+        match self {
+            DiffKind::OnlyLeft => "only in left",
+            DiffKind::OnlyRight => "only in right",
+            DiffKind::Type => "type differs",
+            DiffKind::Metadata => "metadata differs",
+            DiffKind::Content => "content differs",
+        }
+    }
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.kind.description())
+    }
+}
+
+/// Compare `left` and `right`, found at `rel` below the roots and
+/// listed as `path`
+fn diff_node(
+    config: &Config,
+    left: &Node,
+    right: &Node,
+    path: &Path,
+    rel: &Path,
+    out: &mut Vec<Difference>,
+) -> Result<()> {
+    let content_differs = match (&left.kind, &right.kind) {
+        (NodeKind::File(a), NodeKind::File(b)) => a != b,
+        (NodeKind::Symlink(a), NodeKind::Symlink(b)) => a != b,
+        (NodeKind::Device(a), NodeKind::Device(b)) => a != b,
+        (NodeKind::Dir(_), NodeKind::Dir(_)) | (NodeKind::Special, NodeKind::Special) => false,
+        _ => {
+            push(out, path, DiffKind::Type);
+            return Ok(());
+        }
+    };
+    let left_meta =
+        hash_meta_at(config, &left.meta, rel).with_context(|| path.display().to_string())?;
+    let right_meta =
+        hash_meta_at(config, &right.meta, rel).with_context(|| path.display().to_string())?;
+    if left_meta != right_meta {
+        push(out, path, DiffKind::Metadata);
+    }
+    if content_differs {
+        push(out, path, DiffKind::Content);
+    }
+
+    if let (NodeKind::Dir(a), NodeKind::Dir(b)) = (&left.kind, &right.kind) {
+        // entries left out of the hash are compared as absent
+        let included =
+            |(name, child): &(&OsString, &Node)| !left_out(config, child, &rel.join(name));
+        let mut a = a.iter().filter(included).peekable();
+        let mut b = b.iter().filter(included).peekable();
+        loop {
+            match (a.peek(), b.peek()) {
+                (Some((na, ca)), Some((nb, cb))) if na == nb => {
+                    diff_node(config, ca, cb, &path.join(na), &rel.join(na), out)?;
+                    a.next();
+                    b.next();
+                }
+                (Some((na, _)), Some((nb, _))) if na < nb => {
+                    push(out, &path.join(na), DiffKind::OnlyLeft);
+                    a.next();
+                }
+                (Some((na, _)), None) => {
+                    push(out, &path.join(na), DiffKind::OnlyLeft);
+                    a.next();
+                }
+                (_, Some((nb, _))) => {
+                    push(out, &path.join(nb), DiffKind::OnlyRight);
+                    b.next();
+                }
+                (None, None) => break,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether `node` at `rel` below the root is left out of the hash, as
+/// in [`digest_node`]
+fn left_out(config: &Config, node: &Node, rel: &Path) -> bool {
+    if config.skips_entry(rel, node.file_kind(), &node.meta) {
+        return true;
+    }
+    match &node.kind {
+        NodeKind::Dir(children) if config.skips_empty_dir(rel, 0) => children
+            .iter()
+            .all(|(name, child)| left_out(config, child, &rel.join(name))),
+        _ => false,
+    }
+}
+
+fn push(out: &mut Vec<Difference>, path: &Path, kind: DiffKind) {
+    out.push(Difference {
+        path: path.to_path_buf(),
+        kind,
+    });
+}
@@ -0,0 +1,288 @@
+use anyhow::{Result, anyhow};
+use filetime::FileTime;
+use serde::Serialize;
+use std::fs;
+use std::os::unix::fs::{PermissionsExt, symlink};
+use std::path::{Path, PathBuf};
+
+use fdsum::config::Config;
+
+/// Modification time given to every entry of the test trees
+pub const MTIME: i64 = 1_000_000_000;
+
+/// The content of a regular file of a test tree
+#[derive(Clone, Copy)]
+pub enum Content {
+    Text(&'static str),
+    /// N bytes, byte i being `i % 251`
+    Pattern(usize),
+}
+
+impl Content {
+    fn bytes(&self) -> Vec<u8> {
+        match self {
+            Content::Text(text) => text.as_bytes().to_vec(),
+            Content::Pattern(len) => (0..*len).map(|i| (i % 251) as u8).collect(),
+        }
+    }
+}
+
+/// An entry of a test tree by path relative to the root, parents
+/// listed before their entries
+#[derive(Clone, Copy)]
+pub enum TestEntry {
+    Dir(&'static str, u32),
+    File(&'static str, u32, Content),
+    Symlink(&'static str, &'static str),
+}
+
+impl TestEntry {
+    fn path(&self) -> &'static str {
+        match self {
+            TestEntry::Dir(path, _) | TestEntry::File(path, _, _) | TestEntry::Symlink(path, _) => {
+                path
+            }
+        }
+    }
+}
+
+/// A test tree and the digests it must have for each flags string.
+/// Owners and ctimes cannot be fixed, so only content, size, mode,
+/// mtime and names take part.
+pub struct TreeVectors {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub entries: &'static [TestEntry],
+    pub vectors: &'static [(&'static str, &'static str)],
+}
+
+pub const TREES: &[TreeVectors] = &[
+    TreeVectors {
+        name: "basic",
+        description: "Files, an empty file, a subdirectory and a symlink",
+        entries: &[
+            TestEntry::Dir("", 0o755),
+            TestEntry::File("a.txt", 0o644, Content::Text("hello\n")),
+            TestEntry::File("empty", 0o600, Content::Text("")),
+            TestEntry::Dir("sub", 0o750),
+            TestEntry::File("sub/b.bin", 0o640, Content::Pattern(300 * 1024)),
+            TestEntry::Symlink("sub/link", "../a.txt"),
+        ],
+        vectors: &[
+            (
+                "v1:sha256:",
+                "9d16621af3a2281c501421e1ca0b35fd0879003458b7a5bca2efca7c1950c511",
+            ),
+            (
+                "v1:sha256:c",
+                "fddf2d1fc0d0f06db8d90d27d9a8ef65aace7f789411acc720eb97aa2affe37b",
+            ),
+            (
+                "v1:sha256:cspm",
+                "cf62d958abef8abecc98f2af9318556b4a950598771dd1bb4d8227e6c27d53ae",
+            ),
+            (
+                "v2:sha256:c:framing=2",
+                "f9e1aeb2a723b57feb4d798a9b6bbd106d5a3a6c064ecfb23d89397a9c79c909",
+            ),
+            (
+                "v2:sha256:cspm:framing=2",
+                "57ce06499dfa05e5057a9643b2495b38501ca7be96e6ef46e3015f63b428e0fa",
+            ),
+            (
+                "v1:blake3:",
+                "29491de42917c2a975b1953c58760e8060b8df4f9c328a901e499c4fb3f41991",
+            ),
+            (
+                "v1:blake3:c",
+                "7df16ee6874707c10d4c83a5f5043b78138af9a14acfdd324207c6a58e3d9793",
+            ),
+            (
+                "v1:blake3:cspm",
+                "53a1ebfa8f1a9581bfcbc76caf5433040afe6932b7c5c8cff68a01a660432d86",
+            ),
+        ],
+    },
+    TreeVectors {
+        name: "order",
+        description: "Names whose byte order differs from locale and natural order",
+        entries: &[
+            TestEntry::Dir("", 0o755),
+            TestEntry::File("10", 0o644, Content::Text("10")),
+            TestEntry::File("9", 0o644, Content::Text("9")),
+            TestEntry::File("B", 0o644, Content::Text("B")),
+            TestEntry::File("_", 0o644, Content::Text("_")),
+            TestEntry::File("a", 0o644, Content::Text("a")),
+            TestEntry::File("a-b", 0o644, Content::Text("a-b")),
+            TestEntry::File("a.b", 0o644, Content::Text("a.b")),
+            TestEntry::Dir("ab", 0o755),
+            TestEntry::File("ab/x", 0o644, Content::Text("x")),
+            TestEntry::File("\u{e4}", 0o644, Content::Text("\u{e4}")),
+            TestEntry::File("\u{e9}t\u{e9}", 0o644, Content::Text("\u{e9}t\u{e9}")),
+        ],
+        vectors: &[
+            (
+                "v1:sha256:c",
+                "7028c05262d8cf5094fe796c8a8238cbd900cf51b72dec4788b534c5b48b0344",
+            ),
+            (
+                "v2:sha256:cn",
+                "652e9eeea1cc91182c112ab749498d7e543cc2e6164091482d2d11daf53fc702",
+            ),
+            (
+                "v2:sha256:cspmn",
+                "7870782e5655b3352c46ea11e00a741ffe91acfdc62f80890965b2d3273e26ac",
+            ),
+            (
+                "v2:sha256:cspmn:framing=2",
+                "52f804490ea71d5490151f3a4dd60ed3341d5d965f233fbf5fb089ce8541ad95",
+            ),
+        ],
+    },
+];
+
+/// Create the entries of `tree` below `root`, which must not exist
+pub fn create(tree: &TreeVectors, root: &Path) -> Result<()> {
+    if root.symlink_metadata().is_ok() {
+        return Err(anyhow!("Already exists: {}", root.display()));
+    }
+    for entry in tree.entries {
+        let path = root.join(entry.path());
+        match entry {
+            TestEntry::Dir(_, mode) => {
+                fs::create_dir(&path)?;
+                fs::set_permissions(&path, fs::Permissions::from_mode(*mode))?;
+            }
+            TestEntry::File(_, mode, content) => {
+                fs::write(&path, content.bytes())?;
+                fs::set_permissions(&path, fs::Permissions::from_mode(*mode))?;
+            }
+            TestEntry::Symlink(_, target) => symlink(target, &path)?,
+        }
+    }
+    let mtime = FileTime::from_unix_time(MTIME, 0);
+    // directories last, adding entries changes their mtime
+    for entry in tree.entries.iter().rev() {
+        filetime::set_symlink_file_times(root.join(entry.path()), mtime, mtime)?;
+    }
+    Ok(())
+}
+
+/// A test tree in a temporary directory, removed on drop
+pub struct TestTree(PathBuf);
+
+impl TestTree {
+    pub fn create(tree: &TreeVectors) -> Result<Self> {
+        let root = std::env::temp_dir().join(format!(
+            "fdsum-selftest-{}-{}",
+            std::process::id(),
+            tree.name
+        ));
+        create(tree, &root)?;
+        Ok(Self(root))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestTree {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[derive(Serialize)]
+struct VectorsJson {
+    mtime: i64,
+    trees: Vec<TreeJson>,
+}
+
+#[derive(Serialize)]
+struct TreeJson {
+    name: &'static str,
+    description: &'static str,
+    entries: Vec<EntryJson>,
+    vectors: Vec<VectorJson>,
+}
+
+#[derive(Serialize)]
+struct EntryJson {
+    path: &'static str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<&'static str>,
+    /// Length of content in which byte i is `i % 251`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pattern: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<&'static str>,
+}
+
+impl From<&TestEntry> for EntryJson {
+    fn from(entry: &TestEntry) -> Self {
+        let mut out = Self {
+            path: entry.path(),
+            kind: "d",
+            mode: None,
+            content: None,
+            pattern: None,
+            target: None,
+        };
+        match entry {
+            TestEntry::Dir(_, mode) => out.mode = Some(format!("{:04o}", mode)),
+            TestEntry::File(_, mode, content) => {
+                out.kind = "f";
+                out.mode = Some(format!("{:04o}", mode));
+                match content {
+                    Content::Text(text) => out.content = Some(text),
+                    Content::Pattern(len) => out.pattern = Some(*len),
+                }
+            }
+            TestEntry::Symlink(_, target) => {
+                out.kind = "l";
+                out.target = Some(target);
+            }
+        }
+        out
+    }
+}
+
+#[derive(Serialize)]
+struct VectorJson {
+    flags: &'static str,
+    hash: &'static str,
+}
+
+/// Print the test trees and their digests as JSON and, if `dir` is
+/// given, create each tree below it in a directory named after the tree
+pub fn run(config: &Config, dir: Option<&Path>) -> Result<()> {
+    if let Some(dir) = dir {
+        fs::create_dir_all(dir)?;
+        for tree in TREES {
+            create(tree, &dir.join(tree.name))?;
+        }
+    }
+    let json = VectorsJson {
+        mtime: MTIME,
+        trees: TREES
+            .iter()
+            .map(|tree| TreeJson {
+                name: tree.name,
+                description: tree.description,
+                entries: tree.entries.iter().map(EntryJson::from).collect(),
+                vectors: tree
+                    .vectors
+                    .iter()
+                    .map(|&(flags, hash)| VectorJson { flags, hash })
+                    .collect(),
+            })
+            .collect(),
+    };
+    println!("{}", config.to_json(&json)?);
+    Ok(())
+}
@@ -0,0 +1,211 @@
+use anyhow::{Context, Result, anyhow};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+
+use fdsum::config::Config;
+use fdsum::error::ErrorInfo;
+use fdsum::job;
+
+use crate::color;
+
+/// The outcome of verifying one manifest
+#[derive(Debug, Serialize)]
+struct Outcome {
+    manifest: PathBuf,
+    root: Option<PathBuf>,
+    /// "ok", "mismatch" or "error"
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<ErrorInfo>,
+    elapsed_seconds: f64,
+}
+
+/// The manifests given, with directories standing for the `.json`
+/// files in them, in name order
+fn find_manifests(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut out = Vec::new();
+    for path in paths {
+        if !path.is_dir() {
+            out.push(path.clone());
+            continue;
+        }
+        let mut found: Vec<PathBuf> = std::fs::read_dir(path)
+            .with_context(|| path.display().to_string())?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .filter(|file| file.extension().is_some_and(|ext| ext == "json") && file.is_file())
+            .collect();
+        found.sort();
+        out.extend(found);
+    }
+    Ok(out)
+}
+
+/// Strip the quotes of a CSV field
+fn unquote(field: &str) -> String {
+    let field = field.trim();
+    match field.strip_prefix('"').and_then(|f| f.strip_suffix('"')) {
+        Some(inner) => inner.replace("\"\"", "\""),
+        None => field.to_string(),
+    }
+}
+
+/// Read `manifest,root` lines. A header line naming the columns and
+/// lines starting with `#` are skipped.
+fn read_roots(path: &Path) -> Result<BTreeMap<String, PathBuf>> {
+    let text = std::fs::read_to_string(path).with_context(|| path.display().to_string())?;
+    let mut roots = BTreeMap::new();
+    for line in text.lines() {
+        if line.starts_with('#') || line.trim().is_empty() || line.trim() == "manifest,root" {
+            continue;
+        }
+        // manifests named with a comma must be quoted
+        let split = if line.trim_start().starts_with('"') {
+            line.find("\",").map(|i| i + 1)
+        } else {
+            line.find(',')
+        };
+        let i =
+            split.ok_or_else(|| anyhow!("{}: expected manifest,root: {}", path.display(), line))?;
+        roots.insert(unquote(&line[..i]), PathBuf::from(unquote(&line[i + 1..])));
+    }
+    Ok(roots)
+}
+
+/// The root `manifest` is mapped to, by its path as given or its file
+/// name
+fn mapped_root<'a>(roots: &'a BTreeMap<String, PathBuf>, manifest: &Path) -> Option<&'a PathBuf> {
+    roots
+        .get(manifest.to_string_lossy().as_ref())
+        .or_else(|| roots.get(manifest.file_name()?.to_string_lossy().as_ref()))
+}
+
+/// Verify `manifest` against `root`, or the tree named in it. Returns
+/// the status and the tree verified.
+fn verify_one(
+    config: &Config,
+    manifest: &Path,
+    root: Option<&PathBuf>,
+) -> Result<(&'static str, PathBuf)> {
+    let mut config = config.clone();
+    config.path = root.cloned();
+    // digests recorded are of this run only
+    config.detail_digests = Arc::default();
+    config.file_digests = Arc::default();
+    config.file_chunks = Arc::default();
+    config.skipped = Arc::default();
+    let reference = job::read_manifest(&manifest.to_string_lossy())?;
+    job::apply_manifest(&mut config, &reference)?;
+    let tree = config
+        .path
+        .clone()
+        .unwrap_or_else(|| reference.name.clone());
+    let _locks = job::lock_target(&config).with_context(|| tree.display().to_string())?;
+    let result = job::hash_target(&config).with_context(|| tree.display().to_string())?;
+    if let Some(file) = &config.append_results {
+        crate::history::append(file, &result)?;
+    }
+    let status = if result.hash == reference.hash {
+        "ok"
+    } else {
+        "mismatch"
+    };
+    Ok((status, result.name))
+}
+
+/// Verify each of `manifests` against the tree named in it, or the
+/// root it is mapped to in `roots_from`, and summarize the outcomes
+pub fn run(config: &Config, manifests: &[PathBuf], roots_from: Option<&Path>) -> Result<()> {
+    let manifests = find_manifests(manifests)?;
+    if manifests.is_empty() {
+        return Err(anyhow!("No manifests found"));
+    }
+    let roots = roots_from.map(read_roots).transpose()?.unwrap_or_default();
+    for name in roots.keys() {
+        let names = |manifest: &PathBuf| {
+            manifest.to_string_lossy() == *name
+                || manifest
+                    .file_name()
+                    .is_some_and(|file| file.to_string_lossy() == *name)
+        };
+        if !manifests.iter().any(names) {
+            return Err(anyhow!("No manifest {} to verify", name));
+        }
+    }
+
+    // one entry per tree is expected already
+    config
+        .stats
+        .add_entries(manifests.len().saturating_sub(1) as u64);
+    let mut outcomes = Vec::with_capacity(manifests.len());
+    for manifest in &manifests {
+        let root = mapped_root(&roots, manifest);
+        let start = Instant::now();
+        let (status, root, error) = match verify_one(config, manifest, root) {
+            Ok((status, tree)) => (status, Some(tree), None),
+            Err(err) => ("error", root.cloned(), Some(err)),
+        };
+        outcomes.push(Outcome {
+            manifest: manifest.clone(),
+            root,
+            status,
+            error: error.as_ref().map(|err| format!("{:#}", err)),
+            errors: error.iter().map(ErrorInfo::from_error).collect(),
+            elapsed_seconds: (start.elapsed().as_secs_f64() * 100.0).round() / 100.0,
+        });
+    }
+
+    if config.json {
+        println!("{}", config.to_json(&outcomes)?);
+    } else {
+        print_table(&outcomes);
+    }
+    let failed = outcomes.iter().filter(|o| o.status != "ok").count();
+    if failed > 0 {
+        Err(anyhow!(
+            "{} of {} manifests did not verify",
+            failed,
+            outcomes.len()
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn print_table(outcomes: &[Outcome]) {
+    let manifest_width = outcomes
+        .iter()
+        .map(|o| o.manifest.display().to_string().len())
+        .chain([8])
+        .max()
+        .unwrap_or_default();
+    println!(
+        "{:<manifest_width$} {:<8} {:>8}  root",
+        "manifest", "status", "seconds"
+    );
+    for outcome in outcomes {
+        let status = match outcome.status {
+            "ok" => color::ok("Ok"),
+            "mismatch" => color::mismatch("Mismatch"),
+            _ => color::mismatch("Error"),
+        };
+        let root = match (&outcome.root, &outcome.error) {
+            (_, Some(error)) => error.clone(),
+            (Some(root), None) => root.display().to_string(),
+            (None, None) => String::new(),
+        };
+        println!(
+            "{:<manifest_width$} {:<8} {:>8.2}  {}",
+            outcome.manifest.display(),
+            status,
+            outcome.elapsed_seconds,
+            root
+        );
+    }
+}
@@ -0,0 +1,249 @@
+use anyhow::Result;
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::fs;
+use std::io::{BufReader, Read};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use crate::acl;
+use crate::error::{ErrorCode, FsError};
+use crate::hash::EntryMeta;
+
+/// The type of an entry, with the device number for devices
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    Device(u64),
+    Special,
+}
+
+/// What `symlink_metadata` reports about an entry
+#[derive(Debug, Clone)]
+pub struct Stat {
+    pub kind: FileKind,
+    pub meta: EntryMeta,
+}
+
+/// The filesystem operations hashing needs. Paths are those given to
+/// the job, joined with the names returned by `read_dir`.
+pub trait Vfs: Debug + Send + Sync {
+    /// The names of the entries of a directory, in any order
+    fn read_dir(&self, path: &Path) -> Result<Vec<OsString>>;
+
+    /// The metadata of an entry, not following symlinks
+    fn symlink_metadata(&self, path: &Path) -> Result<Stat>;
+
+    /// Open a regular file for reading
+    fn open(&self, path: &Path) -> Result<Box<dyn Read + Send>>;
+
+    fn read_link(&self, path: &Path) -> Result<PathBuf>;
+
+    /// The [`ATTRIBUTES`] set on a regular file or directory, or `None`
+    /// if the source cannot tell them
+    fn attributes(&self, _path: &Path) -> Result<Option<u32>> {
+        Ok(None)
+    }
+
+    /// The project ID of a regular file or directory, as used by
+    /// project quotas of XFS and ext4, 0 if the filesystem has none,
+    /// or `None` if the source cannot tell it
+    fn project(&self, _path: &Path) -> Result<Option<u32>> {
+        Ok(None)
+    }
+
+    /// The file capabilities of a regular file, empty if it has none,
+    /// or `None` if the source cannot tell them
+    fn capabilities(&self, _path: &Path) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// The NFSv4 ACL of a regular file or directory in canonical form
+    /// (see [`acl::canonical_nfs4`]), empty if it has none, or `None`
+    /// if the source cannot tell it
+    fn nfs4_acl(&self, _path: &Path) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+}
+
+/// The attributes hashed: immutable, append-only and nodump, as
+/// FS_IOC_GETFLAGS reports them. Others differ between filesystems
+/// (e.g. extents or compression).
+pub const ATTRIBUTES: u32 = 0x10 | 0x20 | 0x40;
+
+/// The filesystem of the operating system
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OsFs;
+
+impl Vfs for OsFs {
+    fn read_dir(&self, path: &Path) -> Result<Vec<OsString>> {
+        let mut names = Vec::new();
+        let error = |e| FsError::new(ErrorCode::ReadDir, path, e);
+        for entry in fs::read_dir(path).map_err(error)? {
+            names.push(entry.map_err(error)?.file_name());
+        }
+        Ok(names)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> Result<Stat> {
+        let meta =
+            fs::symlink_metadata(path).map_err(|e| FsError::new(ErrorCode::Stat, path, e))?;
+        let filetype = meta.file_type();
+        let kind = if filetype.is_dir() {
+            FileKind::Dir
+        } else if filetype.is_file() {
+            FileKind::File
+        } else if filetype.is_symlink() {
+            FileKind::Symlink
+        } else if filetype.is_block_device() || filetype.is_char_device() {
+            FileKind::Device(meta.rdev())
+        } else if filetype.is_fifo() || filetype.is_socket() {
+            FileKind::Special
+        } else {
+            return Err(FsError {
+                code: ErrorCode::Type,
+                path: path.to_path_buf(),
+                source: None,
+            }
+            .into());
+        };
+        Ok(Stat {
+            kind,
+            meta: EntryMeta::from(&meta),
+        })
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn Read + Send>> {
+        let file = fs::File::open(path).map_err(|e| FsError::new(ErrorCode::Open, path, e))?;
+        Ok(Box::new(BufReader::new(file)))
+    }
+
+    fn read_link(&self, path: &Path) -> Result<PathBuf> {
+        Ok(fs::read_link(path).map_err(|e| FsError::new(ErrorCode::ReadLink, path, e))?)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn attributes(&self, path: &Path) -> Result<Option<u32>> {
+        use std::os::fd::AsRawFd;
+
+        // _IOR('f', 1, long)
+        const FS_IOC_GETFLAGS: libc::c_ulong = 0x8008_6601;
+        let file = open_entry(path)?;
+        let mut flags: libc::c_int = 0;
+        // SAFETY: the kernel writes an int to flags
+        if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_GETFLAGS as _, &mut flags) } != 0 {
+            let err = std::io::Error::last_os_error();
+            return match err.raw_os_error() {
+                // no attributes on this filesystem
+                Some(libc::ENOTTY | libc::EOPNOTSUPP | libc::EINVAL) => Ok(Some(0)),
+                _ => Err(FsError::new(ErrorCode::Stat, path, err).into()),
+            };
+        }
+        Ok(Some(flags as u32 & ATTRIBUTES))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn project(&self, path: &Path) -> Result<Option<u32>> {
+        use std::os::fd::AsRawFd;
+
+        // struct fsxattr of linux/fs.h
+        #[repr(C)]
+        #[derive(Default)]
+        struct FsXattr {
+            xflags: u32,
+            extsize: u32,
+            nextents: u32,
+            projid: u32,
+            cowextsize: u32,
+            pad: [u8; 8],
+        }
+        // _IOR('X', 31, struct fsxattr)
+        const FS_IOC_FSGETXATTR: libc::c_ulong = 0x801c_581f;
+        let file = open_entry(path)?;
+        let mut attr = FsXattr::default();
+        // SAFETY: the kernel writes a struct fsxattr to attr
+        if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FSGETXATTR as _, &mut attr) } != 0 {
+            let err = std::io::Error::last_os_error();
+            return match err.raw_os_error() {
+                // no project IDs on this filesystem
+                Some(libc::ENOTTY | libc::EOPNOTSUPP | libc::EINVAL) => Ok(Some(0)),
+                _ => Err(FsError::new(ErrorCode::Stat, path, err).into()),
+            };
+        }
+        Ok(Some(attr.projid))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn capabilities(&self, path: &Path) -> Result<Option<Vec<u8>>> {
+        Ok(Some(
+            xattr(path, "security.capability")?.unwrap_or_default(),
+        ))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn nfs4_acl(&self, path: &Path) -> Result<Option<Vec<u8>>> {
+        let Some(raw) = xattr(path, acl::NFS4_ACL_XATTR)? else {
+            return Ok(Some(Vec::new()));
+        };
+        let acl = acl::canonical_nfs4(&raw).map_err(|e| e.context(path.display().to_string()))?;
+        Ok(Some(acl))
+    }
+}
+
+/// Open the regular file or directory at `path` for an ioctl, without
+/// following symlinks or blocking on FIFOs replaced meanwhile
+#[cfg(target_os = "linux")]
+fn open_entry(path: &Path) -> Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    Ok(fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK | libc::O_NOFOLLOW)
+        .open(path)
+        .map_err(|e| FsError::new(ErrorCode::Open, path, e))?)
+}
+
+/// The value of the extended attribute `name` of the entry at `path`,
+/// not following symlinks, or `None` if it is not set or the
+/// filesystem has no extended attributes
+#[cfg(target_os = "linux")]
+fn xattr(path: &Path, name: &str) -> Result<Option<Vec<u8>>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path_c = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let name = std::ffi::CString::new(name)?;
+    let mut value: Vec<u8> = Vec::new();
+    loop {
+        // SAFETY: both names are NUL-terminated and value holds its
+        // length; a length of 0 asks for the size of the value
+        let len = unsafe {
+            libc::lgetxattr(
+                path_c.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr().cast(),
+                value.len(),
+            )
+        };
+        if len < 0 {
+            let err = std::io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::ENODATA | libc::EOPNOTSUPP) => Ok(None),
+                // grown since its size was asked
+                Some(libc::ERANGE) => {
+                    value.clear();
+                    continue;
+                }
+                _ => Err(FsError::new(ErrorCode::Stat, path, err).into()),
+            };
+        }
+        let len = len as usize;
+        if value.is_empty() && len > 0 {
+            value.resize(len, 0);
+            continue;
+        }
+        value.truncate(len);
+        return Ok(Some(value));
+    }
+}
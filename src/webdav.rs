@@ -0,0 +1,276 @@
+use anyhow::{Context, Result, anyhow};
+use base64::Engine;
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::hash::{EntryMeta, hash_stream};
+use crate::tree::{Node, NodeKind, VirtualTree};
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:propfind xmlns:D="DAV:"><D:prop>
+<D:resourcetype/><D:getcontentlength/><D:getlastmodified/>
+</D:prop></D:propfind>"#;
+
+/// A WebDAV collection given as `http(s)://[user:password@]host/path`
+#[derive(Debug, Clone)]
+pub struct DavUrl {
+    /// Scheme, host and port
+    origin: String,
+    /// The percent-encoded path of the collection, ending in `/`
+    path: String,
+    authorization: Option<String>,
+}
+
+impl DavUrl {
+    pub fn parse(path: &Path) -> Option<Self> {
+        let url = path.to_str()?;
+        let (scheme, rest) = url.split_once("://")?;
+        if scheme != "http" && scheme != "https" {
+            return None;
+        }
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        let (userinfo, host) = match authority.rsplit_once('@') {
+            Some((userinfo, host)) => (Some(userinfo), host),
+            None => (None, authority),
+        };
+        let authorization = userinfo.map(|userinfo| {
+            let credentials = percent_decode(userinfo);
+            format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD.encode(credentials)
+            )
+        });
+
+        let mut path = path.to_string();
+        if !path.ends_with('/') {
+            path.push('/');
+        }
+        Some(Self {
+            origin: format!("{}://{}", scheme, host),
+            path,
+            authorization,
+        })
+    }
+}
+
+impl std::fmt::Display for DavUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", self.origin, self.path)
+    }
+}
+
+struct Resource {
+    /// Percent-encoded path on the server
+    href: String,
+    is_collection: bool,
+    meta: EntryMeta,
+}
+
+fn percent_decode(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+struct Client {
+    agent: ureq::Agent,
+    url: DavUrl,
+}
+
+impl Client {
+    fn new(url: &DavUrl) -> Self {
+        let agent = ureq::Agent::config_builder()
+            .allow_non_standard_methods(true)
+            .build()
+            .new_agent();
+        Self {
+            agent,
+            url: url.clone(),
+        }
+    }
+
+    fn request(&self, method: &str, href: &str) -> ureq::http::request::Builder {
+        let mut request = ureq::http::Request::builder()
+            .method(method)
+            .uri(format!("{}{}", self.url.origin, href));
+        if let Some(authorization) = &self.url.authorization {
+            request = request.header("Authorization", authorization);
+        }
+        request
+    }
+
+    /// List a collection and its members with PROPFIND
+    fn propfind(&self, href: &str) -> Result<Vec<Resource>> {
+        let request = self
+            .request("PROPFIND", href)
+            .header("Depth", "1")
+            .header("Content-Type", "application/xml; charset=utf-8")
+            .body(PROPFIND_BODY)?;
+        let body = self
+            .agent
+            .run(request)
+            .with_context(|| format!("PROPFIND {}{}", self.url.origin, href))?
+            .body_mut()
+            .read_to_string()?;
+
+        let doc = roxmltree::Document::parse(&body).context("Malformed PROPFIND response")?;
+        let dav = |name| ("DAV:", name);
+        let mut resources = Vec::new();
+        for response in doc
+            .descendants()
+            .filter(|n| n.has_tag_name(dav("response")))
+        {
+            let Some(href) = response
+                .children()
+                .find(|n| n.has_tag_name(dav("href")))
+                .and_then(|n| n.text())
+            else {
+                continue;
+            };
+            // hrefs may be absolute URLs or absolute paths
+            let href = match href.split_once("://") {
+                Some((_, rest)) => rest.find('/').map_or("/", |i| &rest[i..]),
+                None => href,
+            };
+
+            let prop = |name| {
+                response
+                    .descendants()
+                    .filter(|n| n.has_tag_name(dav("propstat")))
+                    .filter(|propstat| {
+                        propstat
+                            .descendants()
+                            .find(|n| n.has_tag_name(dav("status")))
+                            .and_then(|n| n.text())
+                            .is_none_or(|status| status.contains(" 200 "))
+                    })
+                    .flat_map(|propstat| propstat.descendants())
+                    .find(|n| n.has_tag_name(dav(name)))
+            };
+            let is_collection = prop("resourcetype")
+                .is_some_and(|n| n.children().any(|c| c.has_tag_name(dav("collection"))));
+            let mtime = prop("getlastmodified")
+                .and_then(|n| n.text())
+                .and_then(|t| chrono::DateTime::parse_from_rfc2822(t.trim()).ok())
+                .map(|t| t.timestamp());
+            let size = prop("getcontentlength")
+                .and_then(|n| n.text())
+                .and_then(|t| t.trim().parse().ok());
+
+            resources.push(Resource {
+                href: href.to_string(),
+                is_collection,
+                meta: EntryMeta {
+                    is_file: !is_collection,
+                    is_dir: is_collection,
+                    size: if is_collection { None } else { size },
+                    mtime,
+                    ..Default::default()
+                },
+            });
+        }
+        Ok(resources)
+    }
+
+    /// Recursively list the collection at `href`, including itself
+    fn walk(&self, href: &str) -> Result<Vec<Resource>> {
+        let same = |other: &str| {
+            percent_decode(other.trim_end_matches('/'))
+                == percent_decode(href.trim_end_matches('/'))
+        };
+        let (mut out, members): (Vec<_>, Vec<_>) = self
+            .propfind(href)?
+            .into_iter()
+            .partition(|r| same(&r.href));
+        let (collections, files): (Vec<_>, Vec<_>) =
+            members.into_iter().partition(|r| r.is_collection);
+
+        let nested = collections
+            .par_iter()
+            .map(|c| self.walk(&c.href))
+            .collect::<Result<Vec<_>>>()?;
+        out.extend(files);
+        out.extend(nested.into_iter().flatten());
+        Ok(out)
+    }
+
+    fn get(&self, config: &Config, href: &str) -> Result<[u8; 32]> {
+        let request = self.request("GET", href).body(())?;
+        let mut response = self
+            .agent
+            .run(request)
+            .with_context(|| format!("GET {}{}", self.url.origin, href))?;
+        hash_stream(config, &mut response.body_mut().as_reader())
+    }
+}
+
+/// Load a WebDAV collection into a virtual tree. Only sizes and
+/// modification times are available from the server.
+pub fn read_url(config: &Config, url: &DavUrl) -> Result<VirtualTree> {
+    let client = Client::new(url);
+    let resources = client.walk(&url.path)?;
+    if config.include_file_content {
+        config
+            .stats
+            .add_bytes(resources.iter().filter_map(|r| r.meta.size).sum());
+    }
+
+    let nodes = resources
+        .par_iter()
+        .map(|resource| {
+            let kind = if resource.is_collection {
+                NodeKind::Dir(BTreeMap::new())
+            } else if config.include_file_content {
+                NodeKind::File(Some(client.get(config, &resource.href)?))
+            } else {
+                NodeKind::File(None)
+            };
+            Ok(Node {
+                meta: resource.meta.clone(),
+                kind,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let root = percent_decode(&url.path);
+    let mut tree = VirtualTree::new();
+    for (resource, node) in resources.iter().zip(nodes) {
+        let href = percent_decode(&resource.href);
+        let name = href
+            .strip_prefix(root.as_slice())
+            .or_else(|| href.strip_prefix(&root[..root.len() - 1]))
+            .ok_or_else(|| anyhow!("resource outside of {}: {}", url, resource.href))?;
+        let name = PathBuf::from(OsString::from_vec(name.to_vec()));
+        tree.insert(config, &name, node)?;
+    }
+    config.stats.done_entries(1);
+    Ok(tree)
+}
+
+pub fn hash_url(config: &Config, url: &DavUrl) -> Result<[u8; 32]> {
+    read_url(config, url)?.digest(config, Path::new(&url.to_string()))
+}
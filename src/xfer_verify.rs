@@ -0,0 +1,88 @@
+use anyhow::{Context, Result, anyhow};
+use std::path::Path;
+use std::sync::Arc;
+
+use fdsum::config::{Config, HashResultJson};
+use fdsum::job::{self, Verification};
+use fdsum::remote::{self, Remote};
+use fdsum::tree::{DiffKind, Difference};
+
+use crate::color;
+
+/// Hash `source` and the tree at `dest` on another host, compare them
+/// entry by entry and print the entries that differ
+pub fn run(config: &Config, source: &Path, dest: &str) -> Result<()> {
+    let remote = Remote::parse(dest)
+        .ok_or_else(|| anyhow!("Not a tree on another host ([user@]host:/path): {}", dest))?;
+    let mut config = config.clone();
+    // per entry unless limited with --detail
+    if config.detail_depth == 0 {
+        config.detail_depth = usize::MAX;
+    }
+    let mut remote_config = config.clone();
+    // digests recorded are of the local tree only
+    remote_config.detail_digests = Arc::default();
+    remote_config.file_digests = Arc::default();
+    remote_config.file_chunks = Arc::default();
+    remote_config.skipped = Arc::default();
+
+    let _lock = job::lock_root(&config, source)?;
+    let (reference, result) = std::thread::scope(|scope| {
+        let remote = scope.spawn(|| remote::hash_remote(&remote_config, &remote));
+        let local = job::hash_path(&config, source)
+            .map(|hash| HashResultJson::from_result(&config, source.to_path_buf(), &hash))
+            .with_context(|| source.display().to_string());
+        let remote = remote.join().expect("the remote hash does not panic");
+        (local, remote.with_context(|| dest.to_string()))
+    });
+    let verification = Verification {
+        reference: reference?,
+        result: result?,
+    };
+
+    let status = if verification.is_match() {
+        "ok"
+    } else {
+        "mismatch"
+    };
+    let mut changed = verification.changed_entries();
+    if config.json {
+        println!("{}", config.to_json(&verification.report(status))?);
+    } else if verification.is_match() {
+        println!("{} -> {}: {}", source.display(), dest, color::ok("Ok"));
+    } else {
+        println!(
+            "{} -> {}: {}",
+            source.display(),
+            dest,
+            color::mismatch("Mismatch")
+        );
+        for difference in &changed {
+            let change = match difference.kind {
+                DiffKind::OnlyLeft => format!("only in {}", source.display()),
+                DiffKind::OnlyRight => format!("only in {}", dest),
+                kind => kind.description().to_string(),
+            };
+            println!(
+                "  {}: {}",
+                difference.path.display(),
+                color::change(difference.kind, change)
+            );
+        }
+    }
+    if config.lists_changed() {
+        if !verification.is_match() && changed.is_empty() {
+            // nothing recorded to narrow it down
+            changed.push(Difference {
+                path: ".".into(),
+                kind: DiffKind::Content,
+            });
+        }
+        crate::write_changed_files(&config, &changed)?;
+    }
+    if verification.is_match() {
+        Ok(())
+    } else {
+        Err(anyhow!("Checksums did not match"))
+    }
+}